@@ -1,23 +1,43 @@
-use super::bin_encoder::{MusicBinHeader, MusicTagIdentifiers};
+use super::bin_encoder::{
+    encoded_elements_count, BinVersion, DivisionsHeader, ExtendedTagIdentifiers, MusicBinHeader, MusicTagIdentifiers,
+    PartTableHeader, EXTENDED_ELEMENT_ESCAPE, MUSIC_ELEMENT_LENGTH,
+};
 use crate::error;
 use crate::ir::notation::{
-    MeasureInitializer, MeasureMetaData, NoteData, NumericPitchRest, Tempo, TupletData,
+    Arpeggiate, BarStyle, Chord, ClefChangeData, HarmonyData, MeasureInitializer, MeasureMetaData, NoteData, NoteSize, Notehead,
+    NumericPitchRest, PercussionData, Placement, RhythmType, SlurNumber, Tempo, Tremolo, TupletData,
 };
-use crate::ir::MusicElement;
+use crate::ir::{MusicElement, PartName};
 use io::Read;
 use log::error;
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
 use nom::bits::bits;
 use nom::bits::streaming::take as take_bits;
-use nom::bytes::complete::take as take_bytes;
-use nom::combinator::all_consuming;
+use nom::bytes::complete::{tag, take as take_bytes};
+use nom::combinator::{all_consuming, map_res, opt};
 use nom::error::{Error, ErrorKind};
-use nom::multi::{count, many0};
+use nom::multi::{count, length_count, length_data, many0};
+use nom::number::complete::{le_u32, u8 as le_u8};
 use nom::sequence::tuple;
 use nom::{Err, IResult, Needed};
 use num_traits::FromPrimitive;
+#[cfg(feature = "mmap")]
 use std::fs::File;
 use std::io;
-use std::io::BufReader;
+
+/// Converts a raw bit field to the value it encodes, logging which field held an out of range value
+/// so corrupt files can be diagnosed
+fn decode_field<'a, T: FromPrimitive>(
+    input: &'a [u8],
+    field: &str,
+    raw: u8,
+) -> Result<T, Err<Error<&'a [u8]>>> {
+    T::from_u8(raw).ok_or_else(|| {
+        error!("invalid {} value {} in bin data", field, raw);
+        Err::Error(Error::new(input, ErrorKind::Alt))
+    })
+}
 
 fn parse_measure_init(input: &[u8]) -> IResult<&[u8], MusicElement> {
     let take_bits = tuple((
@@ -31,14 +51,10 @@ fn parse_measure_init(input: &[u8]) -> IResult<&[u8], MusicElement> {
     ));
     bits::<_, _, Error<(&[u8], usize)>, _, _>(take_bits)(input).and_then(
         |(inp, (id, beats, beat_type, fifths, tempo, reserve_bits, reserve_bits_2))| {
-            let _id: MusicTagIdentifiers =
-                FromPrimitive::from_u8(id).ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let beats = FromPrimitive::from_u8(beats)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let beat_type = FromPrimitive::from_u8(beat_type)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let key_sig = FromPrimitive::from_u8(fifths)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
+            let _id: MusicTagIdentifiers = decode_field(input, "id", id)?;
+            let beats = decode_field(input, "beats", beats)?;
+            let beat_type = decode_field(input, "beat_type", beat_type)?;
+            let key_sig = decode_field(input, "key_sig", fifths)?;
             let tempo = Tempo::new_from_raw(tempo);
             let _throwaway: u8 = reserve_bits;
             let _throwaway2: u8 = reserve_bits_2;
@@ -61,20 +77,19 @@ fn parse_measure_meta(input: &[u8]) -> IResult<&[u8], MusicElement> {
         take_bits(2usize),
         take_bits(2usize),
         take_bits(3usize),
-        take_bits(7usize),
+        take_bits(5usize),
+        take_bits(2usize),
         count(take_bits(8usize), 2),
     ));
     bits::<_, _, Error<(&[u8], usize)>, _, _>(take_bits)(input).and_then(
-        |(inp, (id, start_end, ending, dal_segno, throwaway, throwaway_vec))| {
-            let _id: MusicTagIdentifiers =
-                FromPrimitive::from_u8(id).ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let start_end = FromPrimitive::from_u8(start_end)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let ending = FromPrimitive::from_u8(ending)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let dal_segno = FromPrimitive::from_u8(dal_segno)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let _throwaway: u8 = throwaway;
+        |(inp, (id, start_end, ending, dal_segno, repeat_times, bar_style, throwaway_vec))| {
+            let _id: MusicTagIdentifiers = decode_field(input, "id", id)?;
+            let start_end = decode_field(input, "start_end", start_end)?;
+            let ending = decode_field(input, "ending", ending)?;
+            let dal_segno = decode_field(input, "dal_segno", dal_segno)?;
+            let repeat_times: u8 = repeat_times;
+            // Files written before bar styles were stored hold zero, a regular barline
+            let bar_style = decode_field(input, "bar_style", bar_style)?;
             let _throwaway_vec: Vec<u8> = throwaway_vec;
             Ok((
                 inp,
@@ -82,6 +97,8 @@ fn parse_measure_meta(input: &[u8]) -> IResult<&[u8], MusicElement> {
                     start_end,
                     ending,
                     dal_segno,
+                    repeat_times: (repeat_times != 0).then_some(repeat_times),
+                    bar_style,
                 }),
             ))
         },
@@ -123,31 +140,20 @@ fn parse_note_data_rest(input: &[u8]) -> IResult<&[u8], MusicElement> {
                 voice,
             ),
         )| {
-            let _id: MusicTagIdentifiers =
-                FromPrimitive::from_u8(id).ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
+            let _id: MusicTagIdentifiers = decode_field(input, "id", id)?;
             let note_rest = NumericPitchRest::new_from_numeric(note_rest);
-            let phrase_dynamics = FromPrimitive::from_u8(phrase_dynamics)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let rhythm_value = FromPrimitive::from_u8(rhythm_value)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
+            let phrase_dynamics = decode_field(input, "phrase_dynamics", phrase_dynamics)?;
+            let rhythm_value = decode_field(input, "rhythm_value", rhythm_value)?;
             let dotted: u8 = dotted;
             let dotted = dotted != 0u8;
-            let arpeggiate = FromPrimitive::from_u8(arpeggiate)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let special_note = FromPrimitive::from_u8(special_note)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let articulation = FromPrimitive::from_u8(articulation)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let trill = FromPrimitive::from_u8(trill)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let ties = FromPrimitive::from_u8(ties)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let chord = FromPrimitive::from_u8(chord)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let slur = FromPrimitive::from_u8(slur)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let voice = FromPrimitive::from_u8(voice)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
+            let arpeggiate = decode_field(input, "arpeggiate", arpeggiate)?;
+            let special_note = decode_field(input, "special_note", special_note)?;
+            let articulation = decode_field(input, "articulation", articulation)?;
+            let trill = decode_field(input, "trill", trill)?;
+            let ties = decode_field(input, "ties", ties)?;
+            let chord = decode_field(input, "chord", chord)?;
+            let slur = decode_field(input, "slur", slur)?;
+            let voice = decode_field(input, "voice", voice)?;
             Ok((
                 inp,
                 MusicElement::NoteRest(NoteData {
@@ -162,7 +168,15 @@ fn parse_note_data_rest(input: &[u8]) -> IResult<&[u8], MusicElement> {
                     ties,
                     chord,
                     slur,
+                    slur_number: SlurNumber::One,
                     voice,
+                    cautionary_accidental: false,
+                    note_size: NoteSize::Full,
+                    notehead: Notehead::Normal,
+                    dynamics_placement: Placement::Default,
+                    tremolo: None,
+                    cents: 0,
+                    staff: None,
                 }),
             ))
         },
@@ -178,7 +192,9 @@ fn parse_tuplet_data(input: &[u8]) -> IResult<&[u8], MusicElement> {
         take_bits(4usize),
         take_bits(1usize),
         take_bits(1usize),
-        count(take_bits(8usize), 2),
+        take_bits(1usize),
+        take_bits(4usize),
+        take_bits(11usize),
     ));
     bits::<_, _, Error<(&[u8], usize)>, _, _>(take_bits)(input).and_then(
         |(
@@ -190,25 +206,28 @@ fn parse_tuplet_data(input: &[u8]) -> IResult<&[u8], MusicElement> {
                 tuplet_actual,
                 tuplet_normal,
                 dotted,
+                hide_bracket,
+                hide_number,
+                normal_type,
                 reserve_bits,
-                throwaway,
             ),
         )| {
-            let _id: MusicTagIdentifiers =
-                FromPrimitive::from_u8(id).ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let start_stop = FromPrimitive::from_u8(start_stop)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let tuplet_number = FromPrimitive::from_u8(tuplet_number)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let actual_notes = FromPrimitive::from_u8(tuplet_actual)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
-            let normal_notes = FromPrimitive::from_u8(tuplet_normal)
-                .ok_or(Err::Error(Error::new(input, ErrorKind::Alt)))?;
+            let _id: MusicTagIdentifiers = decode_field(input, "id", id)?;
+            let start_stop = decode_field(input, "start_stop", start_stop)?;
+            let tuplet_number = decode_field(input, "tuplet_number", tuplet_number)?;
+            let actual_notes = decode_field(input, "actual_notes", tuplet_actual)?;
+            let normal_notes = decode_field(input, "normal_notes", tuplet_normal)?;
 
             let dotted: u8 = dotted;
             let dotted = dotted != 0u8;
-            let _reservebits: u8 = reserve_bits;
-            let _throwaway: Vec<u8> = throwaway;
+            let hide_bracket: u8 = hide_bracket;
+            let hide_number: u8 = hide_number;
+            let normal_type: u8 = normal_type;
+            let normal_type = match normal_type.checked_sub(1) {
+                Some(t) => Some(decode_field(input, "normal_type", t)?),
+                None => None,
+            };
+            let _reservebits: u16 = reserve_bits;
 
             Ok((
                 inp,
@@ -218,12 +237,316 @@ fn parse_tuplet_data(input: &[u8]) -> IResult<&[u8], MusicElement> {
                     actual_notes,
                     normal_notes,
                     dotted,
+                    hide_bracket: hide_bracket != 0,
+                    hide_number: hide_number != 0,
+                    normal_type,
+                }),
+            ))
+        },
+    )
+}
+
+fn parse_harmony_data(input: &[u8]) -> IResult<&[u8], MusicElement> {
+    let take_bits = tuple((
+        take_bits(2usize),
+        take_bits(2usize),
+        take_bits(6usize),
+        take_bits(3usize),
+        take_bits(2usize),
+        take_bits(5usize),
+        take_bits(1usize),
+        take_bits(3usize),
+        take_bits(2usize),
+        take_bits(6usize),
+    ));
+    bits::<_, _, Error<(&[u8], usize)>, _, _>(take_bits)(input).and_then(
+        |(
+            inp,
+            (
+                _id,
+                _escape,
+                _extended_id,
+                root_step,
+                root_alter,
+                kind,
+                has_bass,
+                bass_step,
+                bass_alter,
+                reserve_bits,
+            ),
+        )| {
+            let _id: u8 = _id;
+            let _escape: u8 = _escape;
+            let _extended_id: u8 = _extended_id;
+            let root_step = decode_field(input, "root_step", root_step)?;
+            let root_alter = decode_field(input, "root_alter", root_alter)?;
+            let kind = decode_field(input, "kind", kind)?;
+            let has_bass: u8 = has_bass;
+            let bass = if has_bass != 0u8 {
+                Some((
+                    decode_field(input, "bass_step", bass_step)?,
+                    decode_field(input, "bass_alter", bass_alter)?,
+                ))
+            } else {
+                None
+            };
+            let _reservebits: u8 = reserve_bits;
+            Ok((
+                inp,
+                MusicElement::Harmony(HarmonyData {
+                    root_step,
+                    root_alter,
+                    kind,
+                    bass,
                 }),
             ))
         },
     )
 }
 
+fn parse_clef_change(input: &[u8]) -> IResult<&[u8], MusicElement> {
+    let take_bits = tuple((
+        take_bits(2usize),
+        take_bits(2usize),
+        take_bits(6usize),
+        take_bits(2usize),
+        take_bits(2usize),
+        take_bits(3usize),
+        take_bits(15usize),
+    ));
+    let (inp, (_id, _escape, _extended_id, staff, clef, octave_change, _reserve_bits)): (
+        _,
+        (u8, u8, u8, u8, u8, u8, u16),
+    ) = bits::<_, _, Error<(&[u8], usize)>, _, _>(take_bits)(input)?;
+    let staff = decode_field(input, "staff", staff)?;
+    let clef = decode_field(input, "clef", clef)?;
+    // Sign extend the three bit two's complement value
+    let octave_change = ((octave_change << 5) as i8) >> 5;
+    if octave_change.abs() > ClefChangeData::MAX_OCTAVE_CHANGE {
+        error!("invalid clef octave change {} in bin data", octave_change);
+        return Err(Err::Error(Error::new(input, ErrorKind::Alt)));
+    }
+    Ok((
+        inp,
+        MusicElement::ClefChange(ClefChangeData {
+            staff,
+            clef,
+            octave_change,
+        }),
+    ))
+}
+
+/// Parses a percussion prefix together with the note element, and any note extension, it applies to
+fn parse_percussion(input: &[u8]) -> IResult<&[u8], MusicElement> {
+    let take_bits = tuple((
+        take_bits(2usize),
+        take_bits(2usize),
+        take_bits(6usize),
+        take_bits(7usize),
+        take_bits(15usize),
+    ));
+    let (inp, (_id, _escape, _extended_id, drum, _reserve_bits)): (_, (u8, u8, u8, u8, u16)) =
+        bits::<_, _, Error<(&[u8], usize)>, _, _>(take_bits)(input)?;
+    let drum = decode_field(input, "drum", drum)?;
+    match music_element(inp)? {
+        (inp, MusicElement::NoteRest(note)) => Ok((inp, MusicElement::Percussion(PercussionData { drum, note }))),
+        _ => Err(Err::Error(Error::new(input, ErrorKind::Alt))),
+    }
+}
+
+/// Parses a microtone prefix together with the note element it detunes, and any note extension of it
+fn parse_microtone(input: &[u8]) -> IResult<&[u8], MusicElement> {
+    let take_bits = tuple((
+        take_bits(2usize),
+        take_bits(2usize),
+        take_bits(6usize),
+        take_bits(8usize),
+        take_bits(14usize),
+    ));
+    let (inp, (_id, _escape, _extended_id, cents, _reserve_bits)): (_, (u8, u8, u8, u8, u16)) =
+        bits::<_, _, Error<(&[u8], usize)>, _, _>(take_bits)(input)?;
+    let cents = cents as i8;
+    if cents == 0 || cents.unsigned_abs() > NoteData::MAX_CENTS.unsigned_abs() {
+        error!("invalid cents value {} in bin data", cents);
+        return Err(Err::Error(Error::new(input, ErrorKind::Alt)));
+    }
+    match music_element(inp)? {
+        (inp, MusicElement::NoteRest(note)) => Ok((inp, MusicElement::NoteRest(NoteData { cents, ..note }))),
+        _ => Err(Err::Error(Error::new(input, ErrorKind::Alt))),
+    }
+}
+
+/// Identifier, escape, extended identifier, slur number, cautionary accidental, note size, arpeggiate, breve,
+/// notehead, dynamics placement, tremolo type, tremolo marks and staff
+type NoteExtensionFields = (u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8);
+
+/// Parses a note extension prefix together with the note element it applies to
+fn parse_extended_note(input: &[u8]) -> IResult<&[u8], MusicElement> {
+    let take_bits = tuple((
+        take_bits(2usize),
+        take_bits(2usize),
+        take_bits(6usize),
+        take_bits(2usize),
+        take_bits(1usize),
+        take_bits(2usize),
+        take_bits(2usize),
+        take_bits(1usize),
+        take_bits(3usize),
+        take_bits(2usize),
+        take_bits(3usize),
+        take_bits(4usize),
+        take_bits(2usize),
+    ));
+    let (
+        inp,
+        (
+            _id,
+            _escape,
+            _extended_id,
+            slur_number,
+            cautionary_accidental,
+            note_size,
+            arpeggiate,
+            breve,
+            notehead,
+            dynamics_placement,
+            tremolo_type,
+            tremolo_marks,
+            staff,
+        ),
+    ): (_, NoteExtensionFields) =
+        bits::<_, _, Error<(&[u8], usize)>, _, _>(take_bits)(input)?;
+    let slur_number = decode_field(input, "slur_number", slur_number)?;
+    let note_size = decode_field(input, "note_size", note_size)?;
+    let arpeggiate: Arpeggiate = decode_field(input, "arpeggiate", arpeggiate)?;
+    let notehead = decode_field(input, "notehead", notehead)?;
+    let dynamics_placement = decode_field(input, "dynamics_placement", dynamics_placement)?;
+    let tremolo = match tremolo_type.checked_sub(1) {
+        Some(t) if tremolo_marks <= Tremolo::MAX_MARKS => {
+            Some(Tremolo::new(decode_field(input, "tremolo_type", t)?, tremolo_marks))
+        }
+        Some(_) => {
+            error!("invalid tremolo_marks value {} in bin data", tremolo_marks);
+            return Err(Err::Error(Error::new(input, ErrorKind::Alt)));
+        }
+        None => None,
+    };
+    let staff = match staff {
+        0 => None,
+        staff => Some(decode_field(input, "staff", staff)?),
+    };
+    match parse_id(inp)? {
+        (inp, MusicTagIdentifiers::NoteData) => match parse_note_data_rest(inp)? {
+            (inp, MusicElement::NoteRest(note_data)) => Ok((
+                inp,
+                MusicElement::NoteRest(NoteData {
+                    // The rhythm value of a breve holds a semibreve
+                    note_type: if breve != 0 {
+                        RhythmType::Breve
+                    } else {
+                        note_data.note_type
+                    },
+                    slur_number,
+                    cautionary_accidental: cautionary_accidental != 0,
+                    note_size,
+                    notehead,
+                    dynamics_placement,
+                    tremolo,
+                    staff,
+                    // Only directional arpeggios are stored in the extension
+                    arpeggiate: if arpeggiate.direction().is_some() {
+                        arpeggiate
+                    } else {
+                        note_data.arpeggiate
+                    },
+                    ..note_data
+                }),
+            )),
+            _ => Err(Err::Error(Error::new(input, ErrorKind::Alt))),
+        },
+        _ => Err(Err::Error(Error::new(input, ErrorKind::Alt))),
+    }
+}
+
+fn parse_extended_element(input: &[u8]) -> IResult<&[u8], MusicElement> {
+    let take_bits = tuple((take_bits(2usize), take_bits(2usize), take_bits(6usize)));
+    let (_, (_id, _escape, extended_id)): (_, (u8, u8, u8)) =
+        bits::<_, _, Error<(&[u8], usize)>, _, _>(take_bits)(input)?;
+    let extended_id: ExtendedTagIdentifiers = decode_field(input, "extended_id", extended_id)?;
+    match extended_id {
+        ExtendedTagIdentifiers::Harmony => parse_harmony_data(input),
+        ExtendedTagIdentifiers::NoteExtension => parse_extended_note(input),
+        ExtendedTagIdentifiers::ClefChange => parse_clef_change(input),
+        ExtendedTagIdentifiers::Percussion => parse_percussion(input),
+        ExtendedTagIdentifiers::Microtone => parse_microtone(input),
+        // A chord cluster only follows the note it is stacked on
+        ExtendedTagIdentifiers::ChordCluster => {
+            error!("chord cluster without a preceding note in bin data");
+            Err(Err::Error(Error::new(input, ErrorKind::Alt)))
+        }
+    }
+}
+
+/// Parses a chord cluster element into the pitches of its chord tones
+fn parse_chord_cluster(input: &[u8]) -> IResult<&[u8], Vec<NumericPitchRest>> {
+    let take_bits = tuple((
+        take_bits(2usize),
+        take_bits(2usize),
+        take_bits(6usize),
+        take_bits(7usize),
+        take_bits(7usize),
+        take_bits(7usize),
+        take_bits(1usize),
+    ));
+    let (inp, (id, escape, extended_id, pitch_1, pitch_2, pitch_3, _reserve_bits)): (
+        _,
+        (u8, u8, u8, u8, u8, u8, u8),
+    ) = bits::<_, _, Error<(&[u8], usize)>, _, _>(take_bits)(input)?;
+    if id != MusicTagIdentifiers::Tuplet as u8
+        || escape != EXTENDED_ELEMENT_ESCAPE
+        || extended_id != ExtendedTagIdentifiers::ChordCluster as u8
+    {
+        return Err(Err::Error(Error::new(input, ErrorKind::Tag)));
+    }
+    // Unused slots hold zero
+    let pitches = [pitch_1, pitch_2, pitch_3]
+        .into_iter()
+        .take_while(|&p| p != 0)
+        .map(NumericPitchRest::Pitch)
+        .collect();
+    Ok((inp, pitches))
+}
+
+/// Parses an element together with the chord clusters following it, which are expanded into chord
+/// tones copying every field of the note but its pitch. Clusters are only read from files whose
+/// version supports them.
+fn music_elements(input: &[u8], version: BinVersion) -> IResult<&[u8], Vec<MusicElement>> {
+    let (mut inp, element) = music_element(input)?;
+    let note = match element {
+        MusicElement::NoteRest(note) if version.supports_chord_clusters() => note,
+        _ => return Ok((inp, vec![element])),
+    };
+    let mut elements = vec![element];
+    while let Ok((rest, pitches)) = parse_chord_cluster(inp) {
+        elements.extend(pitches.into_iter().map(|note_rest| {
+            MusicElement::NoteRest(NoteData {
+                note_rest,
+                chord: Chord::Chord,
+                ..note
+            })
+        }));
+        inp = rest;
+    }
+    Ok((inp, elements))
+}
+
+/// Tuplet tagged elements carrying the escape start/stop value are extended elements
+fn is_extended_element(input: &[u8]) -> bool {
+    input
+        .first()
+        .is_some_and(|b| (b >> 4) & 0x3 == EXTENDED_ELEMENT_ESCAPE)
+}
+
 fn parse_id(input: &[u8]) -> IResult<&[u8], MusicTagIdentifiers> {
     bits::<_, _, Error<(&[u8], usize)>, _, _>(take_bits(2usize))(input).and_then(|id| {
         let tag_id: Option<MusicTagIdentifiers> = FromPrimitive::from_u8(id.1);
@@ -237,10 +560,13 @@ fn parse_id(input: &[u8]) -> IResult<&[u8], MusicTagIdentifiers> {
 fn header_parser(input: &[u8]) -> IResult<&[u8], MusicBinHeader> {
     (tuple((take_bytes(4usize), take_bytes(4usize))))(input).and_then(
         |(inp, (id_bytes, length))| {
-            if id_bytes.cmp(&MusicBinHeader::MUSICBIN_MAGIC_NUMBER).is_ne() {
-                error!("Parsed magic number for MusicBin format was incorrect.");
-                return Err(Err::Error(Error::new(input, ErrorKind::Alt)));
-            }
+            let version = match BinVersion::from_magic_number(id_bytes) {
+                Some(version) => version,
+                None => {
+                    error!("Parsed magic number for MusicBin format was incorrect.");
+                    return Err(Err::Error(Error::new(input, ErrorKind::Alt)));
+                }
+            };
 
             let length = u32::from_le_bytes(
                 length
@@ -248,11 +574,63 @@ fn header_parser(input: &[u8]) -> IResult<&[u8], MusicBinHeader> {
                     .expect("Length returned by MusicBin header parser was incorrect byte count"),
             );
 
-            Ok((inp, MusicBinHeader::new(length as usize)))
+            let mut header = MusicBinHeader::new(version, length as usize);
+            if !version.supports_metadata() {
+                return Ok((inp, header));
+            }
+            let (inp, (offset, size)) = tuple((le_u32, le_u32))(inp)?;
+            header.set_metadata_location(offset as usize, size as usize);
+            Ok((inp, header))
         },
     )
 }
 
+fn part_table_string(input: &[u8]) -> IResult<&[u8], String> {
+    map_res(length_data(le_u8), |s: &[u8]| {
+        std::str::from_utf8(s).map(|s| s.to_string())
+    })(input)
+}
+
+fn part_table_parser(input: &[u8]) -> IResult<&[u8], Vec<PartName>> {
+    let (inp, (_magic, length)) =
+        tuple((tag(&PartTableHeader::PART_TABLE_MAGIC_NUMBER[..]), le_u32))(input)?;
+    let (inp, payload) = take_bytes(length as usize)(inp)?;
+    let (_, entries) = all_consuming(length_count(
+        le_u8,
+        tuple((part_table_string, part_table_string)),
+    ))(payload)?;
+    let part_names = entries
+        .into_iter()
+        .map(|(name, abbreviation)| PartName {
+            name,
+            abbreviation: if abbreviation.is_empty() {
+                None
+            } else {
+                Some(abbreviation)
+            },
+        })
+        .collect();
+    Ok((inp, part_names))
+}
+
+fn divisions_parser(input: &[u8]) -> IResult<&[u8], u32> {
+    let (inp, (_magic, length)) =
+        tuple((tag(&DivisionsHeader::DIVISIONS_MAGIC_NUMBER[..]), le_u32))(input)?;
+    let (inp, payload) = take_bytes(length as usize)(inp)?;
+    let (_, divisions) = all_consuming(le_u32)(payload)?;
+    Ok((inp, divisions))
+}
+
+fn metadata_string(input: &[u8]) -> IResult<&[u8], String> {
+    map_res(length_data(le_u32), |s: &[u8]| {
+        std::str::from_utf8(s).map(|s| s.to_string())
+    })(input)
+}
+
+fn metadata_parser(input: &[u8]) -> IResult<&[u8], Vec<String>> {
+    all_consuming(length_count(le_u32, metadata_string))(input)
+}
+
 fn music_element(input: &[u8]) -> IResult<&[u8], MusicElement> {
     if input.is_empty() {
         // This error is expected for EOF condition/ completion of parsing
@@ -264,14 +642,23 @@ fn music_element(input: &[u8]) -> IResult<&[u8], MusicElement> {
         MusicTagIdentifiers::MeasureInitializer => parse_measure_init(id.0),
         MusicTagIdentifiers::MeasureMetaData => parse_measure_meta(id.0),
         MusicTagIdentifiers::NoteData => parse_note_data_rest(id.0),
+        MusicTagIdentifiers::Tuplet if is_extended_element(id.0) => parse_extended_element(id.0),
         MusicTagIdentifiers::Tuplet => parse_tuplet_data(id.0),
     }
 }
 
-fn parse_music_bin(
-    input: &[u8],
-    size: usize,
-) -> IResult<&[u8], (MusicBinHeader, Vec<MusicElement>)> {
+/// Optional sections that may follow the music elements
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MusicBinTrailer {
+    pub part_names: Vec<PartName>,
+    pub divisions: Option<u32>,
+    /// Strings of the metadata section
+    pub metadata: Vec<String>,
+}
+
+type MusicBinContents = (MusicBinHeader, Vec<MusicElement>, MusicBinTrailer);
+
+fn parse_music_bin(input: &[u8], size: usize, max_elements: usize) -> IResult<&[u8], MusicBinContents> {
     if input.len() < size {
         error!("input length of vector less than specified size");
         return Err(Err::Incomplete(Needed::new(size)));
@@ -281,35 +668,117 @@ fn parse_music_bin(
         error!("input length too short.");
         return Err(Err::Incomplete(Needed::new(1)));
     }
-    all_consuming(tuple((header_parser, many0(music_element))))(input)
+    let (inp, header) = header_parser(input)?;
+    // A corrupt length must not turn the rest of the file into millions of elements
+    if header.get_chunk_length() > max_elements {
+        error!(
+            "header length of {} elements exceeds the maximum of {}",
+            header.get_chunk_length(),
+            max_elements
+        );
+        return Err(Err::Failure(Error::new(input, ErrorKind::TooLarge)));
+    }
+    // A note may be preceded by note extension and microtone elements and followed by chord clusters,
+    // so fewer elements can be decoded than the chunk length counts
+    let (inp, element_bytes) = take_bytes(header.get_chunk_length() * MUSIC_ELEMENT_LENGTH)(inp)?;
+    let version = header.get_version();
+    let (_, elements) = all_consuming(many0(|i| music_elements(i, version)))(element_bytes)?;
+    let elements = elements.into_iter().flatten().collect();
+    // The metadata section is last, so the other sections end where it starts
+    let (inp, metadata) = match header.get_metadata_location() {
+        Some((offset, size)) => {
+            let elements_end = version.header_length() + element_bytes.len();
+            if offset < elements_end || offset - elements_end + size != inp.len() {
+                error!("metadata section at offset {} of {} bytes lies outside the trailer", offset, size);
+                return Err(Err::Failure(Error::new(input, ErrorKind::Verify)));
+            }
+            let (metadata_bytes, trailer_bytes) = take_bytes(offset - elements_end)(inp)?;
+            let (_, metadata) = metadata_parser(metadata_bytes)?;
+            (trailer_bytes, metadata)
+        }
+        None => (inp, vec![]),
+    };
+    // The trailing sections are optional, so files written before they existed still decode
+    let (inp, (part_names, divisions)) =
+        all_consuming(tuple((opt(part_table_parser), opt(divisions_parser))))(inp)?;
+    let trailer = MusicBinTrailer {
+        part_names: part_names.unwrap_or_default(),
+        divisions,
+        metadata,
+    };
+    Ok((inp, (header, elements, trailer)))
 }
 
 pub struct MusicDecoder {
-    r: Option<BufReader<File>>,
+    // Any reader, so decoding needs no filesystem
+    r: Option<Box<dyn Read + Send>>,
     data: Vec<u8>,
+    // The reader's file mapped into memory, parsed in place of `data` when present
+    #[cfg(feature = "mmap")]
+    mapped: Option<Mmap>,
+    // Largest element count a header may declare before the file is rejected
+    max_elements: usize,
 }
 
 impl MusicDecoder {
-    pub fn new(reader: Option<BufReader<File>>) -> MusicDecoder {
+    pub const DEFAULT_MAX_ELEMENTS: usize = 5_000_000;
+
+    pub fn new(reader: Option<Box<dyn Read + Send>>) -> MusicDecoder {
         let r = reader;
-        MusicDecoder { r, data: vec![] }
+        MusicDecoder {
+            r,
+            data: vec![],
+            #[cfg(feature = "mmap")]
+            mapped: None,
+            max_elements: Self::DEFAULT_MAX_ELEMENTS,
+        }
+    }
+
+    pub fn set_max_elements(&mut self, max_elements: usize) {
+        self.max_elements = max_elements;
     }
 
     pub fn reader_read(&mut self) -> error::Result<()> {
         match &mut self.r {
             None => Err(error::Error::MissingReader),
             Some(r) => {
-                let _bytes_read = r
-                    .read_to_end(&mut self.data)
-                    .map_err(|e| error::Error::IoKind(e.kind().to_string()))?;
+                let _bytes_read = r.read_to_end(&mut self.data)?;
                 //println!("read {} bytes", bytes_read);
                 Ok(())
             }
         }
     }
 
+    /// Memory maps `file` instead of reading it, so a large file is parsed where it lies rather than
+    /// copied into the decoder.
+    ///
+    /// # Safety
+    ///
+    /// Nothing, in this process or any other, may truncate or modify the file while it is mapped,
+    /// that is until the decoder is dropped or `clear_data` is called. A file changed underneath the
+    /// mapping changes bytes the parser has already checked, and a truncated one faults on access.
+    #[cfg(feature = "mmap")]
+    pub unsafe fn map_file(&mut self, file: &File) -> error::Result<()> {
+        // SAFETY: the caller guarantees the file is left untouched for as long as the mapping lives
+        self.mapped = Some(unsafe { Mmap::map(file)? });
+        Ok(())
+    }
+
+    /// The bytes to parse: the mapped file if there is one, otherwise the data read so far
+    fn bytes(&self) -> &[u8] {
+        #[cfg(feature = "mmap")]
+        if let Some(mapped) = &self.mapped {
+            return mapped;
+        }
+        &self.data
+    }
+
     pub fn clear_data(&mut self) {
         self.data.clear();
+        #[cfg(feature = "mmap")]
+        {
+            self.mapped = None;
+        }
     }
 
     pub fn raw_read(&mut self, bytes: &[u8]) {
@@ -317,24 +786,26 @@ impl MusicDecoder {
     }
 
     pub fn parse_element(&self) -> error::Result<MusicElement> {
-        match music_element(&self.data) {
+        match music_element(self.bytes()) {
             Ok((_, r)) => Ok(r),
             _ => Err(error::Error::Decoding),
         }
     }
 
-    pub fn parse_data(&self) -> error::Result<Vec<MusicElement>> {
-        match parse_music_bin(&self.data, self.data.len()) {
-            Ok((_, (header, elements))) => {
-                if header.get_chunk_length() != elements.len() {
+    pub fn parse_data(&self) -> error::Result<(Vec<MusicElement>, MusicBinTrailer)> {
+        let bytes = self.bytes();
+        match parse_music_bin(bytes, bytes.len(), self.max_elements) {
+            Ok((_, (header, elements, trailer))) => {
+                let encoded_len = encoded_elements_count(&elements, header.get_version());
+                if header.get_chunk_length() != encoded_len {
                     error!(
-                        "MusicBin parsed length {} does not match number of elements {}.",
+                        "MusicBin parsed length {} does not match number of encoded elements {}.",
                         header.get_chunk_length(),
-                        elements.len()
+                        encoded_len
                     );
                     Err(error::Error::Decoding)
                 } else {
-                    Ok(elements)
+                    Ok((elements, trailer))
                 }
             }
             _ => Err(error::Error::Decoding),
@@ -342,112 +813,65 @@ impl MusicDecoder {
     }
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use super::MusicDecoder;
-//     use crate::ir::notation::{
-//         Arpeggiate, Articulation, BeatType, Beats, Chord, DalSegno, Ending, KeySignature,
-//         MeasureInitializer, MeasureMetaData, MeasureStartEnd, MusicElement, NoteConnection,
-//         NoteData, NumericPitchRest, PhraseDynamics, RhythmType, SlurConnection, SpecialNote, Tempo,
-//         Trill, Voice,
-//     };
-//     //use crate::bin_encoder::*;
-//     // use std::io::BufWriter;
-//     // use std::path::PathBuf;
-//     // use std::fs::File;
-
-//     #[test]
-//     fn test_music_note_data_rest_parse() -> Result<(), Box<dyn std::error::Error>> {
-//         let mut music_dec = MusicDecoder::new(None);
-//         let note_rest_data: &[u8] = &[0xa0, 0xef, 0x84, 0x01];
-//         music_dec.raw_read(note_rest_data);
-//         let elem = music_dec.parse_element()?;
-
-//         assert_eq!(
-//             elem,
-//             MusicElement::NoteRest(NoteData {
-//                 note_rest: NumericPitchRest::new_from_numeric(65),
-//                 phrase_dynamics: PhraseDynamics::Forte,
-//                 note_type: RhythmType::SemiBreve,
-//                 dotted: true,
-//                 arpeggiate: Arpeggiate::NoArpeggiation,
-//                 special_note: SpecialNote::None,
-//                 articulation: Articulation::Accent,
-//                 trill: Trill::None,
-//                 ties: NoteConnection::None,
-//                 chord: Chord::NoChord,
-//                 slur: SlurConnection::None,
-//                 voice: Voice::Two,
-//             })
-//         );
-//         Ok(())
-//         // TODO: Add negative cases that fail
-//     }
-
-//     #[test]
-//     fn test_music_meta_parse() -> Result<(), Box<dyn std::error::Error>> {
-//         let mut music_dec = MusicDecoder::new(None);
-//         let measure_meta_data: &[u8] = &[0x4e, 0x00, 0x00, 0x00];
-//         music_dec.raw_read(measure_meta_data);
-//         let elem = music_dec.parse_element()?;
-
-//         assert_eq!(
-//             elem,
-//             MusicElement::MeasureMeta(MeasureMetaData {
-//                 start_end: MeasureStartEnd::MeasureStart,
-//                 ending: Ending::Three,
-//                 dal_segno: DalSegno::DaCapo
-//             })
-//         );
-//         Ok(())
-//         // TODO: Add negative cases that fail
-//     }
-//     /// This function is just used for dumping serialized data structures to file to
-//     /// use for validation test data generation. Can be left commented out
-//     // #[test]
-//     // fn test_dump_bin_file() {
-//     //     let outfile = File::create(PathBuf::from("validation.bin")).expect("IO Error Occurred");
-//     //     let writer = BufWriter::new(BufWriter::new(outfile));
-//     //     let mut validation = MusicEncoder::new(writer);
-//     //     validation.insert_note_data(NoteData {
-//     //         note_rest: NoteRestValue::new_from_numeric(65),
-//     //         phrase_dynamics: PhraseDynamics::Forte,
-//     //         note_type: NoteType::SemiBreve,
-//     //         dotted: true,
-//     //         arpeggiate: Arpeggiate::NoArpeggiation,
-//     //         special_note: SpecialNote::None,
-//     //         articulation: Articulation::Marcato,
-//     //         trill: Trill::None,
-//     //         ties: NoteConnection::NoTie,
-//     //         stress: Stress::NotAccented,
-//     //         chord: Chord::NoChord,
-//     //         slur: SlurConnection::NoSlur,
-//     //         voice: Voice::Two,
-//     //     }).unwrap();
-
-//     //     validation.flush();
-//     // }
-//     #[test]
-//     fn test_music_init_parse() -> Result<(), Box<dyn std::error::Error>> {
-//         let mut music_dec = MusicDecoder::new(None);
-
-//         // Positive case examples
-//         let music_init_data: &[u8] = &[0x12, 0x0c, 0x80, 0x00];
-//         music_dec.raw_read(music_init_data);
-
-//         let elem = music_dec.parse_element()?;
-
-//         assert_eq!(
-//             elem,
-//             MusicElement::MeasureInit(MeasureInitializer {
-//                 beats: Beats::Four,
-//                 beat_type: BeatType::Four,
-//                 key_sig: KeySignature::CMajorAminor,
-//                 tempo: Tempo::default(),
-//             })
-//         );
-//         Ok(())
-//         //music_dec.clear_data();
-//         // TODO: Add negative cases that fail
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::MusicDecoder;
+    use crate::error::Error;
+    use crate::utils::capture_log;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_absurd_header_length_is_rejected() {
+        let mut data = b"MuBi".to_vec();
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        data.extend_from_slice(&[0x80, 0, 0, 0].repeat(16));
+
+        let mut music_dec = MusicDecoder::new(None);
+        music_dec.raw_read(&data);
+        let start = Instant::now();
+        assert_eq!(music_dec.parse_data(), Err(Error::Decoding));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // The same file is still rejected when the limit is raised past the data that is actually present
+        music_dec.set_max_elements(usize::MAX);
+        assert_eq!(music_dec.parse_data(), Err(Error::Decoding));
+    }
+
+    #[test]
+    fn test_header_length_must_match_the_encoded_elements() {
+        // A rest, then the same rest behind a note extension that holds no field of its own. The header
+        // length covers both, but the encoder never writes such an extension, so the file is not canonical.
+        let rest = [0x80, 0, 0, 0];
+        let empty_extension = [0xF0, 0x40, 0, 0];
+        let mut data = b"MuBi".to_vec();
+        data.extend_from_slice(&12u32.to_le_bytes());
+        data.extend_from_slice(&rest);
+        data.extend_from_slice(&empty_extension);
+        data.extend_from_slice(&rest);
+
+        let mut music_dec = MusicDecoder::new(None);
+        music_dec.raw_read(&data);
+        assert_eq!(music_dec.parse_data(), Err(Error::Decoding));
+
+        let mut data = b"MuBi".to_vec();
+        data.extend_from_slice(&8u32.to_le_bytes());
+        data.extend_from_slice(&rest);
+        data.extend_from_slice(&rest);
+        music_dec.clear_data();
+        music_dec.raw_read(&data);
+        assert_eq!(music_dec.parse_data().map(|(elements, _)| elements.len()), Ok(2));
+    }
+
+    #[test]
+    fn test_out_of_range_key_sig_names_the_field() {
+        capture_log::init();
+        capture_log::clear();
+        // Measure initializer in 4/4 with a key signature value of 14, past the last of the 12 keys
+        let mut music_dec = MusicDecoder::new(None);
+        music_dec.raw_read(&[0x13, 0xC0, 0x00, 0x00]);
+        assert_eq!(music_dec.parse_element(), Err(Error::Decoding));
+        assert!(capture_log::messages()
+            .iter()
+            .any(|m| m.contains("key_sig") && m.contains("14")));
+    }
+}
@@ -1,7 +1,9 @@
 use crate::error::Error;
 use crate::ir::notation::*;
+use crate::ir::PartName;
 use bitfield::bitfield;
 use io::Write;
+use log::error;
 use num_derive::FromPrimitive;
 use std::io;
 
@@ -16,21 +18,123 @@ pub enum MusicTagIdentifiers {
     Tuplet = 3,
 }
 
+/// The two bit tag identifier space is fully used, so further element types share the Tuplet
+/// identifier. Tuplet elements never use this start/stop value; when it is present the
+/// `ExtendedTagIdentifiers` field that follows selects the element type.
+pub const EXTENDED_ELEMENT_ESCAPE: u8 = 3;
+
+#[derive(Debug, FromPrimitive)]
+#[repr(u8)]
+pub enum ExtendedTagIdentifiers {
+    Harmony = 0,
+    NoteExtension = 1,
+    ClefChange = 2,
+    Percussion = 3,
+    ChordCluster = 4,
+    Microtone = 5,
+}
+
+/// Revision of the bin format, identified by the magic number of the header. Decoders predating a
+/// revision reject its files instead of misreading elements they do not know.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BinVersion {
+    /// Every note and chord tone is a note element of its own
+    #[default]
+    V1,
+    /// Chord tones that differ from the note they are stacked on only in pitch may be written as
+    /// chord clusters
+    V2,
+    /// The header also records the byte offset and size of a trailing metadata section
+    V3,
+}
+
+impl BinVersion {
+    pub fn magic_number(self) -> [u8; 4] {
+        match self {
+            BinVersion::V1 => MusicBinHeader::MUSICBIN_MAGIC_NUMBER,
+            BinVersion::V2 => MusicBinHeader::MUSICBIN_V2_MAGIC_NUMBER,
+            BinVersion::V3 => MusicBinHeader::MUSICBIN_V3_MAGIC_NUMBER,
+        }
+    }
+
+    pub fn from_magic_number(magic: &[u8]) -> Option<BinVersion> {
+        [BinVersion::V1, BinVersion::V2, BinVersion::V3]
+            .into_iter()
+            .find(|version| version.magic_number() == magic)
+    }
+
+    pub fn supports_chord_clusters(self) -> bool {
+        self >= BinVersion::V2
+    }
+
+    pub fn supports_metadata(self) -> bool {
+        self >= BinVersion::V3
+    }
+
+    /// Byte length of the header of this revision
+    pub fn header_length(self) -> usize {
+        if self.supports_metadata() {
+            MusicBinHeader::METADATA_HEADER_LENGTH
+        } else {
+            MusicBinHeader::HEADER_LENGTH
+        }
+    }
+}
+
+/// Layout: magic number, u32 LE length of the music elements in bytes, counting every encoded
+/// element including note extensions, microtones and chord clusters. From `BinVersion::V3` on it
+/// is followed by the u32 LE byte offset of the metadata section from the start of the file and the
+/// u32 LE size of the section, both zero when the file has none.
+///
+/// The elements may be followed by up to three trailing sections, each optional but always in this
+/// order: the `PartTableHeader` part table, the `DivisionsHeader` divisions and the `MetadataSection`.
+/// The part table and divisions are framed by their own magic number and payload length and are not
+/// covered by the header, which locates only the metadata section. Decoders therefore cut the
+/// metadata section off the end of the file first, rejecting a location that does not end exactly
+/// at the end of the file, then read an optional part table and optional divisions from the bytes
+/// in between, which must hold nothing else. Files predating a section simply lack it: a missing
+/// part table leaves the part unnamed, missing divisions are recomputed from the shortest note, and
+/// `BinVersion::V1` and `BinVersion::V2` files never have a metadata section.
 pub struct MusicBinHeader {
     identifier: [u8; 4],
+    version: BinVersion,
     length: usize,
+    metadata_offset: usize,
+    metadata_size: usize,
 }
 
 impl MusicBinHeader {
     pub const MUSICBIN_MAGIC_NUMBER: [u8; 4] = [b'M', b'u', b'B', b'i'];
+    pub const MUSICBIN_V2_MAGIC_NUMBER: [u8; 4] = [b'M', b'u', b'B', b'2'];
+    pub const MUSICBIN_V3_MAGIC_NUMBER: [u8; 4] = [b'M', b'u', b'B', b'3'];
+    pub const HEADER_LENGTH: usize = 8;
+    pub const METADATA_HEADER_LENGTH: usize = 16;
 
-    pub fn new(length: usize) -> MusicBinHeader {
+    pub fn new(version: BinVersion, length: usize) -> MusicBinHeader {
         MusicBinHeader {
-            identifier: Self::MUSICBIN_MAGIC_NUMBER,
+            identifier: version.magic_number(),
+            version,
             length,
+            metadata_offset: 0,
+            metadata_size: 0,
         }
     }
 
+    /// Records the location of the metadata section, for revisions supporting one
+    pub fn set_metadata_location(&mut self, offset: usize, size: usize) {
+        self.metadata_offset = offset;
+        self.metadata_size = size;
+    }
+
+    /// Byte offset and size of the metadata section, or `None` when the file has none
+    pub fn get_metadata_location(&self) -> Option<(usize, usize)> {
+        (self.metadata_size > 0).then_some((self.metadata_offset, self.metadata_size))
+    }
+
+    pub fn get_version(&self) -> BinVersion {
+        self.version
+    }
+
     pub fn get_chunk_length(&self) -> usize {
         self.length / MUSIC_ELEMENT_LENGTH
     }
@@ -40,6 +144,78 @@ impl MusicBinHeader {
     }
 }
 
+/// Optional section following the music elements holding each part's name and abbreviation.
+///
+/// Layout: magic number, u32 LE payload length, u8 entry count, then for every entry a u8 length
+/// prefixed UTF-8 name followed by a u8 length prefixed abbreviation (zero length when absent).
+/// The entry index is the part index within the file.
+pub struct PartTableHeader;
+
+impl PartTableHeader {
+    pub const PART_TABLE_MAGIC_NUMBER: [u8; 4] = [b'M', b'u', b'P', b'n'];
+    pub const MAX_STRING_LENGTH: usize = u8::MAX as usize;
+    pub const MAX_ENTRIES: usize = u8::MAX as usize;
+
+    /// Byte size of the section holding the given part names, including its magic number and length
+    pub fn encoded_len(part_names: &[PartName]) -> usize {
+        8 + part_table_payload(part_names).len()
+    }
+}
+
+/// Optional section following the part table holding the quarter note divisions of the source
+/// score, so exported MusicXML reproduces the original duration values.
+///
+/// Layout: magic number, u32 LE payload length, u32 LE divisions.
+pub struct DivisionsHeader;
+
+impl DivisionsHeader {
+    pub const DIVISIONS_MAGIC_NUMBER: [u8; 4] = [b'M', b'u', b'D', b'v'];
+    /// Byte size of the section, including its magic number and length
+    pub const ENCODED_LENGTH: usize = 12;
+}
+
+/// Trailing section holding variable length strings the fixed width elements have no room for. It
+/// is written after every other section, at the offset recorded in the header.
+///
+/// Layout: u32 LE string count, then for every string a u32 LE length prefixed UTF-8 string.
+pub struct MetadataSection;
+
+impl MetadataSection {
+    pub const MAX_STRING_LENGTH: usize = u32::MAX as usize;
+    pub const MAX_ENTRIES: usize = u32::MAX as usize;
+
+    /// Byte size of the section holding the given strings
+    pub fn encoded_len(strings: &[String]) -> usize {
+        4 + strings.iter().map(|s| 4 + s.len()).sum::<usize>()
+    }
+}
+
+/// Truncates a string to the longest prefix that fits a u8 length field without splitting a character
+fn part_table_str(s: &str) -> &str {
+    if s.len() <= PartTableHeader::MAX_STRING_LENGTH {
+        return s;
+    }
+    let mut end = PartTableHeader::MAX_STRING_LENGTH;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Count of entries, then the u8 length prefixed name and abbreviation of every part
+fn part_table_payload(part_names: &[PartName]) -> Vec<u8> {
+    let mut payload: Vec<u8> = vec![part_names.len() as u8];
+    for part_name in part_names {
+        let name = part_table_str(part_name.name.as_str());
+        let abbreviation = part_table_str(part_name.abbreviation.as_deref().unwrap_or(""));
+        payload.push(name.len() as u8);
+        payload.extend_from_slice(name.as_bytes());
+        payload.push(abbreviation.len() as u8);
+        payload.extend_from_slice(abbreviation.as_bytes());
+    }
+    payload
+}
+
 // Bit 31 as MSB
 bitfield! {
     pub struct MeasureInitializerBin(MSB0 [u8]);
@@ -60,6 +236,8 @@ bitfield! {
     pub get_start_end, set_start_end: 3, 2;
     pub get_ending, set_ending: 5, 4;
     pub get_dal_segno, set_dal_segno: 8, 6;
+    pub get_repeat_times, set_repeat_times: 13, 9;
+    pub get_bar_style, set_bar_style: 15, 14;
 }
 
 bitfield! {
@@ -91,37 +269,286 @@ bitfield! {
     pub get_actual_note, set_actual_note: 9, 6;
     pub get_normal_note, set_normal_note: 13, 10;
     pub get_dotted, set_dotted: 14;
+    pub get_hide_bracket, set_hide_bracket: 15;
+    pub get_hide_number, set_hide_number: 16;
+    pub get_normal_type, set_normal_type: 20, 17;
+}
+
+bitfield! {
+    pub struct HarmonyDataBin(MSB0 [u8]);
+    impl Debug;
+    u8;
+    pub get_identifier, set_identifier: 1, 0;
+    pub get_escape, set_escape: 3, 2;
+    pub get_extended_identifier, set_extended_identifier: 9, 4;
+    pub get_root_step, set_root_step: 12, 10;
+    pub get_root_alter, set_root_alter: 14, 13;
+    pub get_kind, set_kind: 19, 15;
+    pub get_has_bass, set_has_bass: 20;
+    pub get_bass_step, set_bass_step: 23, 21;
+    pub get_bass_alter, set_bass_alter: 25, 24;
+}
+
+// Precedes a note element carrying fields that NoteDataBin has no spare bits for: a slur number
+// other than one, a cautionary accidental, a cue size, the direction of an arpeggio, a breve, a
+// notehead shape, the placement of a dynamic marking, a tremolo or a staff.
+// Every bit of NoteDataBin is in use, so these fields take an element of their own rather than
+// reserve bits. The element counts towards the header length like any other, so the header
+// announces encoded elements, which can outnumber the elements of the intermediate representation.
+// All eight values of the 3-bit rhythm value are taken, so a breve is written there as a semibreve,
+// which is also how decoders unaware of the breve flag read it.
+bitfield! {
+    pub struct NoteExtensionBin(MSB0 [u8]);
+    impl Debug;
+    u8;
+    pub get_identifier, set_identifier: 1, 0;
+    pub get_escape, set_escape: 3, 2;
+    pub get_extended_identifier, set_extended_identifier: 9, 4;
+    pub get_slur_number, set_slur_number: 11, 10;
+    pub get_cautionary_accidental, set_cautionary_accidental: 12;
+    pub get_note_size, set_note_size: 14, 13;
+    pub get_arpeggiate, set_arpeggiate: 16, 15;
+    pub get_breve, set_breve: 17;
+    pub get_notehead, set_notehead: 20, 18;
+    pub get_dynamics_placement, set_dynamics_placement: 22, 21;
+    pub get_tremolo_type, set_tremolo_type: 25, 23;
+    pub get_tremolo_marks, set_tremolo_marks: 29, 26;
+    pub get_staff, set_staff: 31, 30;
+}
+
+bitfield! {
+    pub struct ClefChangeBin(MSB0 [u8]);
+    impl Debug;
+    u8;
+    pub get_identifier, set_identifier: 1, 0;
+    pub get_escape, set_escape: 3, 2;
+    pub get_extended_identifier, set_extended_identifier: 9, 4;
+    pub get_staff, set_staff: 11, 10;
+    pub get_clef, set_clef: 13, 12;
+    /// Octave change of the clef, as a three bit two's complement value
+    pub get_octave_change, set_octave_change: 16, 14;
+}
+
+bitfield! {
+    /// Precedes the note element of an unpitched note, holding the General MIDI percussion key
+    pub struct PercussionBin(MSB0 [u8]);
+    impl Debug;
+    u8;
+    pub get_identifier, set_identifier: 1, 0;
+    pub get_escape, set_escape: 3, 2;
+    pub get_extended_identifier, set_extended_identifier: 9, 4;
+    pub get_drum, set_drum: 16, 10;
+}
+
+bitfield! {
+    /// Precedes the note element of a microtonal note, holding its deviation in cents from the pitch of
+    /// the note as a two's complement byte
+    pub struct MicrotoneBin(MSB0 [u8]);
+    impl Debug;
+    u8;
+    pub get_identifier, set_identifier: 1, 0;
+    pub get_escape, set_escape: 3, 2;
+    pub get_extended_identifier, set_extended_identifier: 9, 4;
+    pub get_cents, set_cents: 17, 10;
+}
+
+bitfield! {
+    /// Follows a note element, holding the pitches of up to three chord tones stacked on it that
+    /// share all of its other fields. Unused pitch slots hold zero, which is a rest and never a chord tone.
+    pub struct ChordClusterBin(MSB0 [u8]);
+    impl Debug;
+    u8;
+    pub get_identifier, set_identifier: 1, 0;
+    pub get_escape, set_escape: 3, 2;
+    pub get_extended_identifier, set_extended_identifier: 9, 4;
+    pub get_pitch_1, set_pitch_1: 16, 10;
+    pub get_pitch_2, set_pitch_2: 23, 17;
+    pub get_pitch_3, set_pitch_3: 30, 24;
+}
+
+/// Number of chord tones held by one chord cluster element
+pub const CHORD_CLUSTER_PITCHES: usize = 3;
+
+/// Returns how many of the elements following `elems[idx]` can be written as chord clusters: the
+/// chord tones stacked on the pitched note at `idx` that differ from it only in pitch
+pub fn chord_cluster_len(elems: &[MusicElement], idx: usize) -> usize {
+    let base = match elems.get(idx) {
+        Some(MusicElement::NoteRest(n)) if n.note_rest != NumericPitchRest::Rest => n,
+        _ => return 0,
+    };
+    elems[idx + 1..]
+        .iter()
+        .take_while(|e| match e {
+            MusicElement::NoteRest(tone) => {
+                tone.chord == Chord::Chord
+                    && tone.note_rest != NumericPitchRest::Rest
+                    && NoteData {
+                        note_rest: base.note_rest,
+                        chord: base.chord,
+                        ..*tone
+                    } == *base
+            }
+            _ => false,
+        })
+        .count()
+}
+
+/// Returns the number of chord cluster elements written for this many chord tones
+pub fn encoded_chord_cluster_count(tones: usize) -> usize {
+    tones.div_ceil(CHORD_CLUSTER_PITCHES)
+}
+
+/// Returns the number of encoded elements written for a stream of elements of the intermediate
+/// representation in the given revision, which is the element count announced by the header
+pub fn encoded_elements_count(elems: &[MusicElement], version: BinVersion) -> usize {
+    let mut num_encoded_elements = 0;
+    let mut idx = 0;
+    while idx < elems.len() {
+        let cluster_len = if version.supports_chord_clusters() {
+            chord_cluster_len(elems, idx)
+        } else {
+            0
+        };
+        num_encoded_elements += encoded_element_count(&elems[idx]) + encoded_chord_cluster_count(cluster_len);
+        idx += 1 + cluster_len;
+    }
+    num_encoded_elements
+}
+
+/// Returns the number of encoded elements written for an element of the intermediate representation
+pub fn encoded_element_count(element: &MusicElement) -> usize {
+    match element {
+        MusicElement::NoteRest(n) => 1 + note_prefix_count(n),
+        MusicElement::Percussion(p) => 2 + note_prefix_count(&p.note),
+        _ => 1,
+    }
+}
+
+/// Number of elements written before a note element: a microtone and a note extension
+fn note_prefix_count(note_data: &NoteData) -> usize {
+    usize::from(note_data.cents != 0) + usize::from(needs_note_extension(note_data))
+}
+
+fn needs_note_extension(note_data: &NoteData) -> bool {
+    (note_data.slur != SlurConnection::None && note_data.slur_number != SlurNumber::One)
+        || note_data.cautionary_accidental
+        || note_data.note_size != NoteSize::Full
+        || note_data.arpeggiate.direction().is_some()
+        || note_data.note_type == RhythmType::Breve
+        || note_data.notehead != Notehead::Normal
+        || note_data.dynamics_placement != Placement::Default
+        || note_data.tremolo.is_some()
+        || note_data.staff.is_some()
 }
 
 pub struct MusicEncoder<W: Write> {
     w: W,
+    version: BinVersion,
+    bytes_written: usize,
+    // Metadata announced by the header, written by insert_metadata
+    metadata: Option<(usize, Vec<String>)>,
+    // Length of the elements announced by the header, and of the elements written so far
+    length: Option<usize>,
+    element_bytes: usize,
 }
 
 impl<W: Write> MusicEncoder<W> {
     fn write_chunk(&mut self, data: &[u8]) -> Result<(), Error> {
-        self.w
-            .write(data)
-            .map_err(|e| Error::IoKind(e.kind().to_string()))?;
+        self.w.write_all(data)?;
+        self.bytes_written += data.len();
+        Ok(())
+    }
+
+    fn write_element(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.write_chunk(data)?;
+        self.element_bytes += data.len();
         Ok(())
     }
 
     pub fn new(w: W) -> MusicEncoder<W> {
-        MusicEncoder { w }
+        Self::with_version(w, BinVersion::default())
+    }
+
+    pub fn with_version(w: W, version: BinVersion) -> MusicEncoder<W> {
+        MusicEncoder {
+            w,
+            version,
+            bytes_written: 0,
+            metadata: None,
+            length: None,
+            element_bytes: 0,
+        }
+    }
+
+    pub fn get_version(&self) -> BinVersion {
+        self.version
     }
 
     pub fn create_header(&mut self, length: usize) -> Result<(), Error> {
-        let hdr = MusicBinHeader::new(length);
+        self.write_header(MusicBinHeader::new(self.version, length))
+    }
+
+    /// Writes a header announcing a metadata section holding `metadata`, to be written with
+    /// `insert_metadata` after the elements and the `trailer_length` bytes of any part table and
+    /// divisions sections.
+    pub fn create_header_with_metadata(
+        &mut self,
+        length: usize,
+        trailer_length: usize,
+        metadata: &[String],
+    ) -> Result<(), Error> {
+        if !self.version.supports_metadata() {
+            return Err(Error::Unsupported);
+        }
+        if metadata.len() > MetadataSection::MAX_ENTRIES
+            || metadata.iter().any(|s| s.len() > MetadataSection::MAX_STRING_LENGTH)
+        {
+            return Err(Error::OutofBounds);
+        }
+        let offset = self.version.header_length() + length + trailer_length;
+        let mut hdr = MusicBinHeader::new(self.version, length);
+        hdr.set_metadata_location(offset, MetadataSection::encoded_len(metadata));
+        self.metadata = Some((offset, metadata.to_vec()));
+        self.write_header(hdr)
+    }
+
+    fn write_header(&mut self, hdr: MusicBinHeader) -> Result<(), Error> {
+        self.length = Some(hdr.length);
         self.write_chunk(&hdr.identifier)?;
-        self.write_chunk(&(hdr.length as u32).to_le_bytes())
+        self.write_chunk(&(hdr.length as u32).to_le_bytes())?;
+        if self.version.supports_metadata() {
+            let (offset, size) = hdr.get_metadata_location().unwrap_or_default();
+            self.write_chunk(&(offset as u32).to_le_bytes())?;
+            self.write_chunk(&(size as u32).to_le_bytes())?;
+        }
+        Ok(())
     }
 
     pub fn flush(&mut self) -> Result<(), Error> {
-        self.w
-            .flush()
-            .map_err(|e| Error::IoKind(e.kind().to_string()))?;
+        self.w.flush()?;
         Ok(())
     }
 
+    /// Flushes the writer and returns the number of bytes written. The header holds the length of the
+    /// elements before they are written, so this fails when the elements written do not add up to it,
+    /// or when a metadata section the header announced was never written, either of which would leave
+    /// the output unreadable.
+    pub fn finish(mut self) -> Result<usize, Error> {
+        if let Some(length) = self.length.filter(|&length| length != self.element_bytes) {
+            error!(
+                "Header announces {} bytes of elements, but {} were written",
+                length, self.element_bytes
+            );
+            return Err(Error::Encoding);
+        }
+        if self.metadata.is_some() {
+            error!("Header announces a metadata section that was not written");
+            return Err(Error::Encoding);
+        }
+        self.flush()?;
+        Ok(self.bytes_written)
+    }
+
     pub fn insert_measure_initializer(
         &mut self,
         measure_init: MeasureInitializer,
@@ -133,7 +560,7 @@ impl<W: Write> MusicEncoder<W> {
         measure_initializer.set_beat_type(measure_init.beat_type as u8);
         measure_initializer.set_fifths(measure_init.key_sig as u8);
         measure_initializer.set_tempo(measure_init.tempo.get_raw());
-        self.write_chunk(&data)
+        self.write_element(&data)
     }
 
     pub fn insert_measure_metadata(&mut self, measure_meta: MeasureMetaData) -> Result<(), Error> {
@@ -143,17 +570,56 @@ impl<W: Write> MusicEncoder<W> {
         measure_metadata.set_start_end(measure_meta.start_end as u8);
         measure_metadata.set_ending(measure_meta.ending as u8);
         measure_metadata.set_dal_segno(measure_meta.dal_segno as u8);
-        self.write_chunk(&data)
+        // Zero when no count is given
+        measure_metadata.set_repeat_times(measure_meta.repeat_times.unwrap_or(0));
+        measure_metadata.set_bar_style(measure_meta.bar_style as u8);
+        self.write_element(&data)
     }
 
     pub fn insert_note_data(&mut self, note_data: NoteData) -> Result<(), Error> {
+        if note_data.cents != 0 {
+            let mut data: [u8; 4] = [0; 4];
+            let mut microtone_bin = MicrotoneBin(&mut data);
+            microtone_bin.set_identifier(MusicTagIdentifiers::Tuplet as u8);
+            microtone_bin.set_escape(EXTENDED_ELEMENT_ESCAPE);
+            microtone_bin.set_extended_identifier(ExtendedTagIdentifiers::Microtone as u8);
+            microtone_bin.set_cents(note_data.cents as u8);
+            self.write_element(&data)?;
+        }
+        if needs_note_extension(&note_data) {
+            let mut data: [u8; 4] = [0; 4];
+            let mut note_extension_bin = NoteExtensionBin(&mut data);
+            note_extension_bin.set_identifier(MusicTagIdentifiers::Tuplet as u8);
+            note_extension_bin.set_escape(EXTENDED_ELEMENT_ESCAPE);
+            note_extension_bin.set_extended_identifier(ExtendedTagIdentifiers::NoteExtension as u8);
+            note_extension_bin.set_slur_number(note_data.slur_number as u8);
+            note_extension_bin.set_cautionary_accidental(note_data.cautionary_accidental);
+            note_extension_bin.set_note_size(note_data.note_size as u8);
+            note_extension_bin.set_arpeggiate(note_data.arpeggiate as u8);
+            note_extension_bin.set_breve(note_data.note_type == RhythmType::Breve);
+            note_extension_bin.set_notehead(note_data.notehead as u8);
+            note_extension_bin.set_dynamics_placement(note_data.dynamics_placement as u8);
+            // Zero when the note has no tremolo
+            if let Some(tremolo) = note_data.tremolo {
+                note_extension_bin.set_tremolo_type(tremolo.tremolo_type as u8 + 1);
+                note_extension_bin.set_tremolo_marks(tremolo.marks);
+            }
+            // Zero when the staff is derived from the voice
+            note_extension_bin.set_staff(note_data.staff.map_or(0, |s| s as u8));
+            self.write_element(&data)?;
+        }
         let mut data: [u8; 4] = [0; 4];
         let mut note_data_bin = NoteDataBin(&mut data);
         note_data_bin.set_identifier(MusicTagIdentifiers::NoteData as u8);
         note_data_bin.set_note(note_data.note_rest.get_numeric_value());
         note_data_bin.set_phrase_dynamics(note_data.phrase_dynamics as u8);
-        note_data_bin.set_rhythm_value(note_data.note_type as u8);
+        // A breve is marked in the note extension
+        note_data_bin.set_rhythm_value(match note_data.note_type {
+            RhythmType::Breve => RhythmType::SemiBreve as u8,
+            note_type => note_type as u8,
+        });
         note_data_bin.set_dotted(note_data.dotted);
+        // The direction of an arpeggio is kept in the note extension
         note_data_bin.set_arpeggiation(bool::from(note_data.arpeggiate));
         note_data_bin.set_special_note(note_data.special_note as u8);
         note_data_bin.set_articulation(note_data.articulation as u8);
@@ -162,7 +628,99 @@ impl<W: Write> MusicEncoder<W> {
         note_data_bin.set_chord(bool::from(note_data.chord));
         note_data_bin.set_slur(note_data.slur as u8);
         note_data_bin.set_voice(note_data.voice as u8);
-        self.write_chunk(&data)
+        self.write_element(&data)
+    }
+
+    /// Writes the pitches of chord tones stacked on the note element written before, three per element
+    pub fn insert_chord_cluster(&mut self, pitches: &[NumericPitchRest]) -> Result<(), Error> {
+        if !self.version.supports_chord_clusters() || pitches.contains(&NumericPitchRest::Rest) {
+            return Err(Error::Encoding);
+        }
+        for chunk in pitches.chunks(CHORD_CLUSTER_PITCHES) {
+            let pitch = |slot: usize| chunk.get(slot).map_or(0, |p| p.get_numeric_value());
+            let mut data: [u8; 4] = [0; 4];
+            let mut chord_cluster_bin = ChordClusterBin(&mut data);
+            chord_cluster_bin.set_identifier(MusicTagIdentifiers::Tuplet as u8);
+            chord_cluster_bin.set_escape(EXTENDED_ELEMENT_ESCAPE);
+            chord_cluster_bin.set_extended_identifier(ExtendedTagIdentifiers::ChordCluster as u8);
+            chord_cluster_bin.set_pitch_1(pitch(0));
+            chord_cluster_bin.set_pitch_2(pitch(1));
+            chord_cluster_bin.set_pitch_3(pitch(2));
+            self.write_element(&data)?;
+        }
+        Ok(())
+    }
+
+    pub fn insert_harmony_data(&mut self, harmony_data: HarmonyData) -> Result<(), Error> {
+        let mut data: [u8; 4] = [0; 4];
+        let mut harmony_data_bin = HarmonyDataBin(&mut data);
+        harmony_data_bin.set_identifier(MusicTagIdentifiers::Tuplet as u8);
+        harmony_data_bin.set_escape(EXTENDED_ELEMENT_ESCAPE);
+        harmony_data_bin.set_extended_identifier(ExtendedTagIdentifiers::Harmony as u8);
+        harmony_data_bin.set_root_step(harmony_data.root_step as u8);
+        harmony_data_bin.set_root_alter(harmony_data.root_alter as u8);
+        harmony_data_bin.set_kind(harmony_data.kind as u8);
+        if let Some((bass_step, bass_alter)) = harmony_data.bass {
+            harmony_data_bin.set_has_bass(true);
+            harmony_data_bin.set_bass_step(bass_step as u8);
+            harmony_data_bin.set_bass_alter(bass_alter as u8);
+        }
+        self.write_element(&data)
+    }
+
+    pub fn insert_clef_change(&mut self, clef_change: ClefChangeData) -> Result<(), Error> {
+        let mut data: [u8; 4] = [0; 4];
+        let mut clef_change_bin = ClefChangeBin(&mut data);
+        clef_change_bin.set_identifier(MusicTagIdentifiers::Tuplet as u8);
+        clef_change_bin.set_escape(EXTENDED_ELEMENT_ESCAPE);
+        clef_change_bin.set_extended_identifier(ExtendedTagIdentifiers::ClefChange as u8);
+        clef_change_bin.set_staff(clef_change.staff as u8);
+        clef_change_bin.set_clef(clef_change.clef as u8);
+        clef_change_bin.set_octave_change(clef_change.octave_change as u8 & 0b111);
+        self.write_element(&data)
+    }
+
+    pub fn insert_percussion(&mut self, percussion: PercussionData) -> Result<(), Error> {
+        let mut data: [u8; 4] = [0; 4];
+        let mut percussion_bin = PercussionBin(&mut data);
+        percussion_bin.set_identifier(MusicTagIdentifiers::Tuplet as u8);
+        percussion_bin.set_escape(EXTENDED_ELEMENT_ESCAPE);
+        percussion_bin.set_extended_identifier(ExtendedTagIdentifiers::Percussion as u8);
+        percussion_bin.set_drum(percussion.drum.midi_key());
+        self.write_element(&data)?;
+        self.insert_note_data(percussion.note)
+    }
+
+    pub fn insert_part_table(&mut self, part_names: &[PartName]) -> Result<(), Error> {
+        if part_names.len() > PartTableHeader::MAX_ENTRIES {
+            return Err(Error::OutofBounds);
+        }
+        let payload = part_table_payload(part_names);
+        self.write_chunk(&PartTableHeader::PART_TABLE_MAGIC_NUMBER)?;
+        self.write_chunk(&(payload.len() as u32).to_le_bytes())?;
+        self.write_chunk(&payload)
+    }
+
+    pub fn insert_divisions(&mut self, divisions: u32) -> Result<(), Error> {
+        let payload = divisions.to_le_bytes();
+        self.write_chunk(&DivisionsHeader::DIVISIONS_MAGIC_NUMBER)?;
+        self.write_chunk(&(payload.len() as u32).to_le_bytes())?;
+        self.write_chunk(&payload)
+    }
+
+    /// Writes the metadata section announced by the header. It must be the last section written.
+    pub fn insert_metadata(&mut self) -> Result<(), Error> {
+        let (offset, metadata) = self.metadata.take().ok_or(Error::NotInitialized)?;
+        // The header already records where the section starts
+        if offset != self.bytes_written {
+            return Err(Error::Encoding);
+        }
+        let mut payload: Vec<u8> = (metadata.len() as u32).to_le_bytes().to_vec();
+        for s in metadata.iter() {
+            payload.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            payload.extend_from_slice(s.as_bytes());
+        }
+        self.write_chunk(&payload)
     }
 
     pub fn insert_tuplet_data(&mut self, tuplet_data: TupletData) -> Result<(), Error> {
@@ -174,6 +732,143 @@ impl<W: Write> MusicEncoder<W> {
         tuplet_data_bin.set_actual_note(tuplet_data.actual_notes as u8);
         tuplet_data_bin.set_normal_note(tuplet_data.normal_notes as u8);
         tuplet_data_bin.set_dotted(tuplet_data.dotted);
-        self.write_chunk(&data)
+        tuplet_data_bin.set_hide_bracket(tuplet_data.hide_bracket);
+        tuplet_data_bin.set_hide_number(tuplet_data.hide_number);
+        // Zero when the normal notes are of the same type as the tuplet's notes
+        tuplet_data_bin.set_normal_type(tuplet_data.normal_type.map_or(0, |t| t as u8 + 1));
+        self.write_element(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BinVersion, DivisionsHeader, MusicEncoder, MUSIC_ELEMENT_LENGTH};
+    use crate::error::Error;
+    use crate::bin_format::bin_decoder::MusicDecoder;
+    use crate::bin_format::{bin_bytes_to_ir, ir_to_bin, ir_to_bin_with_version};
+    use crate::ir::notation::{
+        Chord, HarmonyAlter, HarmonyData, HarmonyKind, HarmonyStep, NoteData, NumericPitchRest, RhythmType, Voice,
+    };
+    use crate::ir::{MusicElement, PartBuilder};
+    use crate::utils::temp_path;
+    use std::fs::{self, File};
+    use std::io::BufWriter;
+
+    #[test]
+    fn test_harmony_cmaj7_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let cmaj7 = HarmonyData {
+            root_step: HarmonyStep::C,
+            root_alter: HarmonyAlter::None,
+            kind: HarmonyKind::MajorSeventh,
+            bass: None,
+        };
+        let mut data: Vec<u8> = vec![];
+        MusicEncoder::new(&mut data).insert_harmony_data(cmaj7)?;
+
+        let mut music_dec = MusicDecoder::new(None);
+        music_dec.raw_read(&data);
+        assert_eq!(music_dec.parse_element()?, MusicElement::Harmony(cmaj7));
+        Ok(())
+    }
+
+    #[test]
+    fn test_breve_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let breve = NoteData {
+            note_rest: NumericPitchRest::Pitch(40),
+            note_type: RhythmType::Breve,
+            ..Default::default()
+        };
+        assert_eq!(breve.note_type.get_type_string(), "breve");
+        let mut data: Vec<u8> = vec![];
+        MusicEncoder::new(&mut data).insert_note_data(breve)?;
+        assert_eq!(data.len(), 8);
+
+        let mut music_dec = MusicDecoder::new(None);
+        music_dec.raw_read(&data);
+        assert_eq!(music_dec.parse_element()?, MusicElement::NoteRest(breve));
+        Ok(())
+    }
+
+    #[test]
+    fn test_chord_cluster_size_and_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let tone = |pitch| NoteData {
+            note_rest: NumericPitchRest::Pitch(pitch),
+            note_type: RhythmType::SemiBreve,
+            chord: Chord::Chord,
+            ..NoteData::default()
+        };
+        let part = PartBuilder::new()
+            .note(NumericPitchRest::Pitch(40), RhythmType::SemiBreve, Voice::One)
+            .note_data(tone(44))
+            .note_data(tone(47))
+            .note_data(tone(52))
+            .build("P1")?;
+
+        let mut old: Vec<u8> = vec![];
+        ir_to_bin(&mut old, &part, None)?;
+        let mut new: Vec<u8> = vec![];
+        ir_to_bin_with_version(&mut new, &part, None, BinVersion::V2)?;
+        assert!(new.starts_with(b"MuB2"));
+        // The three chord tones share one cluster element instead of taking one element each
+        assert_eq!(old.len() - new.len(), 2 * MUSIC_ELEMENT_LENGTH);
+
+        let max_elements = MusicDecoder::DEFAULT_MAX_ELEMENTS;
+        assert_eq!(bin_bytes_to_ir(&new, max_elements)?.inner(), part.inner());
+        assert_eq!(bin_bytes_to_ir(&old, max_elements)?.inner(), part.inner());
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_section_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let metadata = vec!["Clair de lune".to_string(), "Für Elise, ♩ = 72".to_string()];
+        let note = NoteData {
+            note_rest: NumericPitchRest::Pitch(40),
+            ..NoteData::default()
+        };
+        let divisions_length = DivisionsHeader::ENCODED_LENGTH;
+        let mut data: Vec<u8> = vec![];
+        let mut music_enc = MusicEncoder::with_version(&mut data, BinVersion::V3);
+        music_enc.create_header_with_metadata(MUSIC_ELEMENT_LENGTH, divisions_length, &metadata)?;
+        music_enc.insert_note_data(note)?;
+        music_enc.insert_divisions(4)?;
+        music_enc.insert_metadata()?;
+        assert!(data.starts_with(b"MuB3"));
+
+        let mut music_dec = MusicDecoder::new(None);
+        music_dec.raw_read(&data);
+        let (elements, trailer) = music_dec.parse_data()?;
+        assert_eq!(elements, vec![MusicElement::NoteRest(note)]);
+        assert_eq!(trailer.divisions, Some(4));
+        assert_eq!(trailer.metadata, metadata);
+
+        // The section must start where the header says it does
+        let mut data: Vec<u8> = vec![];
+        let mut music_enc = MusicEncoder::with_version(&mut data, BinVersion::V3);
+        music_enc.create_header_with_metadata(MUSIC_ELEMENT_LENGTH, 0, &metadata)?;
+        music_enc.insert_note_data(note)?;
+        music_enc.insert_divisions(4)?;
+        assert_eq!(music_enc.insert_metadata(), Err(Error::Encoding));
+        Ok(())
+    }
+
+    #[test]
+    fn test_finish_returns_file_size() -> Result<(), Box<dyn std::error::Error>> {
+        let part = PartBuilder::new()
+            .note(NumericPitchRest::Pitch(40), RhythmType::Crochet, Voice::One)
+            .note(NumericPitchRest::Pitch(44), RhythmType::Minim, Voice::One)
+            .build("P1")?;
+        let path = temp_path("finish.bin");
+        let written = ir_to_bin(BufWriter::new(File::create(&path)?), &part, None);
+        let file_size = fs::metadata(&path)?.len();
+        fs::remove_file(&path)?;
+        assert_eq!(written? as u64, file_size);
+
+        // Fewer elements than the header announces make an unreadable file
+        let mut data: Vec<u8> = vec![];
+        let mut music_enc = MusicEncoder::new(&mut data);
+        music_enc.create_header(2 * MUSIC_ELEMENT_LENGTH)?;
+        music_enc.insert_note_data(NoteData::default())?;
+        assert_eq!(music_enc.finish(), Err(Error::Encoding));
+        Ok(())
     }
 }
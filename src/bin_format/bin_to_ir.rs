@@ -1,17 +1,828 @@
 use super::bin_decoder::MusicDecoder;
 use crate::error::Result;
-use crate::ir::MusicalPart;
-use log::debug;
+use crate::ir::{DumpOptions, LayoutBreak, MeasureNumber, MusicalPart, DUMP_TARGET};
+use log::{debug, info, warn};
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read};
 
-pub fn bin_to_ir(reader: BufReader<File>, dump_input: bool) -> Result<MusicalPart> {
-    let mut music_decoder = MusicDecoder::new(Some(reader));
-    music_decoder.reader_read()?;
+pub fn bin_to_ir(reader: BufReader<File>, dump_input: Option<DumpOptions>) -> Result<MusicalPart> {
+    bin_to_ir_with_max_elements(reader, dump_input, MusicDecoder::DEFAULT_MAX_ELEMENTS)
+}
+
+/// Decodes a bin file, rejecting files whose header declares more than `max_elements` elements. With
+/// `dump_input` set, every decoded element is logged to `DUMP_TARGET` in the given dump format and pitch
+/// notation.
+pub fn bin_to_ir_with_max_elements(
+    mut reader: BufReader<File>,
+    dump_input: Option<DumpOptions>,
+    max_elements: usize,
+) -> Result<MusicalPart> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+    bin_bytes_to_ir_with_dump(&bytes, dump_input, max_elements)
+}
+
+/// Decodes a bin file already held in memory
+pub fn bin_bytes_to_ir(bytes: &[u8], max_elements: usize) -> Result<MusicalPart> {
+    bin_bytes_to_ir_with_dump(bytes, None, max_elements)
+}
+
+/// Decodes a bin file already held in memory, dumping the decoded elements when `dump_input` is set
+pub fn bin_bytes_to_ir_with_dump(
+    bytes: &[u8],
+    dump_input: Option<DumpOptions>,
+    max_elements: usize,
+) -> Result<MusicalPart> {
+    let mut music_decoder = MusicDecoder::new(None);
+    music_decoder.set_max_elements(max_elements);
+    music_decoder.raw_read(bytes);
+    decoded_to_ir(&music_decoder, dump_input)
+}
+
+#[cfg(feature = "mmap")]
+/// Decodes a bin file by memory mapping it, so a large file is not also held in a buffer of its own
+///
+/// # Safety
+///
+/// Nothing may truncate or modify `file` until this returns; see [`MusicDecoder::map_file`].
+pub unsafe fn bin_to_ir_mapped(
+    file: &File,
+    dump_input: Option<DumpOptions>,
+    max_elements: usize,
+) -> Result<MusicalPart> {
+    let mut music_decoder = MusicDecoder::new(None);
+    music_decoder.set_max_elements(max_elements);
+    // SAFETY: forwarded to the caller
+    unsafe { music_decoder.map_file(file)? };
+    decoded_to_ir(&music_decoder, dump_input)
+}
 
-    let parsed_elems = music_decoder.parse_data()?;
+fn decoded_to_ir(music_decoder: &MusicDecoder, dump_input: Option<DumpOptions>) -> Result<MusicalPart> {
+    let (parsed_elems, mut trailer) = music_decoder.parse_data()?;
+    if let Some(dump) = dump_input {
+        for line in dump.lines(&parsed_elems) {
+            info!(target: DUMP_TARGET, "{}", line);
+        }
+    }
 
-    let part = MusicalPart::new_from_elems("P1", parsed_elems)?;
+    let mut part = MusicalPart::new_from_elems("P1", parsed_elems)?;
+    if !trailer.part_names.is_empty() {
+        part.set_part_name(Some(trailer.part_names.swap_remove(0)));
+    }
+    for (measure_idx, number) in trailer.metadata.iter().filter_map(|s| MeasureNumber::from_metadata(s)) {
+        part.set_measure_number(measure_idx, number);
+    }
+    for (measure_idx, layout_break) in trailer.metadata.iter().filter_map(|s| LayoutBreak::from_metadata(s)) {
+        part.set_layout_break(measure_idx, layout_break);
+    }
+    if let Some(divisions) = trailer.divisions {
+        // The stored value must still express the shortest note computed from the elements
+        let min_divisions = part.get_initial_divisions().unwrap();
+        if divisions != 0 && divisions % min_divisions == 0 {
+            part.set_initial_divisions(divisions);
+        } else {
+            warn!(
+                "Stored divisions {} is not a multiple of the required {}, ignoring it",
+                divisions, min_divisions
+            );
+        }
+    }
     debug!("Divisions is {}. Voices is {}", part.get_initial_divisions().unwrap(), part.get_num_voices());
     Ok(part)
 }
+
+/// Encodes a part and decodes it again, in memory
+#[cfg(test)]
+pub fn round_trip(part: &MusicalPart) -> Result<MusicalPart> {
+    let mut encoded: Vec<u8> = vec![];
+    super::ir_to_bin(&mut encoded, part, None)?;
+    bin_bytes_to_ir(&encoded, MusicDecoder::DEFAULT_MAX_ELEMENTS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::round_trip;
+    use crate::ir::notation::{
+        Arpeggiate, BarStyle, Clef, ClefChangeData, DrumSound, MeasureStartEnd, NoteSize, Notehead, RhythmType,
+        SlurConnection, SlurNumber, Staff, Tremolo, TremoloType, TupletData, TupletStartStop,
+    };
+    use crate::ir::xml_patch::XmlPatcher;
+    use crate::ir::{
+        ir_to_xml::{ir_to_xml, measures_from_musical_part},
+        xml_to_ir, ExportOptions, MusicElement, NoteFeatures, ParseOptions, PartMap,
+    };
+    use muxml::muxml_types::{Measure, MeasureDirectionNote};
+    use std::fs;
+
+    #[test]
+    fn test_divisions_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let docstring = fs::read_to_string("test/divisions_8.musicxml")?;
+        let opt = roxmltree::ParsingOptions {
+            allow_dtd: true,
+            ..roxmltree::ParsingOptions::default()
+        };
+        let doc = roxmltree::Document::parse_with_options(&docstring, opt)?;
+        let input_divisions = doc
+            .descendants()
+            .find(|n| n.has_tag_name("divisions"))
+            .and_then(|n| n.text())
+            .unwrap()
+            .to_string();
+
+        let partmap = xml_to_ir(docstring.clone(), false, &ParseOptions::default())?;
+        let part = round_trip(partmap.get_part(0).unwrap())?;
+
+        let measures: Vec<Measure> = (&part).into();
+        let exported_divisions = measures
+            .iter()
+            .find_map(|m| m.attributes.as_ref())
+            .map(|a| a.divisions.clone());
+        assert_eq!(exported_divisions, Some(input_divisions));
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlapping_slurs_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let docstring = fs::read_to_string("test/overlapping_slurs.musicxml")?;
+        let partmap = xml_to_ir(docstring, false, &ParseOptions::default())?;
+        let part = round_trip(partmap.get_part(0).unwrap())?;
+
+        let slurs: Vec<(SlurConnection, SlurNumber)> = part
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some((n.slur, n.slur_number)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            slurs,
+            vec![
+                (SlurConnection::StartSlur, SlurNumber::One),
+                (SlurConnection::StartSlur, SlurNumber::Two),
+                (SlurConnection::EndSlur, SlurNumber::One),
+                (SlurConnection::EndSlur, SlurNumber::Two),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cautionary_accidental_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let docstring = fs::read_to_string("test/cautionary_accidental.musicxml")?;
+        let partmap = xml_to_ir(docstring, false, &ParseOptions::default())?;
+        let part = round_trip(partmap.get_part(0).unwrap())?;
+
+        let cautionary: Vec<bool> = part
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some(n.cautionary_accidental),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(cautionary, vec![false, true, false, false]);
+
+        // muxml has no accidental element, so the exported note is patched
+        let mut patcher = XmlPatcher::new();
+        measures_from_musical_part(&part, "P1", &mut patcher, false)?;
+        let notes = "<note><pitch/><type>quarter</type><staff>1</staff></note>".repeat(4);
+        let xml = format!("<score-partwise><part id=\"P1\"><measure number=\"1\">{}</measure></part></score-partwise>", notes);
+        let patched = patcher.apply(&xml)?;
+        let doc = roxmltree::Document::parse(&patched)?;
+        let accidentals: Vec<(usize, Option<&str>)> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("note"))
+            .enumerate()
+            .filter_map(|(idx, n)| {
+                let accidental = n.children().find(|c| c.has_tag_name("accidental"))?;
+                Some((idx, accidental.attribute("parentheses")))
+            })
+            .collect();
+        assert_eq!(accidentals, vec![(1, Some("yes"))]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cue_note_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let docstring = fs::read_to_string("test/cue_note.musicxml")?;
+        let partmap = xml_to_ir(docstring, false, &ParseOptions::default())?;
+        let part = round_trip(partmap.get_part(0).unwrap())?;
+
+        let sizes: Vec<NoteSize> = part
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some(n.note_size),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            sizes,
+            vec![
+                NoteSize::Full,
+                NoteSize::CueSized,
+                NoteSize::Cue,
+                NoteSize::Full,
+                NoteSize::Full
+            ]
+        );
+
+        // The cue note does not count towards the measure, so the voice is not split
+        let mut patcher = XmlPatcher::new();
+        let measures = measures_from_musical_part(&part, "P1", &mut patcher, false)?;
+        assert_eq!(measures.len(), 1);
+        let notes = "<note><pitch/><duration>1</duration><type>quarter</type></note>".repeat(5);
+        let xml = format!("<score-partwise><part id=\"P1\"><measure number=\"1\">{}</measure></part></score-partwise>", notes);
+        let patched = patcher.apply(&xml)?;
+        let doc = roxmltree::Document::parse(&patched)?;
+        let marks: Vec<(bool, Option<&str>)> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("note"))
+            .map(|n| {
+                let cue = n.first_element_child().is_some_and(|c| c.has_tag_name("cue"));
+                let size = n.children().find(|c| c.has_tag_name("type")).and_then(|c| c.attribute("size"));
+                (cue, size)
+            })
+            .collect();
+        assert_eq!(
+            marks,
+            vec![
+                (false, None),
+                (false, Some("cue")),
+                (true, Some("cue")),
+                (false, None),
+                (false, None)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_downward_arpeggio_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let docstring = fs::read_to_string("test/arpeggio_down.musicxml")?;
+        let partmap = xml_to_ir(docstring, false, &ParseOptions::default())?;
+        let part = round_trip(partmap.get_part(0).unwrap())?;
+
+        let arpeggios: Vec<Arpeggiate> = part
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some(n.arpeggiate),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            arpeggios,
+            vec![Arpeggiate::Down, Arpeggiate::Down, Arpeggiate::Down, Arpeggiate::Arpeggiate]
+        );
+
+        // muxml writes arpeggios without a direction, so the exported notes are patched
+        let mut patcher = XmlPatcher::new();
+        measures_from_musical_part(&part, "P1", &mut patcher, false)?;
+        let notes = "<note><pitch/><type>half</type><notations><arpeggiate/></notations></note>".repeat(4);
+        let xml = format!("<score-partwise><part id=\"P1\"><measure number=\"1\">{}</measure></part></score-partwise>", notes);
+        let patched = patcher.apply(&xml)?;
+        let doc = roxmltree::Document::parse(&patched)?;
+        let directions: Vec<Option<&str>> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("arpeggiate"))
+            .map(|n| n.attribute("direction"))
+            .collect();
+        assert_eq!(directions, vec![Some("down"), Some("down"), Some("down"), None]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mid_measure_clef_change_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let docstring = fs::read_to_string("test/clef_change.musicxml")?;
+        let partmap = xml_to_ir(docstring, false, &ParseOptions::default())?;
+        let part = round_trip(partmap.get_part(0).unwrap())?;
+
+        // The initial bass clef is not a change, the treble clef after the second note of bar 2 is
+        let clef_change = MusicElement::ClefChange(ClefChangeData {
+            staff: Staff::TrebleClef,
+            clef: Clef::Treble,
+            octave_change: 0,
+        });
+        let clef_change_idx = part.inner().iter().position(|e| *e == clef_change).unwrap();
+        assert_eq!(part.inner().iter().filter(|e| matches!(e, MusicElement::ClefChange(_))).count(), 1);
+        let notes_before = part.inner()[..clef_change_idx]
+            .iter()
+            .filter(|e| matches!(e, MusicElement::NoteRest(_)))
+            .count();
+        assert_eq!(notes_before, 6);
+
+        // muxml has no mid-measure attributes, so the clef change is patched in before the third note
+        let mut patcher = XmlPatcher::new();
+        measures_from_musical_part(&part, "P1", &mut patcher, false)?;
+        let notes = "<note><pitch/><type>quarter</type></note>".repeat(4);
+        let xml = format!(
+            "<score-partwise><part id=\"P1\"><measure number=\"1\">{0}</measure><measure number=\"2\">{0}</measure></part></score-partwise>",
+            notes
+        );
+        let patched = patcher.apply(&xml)?;
+        let doc = roxmltree::Document::parse(&patched)?;
+        let measure = doc.descendants().filter(|n| n.has_tag_name("measure")).nth(1).unwrap();
+        let children: Vec<&str> = measure.children().map(|n| n.tag_name().name()).collect();
+        assert_eq!(children, vec!["note", "note", "attributes", "note", "note"]);
+        let clef = measure.descendants().find(|n| n.has_tag_name("clef")).unwrap();
+        assert_eq!(clef.attribute("number"), Some("1"));
+        let sign = clef.children().find(|n| n.has_tag_name("sign")).and_then(|n| n.text());
+        assert_eq!(sign, Some("G"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_octave_clef_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let docstring = fs::read_to_string("test/treble_8vb_clef.musicxml")?;
+        let written_pitches = |doc: &roxmltree::Document| -> Vec<(String, String)> {
+            let text = |n: roxmltree::Node, tag| {
+                n.descendants().find(|c| c.has_tag_name(tag)).and_then(|c| c.text()).unwrap_or("").to_string()
+            };
+            doc.descendants()
+                .filter(|n| n.has_tag_name("pitch"))
+                .map(|n| (text(n, "step"), text(n, "octave")))
+                .collect()
+        };
+        let source_pitches = written_pitches(&roxmltree::Document::parse_with_options(
+            &docstring,
+            roxmltree::ParsingOptions {
+                allow_dtd: true,
+                ..roxmltree::ParsingOptions::default()
+            },
+        )?);
+        let partmap = xml_to_ir(docstring, false, &ParseOptions::default())?;
+        let part = round_trip(partmap.get_part(0).unwrap())?;
+
+        // The initial clef is kept as a change, since the part's clefs hold no octave change
+        let clef_changes: Vec<&MusicElement> =
+            part.inner().iter().filter(|e| matches!(e, MusicElement::ClefChange(_))).collect();
+        assert_eq!(
+            clef_changes,
+            vec![&MusicElement::ClefChange(ClefChangeData {
+                staff: Staff::TrebleClef,
+                clef: Clef::Treble,
+                octave_change: -1,
+            })]
+        );
+
+        let mut parts = PartMap::new();
+        parts.push_part("P1", part)?;
+        let xml = ir_to_xml(parts, &ExportOptions::default())?;
+        let doc = roxmltree::Document::parse(&xml)?;
+        // Pitches are written at the octave they were read at, and every treble clef of the staff,
+        // including the one written again with the key change of bar 2, carries the octave change
+        assert_eq!(written_pitches(&doc), source_pitches);
+        let treble_octaves: Vec<Option<&str>> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("clef") && n.attribute("number") == Some("1"))
+            .filter(|n| n.children().any(|c| c.has_tag_name("sign") && c.text() == Some("G")))
+            .map(|n| n.children().find(|c| c.has_tag_name("clef-octave-change")).and_then(|c| c.text()))
+            .collect();
+        assert_eq!(treble_octaves, vec![None, Some("-1"), Some("-1")]);
+
+        // Reading the export back finds the same single change
+        let reread = xml_to_ir(xml, false, &ParseOptions::default())?;
+        let reread_changes = reread
+            .get_part(0)
+            .unwrap()
+            .inner()
+            .iter()
+            .filter(|e| matches!(e, MusicElement::ClefChange(_)))
+            .count();
+        assert_eq!(reread_changes, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cross_staff_note_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let docstring = fs::read_to_string("test/cross_staff.musicxml")?;
+        let partmap = xml_to_ir(docstring, false, &ParseOptions::default())?;
+        // Only the note of voice 1 put on the bass staff keeps its staff, the others follow their voice
+        let staves: Vec<Option<Staff>> = partmap
+            .get_part(0)
+            .unwrap()
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some(n.staff),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(staves, vec![None, None, Some(Staff::BassClef), None, None]);
+
+        let part = round_trip(partmap.get_part(0).unwrap())?;
+
+        let mut parts = PartMap::new();
+        parts.push_part("P1", part)?;
+        let xml = ir_to_xml(parts, &ExportOptions::default())?;
+        let doc = roxmltree::Document::parse(&xml)?;
+        let written: Vec<&str> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("note"))
+            .filter_map(|n| n.children().find(|c| c.has_tag_name("staff")).and_then(|c| c.text()))
+            .collect();
+        assert_eq!(written, vec!["1", "1", "2", "1", "2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tuplet_without_bracket_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let docstring = fs::read_to_string("test/tuplet_no_bracket.musicxml")?;
+        let partmap = xml_to_ir(docstring, false, &ParseOptions::default())?;
+        let part = round_trip(partmap.get_part(0).unwrap())?;
+
+        let tuplets: Vec<TupletData> = part
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::Tuplet(t) => Some(*t),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(tuplets.len(), 2);
+        assert_eq!(tuplets[0].start_stop, TupletStartStop::TupletStart);
+        assert!(tuplets[0].hide_bracket);
+        assert!(!tuplets[0].hide_number);
+
+        // muxml writes tuplets without visibility attributes, so the note starting the tuplet is patched
+        let mut patcher = XmlPatcher::new();
+        measures_from_musical_part(&part, "P1", &mut patcher, false)?;
+        let notes = "<note><pitch/><type>eighth</type><notations><tuplet type=\"start\"/></notations></note>"
+            .to_string()
+            + &"<note><pitch/><type>eighth</type></note>".repeat(4);
+        let xml = format!("<score-partwise><part id=\"P1\"><measure number=\"1\">{}</measure></part></score-partwise>", notes);
+        let patched = patcher.apply(&xml)?;
+        let doc = roxmltree::Document::parse(&patched)?;
+        let tuplet = doc.descendants().find(|n| n.has_tag_name("tuplet")).unwrap();
+        assert_eq!(tuplet.attribute("bracket"), Some("no"));
+        assert_eq!(tuplet.attribute("show-number"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tuplet_normal_type_durations() -> Result<(), Box<dyn std::error::Error>> {
+        // Three eighths in the time of one quarter
+        let docstring = fs::read_to_string("test/tuplet_normal_type.musicxml")?;
+        let partmap = xml_to_ir(docstring, false, &ParseOptions::default())?;
+        let part = round_trip(partmap.get_part(0).unwrap())?;
+
+        let start = part
+            .inner()
+            .iter()
+            .find_map(|e| match e {
+                MusicElement::Tuplet(t) if t.start_stop == TupletStartStop::TupletStart => Some(*t),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(start.normal_type, Some(RhythmType::Crochet));
+        let ticks: Vec<u32> = NoteFeatures::from_elements(part.inner()).iter().map(|f| f.duration).collect();
+        assert_eq!(ticks, vec![320, 320, 320, 960, 1920]);
+
+        let mut patcher = XmlPatcher::new();
+        let measures = measures_from_musical_part(&part, "P1", &mut patcher, true)?;
+        let durations: Vec<&str> = measures[0]
+            .direction_note
+            .iter()
+            .filter_map(|d| match d {
+                MeasureDirectionNote::Note(n) => n.duration.as_deref(),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(durations, vec!["1", "1", "1", "3", "6"]);
+
+        // muxml writes the time modification without its normal type, which is patched in
+        let notes = "<note><time-modification><actual-notes>3</actual-notes><normal-notes>1</normal-notes></time-modification></note>"
+            .repeat(3)
+            + &"<note/>".repeat(2);
+        let xml = format!("<score-partwise><part id=\"P1\"><measure number=\"1\">{}</measure></part></score-partwise>", notes);
+        let patched = patcher.apply(&xml)?;
+        let doc = roxmltree::Document::parse(&patched)?;
+        let normal_types: Vec<&str> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("normal-type"))
+            .filter_map(|n| n.text())
+            .collect();
+        assert_eq!(normal_types, vec!["quarter"; 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_repeat_times_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let docstring = fs::read_to_string("test/repeat_times.musicxml")?;
+        let partmap = xml_to_ir(docstring, false, &ParseOptions::default())?;
+        let part = round_trip(partmap.get_part(0).unwrap())?;
+
+        let repeat_end = part
+            .inner()
+            .iter()
+            .find_map(|e| match e {
+                MusicElement::MeasureMeta(m) if m.start_end == MeasureStartEnd::RepeatEnd => Some(*m),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(repeat_end.repeat_times, Some(3));
+        let pitches: Vec<String> = part
+            .unfold()?
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some(n.note_rest.to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pitches, vec!["C4", "C4", "C4", "G4"]);
+
+        // muxml writes repeats without the times attribute, so the backward repeat is patched
+        let mut patcher = XmlPatcher::new();
+        measures_from_musical_part(&part, "P1", &mut patcher, false)?;
+        let xml = "<score-partwise><part id=\"P1\">\
+            <measure number=\"1\"><barline location=\"left\"><repeat direction=\"forward\"/></barline><note/>\
+            <barline location=\"right\"><repeat direction=\"backward\"/></barline></measure>\
+            <measure number=\"2\"><note/></measure></part></score-partwise>";
+        let patched = patcher.apply(xml)?;
+        let doc = roxmltree::Document::parse(&patched)?;
+        let repeats: Vec<(Option<&str>, Option<&str>)> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("repeat"))
+            .map(|n| (n.attribute("direction"), n.attribute("times")))
+            .collect();
+        assert_eq!(repeats, vec![(Some("forward"), None), (Some("backward"), Some("3"))]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_percussion_part_kept_and_round_trips()-> Result<(), Box<dyn std::error::Error>> {
+        let docstring = fs::read_to_string("test/drum_pattern.musicxml")?;
+        // Unpitched parts are dropped unless asked for
+        let partmap = xml_to_ir(docstring.clone(), false, &ParseOptions::default())?;
+        assert_eq!(partmap.num_parts(), 0);
+
+        let options = ParseOptions {
+            keep_percussion: true,
+            ..ParseOptions::default()
+        };
+        let partmap = xml_to_ir(docstring, false, &options)?;
+        let part = round_trip(partmap.get_part(0).unwrap())?;
+
+        // The kick is found by its MIDI key, the snare by its instrument name
+        let drums: Vec<DrumSound> = part
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::Percussion(p) => Some(p.drum),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            drums,
+            vec![DrumSound::BassDrum, DrumSound::Snare, DrumSound::BassDrum, DrumSound::Snare]
+        );
+        assert_eq!(part.num_measures(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_notehead_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let docstring = fs::read_to_string("test/x_notehead.musicxml")?;
+        let partmap = xml_to_ir(docstring, false, &ParseOptions::default())?;
+        let part = round_trip(partmap.get_part(0).unwrap())?;
+
+        let noteheads: Vec<Notehead> = part
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some(n.notehead),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            noteheads,
+            vec![Notehead::Normal, Notehead::X, Notehead::Diamond, Notehead::Normal]
+        );
+
+        let mut parts = PartMap::new();
+        parts.push_part("P1", part)?;
+        let xml = ir_to_xml(parts, &ExportOptions::default())?;
+        let doc = roxmltree::Document::parse(&xml)?;
+        let written: Vec<Option<&str>> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("note"))
+            .map(|n| n.children().find(|c| c.has_tag_name("notehead")).and_then(|c| c.text()))
+            .collect();
+        assert_eq!(written, vec![None, Some("x"), Some("diamond"), None]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tremolo_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let docstring = fs::read_to_string("test/tremolo.musicxml")?;
+        let partmap = xml_to_ir(docstring, false, &ParseOptions::default())?;
+        let part = round_trip(partmap.get_part(0).unwrap())?;
+
+        let three_slashes = Some(Tremolo::new(TremoloType::Single, 3));
+        let tremolos: Vec<Option<Tremolo>> = part
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some(n.tremolo),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(tremolos, vec![three_slashes, three_slashes, None]);
+
+        let mut parts = PartMap::new();
+        parts.push_part("P1", part)?;
+        let xml = ir_to_xml(parts, &ExportOptions::default())?;
+        let doc = roxmltree::Document::parse(&xml)?;
+        let written: Vec<Option<(Option<&str>, Option<&str>)>> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("note"))
+            .map(|n| {
+                n.descendants()
+                    .find(|c| c.has_tag_name("tremolo"))
+                    .map(|t| (t.attribute("type"), t.text()))
+            })
+            .collect();
+        assert_eq!(
+            written,
+            vec![Some((Some("single"), Some("3"))), Some((Some("single"), Some("3"))), None]
+        );
+        // Both tremolos sit in a single notations element, added to the first note and shared with the
+        // staccato of the second
+        let notations: Vec<usize> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("note"))
+            .map(|n| n.children().filter(|c| c.has_tag_name("notations")).count())
+            .collect();
+        assert_eq!(notations, vec![1, 1, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_quarter_tone_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let docstring = fs::read_to_string("test/quarter_tone.musicxml")?;
+        let options = ParseOptions {
+            microtonal: true,
+            ..ParseOptions::default()
+        };
+        let partmap = xml_to_ir(docstring, false, &options)?;
+        let part = round_trip(partmap.get_part(0).unwrap())?;
+
+        // Each quarter tone is kept as the semitone nearer the natural and a deviation of 50 cents,
+        // here C, E and D sharp, given in semitones above the closing C
+        let notes: Vec<(u8, i8)> = part
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some((n.note_rest.get_numeric_value(), n.cents)),
+                _ => None,
+            })
+            .collect();
+        let c = notes[3].0;
+        assert_eq!(notes, vec![(c, 50), (c + 4, -50), (c + 3, 50), (c, 0)]);
+
+        let mut parts = PartMap::new();
+        parts.push_part("P1", part)?;
+        let xml = ir_to_xml(parts, &ExportOptions::default())?;
+        let doc = roxmltree::Document::parse(&xml)?;
+        let alters: Vec<Option<&str>> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("pitch"))
+            .map(|n| n.children().find(|c| c.has_tag_name("alter")).and_then(|c| c.text()))
+            .collect();
+        assert_eq!(alters, vec![Some("0.5"), Some("-0.5"), Some("1.5"), None]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pickup_measure_numbers_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let docstring = fs::read_to_string("test/pickup_measure.musicxml")?;
+        let partmap = xml_to_ir(docstring, false, &ParseOptions::default())?;
+        let part = round_trip(partmap.get_part(0).unwrap())?;
+
+        let mut parts = PartMap::new();
+        parts.push_part("P1", part)?;
+        let xml = ir_to_xml(parts, &ExportOptions::default())?;
+        let doc = roxmltree::Document::parse(&xml)?;
+        let numbers: Vec<(Option<&str>, Option<&str>)> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("measure"))
+            .map(|n| (n.attribute("number"), n.attribute("implicit")))
+            .collect();
+        assert_eq!(
+            numbers,
+            vec![(Some("0"), Some("yes")), (Some("1"), None), (Some("2"), None)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_layout_breaks_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        // The new-system and new-page attributes of each exported measure's print element
+        type Breaks = Vec<(Option<String>, Option<String>)>;
+        let breaks = |preserve_layout: bool| -> Result<Breaks, Box<dyn std::error::Error>> {
+            let docstring = fs::read_to_string("test/page_break.musicxml")?;
+            let options = ParseOptions {
+                preserve_layout,
+                ..ParseOptions::default()
+            };
+            let partmap = xml_to_ir(docstring, false, &options)?;
+            let part = round_trip(partmap.get_part(0).unwrap())?;
+
+            let mut parts = PartMap::new();
+            parts.push_part("P1", part)?;
+            let xml = ir_to_xml(parts, &ExportOptions::default())?;
+            let doc = roxmltree::Document::parse(&xml)?;
+            Ok(doc
+                .descendants()
+                .filter(|n| n.has_tag_name("measure"))
+                .map(|n| {
+                    let print = n.children().find(|c| c.has_tag_name("print"));
+                    let attribute = |name: &str| print.and_then(|p| p.attribute(name)).map(str::to_string);
+                    (attribute("new-system"), attribute("new-page"))
+                })
+                .collect())
+        };
+        let yes = || Some("yes".to_string());
+        // A system break starts measure 3 and a page break measure 5
+        assert_eq!(
+            breaks(true)?,
+            vec![(None, None), (None, None), (yes(), None), (None, None), (None, yes()), (None, None)]
+        );
+        assert!(breaks(false)?.iter().all(|b| *b == (None, None)));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn test_mapped_decode_matches_buffered() -> Result<(), Box<dyn std::error::Error>> {
+        use super::bin_to_ir_mapped;
+        use crate::bin_format::{bin_bytes_to_ir, ir_to_bin, MusicDecoder};
+        use crate::utils::temp_path;
+        use std::fs::File;
+        let docstring = fs::read_to_string("test/moonlight.musicxml")?;
+        let partmap = xml_to_ir(docstring, false, &ParseOptions::default())?;
+        let mut encoded: Vec<u8> = vec![];
+        ir_to_bin(&mut encoded, partmap.get_part(0).unwrap(), None)?;
+        let buffered = bin_bytes_to_ir(&encoded, MusicDecoder::DEFAULT_MAX_ELEMENTS)?;
+
+        let bin_path = temp_path("mapped.bin");
+        fs::write(&bin_path, &encoded)?;
+        // SAFETY: the file is private to this test and only removed once decoding is done
+        let mapped = unsafe { bin_to_ir_mapped(&File::open(&bin_path)?, None, MusicDecoder::DEFAULT_MAX_ELEMENTS) };
+        fs::remove_file(&bin_path)?;
+
+        let mapped = mapped?;
+        assert!(!mapped.inner().is_empty());
+        assert_eq!(mapped, buffered);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bar_styles_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let docstring = fs::read_to_string("test/final_barline.musicxml")?;
+        let partmap = xml_to_ir(docstring, false, &ParseOptions::default())?;
+        let part = round_trip(partmap.get_part(0).unwrap())?;
+
+        let bar_styles: Vec<BarStyle> = part
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::MeasureMeta(m) if m.start_end == MeasureStartEnd::MeasureEnd => Some(m.bar_style),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            bar_styles,
+            vec![BarStyle::Regular, BarStyle::Double, BarStyle::Dashed, BarStyle::Final]
+        );
+
+        let mut parts = PartMap::new();
+        parts.push_part("P1", part)?;
+        let xml = ir_to_xml(parts, &ExportOptions::default())?;
+        let doc = roxmltree::Document::parse(&xml)?;
+        let exported: Vec<(Option<&str>, Option<&str>)> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("measure"))
+            .map(|m| {
+                let bar_style = m
+                    .descendants()
+                    .find(|n| n.has_tag_name("bar-style"))
+                    .and_then(|n| n.text());
+                (m.attribute("number"), bar_style)
+            })
+            .collect();
+        assert_eq!(
+            exported,
+            vec![
+                (Some("1"), None),
+                (Some("2"), Some("light-light")),
+                (Some("3"), Some("dashed")),
+                (Some("4"), Some("light-heavy"))
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+use super::bin_encoder::{
+    BinVersion, ExtendedTagIdentifiers, MusicTagIdentifiers, EXTENDED_ELEMENT_ESCAPE, MUSIC_ELEMENT_LENGTH,
+};
+use crate::error::{Error, Result};
+use crate::ir::notation::*;
+use num_traits::FromPrimitive;
+use std::fmt::{self, Debug};
+
+/// One bit field of an encoded element
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitField {
+    pub name: &'static str,
+    /// Position of the field's first bit, counting from the most significant bit of the element
+    pub offset: usize,
+    pub width: usize,
+    pub raw: u32,
+    /// What the raw value decodes to
+    pub meaning: String,
+}
+
+impl BitField {
+    /// Line naming the columns written by `Display`
+    pub fn header() -> String {
+        format!("{:>4} {:>5} {:<22} {:>6}  meaning", "bit", "width", "field", "raw")
+    }
+}
+
+impl fmt::Display for BitField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>4} {:>5} {:<22} {:>6}  {}",
+            self.offset, self.width, self.name, self.raw, self.meaning
+        )
+    }
+}
+
+/// Name and width of a field, and how its raw value is decoded
+type FieldSpec = (&'static str, usize, fn(u32) -> String);
+
+fn named<T: FromPrimitive + Debug>(raw: u32) -> String {
+    T::from_u32(raw).map_or_else(|| String::from("invalid"), |value| format!("{:?}", value))
+}
+
+fn flag(raw: u32) -> String {
+    String::from(if raw != 0 { "yes" } else { "no" })
+}
+
+fn number(raw: u32) -> String {
+    raw.to_string()
+}
+
+fn pitch(raw: u32) -> String {
+    NumericPitchRest::new_from_numeric(raw as u8).to_string()
+}
+
+fn reserved(raw: u32) -> String {
+    String::from(if raw == 0 { "reserved" } else { "reserved, not zero" })
+}
+
+// The fields of each element, in the order the decoder's take_bits tuples read them
+
+const MEASURE_INITIALIZER_FIELDS: [FieldSpec; 7] = [
+    ("identifier", 2, named::<MusicTagIdentifiers>),
+    ("beats", 3, named::<Beats>),
+    ("beat_type", 2, named::<BeatType>),
+    ("fifths", 4, named::<KeySignature>),
+    ("tempo", 7, |raw| format!("{} bpm", Tempo::new_from_raw(raw as u8).get_actual())),
+    ("reserved", 8, reserved),
+    ("reserved", 5, reserved),
+];
+
+const MEASURE_META_DATA_FIELDS: [FieldSpec; 7] = [
+    ("identifier", 2, named::<MusicTagIdentifiers>),
+    ("start_end", 2, named::<MeasureStartEnd>),
+    ("ending", 2, named::<Ending>),
+    ("dal_segno", 3, named::<DalSegno>),
+    ("repeat_times", 5, |raw| if raw == 0 { String::from("none") } else { number(raw) }),
+    ("bar_style", 2, named::<BarStyle>),
+    ("reserved", 16, reserved),
+];
+
+const NOTE_DATA_FIELDS: [FieldSpec; 13] = [
+    ("identifier", 2, named::<MusicTagIdentifiers>),
+    ("note", 7, pitch),
+    ("phrase_dynamics", 4, named::<PhraseDynamics>),
+    ("rhythm_value", 3, named::<RhythmType>),
+    ("dotted", 1, flag),
+    ("arpeggiation", 1, flag),
+    ("special_note", 2, named::<SpecialNote>),
+    ("articulation", 3, named::<Articulation>),
+    ("trill", 2, named::<Trill>),
+    ("ties", 2, named::<NoteConnection>),
+    ("chord", 1, named::<Chord>),
+    ("slur", 2, named::<SlurConnection>),
+    ("voice", 2, named::<Voice>),
+];
+
+const TUPLET_DATA_FIELDS: [FieldSpec; 10] = [
+    ("identifier", 2, named::<MusicTagIdentifiers>),
+    ("start_stop", 2, named::<TupletStartStop>),
+    ("tuplet_number", 2, named::<TupletNumber>),
+    ("actual_notes", 4, named::<TupletActual>),
+    ("normal_notes", 4, named::<TupletNormal>),
+    ("dotted", 1, flag),
+    ("hide_bracket", 1, flag),
+    ("hide_number", 1, flag),
+    ("normal_type", 4, |raw| raw.checked_sub(1).map_or(String::from("none"), named::<RhythmType>)),
+    ("reserved", 11, reserved),
+];
+
+/// Identifier, escape and extended identifier opening every extended element
+const EXTENDED_PREFIX_FIELDS: [FieldSpec; 3] = [
+    ("identifier", 2, named::<MusicTagIdentifiers>),
+    ("escape", 2, |raw| {
+        String::from(if raw == EXTENDED_ELEMENT_ESCAPE as u32 { "extended element" } else { "invalid" })
+    }),
+    ("extended_identifier", 6, named::<ExtendedTagIdentifiers>),
+];
+
+const HARMONY_FIELDS: [FieldSpec; 7] = [
+    ("root_step", 3, named::<HarmonyStep>),
+    ("root_alter", 2, named::<HarmonyAlter>),
+    ("kind", 5, named::<HarmonyKind>),
+    ("has_bass", 1, flag),
+    ("bass_step", 3, named::<HarmonyStep>),
+    ("bass_alter", 2, named::<HarmonyAlter>),
+    ("reserved", 6, reserved),
+];
+
+const NOTE_EXTENSION_FIELDS: [FieldSpec; 10] = [
+    ("slur_number", 2, named::<SlurNumber>),
+    ("cautionary_accidental", 1, flag),
+    ("note_size", 2, named::<NoteSize>),
+    ("arpeggiate", 2, named::<Arpeggiate>),
+    ("breve", 1, flag),
+    ("notehead", 3, named::<Notehead>),
+    ("dynamics_placement", 2, named::<Placement>),
+    ("tremolo_type", 3, |raw| raw.checked_sub(1).map_or(String::from("none"), named::<TremoloType>)),
+    ("tremolo_marks", 4, number),
+    ("staff", 2, |raw| if raw == 0 { String::from("from voice") } else { named::<Staff>(raw) }),
+];
+
+const CLEF_CHANGE_FIELDS: [FieldSpec; 4] = [
+    ("staff", 2, named::<Staff>),
+    ("clef", 2, named::<Clef>),
+    // Three bit two's complement
+    ("octave_change", 3, |raw| ((((raw as u8) << 5) as i8) >> 5).to_string()),
+    ("reserved", 15, reserved),
+];
+
+const PERCUSSION_FIELDS: [FieldSpec; 2] = [("drum", 7, named::<DrumSound>), ("reserved", 15, reserved)];
+
+const CHORD_CLUSTER_FIELDS: [FieldSpec; 4] = [
+    ("pitch_1", 7, |raw| if raw == 0 { String::from("unused") } else { pitch(raw) }),
+    ("pitch_2", 7, |raw| if raw == 0 { String::from("unused") } else { pitch(raw) }),
+    ("pitch_3", 7, |raw| if raw == 0 { String::from("unused") } else { pitch(raw) }),
+    ("reserved", 1, reserved),
+];
+
+const MICROTONE_FIELDS: [FieldSpec; 2] = [
+    ("cents", 8, |raw| format!("{:+} cents", raw as u8 as i8)),
+    ("reserved", 14, reserved),
+];
+
+const UNKNOWN_EXTENDED_FIELDS: [FieldSpec; 1] = [("unknown", 22, number)];
+
+/// Splits one encoded element into its bit fields, following the layout its identifier selects
+pub fn element_layout(element: &[u8]) -> Result<Vec<BitField>> {
+    let element: [u8; MUSIC_ELEMENT_LENGTH] = element.try_into().map_err(|_| Error::Parse)?;
+    let bits = u32::from_be_bytes(element);
+    let field_at = |offset: usize, width: usize| (bits >> (32 - offset - width)) & ((1 << width) - 1);
+
+    let identifier = field_at(0, 2);
+    let specs: Vec<FieldSpec> = match MusicTagIdentifiers::from_u32(identifier) {
+        Some(MusicTagIdentifiers::MeasureInitializer) => MEASURE_INITIALIZER_FIELDS.to_vec(),
+        Some(MusicTagIdentifiers::MeasureMetaData) => MEASURE_META_DATA_FIELDS.to_vec(),
+        Some(MusicTagIdentifiers::NoteData) => NOTE_DATA_FIELDS.to_vec(),
+        Some(MusicTagIdentifiers::Tuplet) if field_at(2, 2) != EXTENDED_ELEMENT_ESCAPE as u32 => {
+            TUPLET_DATA_FIELDS.to_vec()
+        }
+        Some(MusicTagIdentifiers::Tuplet) => {
+            let body: &[FieldSpec] = match ExtendedTagIdentifiers::from_u32(field_at(4, 6)) {
+                Some(ExtendedTagIdentifiers::Harmony) => &HARMONY_FIELDS,
+                Some(ExtendedTagIdentifiers::NoteExtension) => &NOTE_EXTENSION_FIELDS,
+                Some(ExtendedTagIdentifiers::ClefChange) => &CLEF_CHANGE_FIELDS,
+                Some(ExtendedTagIdentifiers::Percussion) => &PERCUSSION_FIELDS,
+                Some(ExtendedTagIdentifiers::ChordCluster) => &CHORD_CLUSTER_FIELDS,
+                Some(ExtendedTagIdentifiers::Microtone) => &MICROTONE_FIELDS,
+                None => &UNKNOWN_EXTENDED_FIELDS,
+            };
+            EXTENDED_PREFIX_FIELDS.iter().chain(body).copied().collect()
+        }
+        None => unreachable!("every two bit identifier is assigned"),
+    };
+
+    let mut offset = 0;
+    let mut fields = vec![];
+    for (name, width, decode) in specs {
+        let raw = field_at(offset, width);
+        fields.push(BitField {
+            name,
+            offset,
+            width,
+            raw,
+            meaning: decode(raw),
+        });
+        offset += width;
+    }
+    Ok(fields)
+}
+
+/// Returns the `index`th element of an encoded bin file, counting from zero
+pub fn element_at(bin: &[u8], index: usize) -> Result<&[u8]> {
+    let version = bin
+        .get(..4)
+        .and_then(BinVersion::from_magic_number)
+        .ok_or(Error::Parse)?;
+    let length = bin
+        .get(4..8)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+        .ok_or(Error::Parse)?;
+    if index >= length / MUSIC_ELEMENT_LENGTH {
+        return Err(Error::OutofBounds);
+    }
+    let start = version.header_length() + index * MUSIC_ELEMENT_LENGTH;
+    bin.get(start..start + MUSIC_ELEMENT_LENGTH).ok_or(Error::Parse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{element_layout, BitField};
+    use crate::bin_format::{ir_to_bin, MUSIC_ELEMENT_LENGTH};
+    use crate::ir::notation::{NoteData, NumericPitchRest, PhraseDynamics, RhythmType, Voice};
+    use crate::ir::PartBuilder;
+
+    #[test]
+    fn test_note_element_layout() {
+        let part = PartBuilder::new()
+            .note_data(NoteData {
+                note_rest: NumericPitchRest::Pitch(50),
+                note_type: RhythmType::Crochet,
+                dotted: true,
+                phrase_dynamics: PhraseDynamics::Forte,
+                voice: Voice::Two,
+                ..NoteData::default()
+            })
+            .build("P1")
+            .unwrap();
+        let mut bin: Vec<u8> = vec![];
+        ir_to_bin(&mut bin, &part, None).unwrap();
+        // Header, measure initializer and measure start precede the note
+        let note = &bin[8 + 2 * MUSIC_ELEMENT_LENGTH..8 + 3 * MUSIC_ELEMENT_LENGTH];
+
+        let mut lines = vec![BitField::header()];
+        lines.extend(element_layout(note).unwrap().iter().map(|field| field.to_string()));
+        assert_eq!(
+            lines.join("\n"),
+            " bit width field                     raw  meaning
+   0     2 identifier                  2  NoteData
+   2     7 note                       50  C#4
+   9     4 phrase_dynamics            12  Forte
+  13     3 rhythm_value                5  Crochet
+  16     1 dotted                      1  yes
+  17     1 arpeggiation                0  no
+  18     2 special_note                0  None
+  20     3 articulation                0  None
+  23     2 trill                       0  None
+  25     2 ties                        0  None
+  27     1 chord                       0  NoChord
+  28     2 slur                        0  None
+  30     2 voice                       1  Two"
+        );
+    }
+}
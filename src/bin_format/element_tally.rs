@@ -0,0 +1,170 @@
+use super::bin_encoder::{
+    BinVersion, ExtendedTagIdentifiers, MusicTagIdentifiers, EXTENDED_ELEMENT_ESCAPE, MUSIC_ELEMENT_LENGTH,
+};
+use crate::error::{Error, Result};
+use crate::ir::notation::{MeasureStartEnd, MusicElement, NumericPitchRest, PercussionData};
+use num_traits::FromPrimitive;
+use std::fmt;
+
+/// Number of elements of each kind in a part, counted as the decoder expands them: a note's
+/// extension, microtone and percussion elements are part of the note, and every chord tone of a
+/// chord cluster is a note of its own.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ElementTally {
+    pub elements: usize,
+    pub measures: usize,
+    pub measure_initializers: usize,
+    /// Pitched and unpitched notes, chord tones included
+    pub notes: usize,
+    pub rests: usize,
+    /// Tuplet start and stop elements
+    pub tuplets: usize,
+    pub harmonies: usize,
+    pub clef_changes: usize,
+}
+
+impl ElementTally {
+    /// Tallies decoded elements
+    pub fn from_elements(elems: &[MusicElement]) -> ElementTally {
+        let mut tally = ElementTally {
+            elements: elems.len(),
+            ..ElementTally::default()
+        };
+        for elem in elems {
+            match elem {
+                MusicElement::MeasureInit(_) => tally.measure_initializers += 1,
+                MusicElement::MeasureMeta(meta) => {
+                    if matches!(meta.start_end, MeasureStartEnd::MeasureStart | MeasureStartEnd::RepeatStart) {
+                        tally.measures += 1;
+                    }
+                }
+                MusicElement::NoteRest(n) | MusicElement::Percussion(PercussionData { note: n, .. }) => {
+                    if n.note_rest == NumericPitchRest::Rest {
+                        tally.rests += 1;
+                    } else {
+                        tally.notes += 1;
+                    }
+                }
+                MusicElement::Tuplet(_) => tally.tuplets += 1,
+                MusicElement::Harmony(_) => tally.harmonies += 1,
+                MusicElement::ClefChange(_) => tally.clef_changes += 1,
+            }
+        }
+        tally
+    }
+}
+
+impl fmt::Display for ElementTally {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} elements, {} measures, {} measure initializers, {} notes, {} rests, {} tuplets, {} harmonies, \
+             {} clef changes",
+            self.elements,
+            self.measures,
+            self.measure_initializers,
+            self.notes,
+            self.rests,
+            self.tuplets,
+            self.harmonies,
+            self.clef_changes
+        )
+    }
+}
+
+/// Tallies the elements of an encoded bin file in one pass over their identifiers and the few fields
+/// telling them apart, without decoding them. Fails with `Error::Parse` when the header's length is
+/// not a whole number of elements or runs past the end of the file, in which case only a full decode
+/// can tell what the file holds.
+pub fn tally_elements(bin: &[u8]) -> Result<ElementTally> {
+    let version = bin
+        .get(..4)
+        .and_then(BinVersion::from_magic_number)
+        .ok_or(Error::Parse)?;
+    let length = bin
+        .get(4..8)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+        .ok_or(Error::Parse)?;
+    let start = version.header_length();
+    if length % MUSIC_ELEMENT_LENGTH != 0 || bin.len() < start + length {
+        return Err(Error::Parse);
+    }
+
+    let mut tally = ElementTally::default();
+    // Measure starts and ends
+    let mut measure_meta = 0;
+    for element in bin[start..start + length].chunks_exact(MUSIC_ELEMENT_LENGTH) {
+        let bits = u32::from_be_bytes(element.try_into().unwrap());
+        let field_at = |offset: usize, width: usize| (bits >> (32 - offset - width)) & ((1 << width) - 1);
+        match MusicTagIdentifiers::from_u32(field_at(0, 2)) {
+            Some(MusicTagIdentifiers::MeasureInitializer) => tally.measure_initializers += 1,
+            Some(MusicTagIdentifiers::MeasureMetaData) => {
+                measure_meta += 1;
+                let start_end = MeasureStartEnd::from_u32(field_at(2, 2));
+                if matches!(start_end, Some(MeasureStartEnd::MeasureStart | MeasureStartEnd::RepeatStart)) {
+                    tally.measures += 1;
+                }
+            }
+            Some(MusicTagIdentifiers::NoteData) => {
+                if field_at(2, 7) == 0 {
+                    tally.rests += 1;
+                } else {
+                    tally.notes += 1;
+                }
+            }
+            Some(MusicTagIdentifiers::Tuplet) if field_at(2, 2) != EXTENDED_ELEMENT_ESCAPE as u32 => {
+                tally.tuplets += 1
+            }
+            Some(MusicTagIdentifiers::Tuplet) => match ExtendedTagIdentifiers::from_u32(field_at(4, 6)) {
+                Some(ExtendedTagIdentifiers::Harmony) => tally.harmonies += 1,
+                Some(ExtendedTagIdentifiers::ClefChange) => tally.clef_changes += 1,
+                Some(ExtendedTagIdentifiers::ChordCluster) if version.supports_chord_clusters() => {
+                    // Unused pitch slots hold zero
+                    tally.notes += [field_at(10, 7), field_at(17, 7), field_at(24, 7)]
+                        .iter()
+                        .take_while(|&&pitch| pitch != 0)
+                        .count();
+                }
+                // Note extension, microtone and percussion elements precede the note they belong to
+                _ => {}
+            },
+            None => unreachable!("every two bit identifier is assigned"),
+        }
+    }
+    tally.elements = tally.measure_initializers
+        + measure_meta
+        + tally.notes
+        + tally.rests
+        + tally.tuplets
+        + tally.harmonies
+        + tally.clef_changes;
+    Ok(tally)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tally_elements, ElementTally};
+    use crate::bin_format::{bin_bytes_to_ir, MusicDecoder};
+    use crate::convert::xml_string_to_bin_bytes;
+    use crate::error::Error;
+    use std::fs;
+
+    #[test]
+    fn test_tally_matches_full_decode() {
+        for path in [
+            "test/moonlight.musicxml",
+            "test/clef_change.musicxml",
+            "test/tuplet_normal_type.musicxml",
+            "test/quarter_tone.musicxml",
+        ] {
+            let bin = xml_string_to_bin_bytes(&fs::read_to_string(path).unwrap()).unwrap();
+            let part = bin_bytes_to_ir(&bin, MusicDecoder::DEFAULT_MAX_ELEMENTS).unwrap();
+            assert_eq!(tally_elements(&bin).unwrap(), ElementTally::from_elements(part.inner()), "{}", path);
+        }
+
+        // A header claiming more elements than the file holds is not trusted
+        let mut bin = xml_string_to_bin_bytes(&fs::read_to_string("test/simple.musicxml").unwrap()).unwrap();
+        bin[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(tally_elements(&bin), Err(Error::Parse));
+    }
+}
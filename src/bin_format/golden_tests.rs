@@ -0,0 +1,209 @@
+//! Golden round-trip tests pinning the bin format.
+//!
+//! Each vector in `golden_vectors` is encoded to `test/golden/<name>.bin`, and the test decodes the
+//! file and expects exactly the vector's elements and trailer back. A change to the encoder or
+//! decoder that alters the format fails here instead of silently drifting.
+//!
+//! After an intentional format change, regenerate the fixtures with
+//!
+//! ```text
+//! cargo test regenerate_golden_fixtures -- --ignored
+//! ```
+//!
+//! and commit the updated files together with the change.
+
+use crate::bin_format::bin_decoder::MusicBinTrailer;
+use crate::bin_format::{encoded_element_count, MusicDecoder, MusicEncoder, MUSIC_ELEMENT_LENGTH};
+use crate::error::Result;
+use crate::ir::notation::{
+    Arpeggiate, Articulation, BarStyle, BeatType, Beats, Chord, Clef, ClefChangeData, DalSegno, Ending,
+    HarmonyAlter, HarmonyData, HarmonyKind, HarmonyStep, KeySignature, MeasureInitializer,
+    MeasureMetaData, MeasureStartEnd, MusicElement, NoteConnection, NoteData, NoteSize,
+    NumericPitchRest, PhraseDynamics, RhythmType, SlurConnection, SlurNumber, SpecialNote, Staff,
+    Tempo, Trill, TupletActual, TupletData, TupletNormal, TupletNumber, TupletStartStop, Voice,
+};
+use crate::ir::PartName;
+use std::fs;
+use std::path::PathBuf;
+
+struct GoldenVector {
+    name: &'static str,
+    elements: Vec<MusicElement>,
+    trailer: MusicBinTrailer,
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from("test/golden").join(format!("{}.bin", name))
+}
+
+fn note(note_rest: NumericPitchRest, note_type: RhythmType) -> NoteData {
+    NoteData {
+        note_rest,
+        note_type,
+        ..NoteData::default()
+    }
+}
+
+fn tuplet(start_stop: TupletStartStop) -> MusicElement {
+    MusicElement::Tuplet(TupletData {
+        start_stop,
+        tuplet_number: TupletNumber::One,
+        actual_notes: TupletActual::Three,
+        normal_notes: TupletNormal::Two,
+        dotted: false,
+        hide_bracket: false,
+        hide_number: false,
+        normal_type: None,
+    })
+}
+
+fn golden_vectors() -> Vec<GoldenVector> {
+    let measure_start = MusicElement::MeasureMeta(MeasureMetaData::new(MeasureStartEnd::MeasureStart));
+    let measure_end = MusicElement::MeasureMeta(MeasureMetaData::new(MeasureStartEnd::MeasureEnd));
+    vec![
+        GoldenVector {
+            name: "notes",
+            elements: vec![
+                MusicElement::MeasureInit(MeasureInitializer {
+                    beats: Beats::Four,
+                    beat_type: BeatType::Four,
+                    key_sig: KeySignature::GMajorEminor,
+                    tempo: Tempo::new(120),
+                }),
+                measure_start,
+                MusicElement::NoteRest(NoteData {
+                    dotted: true,
+                    phrase_dynamics: PhraseDynamics::MezzoPiano,
+                    articulation: Articulation::Staccato,
+                    ties: NoteConnection::StartTie,
+                    slur: SlurConnection::StartSlur,
+                    ..note(NumericPitchRest::Pitch(40), RhythmType::Crochet)
+                }),
+                MusicElement::NoteRest(NoteData {
+                    ties: NoteConnection::EndTie,
+                    slur: SlurConnection::EndSlur,
+                    ..note(NumericPitchRest::Pitch(40), RhythmType::Quaver)
+                }),
+                MusicElement::NoteRest(NoteData {
+                    chord: Chord::Chord,
+                    trill: Trill::Diatonic,
+                    ..note(NumericPitchRest::Pitch(44), RhythmType::Quaver)
+                }),
+                MusicElement::NoteRest(NoteData {
+                    special_note: SpecialNote::Acciatura,
+                    ..note(NumericPitchRest::Pitch(47), RhythmType::SemiQuaver)
+                }),
+                tuplet(TupletStartStop::TupletStart),
+                MusicElement::NoteRest(note(NumericPitchRest::Pitch(45), RhythmType::Quaver)),
+                MusicElement::NoteRest(note(NumericPitchRest::Pitch(47), RhythmType::Quaver)),
+                MusicElement::NoteRest(note(NumericPitchRest::Pitch(49), RhythmType::Quaver)),
+                tuplet(TupletStartStop::TupletStop),
+                MusicElement::NoteRest(NoteData {
+                    voice: Voice::Two,
+                    special_note: SpecialNote::Fermata,
+                    ..note(NumericPitchRest::Rest, RhythmType::Minim)
+                }),
+                MusicElement::MeasureMeta(MeasureMetaData {
+                    start_end: MeasureStartEnd::RepeatEnd,
+                    ending: Ending::One,
+                    dal_segno: DalSegno::DaCapo,
+                    repeat_times: None,
+                    bar_style: BarStyle::Regular,
+                }),
+            ],
+            trailer: MusicBinTrailer::default(),
+        },
+        GoldenVector {
+            name: "extensions",
+            elements: vec![
+                measure_start,
+                MusicElement::Harmony(HarmonyData {
+                    root_step: HarmonyStep::D,
+                    root_alter: HarmonyAlter::Flat,
+                    kind: HarmonyKind::MajorSeventh,
+                    bass: Some((HarmonyStep::F, HarmonyAlter::None)),
+                }),
+                MusicElement::NoteRest(NoteData {
+                    slur: SlurConnection::StartSlur,
+                    slur_number: SlurNumber::Two,
+                    cautionary_accidental: true,
+                    ..note(NumericPitchRest::Pitch(42), RhythmType::Minim)
+                }),
+                MusicElement::NoteRest(NoteData {
+                    slur: SlurConnection::EndSlur,
+                    slur_number: SlurNumber::Two,
+                    note_size: NoteSize::Cue,
+                    ..note(NumericPitchRest::Pitch(43), RhythmType::Crochet)
+                }),
+                MusicElement::ClefChange(ClefChangeData {
+                    staff: Staff::BassClef,
+                    clef: Clef::Treble,
+                    octave_change: 0,
+                }),
+                MusicElement::NoteRest(NoteData {
+                    note_size: NoteSize::CueSized,
+                    arpeggiate: Arpeggiate::Down,
+                    ..note(NumericPitchRest::Pitch(47), RhythmType::Crochet)
+                }),
+                measure_end,
+            ],
+            trailer: MusicBinTrailer {
+                part_names: vec![PartName::new("Piano", Some("Pno."))],
+                divisions: Some(8),
+                metadata: vec![],
+            },
+        },
+    ]
+}
+
+fn encode(vector: &GoldenVector) -> Result<Vec<u8>> {
+    let mut data: Vec<u8> = vec![];
+    let mut music_encoder = MusicEncoder::new(&mut data);
+    let num_encoded_elements: usize = vector.elements.iter().map(encoded_element_count).sum();
+    music_encoder.create_header(num_encoded_elements * MUSIC_ELEMENT_LENGTH)?;
+    for element in &vector.elements {
+        match *element {
+            MusicElement::MeasureInit(m) => music_encoder.insert_measure_initializer(m)?,
+            MusicElement::MeasureMeta(m) => music_encoder.insert_measure_metadata(m)?,
+            MusicElement::NoteRest(n) => music_encoder.insert_note_data(n)?,
+            MusicElement::Tuplet(t) => music_encoder.insert_tuplet_data(t)?,
+            MusicElement::Harmony(h) => music_encoder.insert_harmony_data(h)?,
+            MusicElement::ClefChange(c) => music_encoder.insert_clef_change(c)?,
+            MusicElement::Percussion(p) => music_encoder.insert_percussion(p)?,
+        }
+    }
+    if !vector.trailer.part_names.is_empty() {
+        music_encoder.insert_part_table(&vector.trailer.part_names)?;
+    }
+    if let Some(divisions) = vector.trailer.divisions {
+        music_encoder.insert_divisions(divisions)?;
+    }
+    music_encoder.finish()?;
+    Ok(data)
+}
+
+#[test]
+fn test_golden_fixtures_decode() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    for vector in golden_vectors() {
+        let path = golden_path(vector.name);
+        let data = fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+
+        let mut music_dec = MusicDecoder::new(None);
+        music_dec.raw_read(&data);
+        let (elements, trailer) = music_dec.parse_data()?;
+        assert_eq!(elements, vector.elements, "elements of {}", path.display());
+        assert_eq!(trailer, vector.trailer, "trailer of {}", path.display());
+        assert_eq!(encode(&vector)?, data, "encoding of {}", path.display());
+    }
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn regenerate_golden_fixtures() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all("test/golden")?;
+    for vector in golden_vectors() {
+        fs::write(golden_path(vector.name), encode(&vector)?)?;
+    }
+    Ok(())
+}
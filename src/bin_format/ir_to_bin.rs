@@ -1,22 +1,95 @@
-use std::{fs::File, io::BufWriter};
+use std::io::Write;
 
+use super::bin_encoder::{DivisionsHeader, PartTableHeader};
 use crate::bin_format;
-use crate::bin_format::MusicEncoder;
+use crate::bin_format::{BinVersion, MusicEncoder};
 use crate::error::Result;
-use crate::ir::{MusicElement, MusicalPart};
-use log::debug;
+use crate::ir::{DumpOptions, MusicElement, MusicalPart, DUMP_TARGET};
+use log::{info, warn};
 
-pub fn ir_to_bin(
-    writer: BufWriter<File>,
+/// Encodes a part to the bin format. With `dump_input` set, every element is logged to `DUMP_TARGET` as it
+/// is written, in the given dump format and pitch notation. Parts keeping source measure numbers or layout breaks
+/// are written as `BinVersion::V3`, whose metadata section holds them. Returns the number of bytes written.
+pub fn ir_to_bin<W: Write>(
+    writer: W,
     complete_part: &MusicalPart,
-    dump_input: bool,
-) -> Result<()> {
-    let mut music_encoder = MusicEncoder::new(writer);
+    dump_input: Option<DumpOptions>,
+) -> Result<usize> {
+    let version = if complete_part.get_measure_numbers().is_empty() && complete_part.get_layout_breaks().is_empty() {
+        BinVersion::default()
+    } else {
+        BinVersion::V3
+    };
+    ir_to_bin_with_version(writer, complete_part, dump_input, version)
+}
+
+/// Encodes a part to the given revision of the bin format. From `BinVersion::V2` on, chord tones
+/// that differ from the note they are stacked on only in pitch are written as chord clusters.
+pub fn ir_to_bin_with_version<W: Write>(
+    writer: W,
+    complete_part: &MusicalPart,
+    dump_input: Option<DumpOptions>,
+    version: BinVersion,
+) -> Result<usize> {
+    let mut music_encoder = MusicEncoder::with_version(writer, version);
+    let elems = complete_part.inner();
+    // Number of elements following each element that are written as chord clusters
+    let cluster_lens: Vec<usize> = (0..elems.len())
+        .map(|idx| {
+            if version.supports_chord_clusters() {
+                bin_format::chord_cluster_len(elems, idx)
+            } else {
+                0
+            }
+        })
+        .collect();
     // Encode the musical composition into binary format
-    music_encoder.create_header(complete_part.len() * bin_format::MUSIC_ELEMENT_LENGTH)?;
-    for element in complete_part.inner() {
-        if dump_input {
-            debug!("{:?}", element);
+    let num_encoded_elements = bin_format::encoded_elements_count(elems, version);
+    let length = num_encoded_elements * bin_format::MUSIC_ELEMENT_LENGTH;
+    let metadata: Vec<String> = complete_part
+        .get_measure_numbers()
+        .iter()
+        .map(|(&measure_idx, number)| number.to_metadata(measure_idx))
+        .chain(
+            complete_part
+                .get_layout_breaks()
+                .iter()
+                .map(|(&measure_idx, layout_break)| layout_break.to_metadata(measure_idx)),
+        )
+        .collect();
+    let write_metadata = !metadata.is_empty() && version.supports_metadata();
+    if write_metadata {
+        let trailer_length = complete_part
+            .get_part_name()
+            .map_or(0, |part_name| PartTableHeader::encoded_len(std::slice::from_ref(part_name)))
+            + complete_part
+                .get_initial_divisions()
+                .map_or(0, |_| DivisionsHeader::ENCODED_LENGTH);
+        music_encoder.create_header_with_metadata(length, trailer_length, &metadata)?;
+    } else {
+        if !metadata.is_empty() {
+            warn!(
+                "{:?} has no metadata section, dropping {} measure numbers and layout breaks",
+                version,
+                metadata.len()
+            );
+        }
+        music_encoder.create_header(length)?;
+    }
+    if let Some(header) = dump_input.and_then(|dump| dump.header()) {
+        info!(target: DUMP_TARGET, "{}", header);
+    }
+    // Parts parsed from MusicXML also dump where each element came from
+    let provenance = dump_input.and_then(|_| complete_part.provenance());
+    let dump_line = |dump: DumpOptions, idx: usize| match &provenance {
+        Some(provenance) => dump.with_provenance(dump.line(&elems[idx]), &provenance[idx]),
+        None => dump.line(&elems[idx]),
+    };
+    let mut idx = 0;
+    while idx < elems.len() {
+        let element = &elems[idx];
+        if let Some(dump) = dump_input {
+            info!(target: DUMP_TARGET, "{}", dump_line(dump, idx));
         }
         match *element {
             MusicElement::MeasureInit(m) => {
@@ -31,8 +104,42 @@ pub fn ir_to_bin(
             MusicElement::Tuplet(t) => {
                 music_encoder.insert_tuplet_data(t)?;
             }
+            MusicElement::Harmony(h) => {
+                music_encoder.insert_harmony_data(h)?;
+            }
+            MusicElement::ClefChange(c) => {
+                music_encoder.insert_clef_change(c)?;
+            }
+            MusicElement::Percussion(p) => {
+                music_encoder.insert_percussion(p)?;
+            }
+        }
+        let tones = &elems[idx + 1..idx + 1 + cluster_lens[idx]];
+        if !tones.is_empty() {
+            let pitches: Vec<_> = tones
+                .iter()
+                .filter_map(|e| match e {
+                    MusicElement::NoteRest(n) => Some(n.note_rest),
+                    _ => None,
+                })
+                .collect();
+            if let Some(dump) = dump_input {
+                for tone_idx in idx + 1..idx + 1 + tones.len() {
+                    info!(target: DUMP_TARGET, "{}", dump_line(dump, tone_idx));
+                }
+            }
+            music_encoder.insert_chord_cluster(&pitches)?;
         }
+        idx += 1 + tones.len();
+    }
+    if let Some(part_name) = complete_part.get_part_name() {
+        music_encoder.insert_part_table(std::slice::from_ref(part_name))?;
+    }
+    if let Some(divisions) = complete_part.get_initial_divisions() {
+        music_encoder.insert_divisions(divisions)?;
+    }
+    if write_metadata {
+        music_encoder.insert_metadata()?;
     }
-    music_encoder.flush()?;
-    Ok(())
+    music_encoder.finish()
 }
@@ -1,8 +1,24 @@
 mod bin_decoder;
 mod bin_encoder;
 mod bin_to_ir;
+mod bit_layout;
+mod element_tally;
+#[cfg(test)]
+mod golden_tests;
 mod ir_to_bin;
+#[cfg(test)]
+mod roundtrip_tests;
 
-pub use bin_encoder::{MusicEncoder, MUSIC_ELEMENT_LENGTH};
-pub use bin_to_ir::bin_to_ir;
-pub use ir_to_bin::ir_to_bin;
+pub use bin_encoder::{
+    chord_cluster_len, encoded_chord_cluster_count, encoded_element_count, encoded_elements_count, BinVersion,
+    MusicEncoder, MUSIC_ELEMENT_LENGTH,
+};
+pub use bin_decoder::MusicDecoder;
+pub use bit_layout::{element_at, element_layout, BitField};
+pub use element_tally::{tally_elements, ElementTally};
+pub use bin_to_ir::{bin_bytes_to_ir, bin_bytes_to_ir_with_dump, bin_to_ir, bin_to_ir_with_max_elements};
+#[cfg(feature = "mmap")]
+pub use bin_to_ir::bin_to_ir_mapped;
+#[cfg(test)]
+pub use bin_to_ir::round_trip;
+pub use ir_to_bin::{ir_to_bin, ir_to_bin_with_version};
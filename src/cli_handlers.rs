@@ -1,109 +1,1369 @@
-use crate::bin_format::{bin_to_ir, ir_to_bin};
+use crate::bin_format::{
+    bin_bytes_to_ir, bin_to_ir_mapped, bin_to_ir_with_max_elements, element_at, element_layout, ir_to_bin, tally_elements, BitField,
+    ElementTally, MusicDecoder,
+};
+use crate::convert::bin_bytes_to_xml_string_with_options;
 use crate::error::{Error, Result};
 use crate::ir::ir_to_xml::ir_to_xml;
-use crate::ir::{xml_to_ir, multipartxml_to_ir, PartMap};
-use crate::repl_funcs::{add, append, hello, prepend, Context};
+use crate::ir::notation::{
+    BeatType, Beats, KeySignature, MeasureInitializer, NumericPitchRest, PitchNotation, PitchSpelling,
+    TuningTable,
+};
+use crate::ir::{
+    csv_to_ir, scan_unsupported, write_features_flat, write_features_npy, xml_to_ir, multipartxml_to_ir, DumpOptions, ExportOptions,
+    MeasureNumber, MeasureNumbering, MusicElement, MusicalPart, NoteFeatures, OctaveShiftPolicy, ParseOptions, PartMap, PartStats, TiePolicy, UnsupportedReport,
+};
+use crate::repl_funcs::{run_script, Context, COMMANDS};
+use crate::report::ConversionReport;
+use crate::utils::sampling::select_uniform;
+use crate::utils::workers;
+use num_traits::FromPrimitive;
+use std::collections::BTreeMap;
 use std::fs::{self, File};
 use std::io::{BufReader, BufWriter, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+use log::{debug, error, info, warn};
 
 use repl_rs::Result as ReplResult;
 use repl_rs::{crate_description, crate_name, crate_version};
 use repl_rs::{initialize_repl, Repl};
 use repl_rs::{Command, Parameter};
 
-pub fn process_bin_to_xml(input: &PathBuf, output: &PathBuf, dump_input: bool) -> Result<()> {
-    let mut outfile = File::create(output).expect("IO Error occurred on file create()");
-    let infile = File::open(input).expect("IO Error occurred on file open()");
-    let reader = BufReader::new(infile);
-
-    let mut partmap = PartMap::new();
-    // The MusicBin format only supports a single piano part
-    let part = bin_to_ir(reader, dump_input)?;
-    partmap
-        .push_part("P1", part)
-        .expect("Failed to push part to part map");
-    let output = ir_to_xml(partmap);
-    outfile
-        .write_all(output.as_bytes())
-        .expect("IO Error occurred on write_all()");
-    Ok(())
+pub fn process_bin_to_xml(
+    input: &PathBuf,
+    output: &PathBuf,
+    dump_input: Option<DumpOptions>,
+    export_options: &ExportOptions,
+    max_elements: usize,
+    mmap: bool,
+) -> Result<ConversionReport> {
+    let mut report = ConversionReport::new(input, output);
+    let mut outfile = File::create(output)?;
+    let output = if mmap {
+        let mut partmap = PartMap::new();
+        // SAFETY: --mmap is documented as requiring the input be left untouched while converting
+        let part = unsafe { bin_to_ir_mapped(&File::open(input)?, dump_input, max_elements)? };
+        partmap.push_part("P1", part)?;
+        ir_to_xml(partmap, export_options)?
+    } else {
+        let bytes = fs::read(input)?;
+        bin_bytes_to_xml_string_with_options(&bytes, dump_input, export_options, max_elements)?
+    };
+    outfile.write_all(output.as_bytes())?;
+    report.record_output_size();
+    Ok(report)
 }
 
-pub fn process_multipartxml_to_bin(input: &PathBuf, output: &PathBuf, dump_input: bool) -> Result<()> {
-    let outfile = File::create(output).expect("IO Error Occurred");
-    let docstring = fs::read_to_string(input).unwrap();
-    let writer = BufWriter::new(outfile);
+pub fn process_multipartxml_to_bin(
+    input: &PathBuf,
+    output: &PathBuf,
+    dump_input: Option<DumpOptions>,
+    options: &ParseOptions,
+) -> Result<ConversionReport> {
+    let mut report = ConversionReport::new(input, output);
+    let docstring = fs::read_to_string(input)?;
 
-    // xml to bin only writes the first part, because MuBin only supports a single part
-    let partmap = multipartxml_to_ir(docstring, dump_input, input.as_path().to_str().unwrap())?;
+    // MuBin only supports a single part, so parts are combined into one when their layouts match.
+    // Otherwise only the first part is written.
+    let mut partmap = multipartxml_to_ir(docstring, dump_input.is_some(), input.as_path().to_str().unwrap(), options)?;
+    report.add_parts(&partmap);
+    if partmap.num_parts() > 1 {
+        if let Err(e) = partmap.combine_parts() {
+            warn!("Parts could not be combined ({}), writing the first part only", e);
+            report.warnings.push(format!("Parts could not be combined ({}), wrote the first part only", e));
+        }
+    }
+    if options.optimize {
+        info!("Removed {} redundant measure initializers", partmap.coalesce_initializers());
+    }
     let part = partmap.get_part(0).unwrap();
-    ir_to_bin(writer, part, dump_input)?;
+    write_bin_file(output, part, dump_input)?;
+    report.record_output_size();
+    Ok(report)
+}
+
+/// Encodes a part into a new bin file, checking that the file holds every byte the encoder wrote.
+/// Returns the size of the file.
+pub(crate) fn write_bin_file(output: &Path, part: &MusicalPart, dump_input: Option<DumpOptions>) -> Result<usize> {
+    let outfile = File::create(output)?;
+    let written = ir_to_bin(BufWriter::new(outfile), part, dump_input)?;
+    let file_size = fs::metadata(output)?.len();
+    if file_size != written as u64 {
+        error!("{} holds {} bytes, but {} were written", output.display(), file_size, written);
+        return Err(Error::Encoding);
+    }
+    debug!("Wrote {} bytes to {}", written, output.display());
+    Ok(written)
+}
+
+/// Writes the first part of a parsed score to a new bin file, as only single part files exist
+fn write_part_map_file(
+    output: &Path,
+    mut partmap: PartMap,
+    dump_input: Option<DumpOptions>,
+    options: &ParseOptions,
+) -> Result<usize> {
+    if options.optimize {
+        info!("Removed {} redundant measure initializers", partmap.coalesce_initializers());
+    }
+    let part = partmap.get_part(0).ok_or(Error::NotInitialized)?;
+    write_bin_file(output, part, dump_input)
+}
+
+/// Writes each part of a multi-part MusicXML file to its own bin file in the output directory,
+/// named by part id
+pub fn process_split(
+    input: &PathBuf,
+    output: &PathBuf,
+    dump_input: Option<DumpOptions>,
+    options: &ParseOptions,
+) -> Result<Vec<PathBuf>> {
+    let docstring = fs::read_to_string(input)?;
+    let mut partmap = multipartxml_to_ir(docstring, dump_input.is_some(), input.as_path().to_str().unwrap(), options)?;
+    if options.optimize {
+        info!("Removed {} redundant measure initializers", partmap.coalesce_initializers());
+    }
+
+    fs::create_dir_all(output)?;
+    let mut written = vec![];
+    for (part_id, part_idx) in partmap.get_part_ids() {
+        // Parts dropped while parsing, such as percussion, have no index
+        let Some(part) = part_idx.and_then(|idx| partmap.get_part(idx)) else {
+            continue;
+        };
+        // Every bin file must open with the initializer describing its own part
+        if !matches!(part.inner().first(), Some(MusicElement::MeasureInit(_))) {
+            warn!("Skipping part {} without an initial measure initializer", part_id);
+            continue;
+        }
+        let path = output.join(format!("{}.bin", part_id));
+        write_bin_file(&path, part, dump_input)?;
+        written.push(path);
+    }
+    info!("Split {} parts into {}", written.len(), output.display());
+    Ok(written)
+}
+
+pub fn process_xml_to_bin(
+    input: &PathBuf,
+    output: &PathBuf,
+    dump_input: Option<DumpOptions>,
+    options: &ParseOptions,
+) -> Result<ConversionReport> {
+    let mut report = ConversionReport::new(input, output);
+    let docstring = fs::read_to_string(input)?;
+    let partmap = xml_to_ir(docstring, dump_input.is_some(), options)?;
+    report.add_parts(&partmap);
+    write_part_map_file(output, partmap, dump_input, options)?;
+    report.record_output_size();
+    Ok(report)
+}
+
+/// Converts a MusicXML file to a bin file in canonical form, so musically equal scores written with
+/// different divisions, voice numbers or accidentals yield identical bytes, see `MusicalPart::canonicalize`
+pub fn process_canonicalize(
+    input: &PathBuf,
+    output: &PathBuf,
+    dump_input: Option<DumpOptions>,
+    options: &ParseOptions,
+) -> Result<ConversionReport> {
+    let mut report = ConversionReport::new(input, output);
+    let docstring = fs::read_to_string(input)?;
+    let mut partmap = xml_to_ir(docstring, dump_input.is_some(), options)?;
+    report.add_parts(&partmap);
+    partmap.canonicalize();
+    write_part_map_file(output, partmap, dump_input, options)?;
+    report.record_output_size();
+    Ok(report)
+}
+
+/// Whether `dest` was modified after `source`, so converting again would not change it
+fn is_up_to_date(source: &Path, dest: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    match (modified(source), modified(dest)) {
+        (Some(source), Some(dest)) => dest > source,
+        _ => false,
+    }
+}
+
+/// Converts every MusicXML file in the input directory to a bin file of the same name in the output
+/// directory, on at most `threads` worker threads. Returns the written files in the order they completed.
+/// Unless `force` is set, files whose bin output is newer than the source are skipped.
+pub fn process_batch(
+    input: &PathBuf,
+    output: &PathBuf,
+    threads: usize,
+    dump_input: Option<DumpOptions>,
+    options: &ParseOptions,
+    force: bool,
+) -> Result<Vec<PathBuf>> {
+    let mut sources: Vec<PathBuf> = fs::read_dir(input)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "musicxml" || ext == "xml"))
+        .collect();
+    sources.sort();
+    fs::create_dir_all(output)?;
+
+    let dest_of = |path: &Path| output.join(path.with_extension("bin").file_name().unwrap());
+    let inputs: Vec<PathBuf> = sources
+        .iter()
+        .filter(|path| force || !is_up_to_date(path, &dest_of(path)))
+        .cloned()
+        .collect();
+    let skipped = sources.len() - inputs.len();
+    if skipped > 0 {
+        info!("Skipping {} files whose output is up to date", skipped);
+    }
+
+    let results = workers::run(&inputs, threads, |path| -> Result<PathBuf> {
+        let dest = dest_of(path);
+        let docstring = fs::read_to_string(path)?;
+        let partmap = xml_to_ir(docstring, dump_input.is_some(), options)?;
+        write_part_map_file(&dest, partmap, dump_input, options)?;
+        Ok(dest)
+    });
+    let mut written = vec![];
+    for (idx, result) in results {
+        match result {
+            Ok(dest) => written.push(dest),
+            Err(e) => warn!("Skipping {}: {}", inputs[idx].display(), e),
+        }
+    }
+    info!(
+        "Converted {} of {} files into {}, {} up to date",
+        written.len(),
+        inputs.len(),
+        output.display(),
+        skipped
+    );
+    Ok(written)
+}
+
+pub fn process_xml_multi(
+    input: &PathBuf,
+    output: &PathBuf,
+    dump_input: bool,
+    export_options: &ExportOptions,
+    options: &ParseOptions,
+) -> Result<ConversionReport> {
+    let mut report = ConversionReport::new(input, output);
+    let outfile = File::create(output)?;
+    let mut writer = BufWriter::new(outfile);
+
+    let docstring = fs::read_to_string(input)?;
+    let partmap = xml_to_ir(docstring, dump_input, options)?;
+    report.add_parts(&partmap);
+
+    let started = Instant::now();
+    let output_xml = ir_to_xml(partmap, export_options)?;
+    report.export_time = Some(started.elapsed());
+    writer.write_all(output_xml.as_bytes())?;
+    writer.flush()?;
+    report.record_output_size();
+    Ok(report)
+}
+
+pub fn process_validate(input: &PathBuf, dump_input: bool, options: &ParseOptions) -> Result<()> {
+    let docstring = fs::read_to_string(input)?;
+    let partmap = xml_to_ir(docstring, dump_input, options)?;
+
+    let mut discrepancies = 0;
+    let mut dropped_notes = 0;
+    let mut zero_duration_notes = 0;
+    let mut dangling_ties = 0;
+    let mut lint_issues = 0;
+    for idx in 0..partmap.num_parts() {
+        if let Some(part) = partmap.get_part(idx) {
+            println!(
+                "Part {}: {} measures, {} voices, {} measure discrepancies, {} dropped notes, {} zero duration notes, {} dangling ties",
+                part.get_part_str(),
+                part.num_measures(),
+                part.get_num_voices(),
+                part.get_discrepancies(),
+                part.get_dropped_notes(),
+                part.get_zero_duration_notes(),
+                part.get_dangling_ties()
+            );
+            discrepancies += part.get_discrepancies();
+            dropped_notes += part.get_dropped_notes();
+            zero_duration_notes += part.get_zero_duration_notes();
+            dangling_ties += part.get_dangling_ties();
+            for correction in part.get_corrections() {
+                println!("Part {}: {}", part.get_part_str(), correction);
+            }
+            for issue in part.get_lint_issues() {
+                println!("Part {}: {}", part.get_part_str(), issue);
+            }
+            lint_issues += part.get_lint_issues().len();
+        }
+    }
+    // Ties removed under TiePolicy::Fix no longer break the output
+    if options.tie_policy == TiePolicy::Fix {
+        dangling_ties = 0;
+    }
+    let dropped_parts = partmap.get_removed_parts();
+    println!(
+        "{}: {} parts, {} dropped",
+        input.display(),
+        partmap.num_part_ids(),
+        dropped_parts
+    );
+
+    if dropped_parts > 0
+        || discrepancies > 0
+        || dropped_notes > 0
+        || zero_duration_notes > 0
+        || dangling_ties > 0
+        || lint_issues > 0
+    {
+        return Err(Error::Validation(format!(
+            "{} dropped parts, {} measure discrepancies, {} dropped notes, {} zero duration notes, {} dangling ties, {} lint issues",
+            dropped_parts, discrepancies, dropped_notes, zero_duration_notes, dangling_ties, lint_issues
+        )));
+    }
     Ok(())
 }
 
-pub fn process_xml_to_bin(input: &PathBuf, output: &PathBuf, dump_input: bool) -> Result<()> {
-    let outfile = File::create(output).expect("IO Error Occurred");
-    let docstring = fs::read_to_string(input).unwrap();
-    let writer = BufWriter::new(outfile);
+/// Prints, for a MusicXML file or each MusicXML file of a directory, the constructs it uses that are
+/// dropped when converting with `options`. Returns the reports by file.
+pub fn process_report_unsupported(input: &PathBuf, options: &ParseOptions) -> Result<Vec<(PathBuf, UnsupportedReport)>> {
+    let sources: Vec<PathBuf> = if input.is_dir() {
+        let mut sources: Vec<PathBuf> = fs::read_dir(input)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "musicxml" || ext == "xml"))
+            .collect();
+        sources.sort();
+        sources
+    } else {
+        vec![input.clone()]
+    };
 
-    // xml to bin only writes the first part, because MuBin only supports a single part
-    let partmap = xml_to_ir(docstring, dump_input)?;
-    let part = partmap.get_part(0).unwrap();
-    ir_to_bin(writer, part, dump_input)?;
+    let mut reports = vec![];
+    for source in sources {
+        let docstring = fs::read_to_string(&source)?;
+        let report = scan_unsupported(&docstring, options)?;
+        if report.is_empty() {
+            println!("{}: nothing dropped", source.display());
+        } else {
+            println!("{}: {} dropped: {}", source.display(), report.total(), report);
+        }
+        reports.push((source, report));
+    }
+    Ok(reports)
+}
+
+/// Prints every bit field of the `index`th element of a bin file, counting from zero, with its offset,
+/// width, raw value and decoded meaning
+pub fn process_inspect_bits(input: &PathBuf, index: usize) -> Result<()> {
+    let bin = fs::read(input)?;
+    let element = element_at(&bin, index)?;
+    println!(
+        "{}: element {} [{}]",
+        input.display(),
+        index,
+        element.iter().map(|b| format!("{:08b}", b)).collect::<Vec<String>>().join(" ")
+    );
+    println!("{}", BitField::header());
+    for field in element_layout(element)? {
+        println!("{}", field);
+    }
     Ok(())
 }
 
-pub fn process_xml_multi(input: &PathBuf, output: &PathBuf, dump_input: bool) -> Result<()> {
-    let outfile = File::create(output).expect("IO Error Occurred");
-    let mut writer = BufWriter::new(outfile);
+/// Prints how many elements, measures, notes and rests a bin file holds, tallied without decoding
+/// the elements unless the header's length cannot be trusted
+pub fn process_count(input: &PathBuf, max_elements: usize) -> Result<()> {
+    let bin = fs::read(input)?;
+    let tally = match tally_elements(&bin) {
+        Ok(tally) => tally,
+        Err(_) => {
+            warn!("Header length of {} does not match its data, decoding every element.", input.display());
+            ElementTally::from_elements(bin_bytes_to_ir(&bin, max_elements)?.inner())
+        }
+    };
+    println!("{}: {}", input.display(), tally);
+    Ok(())
+}
 
-    let docstring = fs::read_to_string(input).unwrap();
-    let partmap = xml_to_ir(docstring, dump_input)?;
+/// Prints the note density and rhythmic complexity of each part of a MusicXML or bin file
+pub fn process_stats(
+    input: &PathBuf,
+    json: bool,
+    dump_input: Option<DumpOptions>,
+    options: &ParseOptions,
+    max_elements: usize,
+) -> Result<()> {
+    let part_stats: Vec<(String, PartStats)> = if input.extension().is_some_and(|ext| ext == "bin") {
+        let infile = File::open(input)?;
+        let part = bin_to_ir_with_max_elements(BufReader::new(infile), dump_input, max_elements)?;
+        vec![(part.get_part_str().to_string(), part.stats())]
+    } else {
+        let docstring = fs::read_to_string(input)?;
+        let partmap = xml_to_ir(docstring, dump_input.is_some(), options)?;
+        (0..partmap.num_parts())
+            .filter_map(|idx| partmap.get_part(idx))
+            .map(|part| (part.get_part_str().to_string(), part.stats()))
+            .collect()
+    };
 
-    let output_xml = ir_to_xml(partmap);
-    writer
-        .write_all(output_xml.as_bytes())
-        .expect("IO Error occurred on write_all()");
-    writer
-        .flush()
-        .map_err(|e| Error::IoKind(e.kind().to_string()))?;
+    if json {
+        let parts_json: Vec<String> = part_stats
+            .iter()
+            .map(|(part_id, stats)| stats.to_json(part_id))
+            .collect();
+        println!("{{\"parts\":[{}]}}", parts_json.join(","));
+    } else {
+        for (part_id, stats) in part_stats.iter() {
+            println!(
+                "Part {}: {} measures, {} notes, {:.2} notes per measure, {:.1}% tuplet notes, smallest note {}, estimated key {}",
+                part_id,
+                stats.measures,
+                stats.notes,
+                stats.notes_per_measure(),
+                stats.tuplet_fraction() * 100.0,
+                stats.smallest_note_string(),
+                stats.estimated_key_string()
+            );
+        }
+    }
+    Ok(())
+}
 
+/// Writes a fixed width feature record for every note of a bin file, see `NoteFeatures` for the layout.
+/// With `npy` set the records are written as a NumPy `.npy` array, otherwise as flat binary. With
+/// `unfold` set the records follow the performance order of the part, see `MusicalPart::unfold`.
+pub fn process_features(
+    input: &PathBuf,
+    output: &PathBuf,
+    npy: bool,
+    unfold: bool,
+    tuning: &TuningTable,
+    dump_input: Option<DumpOptions>,
+    max_elements: usize,
+) -> Result<()> {
+    let infile = File::open(input)?;
+    let mut part = bin_to_ir_with_max_elements(BufReader::new(infile), dump_input, max_elements)?;
+    if unfold {
+        part = part.unfold()?;
+    }
+    let features = NoteFeatures::from_elements_with_tuning(part.inner(), tuning);
+    let outfile = File::create(output)?;
+    let mut writer = BufWriter::new(outfile);
+    if npy {
+        write_features_npy(&mut writer, &features)?;
+    } else {
+        write_features_flat(&mut writer, &features)?;
+    }
+    writer.flush()?;
+    info!("Wrote {} note records to {}", features.len(), output.display());
     Ok(())
 }
 
-pub fn process_end_to_end(input: &PathBuf, output: &PathBuf, dump_input: bool) -> Result<()> {
+pub fn process_end_to_end(
+    input: &PathBuf,
+    output: &PathBuf,
+    dump_input: Option<DumpOptions>,
+    export_options: &ExportOptions,
+    options: &ParseOptions,
+) -> Result<ConversionReport> {
     let tmp_path = PathBuf::from("tmp.bin");
 
-    process_xml_to_bin(input, &tmp_path, dump_input)?;
-    process_bin_to_xml(&tmp_path, output, dump_input)?;
+    let mut report = process_xml_to_bin(input, &tmp_path, dump_input, options)?;
+    let xml_report = process_bin_to_xml(
+        &tmp_path,
+        output,
+        None,
+        export_options,
+        MusicDecoder::DEFAULT_MAX_ELEMENTS,
+        false,
+    )?;
+    report.output = xml_report.output;
+    report.output_bytes = xml_report.output_bytes;
+    report.warnings.extend(xml_report.warnings);
+    Ok(report)
+}
+
+/// Transposes every pitch and key signature of a bin file by a number of semitones
+pub fn process_transpose(
+    input: &PathBuf,
+    output: &PathBuf,
+    semitones: i8,
+    dump_input: Option<DumpOptions>,
+    max_elements: usize,
+) -> Result<()> {
+    let infile = File::open(input)?;
+    let mut part = bin_to_ir_with_max_elements(BufReader::new(infile), None, max_elements)?;
+    if let Err(e) = part.transpose(semitones) {
+        warn!(
+            "Transposing {} by {} semitones moves notes outside of the supported range.",
+            input.display(),
+            semitones
+        );
+        return Err(e);
+    }
+    write_bin_file(output, &part, dump_input)?;
+    Ok(())
+}
+
+/// Writes a copy of a bin file with every pitch moved by `octaves` octaves, see `MusicalPart::shift_octaves`
+pub fn process_octave_shift(
+    input: &PathBuf,
+    output: &PathBuf,
+    octaves: i8,
+    policy: OctaveShiftPolicy,
+    dump_input: Option<DumpOptions>,
+    max_elements: usize,
+) -> Result<()> {
+    let infile = File::open(input)?;
+    let mut part = bin_to_ir_with_max_elements(BufReader::new(infile), None, max_elements)?;
+    let out_of_range = part.shift_octaves(octaves, policy);
+    if out_of_range > 0 {
+        info!(
+            "Shifting {} by {} octaves moves {} notes outside of the supported range, {}",
+            input.display(),
+            octaves,
+            out_of_range,
+            match policy {
+                OctaveShiftPolicy::Clamp => "clamped them",
+                OctaveShiftPolicy::Drop => "dropped them",
+            }
+        );
+    }
+    write_bin_file(output, &part, dump_input)?;
+    Ok(())
+}
+
+/// Writes a copy of a bin file with its tempos and dynamics perturbed from `seed`, see `MusicalPart::augment`
+pub fn process_augment(
+    input: &PathBuf,
+    output: &PathBuf,
+    seed: u64,
+    tempo_range: u8,
+    dump_input: Option<DumpOptions>,
+    max_elements: usize,
+) -> Result<()> {
+    let infile = File::open(input)?;
+    let mut part = bin_to_ir_with_max_elements(BufReader::new(infile), None, max_elements)?;
+    part.augment(seed, tempo_range);
+    write_bin_file(output, &part, dump_input)?;
+    Ok(())
+}
+
+/// Encodes a CSV melody of `pitch,octave,type,dotted,voice` rows in the given meter, see `csv_to_ir`
+pub fn process_csv_to_bin(
+    input: &PathBuf,
+    output: &PathBuf,
+    beats: Beats,
+    beat_type: BeatType,
+    dump_input: Option<DumpOptions>,
+) -> Result<()> {
+    let csv = fs::read_to_string(input)?;
+    let part = csv_to_ir(&csv, "P1", beats, beat_type)?;
+    write_bin_file(output, &part, dump_input)?;
+    Ok(())
+}
+
+/// Exports a bin file to MusicXML with the black key pitches spelled by `spelling`
+pub fn process_respell(
+    input: &PathBuf,
+    output: &PathBuf,
+    spelling: PitchSpelling,
+    dump_input: Option<DumpOptions>,
+    export_options: &ExportOptions,
+    max_elements: usize,
+) -> Result<ConversionReport> {
+    let export_options = ExportOptions {
+        spelling,
+        ..export_options.clone()
+    };
+    process_bin_to_xml(input, output, dump_input, &export_options, max_elements, false)
+}
+
+pub fn process_concat(
+    inputs: &[PathBuf],
+    output: &PathBuf,
+    dump_input: Option<DumpOptions>,
+    numbering: MeasureNumbering,
+    max_elements: usize,
+) -> Result<()> {
+    if inputs.is_empty() {
+        warn!("No input files were provided to concatenate.");
+        return Err(Error::MissingReader);
+    }
+
+    let mut elems: Vec<MusicElement> = vec![];
+    let mut measure_numbers = vec![];
+    let mut measure_offset = 0;
+    for input in inputs {
+        let infile = File::open(input)?;
+        let part = bin_to_ir_with_max_elements(BufReader::new(infile), None, max_elements)?;
+        // Every file carries its own key, tempo and meter, so the seam must start with that file's initializer,
+        // otherwise the appended measures would silently inherit the previous file's values
+        if !matches!(part.inner().first(), Some(MusicElement::MeasureInit(_))) {
+            warn!(
+                "{} does not begin with a measure initializer, inserting a default one at the seam",
+                input.display()
+            );
+            elems.push(MusicElement::MeasureInit(MeasureInitializer::default()));
+        }
+        if numbering == MeasureNumbering::Reset {
+            for measure_idx in 0..part.num_measures() {
+                let number = part.get_measure_numbers().get(&measure_idx).cloned().unwrap_or(MeasureNumber {
+                    number: (measure_idx + 1).to_string(),
+                    implicit: false,
+                });
+                measure_numbers.push((measure_offset + measure_idx, number));
+            }
+        }
+        measure_offset += part.num_measures();
+        elems.extend_from_slice(part.inner());
+    }
+
+    let mut part = MusicalPart::new_from_elems("P1", elems)?;
+    for (measure_idx, number) in measure_numbers {
+        part.set_measure_number(measure_idx, number);
+    }
+    write_bin_file(output, &part, dump_input)?;
+    info!(
+        "Concatenated {} files into {} musical elements",
+        inputs.len(),
+        part.len()
+    );
+    Ok(())
+}
+
+/// Reads the key signature of the first measure initializer in a bin file
+fn read_bin_key_signature(input: &PathBuf, dump_input: Option<DumpOptions>, max_elements: usize) -> Result<KeySignature> {
+    let infile = File::open(input)?;
+    let part = bin_to_ir_with_max_elements(BufReader::new(infile), dump_input, max_elements)?;
+    part.inner()
+        .iter()
+        .find_map(|e| match e {
+            MusicElement::MeasureInit(m) => Some(m.key_sig),
+            _ => None,
+        })
+        .ok_or(Error::NotInitialized)
+}
+
+pub fn process_sample(
+    input: &PathBuf,
+    output: &PathBuf,
+    per_key: Option<usize>,
+    seed: u64,
+    dump_input: Option<DumpOptions>,
+    max_elements: usize,
+) -> Result<()> {
+    let mut inputs: Vec<PathBuf> = fs::read_dir(input)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "bin"))
+        .collect();
+    // Directory iteration order is platform dependent; sort so a seed always selects the same files
+    inputs.sort();
 
+    let mut by_key: BTreeMap<u8, Vec<PathBuf>> = BTreeMap::new();
+    for path in inputs {
+        match read_bin_key_signature(&path, dump_input, max_elements) {
+            Ok(key_sig) => by_key.entry(key_sig as u8).or_default().push(path),
+            Err(e) => warn!("Skipping {}: {}", path.display(), e),
+        }
+    }
+    let available: BTreeMap<u8, usize> = by_key.iter().map(|(k, v)| (*k, v.len())).collect();
+
+    let selected = select_uniform(by_key, per_key, seed);
+
+    fs::create_dir_all(output)?;
+    let mut total = 0;
+    for (key, paths) in selected.iter() {
+        for path in paths {
+            let dest = output.join(path.file_name().unwrap());
+            fs::copy(path, dest)?;
+        }
+        let key_sig: KeySignature = FromPrimitive::from_u8(*key).unwrap();
+        info!("{:?}: {} of {} files", key_sig, paths.len(), available[key]);
+        total += paths.len();
+    }
+    info!("Sampled {} files into {}", total, output.display());
     Ok(())
 }
 
-pub fn repl_shell() -> ReplResult<()> {
-    let mut repl = initialize_repl!(Context::default())
-        .use_completion(true)
-        .add_command(
-            Command::new("append", append)
-                .with_parameter(Parameter::new("name").set_required(true)?)?
-                .with_help("Append name to end of list"),
+/// Handling of a file containing notes outside the range accepted by `process_filter_range`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum RangePolicy {
+    /// Leave the file out of the output directory and carry on
+    #[default]
+    Drop,
+    /// Stop with an error naming the file and the offending note
+    Error,
+}
+
+impl FromStr for RangePolicy {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<RangePolicy> {
+        match input {
+            "drop" => Ok(RangePolicy::Drop),
+            "error" => Ok(RangePolicy::Error),
+            _ => Err(Error::Parse),
+        }
+    }
+}
+
+/// Returns the first pitch of the part lying outside `min..=max`, if any
+fn find_out_of_range(part: &MusicalPart, min: NumericPitchRest, max: NumericPitchRest) -> Option<NumericPitchRest> {
+    let range = min.get_numeric_value()..=max.get_numeric_value();
+    part.inner().iter().find_map(|e| match e {
+        MusicElement::NoteRest(n) if n.note_rest != NumericPitchRest::Rest => {
+            (!range.contains(&n.note_rest.get_numeric_value())).then_some(n.note_rest)
+        }
+        _ => None,
+    })
+}
+
+/// Copies the bin files of the input directory whose notes all lie within `min..=max` to the output directory
+pub fn process_filter_range(
+    input: &PathBuf,
+    output: &PathBuf,
+    min: NumericPitchRest,
+    max: NumericPitchRest,
+    policy: RangePolicy,
+    dump_input: Option<DumpOptions>,
+    max_elements: usize,
+) -> Result<Vec<PathBuf>> {
+    let mut inputs: Vec<PathBuf> = fs::read_dir(input)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "bin"))
+        .collect();
+    inputs.sort();
+
+    fs::create_dir_all(output)?;
+    let mut written = vec![];
+    for path in inputs.iter() {
+        let infile = File::open(path)?;
+        let part = bin_to_ir_with_max_elements(BufReader::new(infile), dump_input, max_elements)?;
+        if let Some(pitch) = find_out_of_range(&part, min, max) {
+            let msg = format!("{}: {} is outside {}..{}", path.display(), pitch, min, max);
+            match policy {
+                RangePolicy::Drop => {
+                    warn!("Skipping {}", msg);
+                    continue;
+                }
+                RangePolicy::Error => return Err(Error::Validation(msg)),
+            }
+        }
+        let dest = output.join(path.file_name().unwrap());
+        fs::copy(path, &dest)?;
+        written.push(dest);
+    }
+    info!("Kept {} of {} files in {}", written.len(), inputs.len(), output.display());
+    Ok(written)
+}
+
+pub fn repl_shell(pitch_notation: PitchNotation) -> ReplResult<()> {
+    let mut repl = initialize_repl!(Context::new(pitch_notation)).use_completion(true);
+    for shell_command in COMMANDS {
+        let mut command = Command::new(shell_command.name, shell_command.shell);
+        for param in shell_command.params {
+            command = command.with_parameter(Parameter::new(param).set_required(true)?)?;
+        }
+        repl = repl.add_command(command.with_help(shell_command.help));
+    }
+    repl.run()
+}
+
+pub fn process_script(script: &Path, continue_on_error: bool, pitch_notation: PitchNotation) -> Result<()> {
+    for output in run_script(script, continue_on_error, pitch_notation)? {
+        println!("{}", output);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        process_augment, process_batch, process_bin_to_xml, process_canonicalize, process_concat, process_csv_to_bin, process_filter_range,
+        process_features, process_octave_shift,
+        process_sample, process_split, process_transpose, process_validate, process_xml_to_bin, RangePolicy,
+    };
+    use crate::bin_format::bin_to_ir;
+    use crate::ir::PartBuilder;
+    use crate::ir::ParseOptions;
+    use crate::bin_format::{ir_to_bin, MusicDecoder};
+    use crate::ir::notation::{
+        BeatType, Beats, Chord, KeySignature, MeasureInitializer, MeasureMetaData, MeasureStartEnd, NoteData,
+        NumericPitchRest, PhraseDynamics, RhythmType, Tempo, TuningTable, Voice,
+    };
+    use crate::ir::ir_to_xml::ir_to_xml;
+    use crate::ir::{
+        DumpOptions, ExportOptions, MeasureNumber, MeasureNumbering, MusicElement, MusicalPart, OctaveShiftPolicy,
+        PartMap,
+    };
+    use crate::utils::{capture_log, temp_path};
+    use std::fs::{self, File};
+    use std::io::{BufReader, BufWriter};
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime};
+
+    fn write_fixture(path: &Path, key_sig: KeySignature) {
+        let mut elems = vec![
+            MusicElement::MeasureInit(MeasureInitializer {
+                key_sig,
+                ..MeasureInitializer::default()
+            }),
+            MusicElement::MeasureMeta(MeasureMetaData::new(MeasureStartEnd::MeasureStart)),
+        ];
+        for _ in 0..4 {
+            elems.push(MusicElement::NoteRest(NoteData {
+                note_rest: NumericPitchRest::new_from_numeric(60),
+                note_type: RhythmType::Crochet,
+                ..NoteData::default()
+            }));
+        }
+        elems.push(MusicElement::MeasureMeta(MeasureMetaData::new(
+            MeasureStartEnd::MeasureEnd,
+        )));
+        let part = MusicalPart::new_from_elems("P1", elems).unwrap();
+        ir_to_bin(BufWriter::new(File::create(path).unwrap()), &part, None).unwrap();
+    }
+
+    fn sampled_files(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_sample_balances_key_signatures() {
+        let root = temp_path("sample");
+        let input = root.join("input");
+        fs::create_dir_all(&input).unwrap();
+        for idx in 0..3 {
+            write_fixture(&input.join(format!("c{}.bin", idx)), KeySignature::CMajorAminor);
+        }
+        for idx in 0..2 {
+            write_fixture(&input.join(format!("g{}.bin", idx)), KeySignature::GMajorEminor);
+        }
+
+        let first = root.join("first");
+        let second = root.join("second");
+        process_sample(&input, &first, None, 7, None, MusicDecoder::DEFAULT_MAX_ELEMENTS).unwrap();
+        process_sample(&input, &second, None, 7, None, MusicDecoder::DEFAULT_MAX_ELEMENTS).unwrap();
+
+        let first_files = sampled_files(&first);
+        let names = |files: &[PathBuf]| -> Vec<String> {
+            files
+                .iter()
+                .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+                .collect()
+        };
+        assert_eq!(first_files.len(), 4);
+        assert_eq!(names(&first_files).iter().filter(|n| n.starts_with('c')).count(), 2);
+        assert_eq!(names(&first_files).iter().filter(|n| n.starts_with('g')).count(), 2);
+        // The same seed selects the same files
+        assert_eq!(names(&first_files), names(&sampled_files(&second)));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_validate_reports_incomplete_voice() {
+        let options = ParseOptions::default();
+        assert!(
+            process_validate(&PathBuf::from("test/vivace_words_tempo.musicxml"), false, &options)
+                .is_ok()
+        );
+        assert!(
+            process_validate(&PathBuf::from("test/incomplete_voice.musicxml"), false, &options)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_split_writes_one_bin_per_part() {
+        let output = temp_path("split");
+        let written = process_split(
+            &PathBuf::from("test/two_parts.musicxml"),
+            &output,
+            None,
+            &ParseOptions::default(),
         )
-        .add_command(
-            Command::new("prepend", prepend)
-                .with_parameter(Parameter::new("name").set_required(true)?)?
-                .with_help("Prepend name to front of list"),
+        .unwrap();
+        assert_eq!(written, vec![output.join("P1.bin"), output.join("P2.bin")]);
+        for path in written {
+            let part = bin_to_ir(BufReader::new(File::open(path).unwrap()), None).unwrap();
+            assert!(matches!(part.inner()[0], MusicElement::MeasureInit(_)));
+            assert_eq!(part.num_measures(), 1);
+        }
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn test_bin_to_xml_dumps_the_decoded_elements() {
+        // Dump lines are logged at info level, so they show without -v
+        capture_log::init();
+        log::set_max_level(log::LevelFilter::Info);
+        let part = PartBuilder::new()
+            .note(NumericPitchRest::Pitch(40), RhythmType::Crochet, Voice::One)
+            .note(NumericPitchRest::Pitch(44), RhythmType::Minim, Voice::One)
+            .rest(RhythmType::Crochet, Voice::One)
+            .build("P1")
+            .unwrap();
+        let bin_path = temp_path("dump.bin");
+        let xml_path = temp_path("dump.musicxml");
+        ir_to_bin(BufWriter::new(File::create(&bin_path).unwrap()), &part, None).unwrap();
+
+        // Both ways of reading the input dump it
+        for mmap in [false, cfg!(feature = "mmap")] {
+            capture_log::clear();
+            let dump = Some(DumpOptions::default());
+            process_bin_to_xml(&bin_path, &xml_path, dump, &ExportOptions::default(), MusicDecoder::DEFAULT_MAX_ELEMENTS, mmap)
+                .unwrap();
+            let messages = capture_log::messages();
+            let notes: Vec<&String> = messages.iter().filter(|m| m.contains(" v1")).collect();
+            assert_eq!(notes.len(), 3, "{:?}", messages);
+            assert!(notes[0].ends_with("quarter v1"), "{}", notes[0]);
+
+            capture_log::clear();
+            process_bin_to_xml(&bin_path, &xml_path, None, &ExportOptions::default(), MusicDecoder::DEFAULT_MAX_ELEMENTS, mmap)
+                .unwrap();
+            assert!(!capture_log::messages().iter().any(|m| m.contains(" v1")));
+        }
+        fs::remove_file(&bin_path).unwrap();
+        fs::remove_file(&xml_path).unwrap();
+    }
+
+    #[test]
+    fn test_part_names_survive_bin_round_trip() {
+        let output = temp_path("part_names");
+        let written = process_split(
+            &PathBuf::from("test/violin_cello.musicxml"),
+            &output,
+            None,
+            &ParseOptions::default(),
         )
-        .add_command(
-            Command::new("add", add)
-                .with_parameter(Parameter::new("first").set_required(true)?)?
-                .with_parameter(Parameter::new("second").set_required(true)?)?
-                .with_help("Add two numbers together"),
+        .unwrap();
+        let mut partmap = PartMap::new();
+        for (path, part_id) in written.iter().zip(["P1", "P2"]) {
+            partmap
+                .push_part(part_id, bin_to_ir(BufReader::new(File::open(path).unwrap()), None).unwrap())
+                .unwrap();
+        }
+        fs::remove_dir_all(&output).unwrap();
+
+        let xml = ir_to_xml(partmap, &ExportOptions::default()).unwrap();
+        let doc = roxmltree::Document::parse(&xml).unwrap();
+        let names: Vec<(Option<&str>, Option<&str>)> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("score-part"))
+            .map(|n| {
+                let text = |tag| n.children().find(|c| c.has_tag_name(tag)).and_then(|c| c.text());
+                (text("part-name"), text("part-abbreviation"))
+            })
+            .collect();
+        assert_eq!(names, vec![(Some("Violin"), Some("Vln.")), (Some("Cello"), Some("Vc."))]);
+    }
+
+    #[test]
+    fn test_batch_single_thread_converts_in_order() {
+        let root = temp_path("batch");
+        let input = root.join("input");
+        let output = root.join("output");
+        fs::create_dir_all(&input).unwrap();
+        for name in ["c", "a", "b"] {
+            fs::copy("test/vivace_words_tempo.musicxml", input.join(format!("{}.musicxml", name))).unwrap();
+        }
+
+        let written = process_batch(&input, &output, 1, None, &ParseOptions::default(), false).unwrap();
+        assert_eq!(
+            written,
+            vec![output.join("a.bin"), output.join("b.bin"), output.join("c.bin")]
+        );
+        for path in written {
+            assert!(bin_to_ir(BufReader::new(File::open(path).unwrap()), None).is_ok());
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_batch_skips_up_to_date_outputs() {
+        let root = temp_path("batch_since");
+        let input = root.join("input");
+        let output = root.join("output");
+        fs::create_dir_all(&input).unwrap();
+        fs::create_dir_all(&output).unwrap();
+        for name in ["fresh", "stale"] {
+            fs::copy("test/vivace_words_tempo.musicxml", input.join(format!("{}.musicxml", name))).unwrap();
+            fs::write(output.join(format!("{}.bin", name)), b"old output").unwrap();
+        }
+        let set_modified = |path: PathBuf, time: SystemTime| {
+            File::options().write(true).open(path).unwrap().set_modified(time).unwrap();
+        };
+        let now = SystemTime::now();
+        let hour = Duration::from_secs(3600);
+        for name in ["fresh", "stale"] {
+            set_modified(input.join(format!("{}.musicxml", name)), now - hour);
+        }
+        set_modified(output.join("fresh.bin"), now);
+        set_modified(output.join("stale.bin"), now - 2 * hour);
+
+        let written = process_batch(&input, &output, 1, None, &ParseOptions::default(), false).unwrap();
+        assert_eq!(written, vec![output.join("stale.bin")]);
+        assert_eq!(fs::read(output.join("fresh.bin")).unwrap(), b"old output");
+        assert!(bin_to_ir(BufReader::new(File::open(output.join("stale.bin")).unwrap()), None).is_ok());
+
+        // Forcing converts the up to date file as well
+        let written = process_batch(&input, &output, 1, None, &ParseOptions::default(), true).unwrap();
+        assert_eq!(written, vec![output.join("fresh.bin"), output.join("stale.bin")]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_transpose_up_whole_tone() {
+        let root = temp_path("transpose");
+        fs::create_dir_all(&root).unwrap();
+        let input = root.join("c.bin");
+        let output = root.join("d.bin");
+        write_fixture(&input, KeySignature::CMajorAminor);
+        process_transpose(&input, &output, 2, None, MusicDecoder::DEFAULT_MAX_ELEMENTS).unwrap();
+
+        let part = bin_to_ir(BufReader::new(File::open(&output).unwrap()), None).unwrap();
+        assert!(matches!(
+            part.inner()[0],
+            MusicElement::MeasureInit(MeasureInitializer {
+                key_sig: KeySignature::DMajorBminor,
+                ..
+            })
+        ));
+        let pitches: Vec<NumericPitchRest> = part
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some(n.note_rest),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pitches, vec![NumericPitchRest::new_from_numeric(62); 4]);
+
+        // Leaving the supported range fails instead of wrapping
+        assert!(process_transpose(&input, &output, 60, None, MusicDecoder::DEFAULT_MAX_ELEMENTS).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_concat_keeps_each_initializer_at_the_seam() {
+        let root = temp_path("concat");
+        fs::create_dir_all(&root).unwrap();
+        let first = root.join("c.bin");
+        let second = root.join("d.bin");
+        let output = root.join("both.bin");
+        write_fixture(&first, KeySignature::CMajorAminor);
+        write_fixture(&second, KeySignature::DMajorBminor);
+        let first_len = bin_to_ir(BufReader::new(File::open(&first).unwrap()), None).unwrap().len();
+        let inputs = [first.clone(), second.clone()];
+
+        process_concat(&inputs, &output, None, MeasureNumbering::Continue, MusicDecoder::DEFAULT_MAX_ELEMENTS).unwrap();
+        let part = bin_to_ir(BufReader::new(File::open(&output).unwrap()), None).unwrap();
+        assert_eq!(part.len(), 2 * first_len);
+        // The header length counts every element of both files
+        let bytes = fs::read(&output).unwrap();
+        let header_len = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        assert_eq!(header_len / 4, part.len());
+        assert!(matches!(
+            part.inner()[first_len],
+            MusicElement::MeasureInit(MeasureInitializer {
+                key_sig: KeySignature::DMajorBminor,
+                ..
+            })
+        ));
+        assert!(part.get_measure_numbers().is_empty());
+
+        process_concat(&inputs, &output, None, MeasureNumbering::Reset, MusicDecoder::DEFAULT_MAX_ELEMENTS).unwrap();
+        let part = bin_to_ir(BufReader::new(File::open(&output).unwrap()), None).unwrap();
+        assert_eq!(part.len(), 2 * first_len);
+        assert_eq!(
+            part.get_measure_numbers().iter().collect::<Vec<_>>(),
+            vec![(
+                &1,
+                &MeasureNumber {
+                    number: "1".to_string(),
+                    implicit: false
+                }
+            )]
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_octave_shift_clamps_or_drops_notes_leaving_the_range() {
+        let root = temp_path("octave_shift");
+        fs::create_dir_all(&root).unwrap();
+        let input = root.join("input.bin");
+        let output = root.join("output.bin");
+        // C1, G7 and the chord C1 C#2 in a 4/4 measure of quarters
+        let note = |pitch, chord| NoteData {
+            note_rest: NumericPitchRest::Pitch(pitch),
+            note_type: RhythmType::Crochet,
+            chord,
+            ..NoteData::default()
+        };
+        let part = PartBuilder::new()
+            .note_data(note(13, Chord::NoChord))
+            .note_data(note(92, Chord::NoChord))
+            .note_data(note(13, Chord::NoChord))
+            .note_data(note(26, Chord::Chord))
+            .rest(RhythmType::Crochet, Voice::One)
+            .build("P1")
+            .unwrap();
+        ir_to_bin(BufWriter::new(File::create(&input).unwrap()), &part, None).unwrap();
+        let shifted = |octaves, policy| {
+            process_octave_shift(&input, &output, octaves, policy, None, MusicDecoder::DEFAULT_MAX_ELEMENTS).unwrap();
+            let part = bin_to_ir(BufReader::new(File::open(&output).unwrap()), None).unwrap();
+            assert!(matches!(
+                part.inner()[0],
+                MusicElement::MeasureInit(MeasureInitializer {
+                    key_sig: KeySignature::CMajorAminor,
+                    ..
+                })
+            ));
+            part.inner()
+                .iter()
+                .filter_map(|e| match e {
+                    MusicElement::NoteRest(n) => Some((n.note_rest.get_numeric_value(), n.chord)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Up an octave G7 would pass C8, so it is held at G7 or dropped
+        assert_eq!(
+            shifted(1, OctaveShiftPolicy::Clamp),
+            vec![(25, Chord::NoChord), (92, Chord::NoChord), (25, Chord::NoChord), (38, Chord::Chord), (0, Chord::NoChord)]
+        );
+        assert_eq!(
+            shifted(1, OctaveShiftPolicy::Drop),
+            vec![(25, Chord::NoChord), (0, Chord::NoChord), (25, Chord::NoChord), (38, Chord::Chord), (0, Chord::NoChord)]
+        );
+
+        // Down two octaves C1 would pass C0, so it only moves down one octave or is dropped, in which case
+        // C#2 takes its place in the chord
+        assert_eq!(
+            shifted(-2, OctaveShiftPolicy::Clamp),
+            vec![(1, Chord::NoChord), (68, Chord::NoChord), (1, Chord::NoChord), (2, Chord::Chord), (0, Chord::NoChord)]
+        );
+        assert_eq!(
+            shifted(-2, OctaveShiftPolicy::Drop),
+            vec![(0, Chord::NoChord), (68, Chord::NoChord), (2, Chord::NoChord), (0, Chord::NoChord)]
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_augment_is_seeded_and_keeps_tempos_in_range() {
+        let root = temp_path("augment");
+        fs::create_dir_all(&root).unwrap();
+        let input = root.join("input.bin");
+        let mut builder = PartBuilder::new();
+        // Tempos at both ends of the supported range, alternating so every measure has an initializer
+        for bpm in [274, 20].repeat(8) {
+            builder = builder
+                .measure_with(MeasureInitializer {
+                    tempo: Tempo::new(bpm),
+                    ..MeasureInitializer::default()
+                })
+                .note_data(NoteData {
+                    note_rest: NumericPitchRest::Pitch(40),
+                    note_type: RhythmType::SemiBreve,
+                    phrase_dynamics: PhraseDynamics::Fortississimo,
+                    ..NoteData::default()
+                });
+        }
+        let part = builder.build("P1").unwrap();
+        ir_to_bin(BufWriter::new(File::create(&input).unwrap()), &part, None).unwrap();
+
+        let augment = |name: &str, seed: u64| {
+            let output = root.join(name);
+            process_augment(&input, &output, seed, 6, None, MusicDecoder::DEFAULT_MAX_ELEMENTS).unwrap();
+            fs::read(output).unwrap()
+        };
+        let first = augment("first.bin", 7);
+        assert_eq!(first, augment("second.bin", 7));
+
+        let augmented = bin_to_ir(BufReader::new(File::open(root.join("first.bin")).unwrap()), None).unwrap();
+        for elem in augmented.inner() {
+            match elem {
+                MusicElement::MeasureInit(init) => assert!((20..=274).contains(&init.tempo.get_actual())),
+                MusicElement::NoteRest(n) => assert!(matches!(
+                    n.phrase_dynamics,
+                    PhraseDynamics::Fortissimo | PhraseDynamics::Fortississimo
+                )),
+                _ => {}
+            }
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_csv_to_bin_round_trip() {
+        let root = temp_path("csv");
+        fs::create_dir_all(&root).unwrap();
+        let input = root.join("melody.csv");
+        let output = root.join("melody.bin");
+        fs::write(&input, "pitch,octave,type,dotted,voice\nC,4,half,false,1\nF#,4,quarter,false,1\nrest,,quarter,false,1\n")
+            .unwrap();
+        process_csv_to_bin(&input, &output, Beats::Four, BeatType::Four, None).unwrap();
+
+        let part = bin_to_ir(BufReader::new(File::open(&output).unwrap()), None).unwrap();
+        let notes: Vec<(NumericPitchRest, RhythmType)> = part
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some((n.note_rest, n.note_type)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            notes,
+            vec![
+                ("C4".parse::<NumericPitchRest>().unwrap(), RhythmType::Minim),
+                ("F#4".parse::<NumericPitchRest>().unwrap(), RhythmType::Crochet),
+                (NumericPitchRest::Rest, RhythmType::Crochet),
+            ]
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_filter_range_drop_and_error() {
+        let root = temp_path("filter_range");
+        let input = root.join("input");
+        fs::create_dir_all(&input).unwrap();
+        let write_part = |name: &str, low: &str, high: &str| {
+            let part = PartBuilder::new()
+                .measure(Beats::Four, BeatType::Four)
+                .note(low.parse().unwrap(), RhythmType::Minim, Voice::One)
+                .note(high.parse().unwrap(), RhythmType::Minim, Voice::One)
+                .build("P1")
+                .unwrap();
+            ir_to_bin(BufWriter::new(File::create(input.join(name)).unwrap()), &part, None).unwrap();
+        };
+        // Both bounds are inclusive; B1 is one semitone below the range
+        write_part("inside.bin", "C2", "C6");
+        write_part("straddling.bin", "B1", "E3");
+        let min: NumericPitchRest = "C2".parse().unwrap();
+        let max: NumericPitchRest = "C6".parse().unwrap();
+
+        let output = root.join("output");
+        let written = process_filter_range(
+            &input,
+            &output,
+            min,
+            max,
+            RangePolicy::Drop,
+            None,
+            MusicDecoder::DEFAULT_MAX_ELEMENTS,
         )
-        .add_command(
-            Command::new("hello", hello)
-                .with_parameter(Parameter::new("who").set_required(true)?)?
-                .with_help("Greetings!"),
+        .unwrap();
+        assert_eq!(written, vec![output.join("inside.bin")]);
+        assert_eq!(sampled_files(&output), written);
+
+        let result = process_filter_range(
+            &input,
+            &root.join("strict"),
+            min,
+            max,
+            RangePolicy::Error,
+            None,
+            MusicDecoder::DEFAULT_MAX_ELEMENTS,
         );
-    repl.run()
+        assert!(result.is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_report_lists_dropped_percussion_part() {
+        let output = temp_path("report.bin");
+        let input = PathBuf::from("test/unpitched_part.musicxml");
+        let report = process_xml_to_bin(&input, &output, None, &ParseOptions::default()).unwrap();
+        let written = fs::metadata(&output).unwrap().len();
+        fs::remove_file(&output).unwrap();
+
+        assert_eq!(report.input, input);
+        assert_eq!(report.parts_kept, vec!["P1".to_string()]);
+        assert_eq!(report.parts_dropped, vec!["P2".to_string()]);
+        assert!(report.warnings.contains(&"Part P2 was dropped".to_string()));
+        assert_eq!(report.output_bytes, written);
+        assert!(report.render(false).starts_with("WARN test/unpitched_part.musicxml"));
+        assert!(report.render(true).contains("\x1b[33mWARN\x1b[0m"));
+    }
+
+    #[test]
+    fn test_equivalent_scores_canonicalize_to_identical_bytes() {
+        let canonical = |name: &str| {
+            let output = temp_path(&format!("{}.bin", name));
+            let input = PathBuf::from(format!("test/{}.musicxml", name));
+            process_canonicalize(&input, &output, None, &ParseOptions::default()).unwrap();
+            let bytes = fs::read(&output).unwrap();
+            let part = bin_to_ir(BufReader::new(File::open(&output).unwrap()), None).unwrap();
+            fs::remove_file(&output).unwrap();
+            (bytes, part)
+        };
+        // Divisions of 1 and 4, voices 1 and 2 against 1 and 5, a repeated time and key signature,
+        // and black keys spelled with sharps against flats
+        let (sharps, mut part) = canonical("canonical_sharps");
+        assert_eq!(sharps, canonical("canonical_flats").0);
+
+        // Canonicalizing again changes nothing
+        part.canonicalize();
+        let mut bytes = vec![];
+        ir_to_bin(&mut bytes, &part, None).unwrap();
+        assert_eq!(bytes, sharps);
+    }
+
+    #[test]
+    fn test_features_of_unfolded_repeat() {
+        let root = temp_path("features");
+        fs::create_dir_all(&root).unwrap();
+        let input = root.join("repeat.bin");
+        let output = root.join("repeat.features");
+        // |: C4 :| G4
+        let note = |pitch: &str| {
+            MusicElement::NoteRest(NoteData {
+                note_rest: pitch.parse().unwrap(),
+                note_type: RhythmType::SemiBreve,
+                ..NoteData::default()
+            })
+        };
+        let elems = vec![
+            MusicElement::MeasureInit(MeasureInitializer::default()),
+            MusicElement::MeasureMeta(MeasureMetaData::new(MeasureStartEnd::RepeatStart)),
+            note("C4"),
+            MusicElement::MeasureMeta(MeasureMetaData::new(MeasureStartEnd::RepeatEnd)),
+            MusicElement::MeasureMeta(MeasureMetaData::new(MeasureStartEnd::MeasureStart)),
+            note("G4"),
+            MusicElement::MeasureMeta(MeasureMetaData::new(MeasureStartEnd::MeasureEnd)),
+        ];
+        let part = MusicalPart::new_from_elems("P1", elems).unwrap();
+        ir_to_bin(BufWriter::new(File::create(&input).unwrap()), &part, None).unwrap();
+
+        // Pitch, onset and velocity of every record
+        let records = |unfold| -> Vec<(u32, u32, u32)> {
+            let tuning = TuningTable::default();
+            process_features(&input, &output, false, unfold, &tuning, None, MusicDecoder::DEFAULT_MAX_ELEMENTS)
+                .unwrap();
+            let fields: Vec<u32> = fs::read(&output)
+                .unwrap()
+                .chunks(4)
+                .map(|field| u32::from_le_bytes(field.try_into().unwrap()))
+                .collect();
+            fields.chunks(9).map(|r| (r[0], r[6], r[7])).collect()
+        };
+        let c4 = u32::from("C4".parse::<NumericPitchRest>().unwrap().get_numeric_value());
+        let g4 = u32::from("G4".parse::<NumericPitchRest>().unwrap().get_numeric_value());
+        assert_eq!(records(false), vec![(c4, 0, 80), (g4, 3840, 80)]);
+        assert_eq!(records(true), vec![(c4, 0, 80), (c4, 3840, 80), (g4, 7680, 80)]);
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
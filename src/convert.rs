@@ -0,0 +1,91 @@
+//! In memory conversions between MusicXML and the bin format. Nothing on these paths touches the
+//! filesystem or the system clock, so the library built with `--no-default-features` also targets
+//! `wasm32-unknown-unknown`.
+
+use crate::bin_format::{bin_bytes_to_ir_with_dump, ir_to_bin, MusicDecoder};
+use crate::error::{Error, Result};
+use crate::ir::ir_to_xml::ir_to_xml;
+use crate::ir::{xml_to_ir, DumpOptions, ExportOptions, ParseOptions, PartMap};
+use log::info;
+
+/// Converts the first part of a MusicXML document to the bin format
+pub fn xml_string_to_bin_bytes(xml: &str) -> Result<Vec<u8>> {
+    xml_string_to_bin_bytes_with_options(xml, None, &ParseOptions::default())
+}
+
+pub fn xml_string_to_bin_bytes_with_options(
+    xml: &str,
+    dump_input: Option<DumpOptions>,
+    options: &ParseOptions,
+) -> Result<Vec<u8>> {
+    let partmap = xml_to_ir(xml.to_string(), dump_input.is_some(), options)?;
+    part_map_to_bin_bytes(partmap, dump_input, options)
+}
+
+/// Converts the first part of a parsed score to the bin format
+pub fn part_map_to_bin_bytes(
+    mut partmap: PartMap,
+    dump_input: Option<DumpOptions>,
+    options: &ParseOptions,
+) -> Result<Vec<u8>> {
+    // MuBin only supports a single part, so only the first part is written
+    if options.optimize {
+        info!("Removed {} redundant measure initializers", partmap.coalesce_initializers());
+    }
+    let part = partmap.get_part(0).ok_or(Error::NotInitialized)?;
+    let mut bytes = vec![];
+    ir_to_bin(&mut bytes, part, dump_input)?;
+    Ok(bytes)
+}
+
+/// Converts a bin file to a MusicXML document
+pub fn bin_bytes_to_xml_string(bytes: &[u8]) -> Result<String> {
+    bin_bytes_to_xml_string_with_options(
+        bytes,
+        None,
+        &ExportOptions::default(),
+        MusicDecoder::DEFAULT_MAX_ELEMENTS,
+    )
+}
+
+pub fn bin_bytes_to_xml_string_with_options(
+    bytes: &[u8],
+    dump_input: Option<DumpOptions>,
+    export_options: &ExportOptions,
+    max_elements: usize,
+) -> Result<String> {
+    let mut partmap = PartMap::new();
+    // The MusicBin format only supports a single piano part
+    let part = bin_bytes_to_ir_with_dump(bytes, dump_input, max_elements)?;
+    partmap.push_part("P1", part)?;
+    ir_to_xml(partmap, export_options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bin_bytes_to_xml_string, xml_string_to_bin_bytes};
+    use crate::bin_format::{bin_bytes_to_ir, MusicDecoder};
+    use crate::ir::{xml_to_ir, ParseOptions};
+
+    #[test]
+    fn test_in_memory_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let xml_source = include_str!("../test/simple.musicxml");
+        let bytes = xml_string_to_bin_bytes(xml_source)?;
+        // The pickup numbered 0 is kept in the metadata section of a V3 file
+        assert!(bytes.starts_with(b"MuB3"));
+
+        let partmap = xml_to_ir(xml_source.to_string(), false, &ParseOptions::default())?;
+        let decoded = bin_bytes_to_ir(&bytes, MusicDecoder::DEFAULT_MAX_ELEMENTS)?;
+        assert_eq!(decoded.inner(), partmap.get_part(0).unwrap().inner());
+
+        let xml = bin_bytes_to_xml_string(&bytes)?;
+        let doc = roxmltree::Document::parse(&xml)?;
+        // No date was given, so none is written rather than an empty one
+        assert!(!doc.descendants().any(|n| n.has_tag_name("encoding-date")));
+        assert!(doc.descendants().any(|n| n.has_tag_name("encoding")));
+        assert!(doc.descendants().any(|n| n.has_tag_name("part") && n.attribute("id") == Some("P1")));
+        assert!(doc.descendants().any(|n| n.has_tag_name("note")));
+        assert!(bin_bytes_to_xml_string(b"MuBi").is_err());
+        Ok(())
+    }
+}
@@ -1,13 +1,17 @@
 use core::result;
 use mulib::error::Error as MuLibErr;
 use muxml::error::Error as MuError;
+#[cfg(feature = "cli")]
 use repl_rs::Error as ReplError;
+use std::fmt;
+use std::io;
 use thiserror::Error;
 
 pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Error, Debug, PartialEq)]
 pub enum Error {
+    #[cfg(feature = "cli")]
     #[error("Repl crate error {0}")]
     Repl(ReplError),
     #[error("MusicXML crate Error {0}")]
@@ -18,8 +22,8 @@ pub enum Error {
     OutofBounds,
     #[error("Unsupported Feature")]
     Unsupported,
-    #[error("IO Kind {0}")]
-    IoKind(String),
+    #[error("IO error: {0}")]
+    Io(#[from] IoError),
     #[error("Unnamed Error")]
     Unit,
     #[error("Missing Reader")]
@@ -34,18 +38,63 @@ pub enum Error {
     NotInitialized,
     #[error("Decoding Error")]
     Decoding,
-    #[error("ParseIntError")]
+    #[error("Strict mode rejected part {0}: {1}")]
+    StrictViolation(String, String),
+    #[error("Score has {found} parts, more than the maximum of {max}")]
+    TooManyParts { found: usize, max: usize },
+    #[error("Validation failed: {0}")]
+    Validation(String),
+    #[error("Missing required argument {0}")]
+    MissingArgument(&'static str),
+    #[error("Unknown command {0}")]
+    UnknownCommand(String),
+    #[error("Invalid integer: {0}")]
     ParseInt(#[from] std::num::ParseIntError),
     #[error("StrumParse {0}")]
     Strum(#[from] strum::ParseError),
 }
 
+/// An IO error, compared by its kind and message so `Error` stays comparable
+#[derive(Debug)]
+pub struct IoError(pub io::Error);
+
+impl IoError {
+    pub fn kind(&self) -> io::ErrorKind {
+        self.0.kind()
+    }
+}
+
+impl PartialEq for IoError {
+    fn eq(&self, other: &IoError) -> bool {
+        self.0.kind() == other.0.kind() && self.0.to_string() == other.0.to_string()
+    }
+}
+
+impl fmt::Display for IoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for IoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(IoError(e))
+    }
+}
+
 impl From<MuError> for Error {
     fn from(e: MuError) -> Self {
         Error::MuCrate(e)
     }
 }
 
+#[cfg(feature = "cli")]
 impl From<ReplError> for Error {
     fn from(e: ReplError) -> Self {
         Error::Repl(e)
@@ -57,3 +106,26 @@ impl From<MuLibErr> for Error {
         Error::MuLib(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, Result};
+    use std::error::Error as StdError;
+    use std::fs;
+
+    #[test]
+    fn test_io_failure_is_described() {
+        let read = || -> Result<String> { Ok(fs::read_to_string("test/no_such_score.musicxml")?) };
+        let err = read().unwrap_err();
+        assert!(matches!(err, Error::Io(ref e) if e.kind() == std::io::ErrorKind::NotFound));
+        assert!(err.to_string().starts_with("IO error: "), "{}", err);
+        assert!(err.to_string().len() > "IO error: ".len());
+        // The io::Error is reachable through the source chain
+        let io_err = err.source().and_then(|e| e.source()).unwrap();
+        assert!(io_err.downcast_ref::<std::io::Error>().is_some());
+
+        let err = Error::from("twelve".parse::<u8>().unwrap_err());
+        assert_eq!(err.to_string(), "Invalid integer: invalid digit found in string");
+        assert!(err.source().is_some());
+    }
+}
@@ -0,0 +1,258 @@
+use super::notation::{BeatType, Beats, Chord, NoteData, NumericPitchRest, RhythmType, SpecialNote};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The state of one beam level of a note, as written in the MusicXML `<beam>` element
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BeamValue {
+    Begin,
+    Continue,
+    End,
+    /// A partial beam pointing to the following note
+    ForwardHook,
+    /// A partial beam pointing to the preceding note
+    BackwardHook,
+}
+
+impl fmt::Display for BeamValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = match self {
+            BeamValue::Begin => "begin",
+            BeamValue::Continue => "continue",
+            BeamValue::End => "end",
+            BeamValue::ForwardHook => "forward hook",
+            BeamValue::BackwardHook => "backward hook",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+/// The beams of a single note, from the primary (eighth) beam outwards. Unbeamed notes have none.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BeamState {
+    pub levels: Vec<BeamValue>,
+}
+
+impl BeamState {
+    pub fn is_beamed(&self) -> bool {
+        !self.levels.is_empty()
+    }
+
+    /// Serializes the beams as MusicXML `<beam>` elements
+    pub fn to_xml_fragment(&self) -> String {
+        self.levels
+            .iter()
+            .enumerate()
+            .map(|(idx, value)| format!("<beam number=\"{}\">{}</beam>", idx + 1, value))
+            .collect()
+    }
+}
+
+/// Number of beams (or flags) drawn for a rhythm value
+fn beam_count(note_type: RhythmType) -> usize {
+    match note_type {
+        RhythmType::Quaver => 1,
+        RhythmType::SemiQuaver => 2,
+        RhythmType::DemiSemiQuaver => 3,
+        RhythmType::HemiDemiSemiQuaver => 4,
+        RhythmType::SemiHemiDemiSemiQuaver => 5,
+        _ => 0,
+    }
+}
+
+/// Length of one beat in MIDI ticks. Compound meters are beamed by dotted beat.
+fn beat_ticks(beats: Beats, beat_type: BeatType) -> u32 {
+    let crochet = NoteData::default().get_duration_in_midi_ticks(None);
+    let beat_type = u32::from(beat_type);
+    let beat = crochet * 4 / beat_type;
+    let beats = u32::from(beats);
+    if beat_type >= 8 && beats > 3 && beats % 3 == 0 {
+        beat * 3
+    } else {
+        beat
+    }
+}
+
+/// Writes the beam levels of a finished group. A lone note is left unbeamed.
+fn close_group(notes: &[NoteData], group: &mut Vec<usize>, states: &mut [BeamState]) {
+    if group.len() >= 2 {
+        let counts: Vec<usize> = group.iter().map(|&idx| beam_count(notes[idx].note_type)).collect();
+        let max_level = counts.iter().copied().max().unwrap_or(0);
+        for level in 1..=max_level {
+            for (pos, &idx) in group.iter().enumerate() {
+                if counts[pos] < level {
+                    continue;
+                }
+                let prev = pos > 0 && counts[pos - 1] >= level;
+                let next = pos + 1 < counts.len() && counts[pos + 1] >= level;
+                let value = match (prev, next) {
+                    (false, true) => BeamValue::Begin,
+                    (true, true) => BeamValue::Continue,
+                    (true, false) => BeamValue::End,
+                    (false, false) if pos == 0 => BeamValue::ForwardHook,
+                    (false, false) => BeamValue::BackwardHook,
+                };
+                states[idx].levels.push(value);
+            }
+        }
+    }
+    group.clear();
+}
+
+/// Groups the eighth and shorter notes of a measure under beams, one group per beat and voice.
+///
+/// Rests, grace notes and notes of a crochet or longer break a group, as does any note that
+/// extends past the end of the beat it starts in. Chord tones share the beams of the note they are
+/// stacked on. Durations are nominal: notes inside tuplets are not accounted for.
+///
+/// # Arguments
+///
+/// * `notes`      - The notes and rests of one measure, in any mix of voices
+/// * `beats`      - Numerator of the time signature in effect
+/// * `beat_type`  - Denominator of the time signature in effect
+///
+/// Returns the beam state of each note, in the order of `notes`.
+pub fn beam_groups(notes: &[NoteData], beats: Beats, beat_type: BeatType) -> Vec<BeamState> {
+    let beat_len = beat_ticks(beats, beat_type);
+    let mut states = vec![BeamState::default(); notes.len()];
+    // Position within the measure, beat index of the open group and its notes, per voice
+    let mut voices: BTreeMap<u8, (u32, Option<u32>, Vec<usize>)> = BTreeMap::new();
+
+    for (idx, note) in notes.iter().enumerate() {
+        if note.chord == Chord::Chord || note.special_note != SpecialNote::None {
+            continue;
+        }
+        let (position, group_beat, group) = voices.entry(note.voice as u8).or_default();
+        let start = *position;
+        let duration = note.get_duration_in_midi_ticks(None);
+        *position += duration;
+
+        let beat = start / beat_len;
+        let fits = duration > 0 && (start + duration - 1) / beat_len == beat;
+        let beamable = note.note_rest != NumericPitchRest::Rest && beam_count(note.note_type) > 0 && fits;
+        if !beamable || *group_beat != Some(beat) {
+            close_group(notes, group, &mut states);
+            *group_beat = None;
+        }
+        if beamable {
+            group.push(idx);
+            *group_beat = Some(beat);
+        }
+    }
+    for (_, _, group) in voices.values_mut() {
+        close_group(notes, group, &mut states);
+    }
+
+    // Chord tones follow the note they are stacked on
+    for idx in 1..notes.len() {
+        if notes[idx].chord == Chord::Chord {
+            states[idx] = states[idx - 1].clone();
+        }
+    }
+    states
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{beam_groups, BeamState, BeamValue};
+    use crate::ir::notation::{BeatType, Beats, NoteData, NumericPitchRest, RhythmType, Voice};
+
+    fn note(note_type: RhythmType) -> NoteData {
+        NoteData {
+            note_rest: NumericPitchRest::Pitch(40),
+            note_type,
+            ..NoteData::default()
+        }
+    }
+
+    fn state(levels: &[BeamValue]) -> BeamState {
+        BeamState {
+            levels: levels.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_mixed_eighth_and_sixteenths_share_a_beat() {
+        use BeamValue::*;
+        // One beat of eighth, sixteenth, sixteenth, then a beat of sixteenth, eighth, sixteenth
+        let notes = [
+            note(RhythmType::Quaver),
+            note(RhythmType::SemiQuaver),
+            note(RhythmType::SemiQuaver),
+            note(RhythmType::SemiQuaver),
+            note(RhythmType::Quaver),
+            note(RhythmType::SemiQuaver),
+        ];
+        let states = beam_groups(&notes, Beats::Two, BeatType::Four);
+        assert_eq!(
+            states,
+            vec![
+                state(&[Begin]),
+                state(&[Continue, Begin]),
+                state(&[End, End]),
+                state(&[Begin, ForwardHook]),
+                state(&[Continue]),
+                state(&[End, BackwardHook]),
+            ]
+        );
+        assert_eq!(
+            states[3].to_xml_fragment(),
+            "<beam number=\"1\">begin</beam><beam number=\"2\">forward hook</beam>"
+        );
+    }
+
+    #[test]
+    fn test_beams_do_not_cross_beat_boundaries() {
+        use BeamValue::*;
+        // A sixteenth offsets the eighths so the second one straddles the beat and stays unbeamed
+        let notes = [
+            note(RhythmType::SemiQuaver),
+            note(RhythmType::Quaver),
+            note(RhythmType::Quaver),
+            note(RhythmType::SemiQuaver),
+            note(RhythmType::SemiQuaver),
+            note(RhythmType::SemiQuaver),
+            note(RhythmType::Quaver),
+            note(RhythmType::Quaver),
+        ];
+        let states = beam_groups(&notes, Beats::Three, BeatType::Four);
+        assert_eq!(
+            states,
+            vec![
+                state(&[Begin, ForwardHook]),
+                state(&[End]),
+                BeamState::default(),
+                state(&[Begin, Begin]),
+                state(&[Continue, Continue]),
+                state(&[End, End]),
+                state(&[Begin]),
+                state(&[End]),
+            ]
+        );
+
+        // Four eighths in 4/4 are beamed in pairs, one per beat
+        let eighths = [note(RhythmType::Quaver); 4];
+        let states = beam_groups(&eighths, Beats::Four, BeatType::Four);
+        assert_eq!(
+            states,
+            vec![state(&[Begin]), state(&[End]), state(&[Begin]), state(&[End])]
+        );
+
+        // Six eighths in 6/8 are beamed by dotted crochet
+        let eighths = [note(RhythmType::Quaver); 6];
+        let states = beam_groups(&eighths, Beats::Six, BeatType::Eight);
+        assert_eq!(states[0], state(&[Begin]));
+        assert_eq!(states[2], state(&[End]));
+        assert_eq!(states[3], state(&[Begin]));
+    }
+
+    #[test]
+    fn test_rests_break_groups() {
+        let notes = [
+            note(RhythmType::Quaver),
+            NoteData::new_default_rest(RhythmType::Quaver, false, Voice::One),
+        ];
+        let states = beam_groups(&notes, Beats::Four, BeatType::Four);
+        assert!(states.iter().all(|s| !s.is_beamed()));
+    }
+}
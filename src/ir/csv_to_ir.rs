@@ -0,0 +1,122 @@
+use super::musical_part::MusicalPart;
+use super::notation::{BeatType, Beats, NoteData, NumericPitchRest, RhythmType, Voice};
+use super::PartBuilder;
+use crate::error::{Error, Result};
+use num_traits::FromPrimitive;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+/// Parses one `pitch,octave,type,dotted,voice` row, such as `C#,4,quarter,false,1` or `rest,,half,true,1`
+fn parse_row(line_idx: usize, row: &str) -> Result<NoteData> {
+    let invalid = |field: &str, value: &str| {
+        Error::Validation(format!("line {}: invalid {} \"{}\"", line_idx + 1, field, value))
+    };
+    let fields: Vec<&str> = row.split(',').map(str::trim).collect();
+    let [pitch, octave, note_type, dotted, voice] = fields[..] else {
+        return Err(Error::Validation(format!(
+            "line {}: expected 5 fields, found {}",
+            line_idx + 1,
+            fields.len()
+        )));
+    };
+    let note_rest = match pitch {
+        "rest" => NumericPitchRest::Rest,
+        _ => NumericPitchRest::from_str(&format!("{}{}", pitch, octave)).map_err(|_| invalid("pitch", row))?,
+    };
+    let note_type = RhythmType::from_str(note_type).map_err(|_| invalid("type", note_type))?;
+    let dotted = match dotted {
+        "" | "false" | "no" | "0" => false,
+        "true" | "yes" | "1" => true,
+        _ => return Err(invalid("dotted", dotted)),
+    };
+    let voice = voice
+        .parse::<u8>()
+        .ok()
+        .and_then(|v| v.checked_sub(1))
+        .and_then(Voice::from_u8)
+        .ok_or_else(|| invalid("voice", voice))?;
+    Ok(NoteData {
+        note_rest,
+        note_type,
+        dotted,
+        voice,
+        ..NoteData::default()
+    })
+}
+
+/// Converts a CSV melody of `pitch,octave,type,dotted,voice` rows into a part in the given meter.
+///
+/// Pitches are step names with an optional sharp or flat, such as `F#` or `Bb`, or `rest`, which
+/// leaves the octave empty. Types are MusicXML note type names. A new measure starts when the voice
+/// of a row has already filled the current one, so a note crossing a barline is an error rather than
+/// being split. Blank lines, `#` comments and a `pitch,...` header row are skipped.
+pub fn csv_to_ir(csv: &str, part_str: &str, beats: Beats, beat_type: BeatType) -> Result<MusicalPart> {
+    let measure_ticks = NoteData::default().get_duration_in_midi_ticks(None) * 4 * u32::from(beats)
+        / u32::from(beat_type);
+    let mut builder = PartBuilder::new().measure(beats, beat_type);
+    // Time taken by each voice in the current measure
+    let mut voice_ticks: BTreeMap<u8, u32> = BTreeMap::new();
+    for (line_idx, line) in csv.lines().enumerate() {
+        let row = line.trim();
+        if row.is_empty() || row.starts_with('#') || row.starts_with("pitch") {
+            continue;
+        }
+        let note = parse_row(line_idx, row)?;
+        let ticks = note.get_duration_in_midi_ticks(None);
+        if voice_ticks.get(&(note.voice as u8)).copied().unwrap_or(0) >= measure_ticks {
+            builder = builder.measure(beats, beat_type);
+            voice_ticks.clear();
+        }
+        let position = voice_ticks.entry(note.voice as u8).or_insert(0);
+        if *position + ticks > measure_ticks {
+            return Err(Error::Validation(format!(
+                "line {}: the {} crosses the barline",
+                line_idx + 1,
+                note.note_type.get_type_string()
+            )));
+        }
+        *position += ticks;
+        builder = builder.note_data(note);
+    }
+    builder.build(part_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::csv_to_ir;
+    use crate::ir::notation::{BeatType, Beats, MeasureStartEnd, MusicElement, NumericPitchRest, RhythmType};
+
+    #[test]
+    fn test_rows_fill_measures_in_order() {
+        let csv = "pitch,octave,type,dotted,voice\nC,4,half,true,1\nrest,,quarter,false,1\nEb,4,quarter,false,1\nG,4,quarter,false,1\n";
+        let part = csv_to_ir(csv, "P1", Beats::Three, BeatType::Four).unwrap();
+        let measure_starts = part
+            .inner()
+            .iter()
+            .filter(|e| matches!(e, MusicElement::MeasureMeta(m) if m.start_end == MeasureStartEnd::MeasureStart))
+            .count();
+        assert_eq!(measure_starts, 2);
+        let pitch = |name: &str| name.parse::<NumericPitchRest>().unwrap();
+        let notes: Vec<(NumericPitchRest, RhythmType, bool)> = part
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some((n.note_rest, n.note_type, n.dotted)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            notes,
+            vec![
+                (pitch("C4"), RhythmType::Minim, true),
+                (NumericPitchRest::Rest, RhythmType::Crochet, false),
+                (pitch("Eb4"), RhythmType::Crochet, false),
+                (pitch("G4"), RhythmType::Crochet, false),
+            ]
+        );
+
+        // A half note does not fit the last beat of a 3/4 measure
+        let csv = "C,4,half,false,1\nD,4,half,false,1\n";
+        assert!(csv_to_ir(csv, "P1", Beats::Three, BeatType::Four).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use super::notation::{MusicElement, NoteData, PitchNotation};
+use super::Provenance;
+use crate::error::{Error, Result};
+use std::str::FromStr;
+
+/// Log target of the `--dump` lines, logged at info level. Dump output is asked for explicitly, so the
+/// command line tool writes it to standard output whatever the log level.
+pub const DUMP_TARGET: &str = "music2bin::dump";
+
+/// Layout of the `--dump` output
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum DumpFormat {
+    /// The compact one line rendering of each element, e.g. `C#4 quarter v1 f`
+    #[default]
+    Debug,
+    /// Aligned kind, pitch, type, voice and detail columns under a header row
+    Table,
+    /// One JSON object per element
+    Json,
+}
+
+impl FromStr for DumpFormat {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<DumpFormat> {
+        match input {
+            "debug" => Ok(DumpFormat::Debug),
+            "table" => Ok(DumpFormat::Table),
+            "json" => Ok(DumpFormat::Json),
+            _ => Err(Error::Parse),
+        }
+    }
+}
+
+/// How the elements of a part are dumped while encoding
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DumpOptions {
+    pub notation: PitchNotation,
+    pub format: DumpFormat,
+}
+
+impl DumpOptions {
+    pub fn new(notation: PitchNotation, format: DumpFormat) -> DumpOptions {
+        DumpOptions { notation, format }
+    }
+
+    /// Line written before the first element, naming the table columns
+    pub fn header(&self) -> Option<String> {
+        match self.format {
+            DumpFormat::Table => Some(table_row("kind", "pitch", "type", "voice", "detail")),
+            DumpFormat::Debug | DumpFormat::Json => None,
+        }
+    }
+
+    /// Renders one element as a line of the dump
+    pub fn line(&self, elem: &MusicElement) -> String {
+        match self.format {
+            DumpFormat::Debug => elem.display_with(self.notation).to_string(),
+            DumpFormat::Table => {
+                let (kind, note) = element_columns(elem, self.notation);
+                match note {
+                    Some((pitch, n)) => table_row(
+                        kind,
+                        &pitch,
+                        &rhythm(n),
+                        &format!("v{}", n.voice as u8 + 1),
+                        n.phrase_dynamics.abbreviation().unwrap_or_default(),
+                    ),
+                    None => table_row(kind, "", "", "", &elem.display_with(self.notation).to_string()),
+                }
+            }
+            DumpFormat::Json => {
+                let (kind, note) = element_columns(elem, self.notation);
+                match note {
+                    Some((pitch, n)) => format!(
+                        "{{\"kind\":\"{}\",\"pitch\":{},\"type\":\"{}\",\"dotted\":{},\"voice\":{},\"dynamics\":{}}}",
+                        kind,
+                        json_string(&pitch),
+                        n.note_type.get_type_string(),
+                        n.dotted,
+                        n.voice as u8 + 1,
+                        n.phrase_dynamics.abbreviation().map_or(String::from("null"), json_string)
+                    ),
+                    None => format!(
+                        "{{\"kind\":\"{}\",\"detail\":{}}}",
+                        kind,
+                        json_string(&elem.display_with(self.notation).to_string())
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Appends the source measure and beat of an element to its dumped line
+    /// Every line of the dump of `elems`, after the header if the format has one
+    pub fn lines<'a>(&'a self, elems: &'a [MusicElement]) -> impl Iterator<Item = String> + 'a {
+        self.header().into_iter().chain(elems.iter().map(|elem| self.line(elem)))
+    }
+
+    pub fn with_provenance(&self, line: String, provenance: &Provenance) -> String {
+        match self.format {
+            DumpFormat::Debug | DumpFormat::Table => format!("{} {}", line, provenance),
+            DumpFormat::Json => format!(
+                "{},\"measure\":{},\"beat\":{}}}",
+                line.strip_suffix('}').unwrap_or(&line),
+                provenance.measure,
+                provenance.beat
+            ),
+        }
+    }
+}
+
+/// Kind name of the element, and for notes, rests and drum hits the name of their pitch and the note
+fn element_columns(elem: &MusicElement, notation: PitchNotation) -> (&'static str, Option<(String, &NoteData)>) {
+    match elem {
+        MusicElement::MeasureInit(_) => ("init", None),
+        MusicElement::MeasureMeta(_) => ("measure", None),
+        MusicElement::NoteRest(n) => ("note", Some((n.note_rest.name(notation), n))),
+        MusicElement::Tuplet(_) => ("tuplet", None),
+        MusicElement::Harmony(_) => ("harmony", None),
+        MusicElement::ClefChange(_) => ("clef", None),
+        MusicElement::Percussion(p) => ("drum", Some((p.drum.midi_key().to_string(), &p.note))),
+    }
+}
+
+fn rhythm(n: &NoteData) -> String {
+    if n.dotted {
+        format!("{}.", n.note_type.get_type_string())
+    } else {
+        n.note_type.get_type_string()
+    }
+}
+
+fn table_row(kind: &str, pitch: &str, note_type: &str, voice: &str, detail: &str) -> String {
+    format!("{:<8} {:<6} {:<9} {:<5} {}", kind, pitch, note_type, voice, detail)
+        .trim_end()
+        .to_string()
+}
+
+fn json_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DumpFormat, DumpOptions};
+    use crate::ir::notation::{NoteData, NumericPitchRest, PhraseDynamics, PitchNotation, RhythmType, Voice};
+    use crate::ir::PartBuilder;
+
+    #[test]
+    fn test_table_dump_of_small_stream() {
+        let part = PartBuilder::new()
+            .note_data(NoteData {
+                note_rest: NumericPitchRest::Pitch(50),
+                note_type: RhythmType::Crochet,
+                dotted: true,
+                phrase_dynamics: PhraseDynamics::Forte,
+                ..NoteData::default()
+            })
+            .rest(RhythmType::Quaver, Voice::Two)
+            .build("P1")
+            .unwrap();
+        let options = DumpOptions::new(PitchNotation::Letter, DumpFormat::Table);
+        let mut lines: Vec<String> = options.header().into_iter().collect();
+        lines.extend(part.inner().iter().map(|e| options.line(e)));
+        assert_eq!(
+            lines.join("\n"),
+            "\
+kind     pitch  type      voice detail
+init                            |4/4 C-maj 120bpm|
+measure                         |start|
+note     C#4    quarter.  v1    f
+note     rest   eighth    v2
+measure                         |end|"
+        );
+
+        let json = DumpOptions::new(PitchNotation::Letter, DumpFormat::Json);
+        assert_eq!(
+            json.line(&part.inner()[2]),
+            "{\"kind\":\"note\",\"pitch\":\"C#4\",\"type\":\"quarter\",\"dotted\":true,\"voice\":1,\"dynamics\":\"f\"}"
+        );
+    }
+}
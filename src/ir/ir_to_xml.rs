@@ -1,4 +1,6 @@
 #![allow(clippy::too_many_arguments)]
+use crate::error::{Error, Result};
+use crate::ir::ExportOptions;
 use crate::ir::MusicalPart;
 use crate::ir::PartMap;
 use muxml::muxml_types::EndingElement;
@@ -8,21 +10,25 @@ use muxml::muxml_types::{
     ArticulationElement, ArticulationValue, AttributesElement, BackupElement, BarlineElement,
     ClefElement, DirectionElement, DirectionType, DirectionTypeElement, DynamicsElement, Measure,
     MeasureDirectionNote, Notations, NotationsElement, SlurElement, SlurType, SoundElement,
-    TiedElement, TiedType, TimeElement, TimeModificationElement, TupletElement, TupletType,
+    TiedElement, TiedType, TimeElement, TupletElement, TupletType,
     WordsElement,
 };
 use muxml::score::{CompleteParts, ScoreBuilder};
 use muxml::ser::encode_muxml;
 
-//use log::{debug, error};
+use log::warn;
+use mulib::pitch::AccidentalSpelling;
 
 use crate::ir::notation::{
-    Arpeggiate, Articulation, BeatType, Beats, Chord, DescriptiveTempo, MeasureInitializer,
-    MeasureMetaData, MeasureStartEnd, MusicElement, NoteConnection, NoteData, SlurConnection,
-    TimeModification, TupletData, TupletStartStop, Voice,
+    Arpeggiate, Articulation, BarStyle, BeatType, Beats, Chord, ClefChangeData, Clef, DescriptiveTempo, HarmonyAlter,
+    HarmonyData, IsDotted, KeySignature,
+    MeasureInitializer, MeasureMetaData, MeasureStartEnd, MusicElement, NoteConnection, NoteData,
+    NoteSize, Notehead, NumericPitchRest, PercussionData, PhraseDynamics, RhythmType, SlurConnection, TimeModification, Tremolo,
+    Trill, TupletData, TupletStartStop, Voice,
 };
 
-use super::notation::get_staff;
+use super::beaming::beam_groups;
+use super::xml_patch::{PatchAnchor, PatchEdit, XmlPatcher};
 use super::notation::NoteElementWrapper;
 
 fn ser_measure_init(
@@ -32,6 +38,7 @@ fn ser_measure_init(
     cur_measure_idx: i32,
     cur_beat: &mut Beats,
     cur_beat_type: &mut BeatType,
+    clefs: &[Clef],
 ) {
     *cur_beat = e.beats;
     *cur_beat_type = e.beat_type;
@@ -45,17 +52,15 @@ fn ser_measure_init(
             beats: e.beats.to_string(),
             beat_type: e.beat_type.to_string(),
         },
-        staves: "2".to_string(),
-        clef: vec![
-            ClefElement {
-                number: "1".to_string(),
-                sign: "G".to_string(),
-            },
-            ClefElement {
-                number: "2".to_string(),
-                sign: "F".to_string(),
-            },
-        ],
+        staves: clefs.len().to_string(),
+        clef: clefs
+            .iter()
+            .enumerate()
+            .map(|(idx, clef)| ClefElement {
+                number: (idx + 1).to_string(),
+                sign: clef.sign().to_string(),
+            })
+            .collect(),
     });
 
     m.direction_note
@@ -103,6 +108,15 @@ fn ser_measure_meta(
             //m.direction_note = vec![];
         }
         MeasureStartEnd::MeasureEnd => {
+            // muxml has no field for the bar style, which is patched into the barline
+            if e.bar_style != BarStyle::Regular {
+                m.direction_note
+                    .push(MeasureDirectionNote::Barline(BarlineElement {
+                        location: Some("right".to_string()),
+                        ending: None,
+                        repeat: None,
+                    }));
+            }
             // Skip first case where there is no measure populated yet
             measures.push(m.clone());
             *m = Measure::default();
@@ -147,17 +161,294 @@ fn ser_measure_meta(
     };
 }
 
+/// The rhythm value, dot and time modification describing one half of a note split at a barline
+type SplitComponent = (RhythmType, IsDotted, Option<TimeModification>);
+
+/// Remainder of a note that did not fit before the barline. It is placed ahead of the
+/// first note of the same voice in the following measure.
+#[derive(Clone)]
+struct CarriedNote {
+    target_measure_idx: i32,
+    note: NoteData,
+    t_modification: Option<TimeModification>,
+    ties: Vec<TiedType>,
+}
+
+/// Tracks notes that are split into tied pairs because they extend past the end of the measure
+#[derive(Default)]
+struct TieSplitter {
+    carried: Vec<CarriedNote>,
+    // Components of the most recent split note, re-used for chord tones stacked on top of it
+    last_split: Option<(SplitComponent, SplitComponent)>,
+}
+
+impl TieSplitter {
+    fn take_carried(&mut self, voice: Voice, cur_measure_idx: i32) -> Vec<CarriedNote> {
+        let (ready, waiting): (Vec<CarriedNote>, Vec<CarriedNote>) = self
+            .carried
+            .drain(..)
+            .partition(|c| c.note.voice == voice && c.target_measure_idx <= cur_measure_idx);
+        self.carried = waiting;
+        ready
+    }
+}
+
+/// Finds a rhythm value that represents `duration` exactly, so split notes never lose or gain time
+fn exact_component(duration: u32, divisions: u32, beats: u32, beat_type: u32, note: &NoteData) -> Option<SplitComponent> {
+    let (note_type, dotted, t_mod) = match NoteData::from_numeric_duration(duration, divisions).as_slice() {
+        [component] => *component,
+        _ => return None,
+    };
+    let candidate = NoteData {
+        note_type,
+        dotted,
+        ..*note
+    };
+    if candidate.get_duration_numeric(divisions, beats, beat_type, t_mod) == duration {
+        Some((note_type, dotted, t_mod))
+    } else {
+        None
+    }
+}
+
+/// Rest values adding up to `duration`, with semibreves written as minims since a semibreve rest
+/// fills the whole measure
+fn fill_rest_components(duration: u32, divisions: u32) -> Vec<SplitComponent> {
+    NoteData::from_numeric_duration(duration, divisions)
+        .into_iter()
+        .flat_map(|component| match component {
+            (RhythmType::SemiBreve, dotted, None) => vec![(RhythmType::Minim, false, None); if dotted { 3 } else { 2 }],
+            component => vec![component],
+        })
+        .collect()
+}
+
+/// Writes the remainders carried into the current measure that no note of their voice has picked up,
+/// because the voice has no note in this measure or the part ends, each followed by the rests
+/// completing its voice
+fn flush_carried(
+    part: &MusicalPart,
+    m: &mut Measure,
+    cur_measure_idx: i32,
+    prev_voice: &mut Option<Voice>,
+    measure_duration_tally: &mut u32,
+    cur_tuplet_info: &mut Option<TupletElement>,
+    cur_beat: Beats,
+    cur_beat_type: BeatType,
+    tie_splitter: &mut TieSplitter,
+    spelling: AccidentalSpelling,
+) {
+    let divisions = part.get_initial_divisions().unwrap();
+    let measure_length = divisions * u32::from(cur_beat) * 4 / u32::from(cur_beat_type);
+    let (ready, waiting): (Vec<CarriedNote>, Vec<CarriedNote>) = tie_splitter
+        .carried
+        .drain(..)
+        .partition(|c| c.target_measure_idx <= cur_measure_idx);
+    tie_splitter.carried = waiting;
+    for (idx, carried) in ready.iter().enumerate() {
+        let voice = carried.note.voice;
+        ser_note_element(
+            part,
+            carried.note,
+            m,
+            prev_voice,
+            measure_duration_tally,
+            cur_tuplet_info,
+            carried.t_modification,
+            cur_beat,
+            cur_beat_type,
+            carried.ties.clone(),
+            spelling,
+        );
+        // Chord tones carried along with the note come before the rests
+        if ready
+            .get(idx + 1)
+            .is_some_and(|next| next.note.voice == voice && next.note.chord == Chord::Chord)
+        {
+            continue;
+        }
+        let fill = measure_length.saturating_sub(*measure_duration_tally);
+        for (note_type, dotted, t_modification) in fill_rest_components(fill, divisions) {
+            ser_note_element(
+                part,
+                NoteData::new_default_rest(note_type, dotted, voice),
+                m,
+                prev_voice,
+                measure_duration_tally,
+                cur_tuplet_info,
+                t_modification,
+                cur_beat,
+                cur_beat_type,
+                vec![],
+                spelling,
+            );
+        }
+    }
+}
+
 fn ser_note_rest(
     part: &MusicalPart,
     e: NoteData,
     m: &mut Measure,
-    _cur_measure_idx: i32,
+    cur_measure_idx: i32,
+    prev_voice: &mut Option<Voice>,
+    measure_duration_tally: &mut u32,
+    cur_tuplet_info: &mut Option<TupletElement>,
+    cur_t_modification: &Option<TimeModification>,
+    cur_beat: Beats,
+    cur_beat_type: BeatType,
+    tie_splitter: &mut TieSplitter,
+    spelling: AccidentalSpelling,
+) {
+    let divisions = part.get_initial_divisions().unwrap();
+    let beats = u32::from(cur_beat);
+    let beat_type = u32::from(cur_beat_type);
+
+    // Remainders of notes tied over the previous barline come first in their voice
+    if e.chord == Chord::NoChord {
+        for carried in tie_splitter.take_carried(e.voice, cur_measure_idx) {
+            ser_note_element(
+                part,
+                carried.note,
+                m,
+                prev_voice,
+                measure_duration_tally,
+                cur_tuplet_info,
+                carried.t_modification,
+                cur_beat,
+                cur_beat_type,
+                carried.ties,
+                spelling,
+            );
+        }
+    }
+
+    let is_rest = e.note_rest == NumericPitchRest::Rest;
+    let split = if e.chord == Chord::Chord {
+        tie_splitter.last_split
+    } else {
+        tie_splitter.last_split = None;
+        let duration = e.get_duration_numeric(divisions, beats, beat_type, *cur_t_modification);
+        let remaining = (divisions * beats * 4 / beat_type).saturating_sub(*measure_duration_tally);
+        if e.occupies_time() && remaining > 0 && duration > remaining {
+            match (
+                exact_component(remaining, divisions, beats, beat_type, &e),
+                exact_component(duration - remaining, divisions, beats, beat_type, &e),
+            ) {
+                (Some(first), Some(second)) => Some((first, second)),
+                _ => {
+                    warn!(
+                        "M{} note of duration {} exceeds the {} remaining in the measure but cannot be split exactly",
+                        cur_measure_idx, duration, remaining
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        }
+    };
+
+    let mut ties = vec![];
+    match e.ties {
+        NoteConnection::EndTie => ties.push(TiedType::Stop),
+        NoteConnection::None => {}
+        NoteConnection::StartTie => ties.push(TiedType::Start),
+    }
+
+    match split {
+        None => {
+            ser_note_element(
+                part,
+                e,
+                m,
+                prev_voice,
+                measure_duration_tally,
+                cur_tuplet_info,
+                *cur_t_modification,
+                cur_beat,
+                cur_beat_type,
+                ties,
+                spelling,
+            );
+        }
+        Some(((first_type, first_dotted, first_t_mod), (second_type, second_dotted, second_t_mod))) => {
+            // The first component fills the measure and starts a tie, the second is carried over the barline
+            // to end it. Rests are split the same way, but never tied.
+            let mut first_ties = vec![];
+            let mut second_ties = vec![];
+            if !is_rest {
+                if e.ties == NoteConnection::EndTie {
+                    first_ties.push(TiedType::Stop);
+                }
+                first_ties.push(TiedType::Start);
+                second_ties.push(TiedType::Stop);
+                if e.ties == NoteConnection::StartTie {
+                    second_ties.push(TiedType::Start);
+                }
+            }
+            let first = NoteData {
+                note_type: first_type,
+                dotted: first_dotted,
+                slur: if e.slur == SlurConnection::EndSlur {
+                    SlurConnection::None
+                } else {
+                    e.slur
+                },
+                ..e
+            };
+            // The continuation is the same note, so it keeps its staff, size, notehead and tuning, but
+            // what is played or marked once at its onset is not repeated
+            let second = NoteData {
+                note_type: second_type,
+                dotted: second_dotted,
+                slur: if e.slur == SlurConnection::EndSlur {
+                    SlurConnection::EndSlur
+                } else {
+                    SlurConnection::None
+                },
+                phrase_dynamics: PhraseDynamics::None,
+                arpeggiate: Arpeggiate::NoArpeggiation,
+                articulation: Articulation::None,
+                trill: Trill::None,
+                ..e
+            };
+            ser_note_element(
+                part,
+                first,
+                m,
+                prev_voice,
+                measure_duration_tally,
+                cur_tuplet_info,
+                first_t_mod,
+                cur_beat,
+                cur_beat_type,
+                first_ties,
+                spelling,
+            );
+            tie_splitter.carried.push(CarriedNote {
+                target_measure_idx: cur_measure_idx + 1,
+                note: second,
+                t_modification: second_t_mod,
+                ties: second_ties,
+            });
+            tie_splitter.last_split = split;
+        }
+    }
+}
+
+fn ser_note_element(
+    part: &MusicalPart,
+    e: NoteData,
+    m: &mut Measure,
     prev_voice: &mut Option<Voice>,
     measure_duration_tally: &mut u32,
     cur_tuplet_info: &mut Option<TupletElement>,
-    cur_t_modification: &Option<TimeModificationElement>,
+    t_modification: Option<TimeModification>,
     cur_beat: Beats,
     cur_beat_type: BeatType,
+    ties: Vec<TiedType>,
+    spelling: AccidentalSpelling,
 ) {
     // Build the notations Vec here
     let mut notations = None;
@@ -184,7 +475,7 @@ fn ser_note_rest(
                         dynamics: Some(cur_dynamic),
                     }),
                 },
-                staff: get_staff(e.voice, part.get_num_voices()),
+                staff: part.get_note_staff(&e),
                 sound: None,
             }));
     }
@@ -203,64 +494,51 @@ fn ser_note_rest(
             *measure_duration_tally = 0;
         }
     }
-    // if cur_measure_idx == 69 {
-    //     println!("voice: {:?}", e.voice);
-    // }
-    // if cur_measure_idx == 40 {
-    //     println!("tally: {}", *measure_duration_tally);
-    // }
     if e.chord.eq(&Chord::NoChord) {
         let val = e.get_duration_numeric(
             part.get_initial_divisions().unwrap(),
             u32::from(cur_beat),
             u32::from(cur_beat_type),
-            cur_t_modification.as_ref().map(TimeModification::from),
+            t_modification,
         );
         //println!("curdur: {val}");
         *measure_duration_tally += val;
         //println!("mdt: {}", *measure_duration_tally);
     }
 
-    if e.arpeggiate.eq(&Arpeggiate::Arpeggiate) {
-        notations_elems.push(Notations::Arpeggiate);
-    }
-    match e.ties {
-        NoteConnection::EndTie => {
-            notations_elems.push(Notations::Tied(TiedElement {
-                r#type: TiedType::Stop,
-            }));
-        }
-        NoteConnection::None => {}
-        NoteConnection::StartTie => {
-            notations_elems.push(Notations::Tied(TiedElement {
-                r#type: TiedType::Start,
-            }));
-        }
-    }
-
-    if e.articulation.ne(&Articulation::None) {
-        //println!("Articulation: {}", e.articulation.to_string());
-        notations_elems.push(Notations::Articulations(ArticulationElement {
-            articulations: e.articulation.into(),
-        }))
+    // Notations are written in a fixed order, ties before slurs before articulations, so a note that
+    // ends a tie and starts a slur always serializes the same way
+    for tie in ties {
+        notations_elems.push(Notations::Tied(TiedElement { r#type: tie }));
     }
 
     match e.slur {
         SlurConnection::EndSlur => {
             notations_elems.push(Notations::Slur(SlurElement {
                 r#type: SlurType::Stop,
-                number: "1".to_string(),
+                number: e.slur_number.to_string(),
             }));
         }
         SlurConnection::None => {}
         SlurConnection::StartSlur => {
             notations_elems.push(Notations::Slur(SlurElement {
                 r#type: SlurType::Start,
-                number: "1".to_string(),
+                number: e.slur_number.to_string(),
             }));
         }
     }
 
+    if e.articulation.ne(&Articulation::None) {
+        //println!("Articulation: {}", e.articulation.to_string());
+        notations_elems.push(Notations::Articulations(ArticulationElement {
+            articulations: e.articulation.into(),
+        }))
+    }
+
+    if bool::from(e.arpeggiate) {
+        notations_elems.push(Notations::Arpeggiate);
+    }
+
     if !notations_elems.is_empty() {
         notations = Some(NotationsElement {
             notations: notations_elems,
@@ -268,12 +546,13 @@ fn ser_note_rest(
     }
     let note_element_wrap = NoteElementWrapper::create_wrap(
         e,
+        spelling,
         part.get_initial_divisions().unwrap(),
         cur_beat,
         cur_beat_type,
-        cur_t_modification.as_ref().cloned(),
+        t_modification,
         notations,
-        part.get_num_voices(),
+        part.get_note_staff(&e),
     );
     m.direction_note.push(MeasureDirectionNote::Note(
         note_element_wrap.inner().clone(),
@@ -285,7 +564,7 @@ fn ser_tuplet_data(
     t: TupletData,
     m: &mut Measure,
     cur_tuplet_info: &mut Option<TupletElement>,
-    cur_t_modification: &mut Option<TimeModificationElement>,
+    cur_t_modification: &mut Option<TimeModification>,
 ) {
     *cur_t_modification = t.into();
     if t.start_stop == TupletStartStop::TupletStop {
@@ -322,75 +601,652 @@ impl From<Articulation> for ArticulationValue {
     }
 }
 
-fn from_musical_part(t: &MusicalPart) -> Vec<Measure> {
+/// Serializes a chord symbol as a MusicXML <harmony> element
+fn harmony_fragment(h: HarmonyData) -> String {
+    let mut fragment = format!("<harmony><root><root-step>{}</root-step>", h.root_step.to_string());
+    if h.root_alter != HarmonyAlter::None {
+        fragment.push_str(&format!("<root-alter>{}</root-alter>", h.root_alter.to_string()));
+    }
+    fragment.push_str(&format!("</root><kind>{}</kind>", h.kind.to_string()));
+    if let Some((bass_step, bass_alter)) = h.bass {
+        fragment.push_str(&format!("<bass><bass-step>{}</bass-step>", bass_step.to_string()));
+        if bass_alter != HarmonyAlter::None {
+            fragment.push_str(&format!("<bass-alter>{}</bass-alter>", bass_alter.to_string()));
+        }
+        fragment.push_str("</bass>");
+    }
+    fragment.push_str("</harmony>");
+    fragment
+}
+
+/// Serializes a clef change as a MusicXML <attributes> element holding only the changed clef
+fn clef_change_fragment(c: ClefChangeData) -> String {
+    format!(
+        "<attributes><clef number=\"{}\"><sign>{}</sign><line>{}</line>{}</clef></attributes>",
+        c.staff as u8,
+        c.clef.sign(),
+        c.clef.line(),
+        ClefChangeData::octave_change_fragment(c.octave_change)
+    )
+}
+
+/// Remembers a fragment muxml has no element for, to be spliced into the serialized measure
+/// before the note that follows it
+fn ser_measure_fragment(fragment: String, m: &Measure, pending_fragments: &mut Vec<(usize, String)>) {
+    let note_idx = m
+        .direction_note
+        .iter()
+        .filter(|d| matches!(d, MeasureDirectionNote::Note(_)))
+        .count();
+    pending_fragments.push((note_idx, fragment));
+}
+
+/// Edits marking a note as cue sized, or as a silent cue note, which muxml has no fields for
+fn note_size_edits(note_size: NoteSize) -> Vec<PatchEdit> {
+    let cue_sized = PatchEdit::SetAttribute {
+        tag: "type",
+        name: "size",
+        value: "cue".to_string(),
+    };
+    match note_size {
+        NoteSize::Full => vec![],
+        NoteSize::CueSized => vec![cue_sized],
+        NoteSize::Cue => vec![
+            PatchEdit::InsertChild {
+                after: &["grace"],
+                fragment: "<cue/>".to_string(),
+            },
+            cue_sized,
+        ],
+    }
+}
+
+/// Edit writing a tremolo, which muxml has no ornaments for, into the notations of a measure's
+/// `note_idx`th note, or into new notations when muxml wrote none for it
+fn tremolo_edit(tremolo: Tremolo, m: &Measure, note_idx: usize) -> PatchEdit {
+    let has_notations = m
+        .direction_note
+        .iter()
+        .filter_map(|d| match d {
+            MeasureDirectionNote::Note(n) => Some(n),
+            _ => None,
+        })
+        .nth(note_idx)
+        .is_some_and(|n| n.notations.is_some());
+    if has_notations {
+        PatchEdit::InsertIntoDescendant {
+            tag: "notations",
+            fragment: tremolo.to_xml_fragment(),
+        }
+    } else {
+        PatchEdit::InsertChild {
+            after: &["voice", "type", "dot", "accidental", "time-modification", "stem", "staff"],
+            fragment: format!("<notations>{}</notations>", tremolo.to_xml_fragment()),
+        }
+    }
+}
+
+/// Edits hiding the bracket or number of a tuplet, which muxml has no fields for
+fn tuplet_visibility_edits(t: TupletData) -> Vec<PatchEdit> {
+    let mut edits = vec![];
+    if t.hide_bracket {
+        edits.push(PatchEdit::SetAttribute {
+            tag: "tuplet",
+            name: "bracket",
+            value: "no".to_string(),
+        });
+    }
+    if t.hide_number {
+        edits.push(PatchEdit::SetAttribute {
+            tag: "tuplet",
+            name: "show-number",
+            value: "none".to_string(),
+        });
+    }
+    edits
+}
+
+/// Serializes the accidental of a note as a cautionary one, matching the spelling of exported pitches
+fn cautionary_accidental_fragment(note_rest: NumericPitchRest, spelling: AccidentalSpelling) -> String {
+    let accidental = match (note_rest.get_numeric_value().saturating_sub(1) % 12, spelling) {
+        (1 | 3 | 6 | 8 | 10, AccidentalSpelling::Sharp) => "sharp",
+        (1 | 3 | 6 | 8 | 10, AccidentalSpelling::Flat) => "flat",
+        _ => "natural",
+    };
+    format!("<accidental parentheses=\"yes\">{}</accidental>", accidental)
+}
+
+/// A `<forward>` moving `voice` past `duration` divisions of the measure before its first note
+fn forward_fragment(duration: u32, voice: Voice, staff: String) -> String {
+    format!(
+        "<forward><duration>{}</duration><voice>{}</voice><staff>{}</staff></forward>",
+        duration,
+        voice as u8 + 1,
+        staff
+    )
+}
+
+/// Whether a note that is not a rest follows in `voice` before the end of the measure
+fn voice_sounds_later(elems: &[MusicElement], voice: Voice) -> bool {
+    elems
+        .iter()
+        .take_while(|e| {
+            !matches!(
+                e,
+                MusicElement::MeasureMeta(MeasureMetaData {
+                    start_end: MeasureStartEnd::MeasureEnd | MeasureStartEnd::RepeatEnd,
+                    ..
+                })
+            )
+        })
+        .any(|e| match e {
+            MusicElement::NoteRest(n) | MusicElement::Percussion(PercussionData { note: n, .. }) => {
+                n.voice == voice && n.note_rest != NumericPitchRest::Rest
+            }
+            _ => false,
+        })
+}
+
+fn direction_count(m: &Measure) -> usize {
+    m.direction_note
+        .iter()
+        .filter(|d| matches!(d, MeasureDirectionNote::Direction(_)))
+        .count()
+}
+
+/// Compares the duration written for one voice of a measure with the length of its time signature,
+/// so a corrupt part is caught before it is exported as MusicXML that notation programs cannot open.
+/// The first and last measures may be short, as pickups and the measures completing them are.
+fn check_voice_duration(
+    part_id: &str,
+    measure_idx: i32,
+    last_measure_idx: i32,
+    voice: Voice,
+    tally: u32,
+    expected: u32,
+    strict: bool,
+) -> Result<()> {
+    if tally == expected || (tally < expected && (measure_idx == 1 || measure_idx == last_measure_idx)) {
+        return Ok(());
+    }
+    let message = format!(
+        "measure {} voice {} lasts {} divisions instead of the {} of its time signature",
+        measure_idx,
+        voice as u8 + 1,
+        tally,
+        expected
+    );
+    if strict {
+        return Err(Error::StrictViolation(part_id.to_string(), message));
+    }
+    warn!("Part {} {}", part_id, message);
+    Ok(())
+}
+
+fn from_musical_part(
+    t: &MusicalPart,
+    part_id: &str,
+    patcher: &mut XmlPatcher,
+    strict: bool,
+) -> Result<Vec<Measure>> {
     // If the number of voices is 2, voice 1 goes to Treble Cleff, 2 to Bass Clef
     // If the number of voices is 4, voice 1-2 goes to Treble Cleff, 2-3 to Bass Clef
     // However, there will need to be additional heuristics for properly notating based on actual note octaves
     // in the future.
 
     if t.get_initial_divisions().is_none() || t.get_num_voices() == 0 {
-        return vec![];
+        return Ok(vec![]);
     }
+    let divisions = t.get_initial_divisions().unwrap();
+    let last_measure_idx = t
+        .inner()
+        .iter()
+        .filter(|e| {
+            matches!(
+                e,
+                MusicElement::MeasureMeta(MeasureMetaData {
+                    start_end: MeasureStartEnd::MeasureEnd | MeasureStartEnd::RepeatEnd,
+                    ..
+                })
+            )
+        })
+        .count() as i32;
     let mut measures: Vec<Measure> = vec![];
     let mut cur_measure = Measure::default(); // Measure element currently being serialized
     let mut cur_measure_idx = 1;
     let mut cur_tuplet_info: Option<TupletElement> = None;
-    let mut cur_t_modification: Option<TimeModificationElement> = None;
+    let mut cur_t_modification: Option<TimeModification> = None;
     let mut prev_voice = None;
     let mut measure_duration_tally = 0;
     let mut cur_beat = Beats::default();
     let mut cur_beat_type = BeatType::default();
+    let mut tie_splitter = TieSplitter::default();
+    // Chord symbols and clef changes of the current measure, and the index of the note each precedes
+    let mut pending_fragments: Vec<(usize, String)> = vec![];
+    // Clefs in effect, written again whenever the measure attributes are
+    let mut cur_clefs = t.get_clefs();
+    // Octave change of each clef in effect, which muxml has no field for
+    let mut cur_clef_octaves = vec![0; cur_clefs.len()];
+    let mut spelling = t.get_spelling().accidental_spelling(KeySignature::default());
+    // Edits for the notes of the current measure covering what muxml has no element for
+    let mut pending_note_edits: Vec<(usize, PatchEdit)> = vec![];
+    // Edits for the tuplet start written on the next note
+    let mut pending_tuplet_edits: Vec<PatchEdit> = vec![];
+    // Notes of the current measure and their index, for beaming. Measures with tuplets or notes
+    // split at a barline are left unbeamed, since their written durations differ from the notes'.
+    let mut measure_notes: Vec<(usize, NoteData)> = vec![];
+    let mut measure_beamable = true;
+    // Voice and duration of the rests a later voice of the measure starts with, written as a forward
+    // ahead of its first note instead
+    let mut pending_forward: Option<(Voice, u32)> = None;
 
-    for elem in t.inner() {
-        match *elem {
-            MusicElement::MeasureInit(e) => ser_measure_init(
-                t,
-                e,
-                &mut cur_measure,
-                cur_measure_idx,
-                &mut cur_beat,
-                &mut cur_beat_type,
-            ),
-            MusicElement::MeasureMeta(e) => ser_measure_meta(
-                e,
-                &mut cur_measure,
-                &mut cur_measure_idx,
-                &mut measures,
-                &mut prev_voice,
-                &mut measure_duration_tally,
-            ),
-            MusicElement::NoteRest(e) => ser_note_rest(
+    for (elem_idx, elem) in t.inner().iter().enumerate() {
+        let measure_length = divisions * u32::from(cur_beat) * 4 / u32::from(cur_beat_type);
+        if let MusicElement::MeasureMeta(MeasureMetaData {
+            start_end: MeasureStartEnd::MeasureEnd | MeasureStartEnd::RepeatEnd,
+            ..
+        }) = *elem
+        {
+            if let Some(voice) = prev_voice {
+                check_voice_duration(
+                    part_id,
+                    cur_measure_idx,
+                    last_measure_idx,
+                    voice,
+                    measure_duration_tally,
+                    measure_length,
+                    strict,
+                )?;
+            }
+            flush_carried(
                 t,
-                e,
                 &mut cur_measure,
                 cur_measure_idx,
                 &mut prev_voice,
                 &mut measure_duration_tally,
                 &mut cur_tuplet_info,
-                &cur_t_modification,
                 cur_beat,
                 cur_beat_type,
-            ),
-            MusicElement::Tuplet(t) => ser_tuplet_data(
-                t,
-                &mut cur_measure,
-                &mut cur_tuplet_info,
-                &mut cur_t_modification,
-            ),
+                &mut tie_splitter,
+                spelling,
+            );
+            for (note_idx, fragment) in pending_fragments.drain(..) {
+                patcher.push(
+                    PatchAnchor::Measure {
+                        part_id: part_id.to_string(),
+                        measure_idx: (cur_measure_idx - 1) as usize,
+                    },
+                    PatchEdit::InsertBeforeNth {
+                        tag: "note",
+                        nth: note_idx,
+                        fragment,
+                    },
+                );
+            }
+            let written_notes = cur_measure
+                .direction_note
+                .iter()
+                .filter(|d| matches!(d, MeasureDirectionNote::Note(_)))
+                .count();
+            if measure_beamable && written_notes == measure_notes.len() {
+                let notes: Vec<NoteData> = measure_notes.iter().map(|(_, n)| *n).collect();
+                for ((note_idx, _), beams) in measure_notes.iter().zip(beam_groups(&notes, cur_beat, cur_beat_type)) {
+                    if beams.is_beamed() {
+                        pending_note_edits.push((
+                            *note_idx,
+                            PatchEdit::InsertChild {
+                                after: &["voice", "type", "dot", "accidental", "time-modification", "stem", "staff"],
+                                fragment: beams.to_xml_fragment(),
+                            },
+                        ));
+                    }
+                }
+            }
+            // Pushed after the beams, which are inserted at the same place and precede notations
+            for (note_idx, n) in measure_notes.iter() {
+                if let Some(tremolo) = n.tremolo {
+                    pending_note_edits.push((*note_idx, tremolo_edit(tremolo, &cur_measure, *note_idx)));
+                }
+            }
+            measure_notes.clear();
+            measure_beamable = true;
+            for (note_idx, edit) in pending_note_edits.drain(..) {
+                patcher.push(
+                    PatchAnchor::Note {
+                        part_id: part_id.to_string(),
+                        measure_idx: (cur_measure_idx - 1) as usize,
+                        note_idx,
+                    },
+                    edit,
+                );
+            }
+        }
+        match *elem {
+            MusicElement::MeasureInit(e) => {
+                spelling = t.get_spelling().accidental_spelling(e.key_sig);
+                ser_measure_init(
+                    t,
+                    e,
+                    &mut cur_measure,
+                    cur_measure_idx,
+                    &mut cur_beat,
+                    &mut cur_beat_type,
+                    &cur_clefs,
+                );
+                for (idx, &octave_change) in cur_clef_octaves.iter().enumerate().filter(|(_, &o)| o != 0) {
+                    patcher.push(
+                        PatchAnchor::Clef {
+                            part_id: part_id.to_string(),
+                            measure_idx: (cur_measure_idx - 1) as usize,
+                            number: idx as u8 + 1,
+                        },
+                        PatchEdit::InsertChild {
+                            after: &["sign", "line"],
+                            fragment: ClefChangeData::octave_change_fragment(octave_change),
+                        },
+                    );
+                }
+            }
+            MusicElement::MeasureMeta(e) => {
+                // muxml has no field for the times attribute of a repeat
+                if let (MeasureStartEnd::RepeatEnd, Some(times)) = (e.start_end, e.repeat_times) {
+                    patcher.push(
+                        PatchAnchor::Barline {
+                            part_id: part_id.to_string(),
+                            measure_idx: (cur_measure_idx - 1) as usize,
+                            location: "right",
+                        },
+                        PatchEdit::SetAttribute {
+                            tag: "repeat",
+                            name: "times",
+                            value: times.to_string(),
+                        },
+                    );
+                }
+                let bar_style_fragment = format!("<bar-style>{}</bar-style>", e.bar_style.xml_value());
+                match (e.start_end, e.bar_style) {
+                    (_, BarStyle::Regular) | (MeasureStartEnd::MeasureStart | MeasureStartEnd::RepeatStart, _) => {}
+                    (MeasureStartEnd::MeasureEnd, _) => patcher.push(
+                        PatchAnchor::Barline {
+                            part_id: part_id.to_string(),
+                            measure_idx: (cur_measure_idx - 1) as usize,
+                            location: "right",
+                        },
+                        PatchEdit::FillDescendant {
+                            tag: "barline",
+                            fragment: bar_style_fragment,
+                        },
+                    ),
+                    // The bar style comes before the repeat
+                    (MeasureStartEnd::RepeatEnd, _) => patcher.push(
+                        PatchAnchor::Barline {
+                            part_id: part_id.to_string(),
+                            measure_idx: (cur_measure_idx - 1) as usize,
+                            location: "right",
+                        },
+                        PatchEdit::InsertChild {
+                            after: &[],
+                            fragment: bar_style_fragment,
+                        },
+                    ),
+                }
+                ser_measure_meta(
+                    e,
+                    &mut cur_measure,
+                    &mut cur_measure_idx,
+                    &mut measures,
+                    &mut prev_voice,
+                    &mut measure_duration_tally,
+                )
+            }
+            // Unpitched notes are written at their staff position
+            MusicElement::NoteRest(e) | MusicElement::Percussion(PercussionData { note: e, .. }) => {
+                // Rests leading a voice that does not start the measure are placeholders, so the voice is
+                // moved forward to its first note instead of writing them
+                if prev_voice.is_some_and(|v| v != e.voice)
+                    && e.note_rest == NumericPitchRest::Rest
+                    && e.chord == Chord::NoChord
+                    && e.phrase_dynamics == PhraseDynamics::None
+                    && cur_t_modification.is_none()
+                    && !tie_splitter.carried.iter().any(|c| c.note.voice == e.voice)
+                    && voice_sounds_later(&t.inner()[elem_idx + 1..], e.voice)
+                {
+                    let duration =
+                        e.get_duration_numeric(divisions, u32::from(cur_beat), u32::from(cur_beat_type), None);
+                    let skipped = pending_forward.map_or(0, |(_, skipped)| skipped);
+                    pending_forward = Some((e.voice, skipped + duration));
+                    continue;
+                }
+                // The tally restarts when the voice changes, so it holds the whole of the previous voice
+                if let Some(voice) = prev_voice.filter(|v| *v != e.voice) {
+                    check_voice_duration(
+                        part_id,
+                        cur_measure_idx,
+                        last_measure_idx,
+                        voice,
+                        measure_duration_tally,
+                        measure_length,
+                        strict,
+                    )?;
+                }
+                let directions_before = direction_count(&cur_measure);
+                ser_note_rest(
+                    t,
+                    e,
+                    &mut cur_measure,
+                    cur_measure_idx,
+                    &mut prev_voice,
+                    &mut measure_duration_tally,
+                    &mut cur_tuplet_info,
+                    &cur_t_modification,
+                    cur_beat,
+                    cur_beat_type,
+                    &mut tie_splitter,
+                    spelling,
+                );
+                // Only dynamics muxml has a value for are written as a direction
+                let directions = direction_count(&cur_measure);
+                if let (true, Some(placement)) =
+                    (directions > directions_before, e.dynamics_placement.attribute_value())
+                {
+                    patcher.push(
+                        PatchAnchor::Direction {
+                            part_id: part_id.to_string(),
+                            measure_idx: (cur_measure_idx - 1) as usize,
+                            direction_idx: directions - 1,
+                        },
+                        PatchEdit::SetAttribute {
+                            tag: "direction",
+                            name: "placement",
+                            value: placement.to_string(),
+                        },
+                    );
+                }
+                // Notes carried over the barline are written before this note, so it is the last one
+                let note_idx = cur_measure
+                    .direction_note
+                    .iter()
+                    .filter(|d| matches!(d, MeasureDirectionNote::Note(_)))
+                    .count()
+                    - 1;
+                if let Some((voice, duration)) = pending_forward.take() {
+                    // Written after the backup ser_note_rest added ahead of the note
+                    patcher.push(
+                        PatchAnchor::Measure {
+                            part_id: part_id.to_string(),
+                            measure_idx: (cur_measure_idx - 1) as usize,
+                        },
+                        PatchEdit::InsertBeforeNth {
+                            tag: "note",
+                            nth: note_idx,
+                            fragment: forward_fragment(duration, voice, t.get_note_staff(&e)),
+                        },
+                    );
+                    measure_duration_tally += duration;
+                }
+                measure_notes.push((note_idx, e));
+                if cur_t_modification.is_some() || (e.chord == Chord::NoChord && tie_splitter.last_split.is_some()) {
+                    measure_beamable = false;
+                }
+                // Some muxml versions write the articulations of a note as an empty <articulations>
+                if e.articulation != Articulation::None {
+                    pending_note_edits.push((
+                        note_idx,
+                        PatchEdit::FillDescendant {
+                            tag: "articulations",
+                            fragment: format!("<{}/>", e.articulation.to_string()),
+                        },
+                    ));
+                }
+                // A semibreve rest lasts the whole measure, which its type alone only says in measures four
+                // crochets long
+                if e.note_rest == NumericPitchRest::Rest
+                    && e.note_type == RhythmType::SemiBreve
+                    && u32::from(cur_beat) != u32::from(cur_beat_type)
+                {
+                    pending_note_edits.push((
+                        note_idx,
+                        PatchEdit::SetAttribute {
+                            tag: "rest",
+                            name: "measure",
+                            value: "yes".to_string(),
+                        },
+                    ));
+                }
+                if e.cautionary_accidental && e.note_rest != NumericPitchRest::Rest {
+                    pending_note_edits.push((
+                        note_idx,
+                        PatchEdit::InsertChild {
+                            after: &["type", "dot"],
+                            fragment: cautionary_accidental_fragment(e.note_rest, spelling),
+                        },
+                    ));
+                }
+                if e.notehead != Notehead::Normal {
+                    pending_note_edits.push((
+                        note_idx,
+                        PatchEdit::InsertChild {
+                            after: &["voice", "type", "dot", "accidental", "time-modification", "stem"],
+                            fragment: format!("<notehead>{}</notehead>", e.notehead.to_string()),
+                        },
+                    ));
+                }
+                // muxml has no field for the normal type, which a note split at the barline does not keep
+                if let (Some(normal_type), None) = (
+                    cur_t_modification.and_then(|t| t.get_normal_type()),
+                    tie_splitter.last_split,
+                ) {
+                    pending_note_edits.push((
+                        note_idx,
+                        PatchEdit::InsertIntoDescendant {
+                            tag: "time-modification",
+                            fragment: format!("<normal-type>{}</normal-type>", normal_type.get_type_string()),
+                        },
+                    ));
+                }
+                for edit in note_size_edits(e.note_size) {
+                    pending_note_edits.push((note_idx, edit));
+                }
+                for edit in pending_tuplet_edits.drain(..) {
+                    pending_note_edits.push((note_idx, edit));
+                }
+                if let Some(direction) = e.arpeggiate.direction() {
+                    pending_note_edits.push((
+                        note_idx,
+                        PatchEdit::SetAttribute {
+                            tag: "arpeggiate",
+                            name: "direction",
+                            value: direction.to_string(),
+                        },
+                    ));
+                }
+            }
+            MusicElement::Tuplet(t) => {
+                if t.start_stop == TupletStartStop::TupletStart {
+                    pending_tuplet_edits = tuplet_visibility_edits(t);
+                }
+                ser_tuplet_data(
+                    t,
+                    &mut cur_measure,
+                    &mut cur_tuplet_info,
+                    &mut cur_t_modification,
+                )
+            }
+            MusicElement::Harmony(h) => {
+                ser_measure_fragment(harmony_fragment(h), &cur_measure, &mut pending_fragments)
+            }
+            MusicElement::ClefChange(c) => {
+                if let Some(clef) = cur_clefs.get_mut(c.staff as usize - 1) {
+                    *clef = c.clef;
+                    cur_clef_octaves[c.staff as usize - 1] = c.octave_change;
+                }
+                ser_measure_fragment(clef_change_fragment(c), &cur_measure, &mut pending_fragments)
+            }
         }
     }
-    measures
+    // Remainders carried over the last barline are written in a measure of their own
+    if !tie_splitter.carried.is_empty() {
+        cur_measure.number = cur_measure_idx.to_string();
+        prev_voice = None;
+        measure_duration_tally = 0;
+        flush_carried(
+            t,
+            &mut cur_measure,
+            cur_measure_idx,
+            &mut prev_voice,
+            &mut measure_duration_tally,
+            &mut cur_tuplet_info,
+            cur_beat,
+            cur_beat_type,
+            &mut tie_splitter,
+            spelling,
+        );
+        measures.push(cur_measure);
+    }
+    for (&measure_idx, measure_number) in t.get_measure_numbers() {
+        if let Some(m) = measures.get_mut(measure_idx) {
+            m.number = measure_number.number.clone();
+            // muxml has no field for the implicit attribute of a pickup measure
+            if measure_number.implicit {
+                patcher.push(
+                    PatchAnchor::Measure {
+                        part_id: part_id.to_string(),
+                        measure_idx,
+                    },
+                    PatchEdit::SetAttribute {
+                        tag: "measure",
+                        name: "implicit",
+                        value: "yes".to_string(),
+                    },
+                );
+            }
+        }
+    }
+    Ok(measures)
+}
+
+/// Converts a part into muxml measures, collecting patches for the content muxml cannot represent.
+/// With `strict` set, a measure whose voices do not add up to its time signature is an error
+/// instead of a warning.
+pub fn measures_from_musical_part(
+    t: &MusicalPart,
+    part_id: &str,
+    patcher: &mut XmlPatcher,
+    strict: bool,
+) -> Result<Vec<Measure>> {
+    from_musical_part(t, part_id, patcher, strict)
 }
 
+// Only strict conversion fails, so these never lose measures
 impl From<&MusicalPart> for Vec<Measure> {
     fn from(t: &MusicalPart) -> Self {
-        from_musical_part(t)
+        from_musical_part(t, t.get_part_str(), &mut XmlPatcher::new(), false).unwrap_or_default()
     }
 }
 
 impl From<MusicalPart> for Vec<Measure> {
     fn from(t: MusicalPart) -> Self {
-        from_musical_part(&t)
+        from_musical_part(&t, t.get_part_str(), &mut XmlPatcher::new(), false).unwrap_or_default()
     }
 }
 
@@ -407,31 +1263,615 @@ impl From<TupletData> for Option<TupletElement> {
     }
 }
 
-impl From<TupletData> for Option<TimeModificationElement> {
-    fn from(t: TupletData) -> Self {
-        match t.start_stop {
-            TupletStartStop::TupletStart => Some(TimeModificationElement {
-                actual_notes: t.actual_notes.into(),
-                normal_notes: t.normal_notes.into(),
-            }),
-            TupletStartStop::None => None,
-            TupletStartStop::TupletStop => None,
+/// Pushes a `<print>` patch starting each measure with a layout break kept from the source score
+fn push_layout_breaks(parts: &PartMap, patcher: &mut XmlPatcher) {
+    for (part_id, opt_idx) in parts.get_part_ids() {
+        if let Some(part) = opt_idx.and_then(|idx| parts.get_part(idx)) {
+            for (&measure_idx, layout_break) in part.get_layout_breaks() {
+                patcher.push(
+                    PatchAnchor::Measure {
+                        part_id: part_id.clone(),
+                        measure_idx,
+                    },
+                    PatchEdit::InsertChild {
+                        after: &[],
+                        fragment: layout_break.print_fragment(),
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Pushes a `<print new-system="yes"/>` patch at the start of every `measures_per_system`th measure,
+/// except measures already starting with a kept layout break
+fn push_system_breaks(parts: &PartMap, measures_per_system: usize, patcher: &mut XmlPatcher) {
+    if measures_per_system == 0 {
+        return;
+    }
+    for (part_id, opt_idx) in parts.get_part_ids() {
+        if let Some(part) = opt_idx.and_then(|idx| parts.get_part(idx)) {
+            for measure_idx in (measures_per_system..part.num_measures())
+                .step_by(measures_per_system)
+                .filter(|idx| !part.get_layout_breaks().contains_key(idx))
+            {
+                patcher.push(
+                    PatchAnchor::Measure {
+                        part_id: part_id.clone(),
+                        measure_idx,
+                    },
+                    PatchEdit::InsertChild {
+                        after: &[],
+                        fragment: "<print new-system=\"yes\"/>".to_string(),
+                    },
+                );
+            }
         }
     }
 }
 
-pub fn ir_to_xml(parts: PartMap) -> String {
-    let complete_parts: CompleteParts = parts
-        .try_into()
-        .expect("Failed to convert PartMap into CompleteParts");
+pub fn ir_to_xml(parts: PartMap, options: &ExportOptions) -> Result<String> {
+    let (xml, patcher) = encode_score(parts, options)?;
+    patcher.apply(&xml)
+}
+
+/// Serializes the parts with muxml, returning the document along with the edits for the features
+/// muxml has no field for, still to be applied
+fn encode_score(mut parts: PartMap, options: &ExportOptions) -> Result<(String, XmlPatcher)> {
+    let mut patcher = XmlPatcher::new();
+    if let Some(clefs) = &options.clefs {
+        parts.set_clefs(clefs);
+    }
+    parts.set_spelling(options.spelling);
+    if let Some(divisions) = options.output_divisions {
+        parts.set_output_divisions(divisions)?;
+    }
+    push_layout_breaks(&parts, &mut patcher);
+    if let Some(measures_per_system) = options.measures_per_system {
+        push_system_breaks(&parts, measures_per_system, &mut patcher);
+    }
+    let complete_parts: CompleteParts = parts.to_complete_parts(&mut patcher, options.strict, options.parallel)?;
+    if options.encoding_date.is_none() {
+        patcher.push(PatchAnchor::Encoding, PatchEdit::RemoveDescendant { tag: "encoding-date" });
+    }
 
     let score = ScoreBuilder::new()
         .work_title("Untitled".to_string())
         .composer("Untitled".to_string())
         .software("muxml rust crate".to_string())
-        .encoding_date("2023-11-22".to_string())
+        .encoding_date(options.encoding_date.clone().unwrap_or_default())
         .complete_parts(complete_parts)
         .build();
 
-    encode_muxml(score)
+    Ok((encode_muxml(score), patcher))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_score, ir_to_xml, push_system_breaks};
+    use crate::ir::notation::{
+        Clef, MeasureInitializer, MeasureMetaData, MeasureStartEnd, MusicElement, NoteData,
+        NumericPitchRest, RhythmType, Voice,
+    };
+    use muxml::muxml_types::{Measure, MeasureDirectionNote};
+    use crate::ir::xml_patch::XmlPatcher;
+    use crate::ir::{xml_to_ir, ExportOptions, MusicalPart, ParseOptions, PartMap};
+    use std::fs;
+
+    #[test]
+    fn test_new_system_every_fourth_measure() {
+        let mut elems = vec![MusicElement::MeasureInit(MeasureInitializer::default())];
+        for _ in 0..10 {
+            elems.push(MusicElement::MeasureMeta(MeasureMetaData::new(
+                MeasureStartEnd::MeasureStart,
+            )));
+            elems.push(MusicElement::NoteRest(NoteData {
+                note_rest: NumericPitchRest::new_from_numeric(40),
+                note_type: RhythmType::SemiBreve,
+                ..NoteData::default()
+            }));
+            elems.push(MusicElement::MeasureMeta(MeasureMetaData::new(
+                MeasureStartEnd::MeasureEnd,
+            )));
+        }
+        let mut parts = PartMap::new();
+        parts
+            .push_part("P1", MusicalPart::new_from_elems("P1", elems).unwrap())
+            .unwrap();
+
+        let mut patcher = XmlPatcher::new();
+        push_system_breaks(&parts, 4, &mut patcher);
+
+        let measures: String = (1..=10)
+            .map(|n| format!("<measure number=\"{}\"><note/></measure>", n))
+            .collect();
+        let xml = format!("<score-partwise><part id=\"P1\">{}</part></score-partwise>", measures);
+        let patched = patcher.apply(&xml).unwrap();
+
+        let doc = roxmltree::Document::parse(&patched).unwrap();
+        for (idx, measure) in doc.descendants().filter(|n| n.has_tag_name("measure")).enumerate() {
+            let first = measure.first_element_child().unwrap();
+            let has_print = first.has_tag_name("print") && first.attribute("new-system") == Some("yes");
+            assert_eq!(has_print, idx > 0 && idx % 4 == 0, "measure {}", idx + 1);
+        }
+    }
+
+    #[test]
+    fn test_single_staff_treble_part() {
+        let mut elems = vec![
+            MusicElement::MeasureInit(MeasureInitializer::default()),
+            MusicElement::MeasureMeta(MeasureMetaData::new(MeasureStartEnd::MeasureStart)),
+        ];
+        // Two voices would be split over a treble and bass staff on a piano part
+        for voice in [Voice::One, Voice::Two] {
+            elems.push(MusicElement::NoteRest(NoteData {
+                note_rest: NumericPitchRest::new_from_numeric(60),
+                note_type: RhythmType::SemiBreve,
+                voice,
+                ..NoteData::default()
+            }));
+        }
+        elems.push(MusicElement::MeasureMeta(MeasureMetaData::new(
+            MeasureStartEnd::MeasureEnd,
+        )));
+        let mut part = MusicalPart::new_from_elems("P1", elems).unwrap();
+        part.set_clefs(Some(vec![Clef::Treble]));
+
+        let measures = Vec::<Measure>::from(&part);
+        let attributes = measures[0].attributes.as_ref().unwrap();
+        assert_eq!(attributes.staves, "1");
+        assert_eq!(attributes.clef.len(), 1);
+        assert_eq!(attributes.clef[0].sign, "G");
+        for note in measures[0].direction_note.iter().filter_map(|d| match d {
+            MeasureDirectionNote::Note(n) => Some(n),
+            _ => None,
+        }) {
+            assert_eq!(note.staff, "1");
+        }
+    }
+
+    #[test]
+    fn test_parts_without_measures_are_left_out() {
+        let part_ids = |xml: &str, tag: &str| -> Vec<String> {
+            let doc = roxmltree::Document::parse(xml).unwrap();
+            doc.descendants()
+                .filter(|n| n.has_tag_name(tag))
+                .map(|n| n.attribute("id").unwrap().to_string())
+                .collect()
+        };
+
+        // The drum part is dropped while parsing
+        let docstring = fs::read_to_string("test/unpitched_part.musicxml").unwrap();
+        let parts = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        let xml = ir_to_xml(parts, &ExportOptions::default()).unwrap();
+        assert_eq!(part_ids(&xml, "score-part"), vec!["P1"]);
+        assert_eq!(part_ids(&xml, "part"), vec!["P1"]);
+
+        // A part that was kept but holds no notes has no measures to write
+        let docstring = fs::read_to_string("test/two_parts.musicxml").unwrap();
+        let mut parts = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        parts.push_part("P3", MusicalPart::new("P3")).unwrap();
+        let xml = ir_to_xml(parts, &ExportOptions::default()).unwrap();
+        assert_eq!(part_ids(&xml, "score-part"), vec!["P1", "P2"]);
+        assert_eq!(part_ids(&xml, "part"), vec!["P1", "P2"]);
+    }
+
+    #[test]
+    fn test_output_divisions() {
+        use crate::ir::PartBuilder;
+        let parts = || {
+            let part = PartBuilder::new()
+                .note(NumericPitchRest::Pitch(40), RhythmType::SemiQuaver, Voice::One)
+                .note(NumericPitchRest::Pitch(42), RhythmType::SemiQuaver, Voice::One)
+                .note(NumericPitchRest::Pitch(44), RhythmType::Quaver, Voice::One)
+                .rest(RhythmType::Crochet, Voice::One)
+                .rest(RhythmType::Minim, Voice::One)
+                .build("P1")
+                .unwrap();
+            let mut parts = PartMap::new();
+            parts.push_part("P1", part).unwrap();
+            parts
+        };
+        let options = ExportOptions {
+            output_divisions: Some(480),
+            ..ExportOptions::default()
+        };
+        let xml = ir_to_xml(parts(), &options).unwrap();
+        let doc = roxmltree::Document::parse(&xml).unwrap();
+        let divisions = doc.descendants().find(|n| n.has_tag_name("divisions")).unwrap();
+        assert_eq!(divisions.text(), Some("480"));
+        let first_note = doc.descendants().find(|n| n.has_tag_name("note")).unwrap();
+        let duration = first_note.children().find(|n| n.has_tag_name("duration")).unwrap();
+        assert_eq!(duration.text(), Some("120"));
+
+        // A sixteenth is not a whole number of divisions when a quarter note has 2
+        let options = ExportOptions {
+            output_divisions: Some(2),
+            ..ExportOptions::default()
+        };
+        assert!(ir_to_xml(parts(), &options).is_err());
+    }
+
+    #[test]
+    fn test_each_articulation_writes_its_own_element() {
+        use crate::ir::notation::Articulation;
+        use crate::ir::PartBuilder;
+        for (articulation, tag) in [
+            (Articulation::Accent, "accent"),
+            (Articulation::StrongAccent, "strong-accent"),
+            (Articulation::Staccato, "staccato"),
+            (Articulation::Staccatissimo, "staccatissimo"),
+            (Articulation::Tenuto, "tenuto"),
+            (Articulation::DetachedLegato, "detached-legato"),
+            (Articulation::Stress, "stress"),
+        ] {
+            let part = PartBuilder::new()
+                .note_data(NoteData {
+                    note_rest: NumericPitchRest::Pitch(40),
+                    note_type: RhythmType::SemiBreve,
+                    articulation,
+                    ..NoteData::default()
+                })
+                .build("P1")
+                .unwrap();
+            let mut parts = PartMap::new();
+            parts.push_part("P1", part).unwrap();
+            let xml = ir_to_xml(parts, &ExportOptions::default()).unwrap();
+
+            let doc = roxmltree::Document::parse(&xml).unwrap();
+            let articulations = doc.descendants().find(|n| n.has_tag_name("articulations")).unwrap();
+            let children: Vec<&str> = articulations
+                .children()
+                .filter(|c| c.is_element())
+                .map(|c| c.tag_name().name())
+                .collect();
+            assert_eq!(children, vec![tag], "{}", xml);
+        }
+    }
+
+    #[test]
+    fn test_muxml_articulations_output_the_patch_relies_on() {
+        use crate::ir::notation::Articulation;
+        use crate::ir::PartBuilder;
+        // The articulation patch fills an empty <articulations> and leaves one with children alone, so
+        // muxml must write the element, and any child it writes must be the articulation itself
+        for (articulation, tag) in [
+            (Articulation::Accent, "accent"),
+            (Articulation::StrongAccent, "strong-accent"),
+            (Articulation::Staccato, "staccato"),
+            (Articulation::Staccatissimo, "staccatissimo"),
+            (Articulation::Tenuto, "tenuto"),
+            (Articulation::DetachedLegato, "detached-legato"),
+            (Articulation::Stress, "stress"),
+        ] {
+            let part = PartBuilder::new()
+                .note_data(NoteData {
+                    note_rest: NumericPitchRest::Pitch(40),
+                    note_type: RhythmType::SemiBreve,
+                    articulation,
+                    ..NoteData::default()
+                })
+                .build("P1")
+                .unwrap();
+            let mut parts = PartMap::new();
+            parts.push_part("P1", part).unwrap();
+            let (xml, _) = encode_score(parts, &ExportOptions::default()).unwrap();
+
+            let doc = roxmltree::Document::parse(&xml).unwrap();
+            let articulations = doc
+                .descendants()
+                .filter(|n| n.has_tag_name("notations"))
+                .flat_map(|n| n.children())
+                .filter(|n| n.has_tag_name("articulations"))
+                .collect::<Vec<_>>();
+            assert_eq!(articulations.len(), 1, "{}", xml);
+            let children: Vec<&str> = articulations[0]
+                .children()
+                .filter(|c| c.is_element())
+                .map(|c| c.tag_name().name())
+                .collect();
+            assert!(children.is_empty() || children == vec![tag], "{}", xml);
+        }
+    }
+
+    #[test]
+    fn test_tie_stop_and_slur_start_notation_order() {
+        use crate::ir::notation::{Articulation, NoteConnection, SlurConnection};
+        use crate::ir::PartBuilder;
+        let note = |pitch, note_type| NoteData {
+            note_rest: NumericPitchRest::Pitch(pitch),
+            note_type,
+            ..NoteData::default()
+        };
+        let part = PartBuilder::new()
+            .note_data(NoteData {
+                ties: NoteConnection::StartTie,
+                ..note(40, RhythmType::Minim)
+            })
+            .note_data(NoteData {
+                ties: NoteConnection::EndTie,
+                slur: SlurConnection::StartSlur,
+                articulation: Articulation::Staccato,
+                ..note(40, RhythmType::Minim)
+            })
+            .measure_with(MeasureInitializer::default())
+            .note_data(NoteData {
+                slur: SlurConnection::EndSlur,
+                ..note(42, RhythmType::SemiBreve)
+            })
+            .build("P1")
+            .unwrap();
+        let mut parts = PartMap::new();
+        parts.push_part("P1", part).unwrap();
+        let xml = ir_to_xml(parts, &ExportOptions::default()).unwrap();
+
+        let doc = roxmltree::Document::parse(&xml).unwrap();
+        let notations = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("note"))
+            .nth(1)
+            .and_then(|n| n.children().find(|c| c.has_tag_name("notations")))
+            .unwrap();
+        let order: Vec<&str> = notations
+            .children()
+            .filter(|c| c.is_element())
+            .map(|c| c.tag_name().name())
+            .collect();
+        assert_eq!(order, vec!["tied", "slur", "articulations"]);
+    }
+
+    #[test]
+    fn test_whole_note_with_two_beats_left_is_tied_over_the_barline() {
+        use crate::ir::PartBuilder;
+        let note = |pitch, note_type| NoteData {
+            note_rest: NumericPitchRest::Pitch(pitch),
+            note_type,
+            ..NoteData::default()
+        };
+        // Notes as (step, type, rest, tie types) of each measure
+        let written = |part: MusicalPart| {
+            let mut parts = PartMap::new();
+            parts.push_part("P1", part).unwrap();
+            let xml = ir_to_xml(parts, &ExportOptions::default()).unwrap();
+            let doc = roxmltree::Document::parse(&xml).unwrap();
+            doc.descendants()
+                .filter(|n| n.has_tag_name("measure"))
+                .map(|m| {
+                    m.children()
+                        .filter(|n| n.has_tag_name("note"))
+                        .map(|n| {
+                            let text = |tag: &str| n.descendants().find(|c| c.has_tag_name(tag)).and_then(|c| c.text());
+                            let ties: Vec<&str> = n
+                                .descendants()
+                                .filter(|c| c.has_tag_name("tied"))
+                                .filter_map(|c| c.attribute("type"))
+                                .collect();
+                            (
+                                text("step").map(str::to_string),
+                                text("type").unwrap().to_string(),
+                                n.children().any(|c| c.has_tag_name("rest")),
+                                ties.join(","),
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        let c4 = || Some("C".to_string());
+        let d4 = || Some("D".to_string());
+        let half = |step: Option<String>, ties: &str| (step, "half".to_string(), false, ties.to_string());
+        let half_rest = || (None, "half".to_string(), true, String::new());
+
+        // The remainder starts the voice in the following measure
+        let part = PartBuilder::new()
+            .note_data(note(40, RhythmType::Minim))
+            .note_data(note(42, RhythmType::SemiBreve))
+            .measure_with(MeasureInitializer::default())
+            .note_data(note(40, RhythmType::Minim))
+            .build("P1")
+            .unwrap();
+        assert_eq!(
+            written(part),
+            vec![
+                vec![half(c4(), ""), half(d4(), "start")],
+                vec![half(d4(), "stop"), half(c4(), "")]
+            ]
+        );
+
+        // With no measure left, the remainder gets a measure of its own completed by a rest
+        let part = PartBuilder::new()
+            .note_data(note(40, RhythmType::Minim))
+            .note_data(note(42, RhythmType::SemiBreve))
+            .build("P1")
+            .unwrap();
+        assert_eq!(
+            written(part),
+            vec![vec![half(c4(), ""), half(d4(), "start")], vec![half(d4(), "stop"), half_rest()]]
+        );
+    }
+
+    #[test]
+    fn test_tied_continuation_keeps_staff_and_size() {
+        use crate::ir::notation::{NoteConnection, NoteSize, Staff};
+        use crate::ir::PartBuilder;
+        let note = NoteData {
+            note_rest: NumericPitchRest::Pitch(42),
+            note_type: RhythmType::SemiBreve,
+            staff: Some(Staff::BassClef),
+            note_size: NoteSize::CueSized,
+            ..NoteData::default()
+        };
+        let part = PartBuilder::new()
+            .note_data(NoteData {
+                note_type: RhythmType::Minim,
+                ..note
+            })
+            .note_data(note)
+            .build("P1")
+            .unwrap();
+        let mut parts = PartMap::new();
+        parts.push_part("P1", part).unwrap();
+        let xml = ir_to_xml(parts, &ExportOptions::default()).unwrap();
+
+        let parts = xml_to_ir(xml, false, &ParseOptions::default()).unwrap();
+        let notes: Vec<NoteData> = parts
+            .get_part(0)
+            .unwrap()
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) if n.note_rest != NumericPitchRest::Rest => Some(*n),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(notes.len(), 3);
+        // The whole note overruns the barline and is written as two halves tied across it
+        assert_eq!(notes[1].ties, NoteConnection::StartTie);
+        assert_eq!(notes[2].ties, NoteConnection::EndTie);
+        for n in notes.iter() {
+            assert_eq!((n.note_type, n.staff, n.note_size), (RhythmType::Minim, Some(Staff::BassClef), NoteSize::CueSized));
+        }
+    }
+
+    #[test]
+    fn test_dynamics_placement_round_trip() {
+        use crate::ir::notation::{PhraseDynamics, Placement};
+        let placements = |parts: &PartMap| -> Vec<Placement> {
+            parts
+                .get_part(0)
+                .unwrap()
+                .inner()
+                .iter()
+                .filter_map(|e| match e {
+                    MusicElement::NoteRest(n) if n.phrase_dynamics != PhraseDynamics::None => Some(n.dynamics_placement),
+                    _ => None,
+                })
+                .collect()
+        };
+        let docstring = fs::read_to_string("test/dynamics_placement.musicxml").unwrap();
+        let parts = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        assert_eq!(placements(&parts), vec![Placement::Below, Placement::Default]);
+
+        let xml = ir_to_xml(parts, &ExportOptions::default()).unwrap();
+        let doc = roxmltree::Document::parse(&xml).unwrap();
+        // The tempo words keep the default placement
+        let written: Vec<(bool, Option<&str>)> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("direction"))
+            .map(|n| (n.descendants().any(|c| c.has_tag_name("dynamics")), n.attribute("placement")))
+            .collect();
+        assert_eq!(written, vec![(false, None), (true, Some("below")), (true, None)]);
+
+        let parts = xml_to_ir(xml, false, &ParseOptions::default()).unwrap();
+        assert_eq!(placements(&parts), vec![Placement::Below, Placement::Default]);
+    }
+
+    #[test]
+    fn test_short_measure_fails_strict_export() {
+        use crate::error::Error;
+        // The second of three 4/4 measures holds only three quarter notes
+        let docstring = fs::read_to_string("test/short_measure.musicxml").unwrap();
+        let parts = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+
+        let xml = ir_to_xml(parts.clone(), &ExportOptions::default()).unwrap();
+        let doc = roxmltree::Document::parse(&xml).unwrap();
+        assert_eq!(doc.descendants().filter(|n| n.has_tag_name("measure")).count(), 3);
+
+        let options = ExportOptions {
+            strict: true,
+            ..ExportOptions::default()
+        };
+        match ir_to_xml(parts, &options) {
+            Err(Error::StrictViolation(part_id, message)) => {
+                assert_eq!(part_id, "P1");
+                assert!(message.starts_with("measure 2 voice 1 lasts"), "{}", message);
+            }
+            result => panic!("expected a strict violation, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_parallel_export_matches_serial_export() {
+        let docstring = fs::read_to_string("test/multipart_pathetique.musicxml").unwrap();
+        let parts = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        assert!(parts.num_parts() > 1);
+
+        let serial = ir_to_xml(parts.clone(), &ExportOptions::default()).unwrap();
+        let options = ExportOptions {
+            parallel: true,
+            ..ExportOptions::default()
+        };
+        for _ in 0..4 {
+            assert_eq!(ir_to_xml(parts.clone(), &options).unwrap(), serial);
+        }
+    }
+
+    /// Prints how much faster `parallel` exports the four parts of the Pathetique. Timing depends on the
+    /// machine, so it only runs on request:
+    /// `cargo test --release measure_parallel_export_speedup -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn measure_parallel_export_speedup() {
+        use std::time::{Duration, Instant};
+        let docstring = fs::read_to_string("test/multipart_pathetique.musicxml").unwrap();
+        let parts = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        assert_eq!(parts.num_parts(), 4);
+
+        const RUNS: u32 = 20;
+        let time_export = |parallel: bool| -> Duration {
+            let options = ExportOptions {
+                parallel,
+                ..ExportOptions::default()
+            };
+            // One untimed run first, so the thread pool is started before timing
+            ir_to_xml(parts.clone(), &options).unwrap();
+            let started = Instant::now();
+            for _ in 0..RUNS {
+                ir_to_xml(parts.clone(), &options).unwrap();
+            }
+            started.elapsed() / RUNS
+        };
+        let serial = time_export(false);
+        let parallel = time_export(true);
+        println!(
+            "4 parts: serial {:?}, parallel {:?}, speedup {:.2}x",
+            serial,
+            parallel,
+            serial.as_secs_f64() / parallel.as_secs_f64()
+        );
+    }
+
+    #[test]
+    fn test_voice_entering_late_is_moved_forward() {
+        let docstring = fs::read_to_string("test/late_voice.musicxml").unwrap();
+        let parts = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        let xml = ir_to_xml(parts, &ExportOptions::default()).unwrap();
+        let doc = roxmltree::Document::parse(&xml).unwrap();
+        let measure = doc.descendants().find(|n| n.has_tag_name("measure")).unwrap();
+        let child_text = |n: roxmltree::Node, tag: &str| {
+            n.children().find(|c| c.has_tag_name(tag)).and_then(|c| c.text()).map(str::to_string)
+        };
+        let timeline: Vec<(String, Option<String>, Option<String>)> = measure
+            .children()
+            .filter(|n| ["note", "backup", "forward"].contains(&n.tag_name().name()))
+            .map(|n| (n.tag_name().name().to_string(), child_text(n, "duration"), child_text(n, "voice")))
+            .collect();
+        let entry = |tag: &str, duration: &str, voice: Option<&str>| {
+            (tag.to_string(), Some(duration.to_string()), voice.map(str::to_string))
+        };
+        // The half rest voice 2 starts with is written as a forward to beat 3
+        assert_eq!(
+            timeline,
+            vec![
+                entry("note", "1", Some("1")),
+                entry("note", "1", Some("1")),
+                entry("note", "1", Some("1")),
+                entry("note", "1", Some("1")),
+                entry("backup", "4", None),
+                entry("forward", "2", Some("2")),
+                entry("note", "1", Some("2")),
+                entry("note", "1", Some("2")),
+            ]
+        );
+    }
 }
@@ -0,0 +1,84 @@
+use super::notation::{KeySignature, MusicElement};
+use num_traits::FromPrimitive;
+
+/// Krumhansl-Kessler probe tone ratings of the pitch classes of a major key, tonic first
+const MAJOR_PROFILE: [f64; 12] = [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+/// Krumhansl-Kessler probe tone ratings of the pitch classes of a minor key, tonic first
+const MINOR_PROFILE: [f64; 12] = [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+
+/// Total duration of the pitched notes of each pitch class, C first
+fn pitch_class_histogram(elems: &[MusicElement]) -> [f64; 12] {
+    let mut histogram = [0.0; 12];
+    for elem in elems {
+        if let MusicElement::NoteRest(n) = elem {
+            if let Some(midi) = n.note_rest.get_midi_numeric_pitch_value() {
+                histogram[midi as usize % 12] += f64::from(n.get_duration_in_midi_ticks(None));
+            }
+        }
+    }
+    histogram
+}
+
+/// Pearson correlation of the histogram with a key profile whose tonic is the pitch class `tonic`
+fn correlation(histogram: &[f64; 12], profile: &[f64; 12], tonic: usize) -> f64 {
+    let mean = |values: &mut dyn Iterator<Item = f64>| values.sum::<f64>() / 12.0;
+    let histogram_mean = mean(&mut histogram.iter().copied());
+    let profile_mean = mean(&mut profile.iter().copied());
+    let (mut covariance, mut histogram_var, mut profile_var) = (0.0, 0.0, 0.0);
+    for (pitch_class, weight) in histogram.iter().enumerate() {
+        let h = weight - histogram_mean;
+        let p = profile[(pitch_class + 12 - tonic) % 12] - profile_mean;
+        covariance += h * p;
+        histogram_var += h * h;
+        profile_var += p * p;
+    }
+    covariance / (histogram_var * profile_var).sqrt()
+}
+
+/// Estimates the key signature of a part from the duration weighted pitch class histogram of its
+/// notes, picking the major or minor key whose Krumhansl-Kessler profile correlates best. Meant for
+/// files that declare no key, which otherwise read as C major and spell their accidentals as such.
+///
+/// Decoded pitches carry no spelling, so enharmonic keys cannot be told apart; this matches the
+/// key signatures, which hold one key per position on the circle of fifths. Returns `None` when the
+/// part has no pitched notes or every pitch class is equally weighted.
+pub fn estimate_key(elems: &[MusicElement]) -> Option<KeySignature> {
+    let histogram = pitch_class_histogram(elems);
+    let first = histogram[0];
+    if histogram.iter().all(|&weight| weight == first) {
+        return None;
+    }
+    (0..12)
+        .flat_map(|tonic| {
+            [
+                (correlation(&histogram, &MAJOR_PROFILE, tonic), tonic),
+                // A minor key shares the signature of the major key a minor third above
+                (correlation(&histogram, &MINOR_PROFILE, tonic), (tonic + 3) % 12),
+            ]
+        })
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .and_then(|(_, major_tonic)| KeySignature::from_usize(major_tonic * 7 % 12))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::estimate_key;
+    use crate::ir::notation::{BeatType, Beats, KeySignature, RhythmType, Voice};
+    use crate::ir::PartBuilder;
+
+    #[test]
+    fn test_g_major_melody() {
+        // G A B C D E F# G, then down the tonic triad to G
+        let melody = ["G4", "A4", "B4", "C5", "D5", "E5", "F#5", "G5", "D5", "B4", "G4", "G4"];
+        let mut builder = PartBuilder::new();
+        for measure in melody.chunks(4) {
+            builder = builder.measure(Beats::Four, BeatType::Four);
+            for pitch in measure {
+                builder = builder.note(pitch.parse().unwrap(), RhythmType::Crochet, Voice::One);
+            }
+        }
+        let part = builder.build("P1").unwrap();
+        assert_eq!(estimate_key(part.inner()), Some(KeySignature::GMajorEminor));
+        assert_eq!(estimate_key(&[]), None);
+    }
+}
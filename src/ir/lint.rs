@@ -0,0 +1,174 @@
+use super::notation::{Ending, MeasureStartEnd, MusicElement};
+use std::fmt;
+
+/// A structural problem of an element stream, found by [`lint_elements`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintIssue {
+    /// One based position of the offending measure
+    pub measure: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "measure {}: {}", self.measure, self.message)
+    }
+}
+
+/// Checks that repeats and endings are balanced, so they can be written as valid barlines.
+///
+/// A repeat start must be followed by a repeat end before the next repeat start or the end of the
+/// part. A repeat end needs an open repeat start, except for the first one of a part, which repeats
+/// from the beginning, and those closing a later ending of an already repeated section. Every ending
+/// started on a measure must be stopped, with the same number, before the next one starts.
+pub fn lint_elements(elems: &[MusicElement]) -> Vec<LintIssue> {
+    let mut issues = vec![];
+    let mut measure = 0;
+    // Measure of the repeat start not yet ended
+    let mut open_repeat: Option<usize> = None;
+    let mut seen_repeat = false;
+    // Number and measure of the ending not yet stopped
+    let mut open_ending: Option<(Ending, usize)> = None;
+    // The current measure lies within an ending
+    let mut in_ending = false;
+    for elem in elems {
+        let MusicElement::MeasureMeta(meta) = elem else {
+            continue;
+        };
+        match meta.start_end {
+            MeasureStartEnd::MeasureStart | MeasureStartEnd::RepeatStart => {
+                measure += 1;
+                in_ending = open_ending.is_some();
+                if meta.start_end == MeasureStartEnd::RepeatStart {
+                    if let Some(start) = open_repeat {
+                        issues.push(LintIssue {
+                            measure,
+                            message: format!("repeat start inside the repeat started in measure {}", start),
+                        });
+                    }
+                    open_repeat = Some(measure);
+                    seen_repeat = true;
+                }
+                if meta.ending != Ending::None {
+                    if let Some((ending, start)) = open_ending {
+                        issues.push(LintIssue {
+                            measure: start,
+                            message: format!("ending {} is never stopped", ending.to_string()),
+                        });
+                    }
+                    open_ending = Some((meta.ending, measure));
+                    in_ending = true;
+                }
+            }
+            MeasureStartEnd::MeasureEnd | MeasureStartEnd::RepeatEnd => {
+                if meta.start_end == MeasureStartEnd::RepeatEnd
+                    && open_repeat.take().is_none()
+                    && seen_repeat
+                    && !in_ending
+                {
+                    issues.push(LintIssue {
+                        measure,
+                        message: String::from("repeat end without a repeat start"),
+                    });
+                }
+                if meta.start_end == MeasureStartEnd::RepeatEnd {
+                    seen_repeat = true;
+                }
+                if meta.ending != Ending::None {
+                    match open_ending.take() {
+                        Some((ending, _)) if ending == meta.ending => {}
+                        Some((ending, start)) => issues.push(LintIssue {
+                            measure,
+                            message: format!(
+                                "ending {} stops the ending {} started in measure {}",
+                                meta.ending.to_string(),
+                                ending.to_string(),
+                                start
+                            ),
+                        }),
+                        None => issues.push(LintIssue {
+                            measure,
+                            message: format!("ending {} is stopped without being started", meta.ending.to_string()),
+                        }),
+                    }
+                }
+            }
+        }
+    }
+    if let Some(start) = open_repeat {
+        issues.push(LintIssue {
+            measure: start,
+            message: String::from("repeat start is never ended"),
+        });
+    }
+    if let Some((ending, start)) = open_ending {
+        issues.push(LintIssue {
+            measure: start,
+            message: format!("ending {} is never stopped", ending.to_string()),
+        });
+    }
+    issues.sort_by_key(|issue| issue.measure);
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::lint_elements;
+    use crate::ir::notation::{Ending, MeasureMetaData, MeasureStartEnd, MusicElement};
+    use crate::ir::{xml_to_ir, ParseOptions};
+    use std::fs;
+
+    fn lint_fixture(path: &str) -> Vec<String> {
+        let docstring = fs::read_to_string(path).unwrap();
+        let options = ParseOptions {
+            lint: true,
+            ..ParseOptions::default()
+        };
+        let part_map = xml_to_ir(docstring, false, &options).unwrap();
+        part_map
+            .get_part(0)
+            .unwrap()
+            .get_lint_issues()
+            .iter()
+            .map(|issue| issue.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_unbalanced_repeat_is_reported() {
+        assert_eq!(
+            lint_fixture("test/unbalanced_repeat.musicxml"),
+            vec!["measure 2: repeat end without a repeat start"]
+        );
+    }
+
+    #[test]
+    fn test_unmatched_ending_is_reported() {
+        assert_eq!(
+            lint_fixture("test/unmatched_ending.musicxml"),
+            vec!["measure 2: ending 1 is never stopped"]
+        );
+    }
+
+    #[test]
+    fn test_first_and_second_endings_are_balanced() {
+        let meta = |start_end, ending| {
+            MusicElement::MeasureMeta(MeasureMetaData {
+                ending,
+                ..MeasureMetaData::new(start_end)
+            })
+        };
+        // ||: m1 | 1. m2 :|| 2. m3 |, then a section repeated from a backward repeat only
+        let elems = vec![
+            meta(MeasureStartEnd::RepeatStart, Ending::None),
+            meta(MeasureStartEnd::MeasureEnd, Ending::None),
+            meta(MeasureStartEnd::MeasureStart, Ending::One),
+            meta(MeasureStartEnd::RepeatEnd, Ending::One),
+            meta(MeasureStartEnd::MeasureStart, Ending::Two),
+            meta(MeasureStartEnd::MeasureEnd, Ending::Two),
+            meta(MeasureStartEnd::MeasureStart, Ending::None),
+            meta(MeasureStartEnd::MeasureEnd, Ending::None),
+        ];
+        assert!(lint_elements(&elems).is_empty());
+    }
+}
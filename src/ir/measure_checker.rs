@@ -1,12 +1,46 @@
 use super::notation::{
-    BeatType, Beats, Chord, MeasureInitializer, MusicElement, NoteData, SpecialNote,
+    BeatType, Beats, Chord, IsDotted, MeasureInitializer, MusicElement, NoteData, PercussionData, RhythmType,
     TimeModification, Voice,
 };
-use log::{error, info, warn};
+use log::{debug, error, warn};
 use num_traits::FromPrimitive;
 use std::cmp::Ordering;
 use std::collections::BTreeSet;
 use std::convert::From;
+use std::fmt;
+
+/// Rests inserted into a voice whose duration fell short of the measure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MeasureCorrection {
+    /// Zero based index of the measure in the MusicXML part
+    pub measure_idx: usize,
+    pub voice: Voice,
+    /// Missing duration, in divisions
+    pub discrepancy: u32,
+    /// Rhythm value of each inserted rest
+    pub rests: Vec<(RhythmType, IsDotted)>,
+}
+
+impl fmt::Display for MeasureCorrection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rests: Vec<String> = self
+            .rests
+            .iter()
+            .map(|(rhythm, dotted)| {
+                let dot = if *dotted { "dotted " } else { "" };
+                format!("{}{}", dot, rhythm.get_type_string())
+            })
+            .collect();
+        write!(
+            f,
+            "measure {} voice {}: {} divisions short, inserted {} rest",
+            self.measure_idx + 1,
+            self.voice as u8 + 1,
+            self.discrepancy,
+            rests.join(", ")
+        )
+    }
+}
 
 #[derive(Eq, PartialEq, Default, Debug, Clone)]
 pub struct MeasureChecker {
@@ -18,6 +52,10 @@ pub struct MeasureChecker {
     part_str: String,
     measure_idx: usize,
     forward_duration: usize,
+    // Placeholder rests inserted to make the voices add up
+    corrections: Vec<MeasureCorrection>,
+    // Notes removed because the divisions are too coarse to give them any duration
+    zero_duration_notes: usize,
 }
 
 impl MeasureChecker {
@@ -38,6 +76,8 @@ impl MeasureChecker {
             part_str: part_str.to_string(),
             measure_idx,
             forward_duration,
+            corrections: vec![],
+            zero_duration_notes: 0,
         }
     }
 
@@ -55,19 +95,96 @@ impl MeasureChecker {
         self.measure_idx
     }
 
+    pub fn discrepancies(&self) -> usize {
+        self.corrections.len()
+    }
+
+    pub fn corrections(&self) -> &[MeasureCorrection] {
+        &self.corrections
+    }
+
+    fn record_correction(&mut self, voice: Voice, discrepancy: u32, rests: &[NoteData]) {
+        self.corrections.push(MeasureCorrection {
+            measure_idx: self.measure_idx,
+            voice,
+            discrepancy,
+            rests: rests.iter().map(|rest| (rest.note_type, rest.dotted)).collect(),
+        });
+    }
+
+    pub fn zero_duration_notes(&self) -> usize {
+        self.zero_duration_notes
+    }
+
+    /// Removes notes whose duration truncates to zero at the measure's divisions, together with the
+    /// chord tones stacked on them, reporting each one instead of emitting a zero-length note
+    pub fn remove_zero_duration_notes(&mut self) {
+        let mut time_mod: Option<TimeModification> = None;
+        let mut removing_chord = false;
+        let quarter_division = self.quarter_division;
+        let beats = u32::from(self.beats);
+        let beat_type = u32::from(self.beat_type);
+        let part_str = self.part_str.as_str();
+        let measure_idx = self.measure_idx;
+        let mut removed = 0;
+        self.measure.retain(|elem| match *elem {
+            MusicElement::Tuplet(t) => {
+                time_mod = t.into();
+                true
+            }
+            MusicElement::NoteRest(n) | MusicElement::Percussion(PercussionData { note: n, .. })
+                if n.chord == Chord::Chord =>
+            {
+                !removing_chord
+            }
+            MusicElement::NoteRest(n) | MusicElement::Percussion(PercussionData { note: n, .. }) => {
+                removing_chord = n.occupies_time()
+                    && n.get_duration_numeric(quarter_division, beats, beat_type, time_mod) == 0;
+                if removing_chord {
+                    error!(
+                        "{}M{} {} note has no duration at {} divisions per quarter note, removing it.",
+                        part_str,
+                        measure_idx,
+                        n.note_type.get_type_string(),
+                        quarter_division
+                    );
+                    removed += 1;
+                }
+                !removing_chord
+            }
+            _ => true,
+        });
+        self.zero_duration_notes += removed;
+    }
+
     pub fn conform_backup_placeholder_rests(&mut self, backup_duration: usize) {
         // Backup elements are only inserted when voice changes happen.
         // Calculate duration to current point, since previous voice began, based on notes in the measure, and accounting for corresponding
         // time modifying elements
-        let actual_duration = backup_duration - self.forward_duration;
-        let last_backup_idx = self.measure.len() - self.elems_since_backup;
+        let actual_duration = match backup_duration.checked_sub(self.forward_duration) {
+            Some(actual_duration) => actual_duration,
+            None => {
+                // No placeholder rest can be derived from an inconsistent backup, so the next voice is assumed
+                // to start where the backup leaves it
+                warn!(
+                    "{}M{} backup of {} overshoots the forward offset {}, not inserting placeholder rests.",
+                    self.part_str.as_str(),
+                    self.measure_idx,
+                    backup_duration,
+                    self.forward_duration
+                );
+                self.clear_elems_since_backup();
+                return;
+            }
+        };
+        let last_backup_idx = self.measure.len().saturating_sub(self.elems_since_backup);
         let mut time_mod: Option<TimeModification> = None;
         let mut current_voice = Voice::One;
         let duration_since_backup: usize = self.measure[last_backup_idx..]
             .iter()
             .cloned()
             .map(|element| match element {
-                MusicElement::NoteRest(n) => {
+                MusicElement::NoteRest(n) | MusicElement::Percussion(PercussionData { note: n, .. }) => {
                     // chords have a duration in musicxml, but this duration is always identical to the previous note it's
                     // attached to. Chord duration shouldn't impact the total summation.
                     current_voice = n.voice;
@@ -97,31 +214,23 @@ impl MeasureChecker {
         match actual_duration.cmp(&duration_since_backup) {
             Ordering::Less => {
                 let discrepancy = duration_since_backup - actual_duration;
-                println!("{}M{} duration tally {} did not match the backup element's duration {actual_duration}, qtr_div: {} inserting rests to accommodate {discrepancy} discrepancy.", self.part_str.as_str(), self.measure_idx, duration_since_backup, self.quarter_division);
-
-                match NoteData::from_numeric_duration(discrepancy as u32, self.quarter_division) {
-                    Some((duration, is_dotted, time_mod)) => {
-                        if time_mod.is_some() {
-                            warn!("time modification for rest is present, but not being used.")
-                        }
-                        // The new rest should begin on the next voice after the current one.
-                        self.measure
-                            .push(MusicElement::NoteRest(NoteData::new_default_rest(
-                                duration,
-                                is_dotted,
-                                current_voice.next(),
-                            )));
-                    }
-                    None => {
-                        panic!(
-                            "Could not convert {} in a rest duration value.",
-                            discrepancy
-                        );
-                    }
+                warn!("{}M{} duration tally {} did not match the backup element's duration {actual_duration}, qtr_div: {} inserting rests to accommodate {discrepancy} discrepancy.", self.part_str.as_str(), self.measure_idx, duration_since_backup, self.quarter_division);
+
+                let components =
+                    NoteData::from_numeric_duration(discrepancy as u32, self.quarter_division);
+                if components.iter().any(|(_, _, time_mod)| time_mod.is_some()) {
+                    warn!("time modification for rest is present, but not being used.")
+                }
+                // The new rests should begin on the next voice after the current one.
+                let rest = NoteData::new_default_rest(RhythmType::default(), false, current_voice.next());
+                let rests = rest.tied_components(&components);
+                self.record_correction(current_voice.next(), discrepancy as u32, &rests);
+                for rest in rests {
+                    self.measure.push(MusicElement::NoteRest(rest));
                 }
             }
             Ordering::Greater => {
-                info!(
+                debug!(
                     "Backup_duration {} was > duration_since_backup {} Assuming beginning of measure",
                     actual_duration, duration_since_backup
                 );
@@ -142,6 +251,30 @@ impl MeasureChecker {
         &mut self.measure
     }
 
+    /// Fills a measure without any notes or rests with a single full measure rest in voice one
+    pub fn fill_empty_measure(&mut self) {
+        if self
+            .measure
+            .iter()
+            .any(|e| matches!(e, MusicElement::NoteRest(_) | MusicElement::Percussion(_)))
+        {
+            return;
+        }
+        warn!(
+            "{}M{} is empty, inserting a full measure rest.",
+            self.part_str.as_str(),
+            self.measure_idx
+        );
+        // A semibreve rest lasts the whole measure whatever the time signature, like a rest marked
+        // measure="yes", so no meter needs it pieced together from shorter rests
+        self.measure
+            .push(MusicElement::NoteRest(NoteData::new_default_rest(
+                RhythmType::SemiBreve,
+                false,
+                Voice::One,
+            )));
+    }
+
     pub fn remove_incomplete_voices(&mut self, voices: &BTreeSet<u8>) {
         let mut voice_durations: [u32; Self::MAX_SUPPORTED_VOICES] =
             [0; Self::MAX_SUPPORTED_VOICES];
@@ -165,9 +298,9 @@ impl MeasureChecker {
             // }
             match elem {
                 MusicElement::Tuplet(t) => time_mod = t.into(),
-                MusicElement::NoteRest(n) => {
-                    // Do not include chord notes or grace notes in the count, as they do not impact measure duration
-                    if n.chord == Chord::NoChord && n.special_note == SpecialNote::None {
+                MusicElement::NoteRest(n) | MusicElement::Percussion(PercussionData { note: n, .. }) => {
+                    // Do not include chord notes, grace notes or cue notes in the count, as they do not impact measure duration
+                    if n.chord == Chord::NoChord && n.occupies_time() {
                         voice_durations[n.voice as usize] += n.get_duration_numeric(
                             self.quarter_division,
                             u32::from(self.beats),
@@ -182,6 +315,9 @@ impl MeasureChecker {
 
                     prev_voice = n.voice as usize;
                 }
+                MusicElement::Harmony(_) | MusicElement::ClefChange(_) => {
+                    // Chord symbols and clef changes do not occupy time in any voice
+                }
                 _ => {
                     error!("Unhandled element case");
                 }
@@ -197,32 +333,23 @@ impl MeasureChecker {
             if voice_durations[voice_idx] != 0 && voice_durations[voice_idx] < first_voice_duration
             {
                 let discrepancy = first_voice_duration - voice_durations[voice_idx];
-                println!(
-                    "{}M{} Voice Zero: {first_voice_duration} duration Voice {voice_idx}: {} duration {} discrepancy", self.part_str.as_str(), self.measure_idx,
+                warn!(
+                    "{}M{} Voice Zero: {first_voice_duration} duration Voice {voice_idx}: {} duration {} discrepancy, inserting rests.", self.part_str.as_str(), self.measure_idx,
                     voice_durations[voice_idx],discrepancy
                 );
                 // insert rest of discrepancy length at index at measure[voice_last_idx[voice_idx]]
-                println!("Inserting rest due to voice length incorrect.");
-                if let Some((duration, is_dotted, time_mod)) =
-                    NoteData::from_numeric_duration(discrepancy, self.quarter_division)
-                {
-                    if time_mod.is_some() {
-                        warn!("time modification for rest is present, but not being used.")
-                    }
-                    // The new rest should begin on the current voice to correct the total duration.
-                    self.measure.insert(
-                        voice_last_idx[voice_idx],
-                        MusicElement::NoteRest(NoteData::new_default_rest(
-                            duration,
-                            is_dotted,
-                            FromPrimitive::from_u8(voice_idx as u8).unwrap(),
-                        )),
-                    );
-                } else {
-                    panic!(
-                        "Could not convert {} in a rest duration value.",
-                        discrepancy
-                    );
+                let components = NoteData::from_numeric_duration(discrepancy, self.quarter_division);
+                if components.iter().any(|(_, _, time_mod)| time_mod.is_some()) {
+                    warn!("time modification for rest is present, but not being used.")
+                }
+                // The new rests should begin on the current voice to correct the total duration.
+                let voice = FromPrimitive::from_u8(voice_idx as u8).unwrap();
+                let rest = NoteData::new_default_rest(RhythmType::default(), false, voice);
+                let rests = rest.tied_components(&components);
+                self.record_correction(voice, discrepancy, &rests);
+                for (offset, rest) in rests.into_iter().enumerate() {
+                    self.measure
+                        .insert(voice_last_idx[voice_idx] + offset, MusicElement::NoteRest(rest));
                 }
             }
         }
@@ -1,23 +1,185 @@
+mod beaming;
+mod csv_to_ir;
+mod element_dump;
+mod key_estimation;
+mod lint;
 mod musical_part;
 mod muxml_parser;
+mod note_features;
+mod part_builder;
 mod part_map;
+mod part_stats;
+mod provenance;
+mod unfold;
+mod unsupported;
 
 pub mod ir_to_xml;
 pub mod measure_checker;
 pub mod notation;
 pub mod xml_to_ir;
 pub mod multipartxml_to_ir;
+pub mod xml_patch;
 
-pub use musical_part::MusicalPart;
-use notation::{TimeModification, TupletActual, TupletNormal};
+pub use beaming::{beam_groups, BeamState, BeamValue};
+pub use csv_to_ir::csv_to_ir;
+pub use element_dump::{DumpFormat, DumpOptions, DUMP_TARGET};
+pub use key_estimation::estimate_key;
+pub use lint::{lint_elements, LintIssue};
+pub use measure_checker::MeasureCorrection;
+pub use musical_part::{LayoutBreak, MeasureNumber, MusicalPart, PartName};
+use notation::{Clef, PitchSpelling, TempoRounding, TimeModification, TupletActual, TupletNormal};
 pub use notation::{MusicElement, TupletNumber};
+pub use note_features::{compute_onsets, write_features_flat, write_features_npy, NoteFeatures, NOTE_FEATURE_FIELDS};
+pub use part_builder::PartBuilder;
 pub use part_map::PartMap;
+pub use part_stats::PartStats;
+pub use provenance::{compute_provenance, Provenance};
+pub use unsupported::{scan_unsupported, UnsupportedReport};
 
 pub use xml_to_ir::xml_to_ir;
 pub use multipartxml_to_ir::multipartxml_to_ir;
 
 use muxml::muxml_types::TimeModificationElement;
 
+/// Options controlling how MusicXML input is converted into the intermediate representation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParseOptions {
+    /// Fail with an error instead of silently dropping parts or voices that cannot be represented
+    pub strict: bool,
+    /// Remove elements that do not change the meaning of the piece, such as repeated measure initializers
+    pub optimize: bool,
+    /// What to do with ties that are started but never stopped
+    pub tie_policy: TiePolicy,
+    /// Keep parts with unpitched notes, storing those notes as percussion elements
+    pub keep_percussion: bool,
+    /// Most parts a score may have
+    pub max_parts: usize,
+    /// What to do with a score that has more than `max_parts` parts
+    pub part_limit: PartLimitPolicy,
+    /// Write the key estimated from the notes into the measure initializers of parts that declare no key
+    pub estimate_key: bool,
+    /// Check that the repeats and endings of every part are balanced, warning about each issue found
+    pub lint: bool,
+    /// Read fractional alters, such as `0.5` for a quarter tone sharp, as a deviation in cents from the
+    /// nearest semitone instead of rejecting them
+    pub microtonal: bool,
+    /// Which voices are kept from a part with more voices than are supported
+    pub voice_limit: VoiceLimitPolicy,
+    /// Keep the system and page breaks of the score's `<print>` elements
+    pub preserve_layout: bool,
+    /// What to do with the tablature staves of parts pairing them with pitched staves
+    pub tab_staves: TabStaffPolicy,
+    /// How tempos between two of the tempos the bin format stores are rounded
+    pub tempo_rounding: TempoRounding,
+}
+
+impl ParseOptions {
+    pub const DEFAULT_MAX_PARTS: usize = 4;
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            strict: false,
+            optimize: false,
+            tie_policy: TiePolicy::default(),
+            keep_percussion: false,
+            max_parts: ParseOptions::DEFAULT_MAX_PARTS,
+            part_limit: PartLimitPolicy::default(),
+            estimate_key: false,
+            lint: false,
+            microtonal: false,
+            voice_limit: VoiceLimitPolicy::default(),
+            preserve_layout: false,
+            tab_staves: TabStaffPolicy::default(),
+            tempo_rounding: TempoRounding::default(),
+        }
+    }
+}
+
+/// Handling of a score with more parts than the configured maximum
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum PartLimitPolicy {
+    /// Fail with [`Error::TooManyParts`](crate::error::Error::TooManyParts)
+    #[default]
+    Error,
+    /// Keep the first parts of the score, in part list order, and drop the rest
+    Truncate,
+}
+
+/// Choice of the voices kept from a part with more voices than are supported. The notes of the other
+/// voices are dropped.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum VoiceLimitPolicy {
+    /// Keep the lowest numbered voices
+    #[default]
+    Lowest,
+    /// Keep the voices with the most notes, preferring the lower numbered of voices with as many
+    MostNotes,
+}
+
+/// Handling of the notes of a tablature staff, such as the one a guitar part writes under its pitched staff
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum TabStaffPolicy {
+    /// Drop the tablature staff's notes, keeping the part's other staves
+    #[default]
+    Drop,
+    /// Keep the tablature staff's notes, reading the pitch of those written without one from the fret
+    /// of their string
+    Convert,
+}
+
+/// Handling of the notes an octave shift moves outside of the supported range
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum OctaveShiftPolicy {
+    /// Shift the note by as many of the octaves as keep it in range
+    #[default]
+    Clamp,
+    /// Replace the note with a rest of the same duration, leaving out chord tones
+    Drop,
+}
+
+/// Numbering of the measures of bin files appended to one another
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum MeasureNumbering {
+    /// Number the measures of every file on from the last measure of the file before it
+    #[default]
+    Continue,
+    /// Start the measures of every file again from the numbers that file gives them
+    Reset,
+}
+
+/// Handling of a tie start without a matching tie stop on the same pitch and voice
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum TiePolicy {
+    /// Report dangling ties and leave them in place
+    #[default]
+    Warn,
+    /// Report dangling ties and remove them
+    Fix,
+}
+
+/// Options controlling how the intermediate representation is serialized to MusicXML
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ExportOptions {
+    /// Value written to the `<encoding-date>` element (YYYY-MM-DD). The element is left out when `None`,
+    /// as an empty one is not a valid date.
+    pub encoding_date: Option<String>,
+    /// Start a new system every this many measures, so long scores don't render on a single line
+    pub measures_per_system: Option<usize>,
+    /// Clef of each staff, overriding the clefs derived from the part names
+    pub clefs: Option<Vec<Clef>>,
+    /// Spelling of black key pitches
+    pub spelling: PitchSpelling,
+    /// Divisions per quarter note to write durations in, instead of the smallest the notes need
+    pub output_divisions: Option<u32>,
+    /// Fail instead of warning when a measure's voices do not add up to its time signature
+    pub strict: bool,
+    /// Serialize the parts of a multi-part score concurrently. The output is the same as serializing
+    /// them one after the other.
+    pub parallel: bool,
+}
+
 fn convert_time_modification(t_mod: &TimeModificationElement) -> TimeModification {
     let tup_ac = TupletActual::try_from(t_mod.actual_notes.as_ref())
         .expect("Cannot convert this TupletActual string.");
@@ -1,20 +1,26 @@
 use super::muxml_parser::{
-    does_note_contain_unpitched, parse_backup_tag, parse_direction_tag, parse_note_tag,
+    does_note_contain_unpitched, find_initial_attributes, is_rest_only_measure, parse_backup_tag, parse_direction_tag,
+    parse_attributes_tag, parse_bar_style, parse_harmony_tag, parse_measure_number, parse_measure_style_tag, parse_note_tag,
+    find_tab_staves, note_staff, parse_print_tag, parse_repeat_times, parse_score_part_tag, select_voices, MeasureStyle,
 };
 use crate::error::{Result,Error};
 use crate::ir::notation::{
-    BeatType, Beats, Ending, KeySignature, MeasureInitializer, MeasureMetaData, MeasureStartEnd,
+    BeatType, Beats, DescriptiveTempo, Ending, KeySignature, MeasureInitializer, MeasureMetaData, MeasureStartEnd,
     Tempo
 };
-use crate::ir::{MusicalPart, PartMap};
+use crate::ir::{MusicalPart, ParseOptions, PartLimitPolicy, PartMap, PartName, TabStaffPolicy};
 
-use log::info;
+use log::{info, warn};
 use roxmltree::*;
+use std::collections::BTreeMap;
 use std::str::FromStr;
 
-const MAX_SUPPORTED_PARTS: usize = 4;
-
-pub fn multipartxml_to_ir(docstring: String, _dump_input: bool, input_filename: &str) -> Result<PartMap> {
+pub fn multipartxml_to_ir(
+    docstring: String,
+    _dump_input: bool,
+    input_filename: &str,
+    options: &ParseOptions,
+) -> Result<PartMap> {
     let opt = ParsingOptions {
         allow_dtd: true,
         ..ParsingOptions::default()
@@ -28,26 +34,44 @@ pub fn multipartxml_to_ir(docstring: String, _dump_input: bool, input_filename:
         .descendants()
         .filter(|n| n.has_tag_name("score-part"));
 
+    let found_parts = xml_score_parts.clone().count();
+    if found_parts > options.max_parts {
+        match options.part_limit {
+            PartLimitPolicy::Error => {
+                warn!("The number of parts {} exceeds the supported amount {}", found_parts, options.max_parts);
+                return Err(Error::TooManyParts {
+                    found: found_parts,
+                    max: options.max_parts,
+                });
+            }
+            PartLimitPolicy::Truncate => warn!(
+                "The number of parts {} exceeds the supported amount {}, keeping the first {}",
+                found_parts, options.max_parts, options.max_parts
+            ),
+        }
+    }
+    let xml_score_parts = xml_score_parts.take(options.max_parts);
+
+    let mut ir_part_names: BTreeMap<String, PartName> = BTreeMap::new();
     for xml_score_part in xml_score_parts.clone() {
         let part_id = xml_score_part.attribute("id").unwrap();
         ir_part_map
             .add_part_id(part_id)
             .expect("PartMap is not empty");
+        if let Some(part_name) = parse_score_part_tag(&xml_score_part) {
+            ir_part_names.insert(part_id.to_string(), part_name);
+        }
     }
 
     info!(
         "Preprocessing check found {} possible parts",
         ir_part_map.num_part_ids()
     );
-    if ir_part_map.num_part_ids() > MAX_SUPPORTED_PARTS {
-        println!("The number of parts {} exceeds the supported amount {}", ir_part_map.num_part_ids(), MAX_SUPPORTED_PARTS);
-        return Err(Error::Unit);
-    }
-    if ir_part_map.num_part_ids() == MAX_SUPPORTED_PARTS {
-        println!("File name {}", input_filename);
+    if ir_part_map.num_part_ids() == options.max_parts {
+        info!("File name {}", input_filename);
         for score_part in xml_score_parts {
             match score_part.descendants().find(|n| n.has_tag_name("part-name")).unwrap().text() {
-                Some(t) => println!("Name {}", t),
+                Some(t) => info!("Name {}", t),
                 None => (),
             }
         }
@@ -62,13 +86,35 @@ pub fn multipartxml_to_ir(docstring: String, _dump_input: bool, input_filename:
             .find(|n| n.has_tag_name("part") && n.attribute("id").unwrap().eq(ir_part_str.as_str()));
 
         let mut ir_musical_part: MusicalPart = MusicalPart::new(ir_part_str.as_str());
+        ir_musical_part.set_part_name(ir_part_names.remove(&ir_part_str));
+        if let Some(voices) = select_voices(&xml_part_tag.unwrap(), options.voice_limit) {
+            warn!(
+                "Part {} has more than {} voices, keeping voices {:?} and dropping the notes of the others",
+                ir_part_str,
+                MusicalPart::MAX_SUPPORTED_VOICES,
+                voices
+            );
+            ir_musical_part.keep_voices(voices);
+        }
+
+        let tab_staves = find_tab_staves(&xml_part_tag.unwrap());
+        if !tab_staves.is_empty() && options.tab_staves == TabStaffPolicy::Drop {
+            info!("Part {} dropping the notes of tablature staves {:?}", ir_part_str, tab_staves.keys());
+        }
 
         let xml_measures = xml_part_tag
             .unwrap()
             .children()
-            .filter(|n| n.has_tag_name("measure"));
+            .filter(|n| n.has_tag_name("measure"))
+            .collect::<Vec<_>>();
 
-        for (xml_measure_idx, xml_measure) in xml_measures.enumerate() {
+        // Each individual part duplicates the divisions entry, usually but not always in its first measure
+        let (xml_attributes_idx, quarter_division) = find_initial_attributes(ir_part_str.as_str(), &xml_measures)?;
+        ir_musical_part.set_initial_divisions(quarter_division);
+
+        // Number of measures back a measure repeat copies from, while inside a measure repeat
+        let mut measures_repeated: Option<usize> = None;
+        for (xml_measure_idx, xml_measure) in xml_measures.iter().enumerate() {
             // if dump_input {
             //     debug!("Measure_idx {measure_idx} start");
             // }
@@ -77,20 +123,16 @@ pub fn multipartxml_to_ir(docstring: String, _dump_input: bool, input_filename:
             let mut ir_measure_meta_start = MeasureMetaData::new(MeasureStartEnd::MeasureStart);
             let mut ir_measure_meta_end = MeasureMetaData::new(MeasureStartEnd::MeasureEnd);
 
-            // Each individual part duplicates the divisions entry at measure idx 0 (usually, but not always measure number 1)
-            let mut quarter_division = 0;
-            if xml_measure_idx == 0 {
-                if let Some(div) = xml_measure.descendants().find(|n| n.has_tag_name("divisions")) {
-                    quarter_division = div.text().unwrap().parse::<u32>().unwrap();
-                } else {
-                    panic!("No divisions tag found.");
-                }
-            }
-            ir_musical_part.set_initial_divisions(quarter_division);
+            // Measures before the part's first attributes, such as an empty pickup, are read with them
+            let xml_attributes_measure = if xml_measure_idx < xml_attributes_idx {
+                &xml_measures[xml_attributes_idx]
+            } else {
+                xml_measure
+            };
 
             // TODO: All of this XML parsing logic should be abstracted away another data type with methods
             // that can be re-used across xml2bin and xml multipart
-            if let Some(xml_time_tag) = xml_measure.descendants().find(|n| n.has_tag_name("time")) {
+            if let Some(xml_time_tag) = xml_attributes_measure.descendants().find(|n| n.has_tag_name("time")) {
                 let xml_beats_tag = xml_time_tag.children().find(|n| n.has_tag_name("beats")).unwrap();
                 let xml_beat_type_tag = xml_time_tag.children().find(|n| n.has_tag_name("beat-type")).unwrap();
 
@@ -99,10 +141,12 @@ pub fn multipartxml_to_ir(docstring: String, _dump_input: bool, input_filename:
                     BeatType::from_str(xml_beat_type_tag.text().unwrap()).unwrap();
             };
 
-            if let Some(xml_repeat_tag) = xml_measure.descendants().find(|n| n.has_tag_name("repeat")) {
+            // A measure may both start and end a repeat
+            for xml_repeat_tag in xml_measure.descendants().filter(|n| n.has_tag_name("repeat")) {
                 let measure_direction_str = xml_repeat_tag.attribute("direction").unwrap();
                 if measure_direction_str.eq("backward") {
                     ir_measure_meta_end.start_end = MeasureStartEnd::RepeatEnd;
+                    ir_measure_meta_end.repeat_times = parse_repeat_times(&xml_repeat_tag);
                 } else if measure_direction_str.eq("forward") {
                     ir_measure_meta_start.start_end = MeasureStartEnd::RepeatStart;
                 } else {
@@ -112,8 +156,9 @@ pub fn multipartxml_to_ir(docstring: String, _dump_input: bool, input_filename:
                         measure_direction_str
                     );
                 }
-            };
+            }
 
+            ir_measure_meta_end.bar_style = parse_bar_style(xml_measure);
             let xml_barlines = xml_measure.descendants().filter(|n| n.has_tag_name("barline"));
             for xml_barline in xml_barlines {
                 if let Some(xml_ending_tag) = xml_barline.descendants().find(|n| n.has_tag_name("ending")) {
@@ -141,7 +186,7 @@ pub fn multipartxml_to_ir(docstring: String, _dump_input: bool, input_filename:
                 };
             }
 
-            if let Some(ir_key_sig) = match xml_measure.descendants().find(|n| n.has_tag_name("fifths")) {
+            if let Some(ir_key_sig) = match xml_attributes_measure.descendants().find(|n| n.has_tag_name("fifths")) {
                 Some(xml_fifths_tag) => KeySignature::from_str(xml_fifths_tag.text().unwrap()).ok(),
                 None => None,
             } {
@@ -152,10 +197,22 @@ pub fn multipartxml_to_ir(docstring: String, _dump_input: bool, input_filename:
                 .descendants()
                 .find(|n| n.has_tag_name("sound") && n.attribute("tempo").is_some())
             {
-                Some(n) => Tempo::from_str(n.attribute("tempo").unwrap()).ok(),
+                Some(n) => n
+                    .attribute("tempo")
+                    .unwrap()
+                    .parse::<i32>()
+                    .ok()
+                    .map(|bpm| Tempo::new_rounded(bpm, options.tempo_rounding)),
                 None => None,
             } {
                 ir_measure_init.tempo = xml_tempo;
+            } else if let Some(descriptive_tempo) = xml_measure
+                .descendants()
+                .filter(|n| n.has_tag_name("words"))
+                .find_map(|n| n.text().and_then(DescriptiveTempo::from_words))
+            {
+                // Scores giving only a textual marking such as "Andante" fall back to a representative BPM
+                ir_measure_init.tempo = Tempo::new_rounded(descriptive_tempo.to_bpm(), options.tempo_rounding);
             }
 
             if ir_musical_part.get_cur_init_measure_idx().is_none() {
@@ -174,21 +231,84 @@ pub fn multipartxml_to_ir(docstring: String, _dump_input: bool, input_filename:
                 forward_duration = duration_tag.parse::<usize>().unwrap();
             }
 
+            let measure_styles = parse_measure_style_tag(xml_measure);
+            let mut multiple_rest = None;
+            for measure_style in measure_styles {
+                match measure_style {
+                    MeasureStyle::MultipleRest(num_measures) => multiple_rest = Some(num_measures),
+                    MeasureStyle::MeasureRepeatStart(num_measures) => measures_repeated = Some(num_measures),
+                    MeasureStyle::MeasureRepeatStop => measures_repeated = None,
+                }
+            }
+
+            if let Some(measure_number) = parse_measure_number(xml_measure) {
+                let measure_idx = ir_musical_part.num_measures();
+                ir_musical_part.set_measure_number(measure_idx, measure_number);
+            }
+            if let (true, Some(layout_break)) = (options.preserve_layout, parse_print_tag(xml_measure)) {
+                let measure_idx = ir_musical_part.num_measures();
+                ir_musical_part.set_layout_break(measure_idx, layout_break);
+            }
             ir_musical_part.push_meta_start(ir_measure_meta_start, forward_duration, xml_measure_idx);
 
-            let xml_measure_elements = xml_measure.children().filter(|n| {
-                n.has_tag_name("note") || n.has_tag_name("direction") || n.has_tag_name("backup")
-            });
+            // The notes written in a repeated measure are placeholders, so the referenced measure is copied instead
+            if let Some(measures_back) = measures_repeated {
+                match ir_musical_part.previous_measure_elems(measures_back) {
+                    Some(repeated_elems) => {
+                        for elem in repeated_elems {
+                            ir_musical_part.push_measure_elem(elem);
+                        }
+                    }
+                    None => warn!(
+                        "{}M{} repeats {} measures back, before the start of the part.",
+                        ir_part_str, xml_measure_idx, measures_back
+                    ),
+                }
+            }
+
+            let xml_measure_elements = xml_measure
+                .children()
+                .filter(|n| {
+                    n.has_tag_name("note")
+                        || n.has_tag_name("direction")
+                        || n.has_tag_name("backup")
+                        || n.has_tag_name("harmony")
+                        || n.has_tag_name("attributes")
+                })
+                .filter(|_| measures_repeated.is_none());
+            // The backup following dropped tablature notes rewinds over them only
+            let mut after_dropped_tab = false;
             for xml_measure_element in xml_measure_elements {
+                let tab_tuning = match xml_measure_element.tag_name().name() {
+                    "note" => tab_staves.get(&note_staff(&xml_measure_element)),
+                    _ => None,
+                };
+                if tab_tuning.is_some() && options.tab_staves == TabStaffPolicy::Drop {
+                    after_dropped_tab = true;
+                    continue;
+                }
+                if xml_measure_element.tag_name().name() == "backup" && after_dropped_tab {
+                    after_dropped_tab = false;
+                    continue;
+                }
+                after_dropped_tab = false;
                 if xml_measure_element.tag_name().name() == "note" {
-                    // If a measure contains percussive (unpitched) content,
-                    // throw this entire part away because we do not analyze drum content
-                    if !does_note_contain_unpitched(&xml_measure_element) {
+                    // If a measure contains percussive (unpitched) content, throw this entire part away
+                    // because we do not analyze drum content, unless percussion parts are kept
+                    if options.keep_percussion || !does_note_contain_unpitched(&xml_measure_element) {
                         parse_note_tag(
                             &xml_measure_element,
                             &mut ir_musical_part,
-                        );
+                            options,
+                            tab_tuning,
+                        )?;
                     } else {
+                        if options.strict {
+                            return Err(Error::StrictViolation(
+                                ir_part_str,
+                                "contains unpitched (percussion) notes".to_string(),
+                            ));
+                        }
                         remove_cur_part = true;
                         break;
                     }
@@ -196,6 +316,10 @@ pub fn multipartxml_to_ir(docstring: String, _dump_input: bool, input_filename:
                     parse_direction_tag(&xml_measure_element, &mut ir_musical_part);
                 } else if xml_measure_element.tag_name().name() == "backup" {
                     parse_backup_tag(&xml_measure_element, &mut ir_musical_part);
+                } else if xml_measure_element.tag_name().name() == "harmony" {
+                    parse_harmony_tag(&xml_measure_element, &mut ir_musical_part, options)?;
+                } else if xml_measure_element.tag_name().name() == "attributes" {
+                    parse_attributes_tag(&xml_measure_element, &mut ir_musical_part);
                 }
             }
             if !remove_cur_part {
@@ -203,6 +327,24 @@ pub fn multipartxml_to_ir(docstring: String, _dump_input: bool, input_filename:
             } else {
                 break;
             }
+
+            if let Some(num_measures) = multiple_rest {
+                // Exporters either keep the measures covered by the multiple rest, each holding a rest,
+                // or omit them. Only the omitted ones are inserted, as empty measures filled with a rest.
+                let covered_measures = xml_measures[xml_measure_idx + 1..]
+                    .iter()
+                    .take(num_measures.saturating_sub(1))
+                    .take_while(|m| is_rest_only_measure(m))
+                    .count();
+                for _ in covered_measures..num_measures.saturating_sub(1) {
+                    ir_musical_part.push_meta_start(
+                        MeasureMetaData::new(MeasureStartEnd::MeasureStart),
+                        0,
+                        xml_measure_idx,
+                    );
+                    ir_musical_part.push_meta_end(MeasureMetaData::new(MeasureStartEnd::MeasureEnd));
+                }
+            }
         } // Process next measure in part
         // let voice_cnt = if voices.len() > MeasureChecker::MAX_SUPPORTED_VOICES {
         //     info!(
@@ -216,13 +358,26 @@ pub fn multipartxml_to_ir(docstring: String, _dump_input: bool, input_filename:
         //     voices.len()
         // };
         // ir_musical_part.set_num_voices(voice_cnt);
+        let declares_key = xml_measures
+            .iter()
+            .any(|m| m.descendants().any(|n| n.has_tag_name("fifths")));
+        if options.estimate_key && !declares_key {
+            if let Some(key_sig) = ir_musical_part.apply_estimated_key() {
+                info!("{} declares no key, using the estimated {}", ir_part_str, key_sig.major_name());
+            }
+        }
+        ir_musical_part.check_ties(options.tie_policy);
+        ir_musical_part.omit_derived_staves();
+        if options.lint {
+            ir_musical_part.lint();
+        }
         total_voices += ir_musical_part.get_num_voices();
         if !remove_cur_part {
             ir_part_map
                 .push_part(ir_part_str.as_str(), ir_musical_part)
                 .expect("Failed t push musical part to part map");
         } else {
-            println!("Remove part {}", ir_part_str);
+            info!("Remove part {}", ir_part_str);
             ir_part_map.remove_part(ir_part_str.as_str());
             remove_cur_part = false;
         }
@@ -232,12 +387,12 @@ pub fn multipartxml_to_ir(docstring: String, _dump_input: bool, input_filename:
         // voice_cnt,
         // );
     } // Process next part or loop completed
-    if ir_part_map.num_part_ids() == MAX_SUPPORTED_PARTS {
-        println!("Total voices is {}", total_voices);
+    if ir_part_map.num_part_ids() == options.max_parts {
+        info!("Total voices is {}", total_voices);
     }
     // At this point, any vec_idx that is still None in the parts list can be discarded from the BTreeMap
     let parts_removed = ir_part_map.get_removed_parts();
-    println!("Processing step removed {} parts", parts_removed);
+    info!("Processing step removed {} parts", parts_removed);
 
     // Combine parts into one part
     // if total_voice == 4 && ir_part_map.num_parts() == 4 {
@@ -245,3 +400,74 @@ pub fn multipartxml_to_ir(docstring: String, _dump_input: bool, input_filename:
     // }
     Ok(ir_part_map)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::multipartxml_to_ir;
+    use crate::error::Error;
+    use crate::ir::notation::{BeatType, Beats, KeySignature, MusicElement, NoteData, NumericPitchRest, RhythmType};
+    use crate::ir::{ParseOptions, PartLimitPolicy};
+    use std::fs;
+
+    #[test]
+    fn test_too_many_parts_error_and_truncate() {
+        let parse = |options: &ParseOptions| {
+            let docstring = fs::read_to_string("test/five_parts.musicxml").unwrap();
+            multipartxml_to_ir(docstring, false, "five_parts.musicxml", options)
+        };
+        assert_eq!(
+            parse(&ParseOptions::default()).unwrap_err(),
+            Error::TooManyParts { found: 5, max: 4 }
+        );
+
+        let options = ParseOptions {
+            part_limit: PartLimitPolicy::Truncate,
+            ..ParseOptions::default()
+        };
+        let part_map = parse(&options).unwrap();
+        assert_eq!(part_map.keys(), vec!["P1", "P2", "P3", "P4"]);
+        assert_eq!(part_map.num_parts(), 4);
+
+        // Raising the cap keeps every part
+        let options = ParseOptions {
+            max_parts: 5,
+            ..ParseOptions::default()
+        };
+        assert_eq!(parse(&options).unwrap().num_parts(), 5);
+    }
+
+    #[test]
+    fn test_attributes_after_empty_pickup() {
+        let docstring = fs::read_to_string("test/late_attributes.musicxml").unwrap();
+        let part_map =
+            multipartxml_to_ir(docstring, false, "late_attributes.musicxml", &ParseOptions::default()).unwrap();
+        let part = part_map.get_part(0).unwrap();
+        assert_eq!(part.get_initial_divisions(), Some(2));
+        assert_eq!(part.num_measures(), 2);
+
+        // The pickup is read with the time and key declared in the following measure
+        let inits: Vec<_> = part
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::MeasureInit(init) => Some(*init),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(inits.len(), 1);
+        assert_eq!((inits[0].beats, inits[0].beat_type), (Beats::Three, BeatType::Four));
+        assert_eq!(inits[0].key_sig, KeySignature::GMajorEminor);
+
+        let notes: Vec<&NoteData> = part
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some(n),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(notes.len(), 5);
+        assert_eq!(notes[0].note_rest, NumericPitchRest::Rest);
+        assert_eq!(notes[1].note_type, RhythmType::Crochet);
+    }
+}
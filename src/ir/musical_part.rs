@@ -1,8 +1,13 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use num::integer::lcm;
-use super::{measure_checker::MeasureChecker, notation::{MeasureInitializer, MeasureMetaData, MusicElement, PhraseDynamics}};
+use num_traits::FromPrimitive;
+use super::{measure_checker::{MeasureChecker, MeasureCorrection}, notation::{get_staff, Chord, Clef, KeySignature, MeasureInitializer, MeasureMetaData, MeasureStartEnd, MusicElement, NoteConnection, NoteData, NumericPitchRest, PercussionData, PhraseDynamics, PitchSpelling, Placement, Staff, Tempo, Voice}};
+use super::{compute_provenance, estimate_key, OctaveShiftPolicy, PartStats, Provenance, TiePolicy};
+use super::lint::{lint_elements, LintIssue};
+use super::unfold::unfold_elements;
 use crate::error::{Result,Error};
-use log::{trace,error};
+use crate::utils::sampling::SplitMix64;
+use log::{trace,error,warn};
 
 type VoiceIdx = u8;
 type MeasureIdx = usize;
@@ -35,6 +40,98 @@ impl DivisionsVec {
     }
 }
 
+/// The instrument name and optional abbreviation shown for a part in the score's part list
+#[derive(Eq, PartialEq, Default, Debug, Clone)]
+pub struct PartName {
+    pub name: String,
+    pub abbreviation: Option<String>,
+}
+
+impl PartName {
+    pub fn new(name: &str, abbreviation: Option<&str>) -> PartName {
+        PartName {
+            name: name.to_string(),
+            abbreviation: abbreviation.map(|a| a.to_string()),
+        }
+    }
+}
+
+/// The number a measure is shown with in the source score, kept where it differs from the measure's
+/// position, such as an implicit pickup measure numbered 0
+#[derive(Eq, PartialEq, Default, Debug, Clone)]
+pub struct MeasureNumber {
+    pub number: String,
+    pub implicit: bool,
+}
+
+impl MeasureNumber {
+    /// Prefix of the bin metadata strings holding measure numbers
+    const METADATA_KEY: &'static str = "measure-number";
+
+    /// Encodes the number of the measure at the zero based `measure_idx` as a bin metadata string
+    pub fn to_metadata(&self, measure_idx: usize) -> String {
+        let implicit = if self.implicit { "implicit" } else { "" };
+        format!("{}:{}:{}:{}", Self::METADATA_KEY, measure_idx, implicit, self.number)
+    }
+
+    /// Decodes a metadata string written by `to_metadata`, returning `None` for other metadata
+    pub fn from_metadata(s: &str) -> Option<(MeasureIdx, MeasureNumber)> {
+        let mut fields = s.splitn(4, ':');
+        if fields.next()? != Self::METADATA_KEY {
+            return None;
+        }
+        let measure_idx = fields.next()?.parse::<MeasureIdx>().ok()?;
+        let implicit = fields.next()? == "implicit";
+        let number = fields.next()?.to_string();
+        Some((measure_idx, MeasureNumber { number, implicit }))
+    }
+}
+
+/// A break the source score starts a measure with, from the `new-system` or `new-page` attribute of
+/// its `<print>` element
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum LayoutBreak {
+    System,
+    Page,
+}
+
+impl LayoutBreak {
+    /// Prefix of the bin metadata strings holding layout breaks
+    const METADATA_KEY: &'static str = "layout-break";
+
+    fn name(self) -> &'static str {
+        match self {
+            LayoutBreak::System => "system",
+            LayoutBreak::Page => "page",
+        }
+    }
+
+    /// Encodes a break before the measure at the zero based `measure_idx` as a bin metadata string
+    pub fn to_metadata(self, measure_idx: usize) -> String {
+        format!("{}:{}:{}", Self::METADATA_KEY, measure_idx, self.name())
+    }
+
+    /// Decodes a metadata string written by `to_metadata`, returning `None` for other metadata
+    pub fn from_metadata(s: &str) -> Option<(MeasureIdx, LayoutBreak)> {
+        let mut fields = s.splitn(3, ':');
+        if fields.next()? != Self::METADATA_KEY {
+            return None;
+        }
+        let measure_idx = fields.next()?.parse::<MeasureIdx>().ok()?;
+        let layout_break = match fields.next()? {
+            "system" => LayoutBreak::System,
+            "page" => LayoutBreak::Page,
+            _ => return None,
+        };
+        Some((measure_idx, layout_break))
+    }
+
+    /// The `<print>` element starting a measure with this break
+    pub fn print_fragment(self) -> String {
+        format!("<print new-{}=\"yes\"/>", self.name())
+    }
+}
+
 #[derive(Eq, PartialEq, Default, Debug, Clone)]
 pub struct MusicalPart {
     elems: Vec<MusicElement>,
@@ -42,9 +139,40 @@ pub struct MusicalPart {
     measure_checker: Option<MeasureChecker>,
     part_str: String,
     voices: BTreeSet<VoiceIdx>,
+    // Index of each source voice, assigned in the order the voices are first seen so a voice appearing
+    // after a higher numbered one does not shift the index of the voices already read
+    voice_indices: BTreeMap<VoiceIdx, usize>,
     // The index in the vector of elements containing the most recent Measure Initializer
     cur_init_measure_idx: Option<MeasureIdx>,
     pub cur_phrase_dyn: Option<PhraseDynamics>,
+    // Placement of the direction holding cur_phrase_dyn
+    pub cur_dyn_placement: Placement,
+    part_name: Option<PartName>,
+    // Source numbers of the measures not numbered by their position, by zero based measure index
+    measure_numbers: BTreeMap<MeasureIdx, MeasureNumber>,
+    // System and page breaks of the source score, by zero based measure index
+    layout_breaks: BTreeMap<MeasureIdx, LayoutBreak>,
+    // First voice written to the bass clef staff, when known from combining parts
+    lower_staff_voice: Option<Voice>,
+    // Measures whose voices had to be padded with rests, and notes skipped for exceeding the voice limit
+    discrepancies: usize,
+    // The rests padding each voice that fell short
+    corrections: Vec<MeasureCorrection>,
+    dropped_notes: usize,
+    // Notes removed for having no duration at the part's divisions
+    zero_duration_notes: usize,
+    // Ties started without a matching stop, found by check_ties
+    dangling_ties: usize,
+    // Unbalanced repeats and endings, found by lint
+    lint_issues: Vec<LintIssue>,
+    // Clef of each exported staff, when set explicitly instead of derived from the part name
+    clefs: Option<Vec<Clef>>,
+    // Spelling of black key pitches in exported MusicXML
+    spelling: PitchSpelling,
+    // Clef and clef octave change of each staff while parsing, to tell clef changes from the initial clefs
+    cur_clefs: [Option<(Clef, i8)>; 2],
+    // Source measure index of each measure, for parts parsed from MusicXML
+    source_measures: Vec<usize>,
 }
 
 impl MusicalPart {
@@ -59,8 +187,24 @@ impl MusicalPart {
             measure_checker: None,
             part_str: part_str.to_string(),
             voices: BTreeSet::new(),
+            voice_indices: BTreeMap::new(),
             cur_init_measure_idx: None,
             cur_phrase_dyn: None,
+            cur_dyn_placement: Placement::Default,
+            part_name: None,
+            measure_numbers: BTreeMap::new(),
+            layout_breaks: BTreeMap::new(),
+            lower_staff_voice: None,
+            discrepancies: 0,
+            corrections: vec![],
+            dropped_notes: 0,
+            zero_duration_notes: 0,
+            dangling_ties: 0,
+            lint_issues: vec![],
+            clefs: None,
+            spelling: PitchSpelling::default(),
+            cur_clefs: [None; 2],
+            source_measures: vec![],
         };
         temp_mpart.update_divisions_voices()?;
         Ok(temp_mpart)
@@ -73,9 +217,425 @@ impl MusicalPart {
             measure_checker: None,
             part_str: part_str.to_string(),
             voices: BTreeSet::new(),
+            voice_indices: BTreeMap::new(),
             cur_init_measure_idx: None,
             cur_phrase_dyn: None,
+            cur_dyn_placement: Placement::Default,
+            part_name: None,
+            measure_numbers: BTreeMap::new(),
+            layout_breaks: BTreeMap::new(),
+            lower_staff_voice: None,
+            discrepancies: 0,
+            corrections: vec![],
+            dropped_notes: 0,
+            zero_duration_notes: 0,
+            dangling_ties: 0,
+            lint_issues: vec![],
+            clefs: None,
+            spelling: PitchSpelling::default(),
+            cur_clefs: [None; 2],
+            source_measures: vec![],
+        }
+    }
+    pub fn get_part_str(&self) -> &str {
+        self.part_str.as_str()
+    }
+    pub fn get_part_name(&self) -> Option<&PartName> {
+        self.part_name.as_ref()
+    }
+    pub fn set_part_name(&mut self, part_name: Option<PartName>) {
+        self.part_name = part_name;
+    }
+    pub fn get_measure_numbers(&self) -> &BTreeMap<MeasureIdx, MeasureNumber> {
+        &self.measure_numbers
+    }
+    pub fn get_layout_breaks(&self) -> &BTreeMap<MeasureIdx, LayoutBreak> {
+        &self.layout_breaks
+    }
+    /// Records a break before the measure at the zero based `measure_idx`
+    pub fn set_layout_break(&mut self, measure_idx: MeasureIdx, layout_break: LayoutBreak) {
+        self.layout_breaks.insert(measure_idx, layout_break);
+    }
+    /// Records the source number of the measure at the zero based `measure_idx`. Numbers matching
+    /// the measure's position are not stored, since export numbers measures that way.
+    pub fn set_measure_number(&mut self, measure_idx: MeasureIdx, number: MeasureNumber) {
+        if number.implicit || number.number != (measure_idx + 1).to_string() {
+            self.measure_numbers.insert(measure_idx, number);
+        } else {
+            self.measure_numbers.remove(&measure_idx);
+        }
+    }
+    pub fn get_lower_staff_voice(&self) -> Option<Voice> {
+        self.lower_staff_voice
+    }
+    pub fn set_lower_staff_voice(&mut self, voice: Option<Voice>) {
+        self.lower_staff_voice = voice;
+    }
+    pub fn set_clefs(&mut self, clefs: Option<Vec<Clef>>) {
+        self.clefs = clefs;
+    }
+    pub fn set_spelling(&mut self, spelling: PitchSpelling) {
+        self.spelling = spelling;
+    }
+    pub fn get_spelling(&self) -> PitchSpelling {
+        self.spelling
+    }
+    /// Clefs of the staves the part is exported on: the explicitly set clefs, or else those suited to
+    /// the part's instrument name, or else a treble and bass staff
+    pub fn get_clefs(&self) -> Vec<Clef> {
+        self.clefs
+            .clone()
+            .or_else(|| {
+                self.part_name
+                    .as_ref()
+                    .and_then(|p| Clef::from_part_name(&p.name))
+            })
+            .unwrap_or_else(|| Clef::PIANO.to_vec())
+    }
+    /// Records the clef of a staff and its octave change, returning whether it changes the clef already
+    /// in effect. A first clef with an octave change counts as a change of the plain initial clef.
+    pub fn update_clef(&mut self, staff: Staff, clef: Clef, octave_change: i8) -> bool {
+        match self.cur_clefs[staff as usize - 1].replace((clef, octave_change)) {
+            Some(prev_clef) => prev_clef != (clef, octave_change),
+            None => octave_change != 0,
+        }
+    }
+    pub fn get_staff(&self, voice: Voice) -> String {
+        if self.get_clefs().len() == 1 {
+            return 1.to_string();
+        }
+        get_staff(voice, self.get_num_voices(), self.lower_staff_voice)
+    }
+    pub fn get_discrepancies(&self) -> usize {
+        self.discrepancies
+    }
+    /// Rests inserted while parsing to make each measure's voices add up, one entry per padded voice
+    pub fn get_corrections(&self) -> &[MeasureCorrection] {
+        &self.corrections
+    }
+    pub fn get_dropped_notes(&self) -> usize {
+        self.dropped_notes
+    }
+    pub fn note_dropped(&mut self) {
+        self.dropped_notes += 1;
+    }
+    pub fn get_zero_duration_notes(&self) -> usize {
+        self.zero_duration_notes
+    }
+    pub fn get_dangling_ties(&self) -> usize {
+        self.dangling_ties
+    }
+    /// Finds tie starts that are never stopped on the same pitch and voice, either because the part ends
+    /// or because the next note of that pitch starts a new tie. With `TiePolicy::Fix` the dangling ties are removed.
+    pub fn check_ties(&mut self, policy: TiePolicy) -> usize {
+        let mut open_ties: BTreeMap<(u8, u8), usize> = BTreeMap::new();
+        let mut dangling = vec![];
+        for (idx, elem) in self.elems.iter().enumerate() {
+            if let MusicElement::NoteRest(n) = elem {
+                let key = (n.voice as u8, n.note_rest.get_numeric_value());
+                match n.ties {
+                    NoteConnection::StartTie => {
+                        if let Some(open_idx) = open_ties.insert(key, idx) {
+                            dangling.push(open_idx);
+                        }
+                    }
+                    NoteConnection::EndTie => {
+                        open_ties.remove(&key);
+                    }
+                    NoteConnection::None => {}
+                }
+            }
+        }
+        dangling.extend(open_ties.into_values());
+        dangling.sort_unstable();
+
+        for idx in dangling.iter() {
+            if let MusicElement::NoteRest(n) = &mut self.elems[*idx] {
+                warn!(
+                    "{} tie starting on {} in voice {} is never stopped{}",
+                    self.part_str,
+                    n.note_rest,
+                    n.voice as u8 + 1,
+                    if policy == TiePolicy::Fix { ", removing it" } else { "" }
+                );
+                if policy == TiePolicy::Fix {
+                    n.ties = NoteConnection::None;
+                }
+            }
+        }
+        self.dangling_ties = dangling.len();
+        self.dangling_ties
+    }
+    /// Clears the staff of every note the voice already routes to that staff, so only the staves
+    /// differing from the derived ones are stored, returning how many staves are kept
+    pub fn omit_derived_staves(&mut self) -> usize {
+        let num_voices = self.get_num_voices();
+        let mut kept = 0;
+        for elem in self.elems.iter_mut() {
+            if let MusicElement::NoteRest(n) | MusicElement::Percussion(PercussionData { note: n, .. }) = elem {
+                if let Some(staff) = n.staff {
+                    if (staff as u8).to_string() == get_staff(n.voice, num_voices, self.lower_staff_voice) {
+                        n.staff = None;
+                    } else {
+                        kept += 1;
+                    }
+                }
+            }
+        }
+        kept
+    }
+    /// Staff a note is written on: the first on a single staff part, otherwise the staff the score put
+    /// it on, or else the one derived from its voice
+    pub fn get_note_staff(&self, note: &NoteData) -> String {
+        match note.staff {
+            Some(staff) if self.get_clefs().len() > 1 => (staff as u8).to_string(),
+            _ => self.get_staff(note.voice),
+        }
+    }
+    pub fn get_lint_issues(&self) -> &[LintIssue] {
+        &self.lint_issues
+    }
+    /// Checks that the repeats and endings of the part are balanced, logging and keeping every issue found
+    pub fn lint(&mut self) -> usize {
+        self.lint_issues = lint_elements(&self.elems);
+        for issue in self.lint_issues.iter() {
+            warn!("{} {}", self.part_str, issue);
+        }
+        self.lint_issues.len()
+    }
+    /// Removes measure initializers equal to the one already in effect, returning how many were removed
+    pub fn coalesce_initializers(&mut self) -> usize {
+        let num_elems = self.elems.len();
+        let mut active_init: Option<MeasureInitializer> = None;
+        self.elems.retain(|e| match e {
+            MusicElement::MeasureInit(init) if active_init == Some(*init) => false,
+            MusicElement::MeasureInit(init) => {
+                active_init = Some(*init);
+                true
+            }
+            _ => true,
+        });
+        // Element indices have shifted, so the tracked initializer must be looked up again
+        self.cur_init_measure_idx = self
+            .elems
+            .iter()
+            .rposition(|e| matches!(e, MusicElement::MeasureInit(_)));
+        num_elems - self.elems.len()
+    }
+    /// Numbers the voices the part's notes use from voice one upwards, keeping their order, so a part
+    /// using only voices one and three stores them as one and two. Returns whether any voice moved.
+    pub fn renumber_voices(&mut self) -> bool {
+        let mut used = BTreeSet::new();
+        for elem in self.elems.iter() {
+            if let MusicElement::NoteRest(n) | MusicElement::Percussion(PercussionData { note: n, .. }) = elem {
+                used.insert(n.voice as u8);
+            }
+        }
+        let renumbered = |voice: Voice| {
+            used.iter()
+                .position(|&v| v == voice as u8)
+                .and_then(Voice::from_usize)
+                .unwrap_or(voice)
+        };
+        let mut moved = false;
+        for elem in self.elems.iter_mut() {
+            if let MusicElement::NoteRest(n) | MusicElement::Percussion(PercussionData { note: n, .. }) = elem {
+                let voice = renumbered(n.voice);
+                moved |= voice != n.voice;
+                n.voice = voice;
+            }
+        }
+        // The first voice of the lower staff keeps its place among the voices
+        self.lower_staff_voice = self.lower_staff_voice.map(|v| {
+            Voice::from_usize(used.iter().filter(|&&u| u < v as u8).count()).unwrap_or(v)
+        });
+        self.voices = (0..used.len() as u8).collect();
+        self.voice_indices = self.voices.iter().map(|&v| (v, v as usize)).collect();
+        moved
+    }
+    /// Rewrites the part in a canonical form, so parts that only differ in divisions, voice numbers,
+    /// repeated attributes or accidental spelling store the same bytes: the voices are renumbered
+    /// compactly, redundant initializers and derived staves are dropped, the divisions are the smallest
+    /// that express every note and black keys are spelled by the default spelling. Canonicalizing a
+    /// canonical part leaves it unchanged.
+    pub fn canonicalize(&mut self) {
+        self.renumber_voices();
+        self.coalesce_initializers();
+        self.omit_derived_staves();
+        self.divisions = Some(self.min_divisions());
+        self.spelling = PitchSpelling::default();
+    }
+    /// Transposes every pitch, key signature and chord symbol by `semitones`. Fails without changing
+    /// the part when a pitch would leave the supported range.
+    pub fn transpose(&mut self, semitones: i8) -> Result<()> {
+        let mut transposed = self.elems.clone();
+        let mut flats = false;
+        for elem in transposed.iter_mut() {
+            match elem {
+                MusicElement::MeasureInit(init) => {
+                    init.key_sig = init.key_sig.transpose(semitones);
+                    flats = init.key_sig.uses_flats();
+                }
+                MusicElement::NoteRest(n) => {
+                    n.note_rest = n.note_rest.transpose(semitones)?;
+                }
+                MusicElement::Harmony(h) => {
+                    *h = h.transpose(semitones, flats);
+                }
+                // Unpitched notes are written at a fixed staff position for their drum
+                MusicElement::MeasureMeta(_)
+                | MusicElement::Tuplet(_)
+                | MusicElement::ClefChange(_)
+                | MusicElement::Percussion(_) => {}
+            }
+        }
+        self.elems = transposed;
+        Ok(())
+    }
+    /// Moves every pitch by `octaves` octaves, leaving the key signatures and chord symbols as they
+    /// are. Notes that would leave the supported range of C0 to C8 are handled by `policy`. Returns the
+    /// number of notes that were clamped or dropped.
+    pub fn shift_octaves(&mut self, octaves: i8, policy: OctaveShiftPolicy) -> usize {
+        let mut out_of_range = 0;
+        let mut shifted = Vec::with_capacity(self.elems.len());
+        // Whether the note the current chord tones are stacked on was replaced by a rest
+        let mut chord_dropped = false;
+        for elem in self.elems.iter() {
+            let MusicElement::NoteRest(n) = elem else {
+                shifted.push(*elem);
+                continue;
+            };
+            let (note_rest, clamped) = n.note_rest.shift_octaves_clamped(octaves);
+            if clamped {
+                out_of_range += 1;
+            }
+            match (policy, clamped, n.chord) {
+                (OctaveShiftPolicy::Drop, true, Chord::Chord) => {}
+                (OctaveShiftPolicy::Drop, true, Chord::NoChord) => {
+                    chord_dropped = true;
+                    shifted.push(MusicElement::NoteRest(NoteData {
+                        note_rest: NumericPitchRest::Rest,
+                        ties: NoteConnection::None,
+                        ..*n
+                    }));
+                }
+                // The first kept chord tone takes the place of the dropped note
+                (_, _, Chord::Chord) if chord_dropped => {
+                    chord_dropped = false;
+                    shifted.pop();
+                    shifted.push(MusicElement::NoteRest(NoteData {
+                        note_rest,
+                        chord: Chord::NoChord,
+                        ..*n
+                    }));
+                }
+                (_, _, chord) => {
+                    if chord == Chord::NoChord {
+                        chord_dropped = false;
+                    }
+                    shifted.push(MusicElement::NoteRest(NoteData { note_rest, ..*n }));
+                }
+            }
+        }
+        self.elems = shifted;
+        out_of_range
+    }
+    /// Perturbs the part for data augmentation, deterministically for a given seed. Every measure
+    /// initializer's tempo moves by up to `tempo_range` BPM either way, kept within the supported
+    /// tempos, and every note's dynamic level moves one step louder, softer or stays.
+    pub fn augment(&mut self, seed: u64, tempo_range: u8) {
+        let mut rng = SplitMix64::new(seed);
+        let tempo_range = i32::from(tempo_range);
+        for elem in self.elems.iter_mut() {
+            match elem {
+                MusicElement::MeasureInit(init) => {
+                    let delta = rng.below(2 * tempo_range as usize + 1) as i32 - tempo_range;
+                    init.tempo = Tempo::new(init.tempo.get_actual() + delta);
+                }
+                MusicElement::NoteRest(n) | MusicElement::Percussion(PercussionData { note: n, .. }) => {
+                    n.phrase_dynamics = n.phrase_dynamics.step(rng.below(3) as i8 - 1);
+                }
+                MusicElement::MeasureMeta(_)
+                | MusicElement::Tuplet(_)
+                | MusicElement::Harmony(_)
+                | MusicElement::ClefChange(_) => {}
+            }
+        }
+    }
+    /// Returns a copy of the part with repeats, endings and D.C./D.S. jumps written out in
+    /// performance order
+    pub fn unfold(&self) -> Result<MusicalPart> {
+        let mut unfolded = self.clone();
+        unfolded.elems = unfold_elements(&self.elems)?;
+        // Repeated measures no longer line up with the source numbering
+        unfolded.measure_numbers.clear();
+        unfolded.layout_breaks.clear();
+        unfolded.cur_init_measure_idx = unfolded
+            .elems
+            .iter()
+            .rposition(|e| matches!(e, MusicElement::MeasureInit(_)));
+        Ok(unfolded)
+    }
+    /// The source measure and beat of every element, parallel to `inner()`. Only parts parsed from
+    /// MusicXML know where their measures came from, and only while no measures have been added or
+    /// removed since.
+    pub fn provenance(&self) -> Option<Vec<Provenance>> {
+        if self.source_measures.is_empty() || self.source_measures.len() != self.num_measures() {
+            return None;
+        }
+        Some(compute_provenance(&self.elems, &self.source_measures))
+    }
+    pub fn num_measures(&self) -> usize {
+        self.elems
+            .iter()
+            .filter(|e| {
+                matches!(
+                    e,
+                    MusicElement::MeasureMeta(MeasureMetaData {
+                        start_end: MeasureStartEnd::MeasureEnd | MeasureStartEnd::RepeatEnd,
+                        ..
+                    })
+                )
+            })
+            .count()
+    }
+    /// Sets the key of every measure initializer to the key estimated from the notes, for parts that
+    /// declare none. Returns the estimate, or `None` when there are no notes to estimate it from.
+    pub fn apply_estimated_key(&mut self) -> Option<KeySignature> {
+        let key_sig = estimate_key(&self.elems)?;
+        for elem in self.elems.iter_mut() {
+            if let MusicElement::MeasureInit(init) = elem {
+                init.key_sig = key_sig;
+            }
+        }
+        Some(key_sig)
+    }
+    pub fn stats(&self) -> PartStats {
+        PartStats::from_elements(&self.elems)
+    }
+    /// Returns the notes and other content of the completed measure this many measures back
+    pub fn previous_measure_elems(&self, measures_back: usize) -> Option<Vec<MusicElement>> {
+        let mut measure_ranges = vec![];
+        let mut measure_start = None;
+        for (idx, elem) in self.elems.iter().enumerate() {
+            if let MusicElement::MeasureMeta(meta) = elem {
+                match meta.start_end {
+                    MeasureStartEnd::MeasureStart | MeasureStartEnd::RepeatStart => {
+                        measure_start = Some(idx + 1);
+                    }
+                    MeasureStartEnd::MeasureEnd | MeasureStartEnd::RepeatEnd => {
+                        if let Some(start) = measure_start.take() {
+                            measure_ranges.push(start..idx);
+                        }
+                    }
+                }
+            }
         }
+        let range = measure_ranges
+            .len()
+            .checked_sub(measures_back)
+            .and_then(|idx| measure_ranges.get(idx))?;
+        Some(self.elems[range.clone()].to_vec())
     }
     pub fn len(&self) -> usize {
         self.elems.len()
@@ -96,15 +656,25 @@ impl MusicalPart {
     pub fn get_num_voices(&self) -> usize {
         self.voices.len()
     }
+    /// Reserves the voices of a part with more voices than are supported, chosen before its notes are
+    /// read, so notes of any other voice are refused by `insert_new_voice` whatever order they come in.
+    /// The reserved voices keep their order.
+    pub fn keep_voices(&mut self, voices: BTreeSet<VoiceIdx>) {
+        self.voice_indices = voices.iter().enumerate().map(|(idx, &v)| (v, idx)).collect();
+        self.voices = voices;
+    }
+    /// Returns the index of a source voice, giving a voice not seen before the next free index
     pub fn insert_new_voice(&mut self, voice_num: VoiceIdx) -> Result<usize> {
-        self.voices.insert(voice_num);
-        if self.voices.len() > MeasureChecker::MAX_SUPPORTED_VOICES {
-            // Don't let the number of voices in the voices set exceed the maximum
-            self.voices.remove(&voice_num);
-            Err(Error::OutofBounds)
-        } else {
-            Ok(self.voices.iter().position(|&x| x == voice_num).unwrap())
+        if let Some(&voice_idx) = self.voice_indices.get(&voice_num) {
+            return Ok(voice_idx);
+        }
+        if self.voices.len() >= MeasureChecker::MAX_SUPPORTED_VOICES {
+            return Err(Error::OutofBounds);
         }
+        let voice_idx = self.voice_indices.len();
+        self.voices.insert(voice_num);
+        self.voice_indices.insert(voice_num, voice_idx);
+        Ok(voice_idx)
     }
     fn push(&mut self, elem: MusicElement) {
         self.elems.push(elem);
@@ -151,6 +721,7 @@ impl MusicalPart {
             panic!("Could not pattern match MusicElement::MeasureInit at target index.");
         };
         self.elems.push(MusicElement::MeasureMeta(meta_start));
+        self.source_measures.push(xml_measure_idx);
     }
     pub fn push_measure_elem(&mut self, measure_elem: MusicElement) {
         if let Some(measure_checker) = &mut self.measure_checker {
@@ -168,7 +739,12 @@ impl MusicalPart {
     }
     pub fn push_meta_end(&mut self, meta_end: MeasureMetaData) {
         if let Some(measure_checker) = &mut self.measure_checker {
+            measure_checker.remove_zero_duration_notes();
+            measure_checker.fill_empty_measure();
             measure_checker.remove_incomplete_voices(&self.voices);
+            self.discrepancies += measure_checker.discrepancies();
+            self.corrections.extend_from_slice(measure_checker.corrections());
+            self.zero_duration_notes += measure_checker.zero_duration_notes();
             self.elems.append(measure_checker.as_inner());
             self.elems.push(MusicElement::MeasureMeta(meta_end));
         } else {
@@ -191,6 +767,27 @@ impl MusicalPart {
     }
 
     fn update_divisions_voices(&mut self) -> Result<()> {
+        for elem in (&self.elems).iter() {
+            trace!("{:?}", elem);
+            if let MusicElement::NoteRest(n) | MusicElement::Percussion(PercussionData { note: n, .. }) = elem {
+                self.voices.insert(n.voice as u8);
+                self.voice_indices.insert(n.voice as u8, n.voice as usize);
+            }
+        }
+        if self.voices.len() > MusicalPart::MAX_SUPPORTED_VOICES {
+            error!(
+                "Maximum supported voices is {} but piece contains {}.",
+                MusicalPart::MAX_SUPPORTED_VOICES,
+                self.voices.len()
+            );
+            return Err(Error::OutofBounds);
+        }
+        self.divisions = Some(self.min_divisions());
+        Ok(())
+    }
+
+    /// Smallest divisions per quarter note that express the duration of every note exactly
+    pub fn min_divisions(&self) -> u32 {
         // For tuplets, the associated note type is embedded in the NoteData type. The Tuplet data information element
         // precedes the note data element, so to determine the shortest value represented in the piece, both the tuplet information
         // is needed and all of the notes within the tuplet section. For the minimum, we're looking for the shortest note type
@@ -199,32 +796,31 @@ impl MusicalPart {
 
         let mut integers_v = DivisionsVec::new();
         let mut time_mod = None;
-
-        for elem in (&self.elems).iter() {
-            trace!("{:?}", elem);
+        for elem in self.elems.iter() {
             match elem {
                 MusicElement::Tuplet(t) => {
                     time_mod = (*t).into();
                 }
-                MusicElement::NoteRest(n) => {
-                    self.voices.insert(n.voice as u8);
+                MusicElement::NoteRest(n) | MusicElement::Percussion(PercussionData { note: n, .. }) => {
                     integers_v.add(n.get_note_multiple(time_mod).map_or_else(|| 0, |v| v));
                 }
                 _ => {}
             }
         }
-        if self.voices.len() > MusicalPart::MAX_SUPPORTED_VOICES {
-            error!(
-                "Maximum supported voices is {} but piece contains {}.",
-                MusicalPart::MAX_SUPPORTED_VOICES,
-                self.voices.len()
-            );
-            return Err(Error::OutofBounds);
+        integers_v.find_lcm()
+    }
+
+    /// Sets the divisions per quarter note the part is exported with, failing if a note
+    /// duration would not be a whole number of divisions
+    pub fn set_output_divisions(&mut self, divisions: u32) -> Result<()> {
+        let min_divisions = self.min_divisions();
+        if divisions == 0 || divisions % min_divisions != 0 {
+            return Err(Error::Validation(format!(
+                "Part {} cannot be written with {} divisions, its notes need a multiple of {}",
+                self.part_str, divisions, min_divisions
+            )));
         }
-        self.divisions = Some(integers_v.find_lcm());
-        // for (idx, elem) in integers_v.inner().iter().enumerate() {
-        //     println!("{idx},{elem}");
-        // }
+        self.divisions = Some(divisions);
         Ok(())
     }
 }
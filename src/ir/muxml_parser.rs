@@ -2,16 +2,20 @@ use log::warn;
 use mulib::pitch::{Alter, Octave, Pitch, PitchOctave, Step};
 use num_traits::FromPrimitive;
 use roxmltree::*;
+use std::collections::{BTreeMap, BTreeSet};
 use std::str::FromStr;
 use strum::EnumCount;
 
 use crate::ir::notation::{
-    Arpeggiate, Articulation, Chord, NoteConnection, NoteData, NumericPitchRest, PhraseDynamics,
-    RhythmType, SlurConnection, SpecialNote, TimeModification, TupletData, TupletStartStop,
+    Arpeggiate, Articulation, BarStyle, Chord, Clef, ClefChangeData, DrumSound, HarmonyAlter, HarmonyData, HarmonyKind,
+    HarmonyStep, MeasureMetaData, NoteConnection, NoteData, NoteSize, Notehead, NumericPitchRest, PercussionData,
+    PhraseDynamics, Placement, RhythmType, SlurConnection, SlurNumber, SpecialNote, Staff, TimeModification,
+    Tremolo, TremoloType, TupletData, TupletStartStop,
 };
-use crate::ir::{MusicElement, TupletNumber};
+use crate::error::{Error, Result};
+use crate::ir::{MusicElement, ParseOptions, TupletNumber, VoiceLimitPolicy};
 
-use super::MusicalPart;
+use super::{LayoutBreak, MeasureNumber, MusicalPart, PartName};
 
 const MAX_NUMBER_OF_SUPPORTED_TUPLET_ELEMENTS: usize = TupletNumber::COUNT;
 
@@ -29,20 +33,459 @@ pub fn parse_backup_tag(measure_element: &Node<'_, '_>, part: &mut MusicalPart)
     part.update_backup_duration(duration_val as usize);
 }
 
+/// Velocity of forte, which the `dynamics` attribute of `<sound>` is a percentage of
+const SOUND_DYNAMICS_FORTE_VELOCITY: f64 = 90.0;
+
 pub fn parse_direction_tag(measure_element: &Node<'_, '_>, part: &mut MusicalPart) {
 
     let xml_dynamics_tag = measure_element
-        .children()
+        .descendants()
         .find(|n| n.has_tag_name("dynamics"));
+    // Playback dynamics as a percentage of forte, used when there is no marking to read
+    let sound_dynamics = measure_element
+        .children()
+        .find(|n| n.has_tag_name("sound"))
+        .and_then(|n| n.attribute("dynamics"))
+        .and_then(|d| d.trim().parse::<f64>().ok());
 
-    if xml_dynamics_tag.is_some() {
-        part.cur_phrase_dyn = match PhraseDynamics::from_str(xml_dynamics_tag.unwrap().first_element_child().unwrap().tag_name().name()) {
-            Ok(t) => Some(t),
-            Err(_) => None,
-        };
+    if let Some(xml_dynamics_tag) = xml_dynamics_tag {
+        part.cur_phrase_dyn = xml_dynamics_tag
+            .first_element_child()
+            .and_then(|n| PhraseDynamics::from_str(n.tag_name().name()).ok());
+        part.cur_dyn_placement = measure_element
+            .attribute("placement")
+            .and_then(|p| Placement::from_str(p).ok())
+            .unwrap_or_default();
+    } else if let Some(sound_dynamics) = sound_dynamics {
+        part.cur_phrase_dyn = Some(PhraseDynamics::from_velocity(
+            sound_dynamics * SOUND_DYNAMICS_FORTE_VELOCITY / 100.0,
+        ));
+        part.cur_dyn_placement = Placement::Default;
     } else {
         part.cur_phrase_dyn = None;
+        part.cur_dyn_placement = Placement::Default;
+    }
+}
+
+fn parse_harmony_step_alter(
+    parent: &Node<'_, '_>,
+    step_tag: &str,
+    alter_tag: &str,
+) -> Option<(HarmonyStep, HarmonyAlter)> {
+    let step = parent
+        .children()
+        .find(|n| n.has_tag_name(step_tag))
+        .and_then(|n| n.text())
+        .and_then(|t| HarmonyStep::from_str(t.trim()).ok())?;
+    let alter = match parent.children().find(|n| n.has_tag_name(alter_tag)) {
+        Some(n) => HarmonyAlter::from_str(n.text()?.trim()).ok()?,
+        None => HarmonyAlter::None,
+    };
+    Some((step, alter))
+}
+
+pub fn parse_harmony_tag(
+    measure_element: &Node<'_, '_>,
+    part: &mut MusicalPart,
+    options: &ParseOptions,
+) -> Result<()> {
+    let root = measure_element
+        .children()
+        .find(|n| n.has_tag_name("root"))
+        .and_then(|n| parse_harmony_step_alter(&n, "root-step", "root-alter"));
+    let kind_str = measure_element
+        .children()
+        .find(|n| n.has_tag_name("kind"))
+        .and_then(|n| n.text())
+        .unwrap_or("");
+    let kind = HarmonyKind::from_str(kind_str.trim());
+
+    match (root, kind) {
+        (Some((root_step, root_alter)), Ok(kind)) => {
+            let bass = measure_element
+                .children()
+                .find(|n| n.has_tag_name("bass"))
+                .and_then(|n| parse_harmony_step_alter(&n, "bass-step", "bass-alter"));
+            part.push_measure_elem(MusicElement::Harmony(HarmonyData {
+                root_step,
+                root_alter,
+                kind,
+                bass,
+            }));
+            Ok(())
+        }
+        _ => {
+            if options.strict {
+                return Err(Error::StrictViolation(
+                    part.get_part_str().to_string(),
+                    format!("unsupported harmony kind \"{}\"", kind_str),
+                ));
+            }
+            warn!("Skipping harmony with unsupported root or kind \"{}\"", kind_str);
+            Ok(())
+        }
+    }
+}
+
+/// Pushes a clef change for each clef of an `<attributes>` element differing from the clef in effect
+/// on its staff. The first clef of each staff is the part's initial clef, so is not pushed, unless it
+/// has an octave change, which the part's clefs cannot hold.
+pub fn parse_attributes_tag(measure_element: &Node<'_, '_>, part: &mut MusicalPart) {
+    for xml_clef in measure_element.children().filter(|n| n.has_tag_name("clef")) {
+        let staff_number = xml_clef.attribute("number").unwrap_or("1");
+        let staff = match staff_number.parse::<u8>().ok().and_then(Staff::from_u8) {
+            Some(staff) => staff,
+            None => {
+                warn!("Skipping clef of unsupported staff {}", staff_number);
+                continue;
+            }
+        };
+        let sign = xml_clef
+            .children()
+            .find(|n| n.has_tag_name("sign"))
+            .and_then(|n| n.text())
+            .unwrap_or("")
+            .trim();
+        let octave_change = match xml_clef.children().find(|n| n.has_tag_name("clef-octave-change")) {
+            Some(n) => match n.text().unwrap_or("").trim().parse::<i8>() {
+                Ok(change) if change.abs() <= ClefChangeData::MAX_OCTAVE_CHANGE => change,
+                _ => {
+                    warn!("Ignoring unsupported clef octave change \"{}\"", n.text().unwrap_or(""));
+                    0
+                }
+            },
+            None => 0,
+        };
+        match Clef::from_str(sign) {
+            Ok(clef) => {
+                if part.update_clef(staff, clef, octave_change) {
+                    part.push_measure_elem(MusicElement::ClefChange(ClefChangeData {
+                        staff,
+                        clef,
+                        octave_change,
+                    }));
+                }
+            }
+            Err(_) => warn!("Skipping clef with unsupported sign \"{}\"", sign),
+        }
+    }
+}
+
+pub fn parse_score_part_tag(score_part_element: &Node<'_, '_>) -> Option<PartName> {
+    let name = score_part_element
+        .children()
+        .find(|n| n.has_tag_name("part-name"))
+        .and_then(|n| n.text())?;
+    let abbreviation = score_part_element
+        .children()
+        .find(|n| n.has_tag_name("part-abbreviation"))
+        .and_then(|n| n.text());
+    Some(PartName::new(name.trim(), abbreviation.map(|a| a.trim())))
+}
+
+/// Bar compression declared by a measure's `<measure-style>`
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum MeasureStyle {
+    /// This measure begins a run of this many whole-measure rests
+    MultipleRest(usize),
+    /// This measure and those following repeat the content from this many measures back
+    MeasureRepeatStart(usize),
+    MeasureRepeatStop,
+}
+
+pub fn parse_measure_style_tag(xml_measure: &Node<'_, '_>) -> Vec<MeasureStyle> {
+    xml_measure
+        .descendants()
+        .filter(|n| n.has_tag_name("measure-style"))
+        .flat_map(|n| n.children())
+        .filter_map(|n| match n.tag_name().name() {
+            "multiple-rest" => n
+                .text()
+                .and_then(|t| t.trim().parse::<usize>().ok())
+                .map(MeasureStyle::MultipleRest),
+            "measure-repeat" => match n.attribute("type") {
+                // The repeated pattern length defaults to a single measure when the text is omitted
+                Some("start") => Some(MeasureStyle::MeasureRepeatStart(
+                    n.text()
+                        .and_then(|t| t.trim().parse::<usize>().ok())
+                        .unwrap_or(1),
+                )),
+                Some("stop") => Some(MeasureStyle::MeasureRepeatStop),
+                t => {
+                    warn!("Ignoring measure-repeat with unsupported type {:?}", t);
+                    None
+                }
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+/// True when the measure holds no pitched or unpitched notes, such as a measure covered by a multiple rest
+pub fn is_rest_only_measure(xml_measure: &Node<'_, '_>) -> bool {
+    xml_measure
+        .children()
+        .filter(|n| n.has_tag_name("note"))
+        .all(|n| n.children().any(|c| c.has_tag_name("rest")))
+}
+
+/// Reads the `number` and `implicit` attributes of a measure
+pub fn parse_measure_number(xml_measure: &Node<'_, '_>) -> Option<MeasureNumber> {
+    let number = xml_measure.attribute("number")?;
+    Some(MeasureNumber {
+        number: number.to_string(),
+        implicit: xml_measure.attribute("implicit") == Some("yes"),
+    })
+}
+
+/// Reads the break a measure's `<print>` element starts it with. A page break also starts a new system,
+/// so it takes precedence.
+pub fn parse_print_tag(xml_measure: &Node<'_, '_>) -> Option<LayoutBreak> {
+    let xml_print = xml_measure.children().find(|n| n.has_tag_name("print"))?;
+    if xml_print.attribute("new-page") == Some("yes") {
+        Some(LayoutBreak::Page)
+    } else if xml_print.attribute("new-system") == Some("yes") {
+        Some(LayoutBreak::System)
+    } else {
+        None
+    }
+}
+
+/// Chooses the voices kept from a part that has more than `MusicalPart::MAX_SUPPORTED_VOICES`, following
+/// `policy`, or `None` when every voice of the part is supported
+pub fn select_voices(xml_part: &Node<'_, '_>, policy: VoiceLimitPolicy) -> Option<BTreeSet<u8>> {
+    let mut note_counts: BTreeMap<u8, usize> = BTreeMap::new();
+    for xml_note in xml_part.descendants().filter(|n| n.has_tag_name("note")) {
+        let voice = xml_note
+            .children()
+            .find(|n| n.has_tag_name("voice"))
+            .and_then(|n| n.text())
+            .and_then(|text| text.trim().parse::<u8>().ok());
+        if let Some(voice) = voice {
+            *note_counts.entry(voice).or_insert(0) += 1;
+        }
+    }
+    if note_counts.len() <= MusicalPart::MAX_SUPPORTED_VOICES {
+        return None;
+    }
+    let mut voices: Vec<(u8, usize)> = note_counts.into_iter().collect();
+    if policy == VoiceLimitPolicy::MostNotes {
+        // The sort is stable, so voices with as many notes stay in numeric order
+        voices.sort_by(|a, b| b.1.cmp(&a.1));
+    }
+    Some(
+        voices
+            .into_iter()
+            .take(MusicalPart::MAX_SUPPORTED_VOICES)
+            .map(|(voice, _)| voice)
+            .collect(),
+    )
+}
+
+/// Splits a possibly fractional `<alter>`, such as `0.5` for a quarter tone sharp, into whole semitones
+/// and the deviation from them in cents. A deviation of half a semitone is taken from the semitone
+/// nearer the natural, so a quarter tone sharp is a natural raised by 50 cents.
+fn parse_microtonal_alter(text: &str) -> Option<(Alter, i8)> {
+    let alter: f32 = text.trim().parse().ok()?;
+    let mut semitones = alter.round();
+    if (alter - semitones).abs() == 0.5 {
+        semitones = alter.trunc();
+    }
+    let cents = ((alter - semitones) * 100.0).round() as i8;
+    let alter = Alter::from_num_string(&(semitones as i32).to_string()).ok()?;
+    Some((alter, cents))
+}
+
+/// Reads a `<tremolo>` element, whose type defaults to a single note tremolo
+fn parse_tremolo(xml_tremolo: Node<'_, '_>) -> Option<Tremolo> {
+    let tremolo_type = match xml_tremolo.attribute("type") {
+        Some(t) => TremoloType::from_str(t)
+            .map_err(|_| warn!("Unsupported tremolo type {}, dropping the tremolo", t))
+            .ok()?,
+        None => TremoloType::Single,
+    };
+    let text = xml_tremolo.text().unwrap_or_default().trim();
+    match text.parse::<u8>() {
+        Ok(marks) if marks <= Tremolo::MAX_MARKS => Some(Tremolo::new(tremolo_type, marks)),
+        _ => {
+            warn!("Unsupported tremolo marks {:?}, dropping the tremolo", text);
+            None
+        }
+    }
+}
+
+/// Reads the `times` attribute of a backward repeat, clamped to what the bin format can hold
+pub fn parse_repeat_times(xml_repeat: &Node<'_, '_>) -> Option<u8> {
+    let times = xml_repeat.attribute("times")?;
+    match times.trim().parse::<u32>() {
+        Ok(t) if t > u32::from(MeasureMetaData::MAX_REPEAT_TIMES) => {
+            warn!(
+                "Repeat played {} times exceeds the maximum of {}, clamping it",
+                t,
+                MeasureMetaData::MAX_REPEAT_TIMES
+            );
+            Some(MeasureMetaData::MAX_REPEAT_TIMES)
+        }
+        Ok(0) | Err(_) => {
+            warn!("Ignoring unsupported repeat times {:?}", times);
+            None
+        }
+        Ok(t) => Some(t as u8),
+    }
+}
+
+/// Reads the style of the barline closing a measure. The barline of a backward repeat is left out,
+/// since the repeat draws it.
+pub fn parse_bar_style(xml_measure: &Node<'_, '_>) -> BarStyle {
+    xml_measure
+        .children()
+        .filter(|n| n.has_tag_name("barline") && n.attribute("location").unwrap_or("right") == "right")
+        .filter(|n| !n.children().any(|c| c.has_tag_name("repeat")))
+        .find_map(|n| n.children().find(|c| c.has_tag_name("bar-style")))
+        .and_then(|n| n.text())
+        .map_or(BarStyle::Regular, |style| BarStyle::from_xml(style.trim()))
+}
+
+/// Finds the measure whose `<attributes>` first declare the divisions of a part, returning its index and the divisions.
+///
+/// The attributes normally open the first measure, but some files, such as the parts of a split score, only declare them
+/// after an empty pickup measure. Divisions are only required by the first note, so the measures before may be read with
+/// them. A part without notes or divisions is read with a single division per quarter note.
+pub fn find_initial_attributes(part_str: &str, xml_measures: &[Node<'_, '_>]) -> Result<(usize, u32)> {
+    let first_note_idx = xml_measures
+        .iter()
+        .position(|m| m.children().any(|n| n.has_tag_name("note")));
+    let first_divisions = xml_measures.iter().enumerate().find_map(|(idx, m)| {
+        m.descendants()
+            .find(|n| n.has_tag_name("divisions"))
+            .map(|div| (idx, div))
+    });
+    match (first_divisions, first_note_idx) {
+        (Some((idx, _)), Some(note_idx)) if note_idx < idx => Err(Error::Validation(format!(
+            "{}M{} has notes before the part declares its divisions",
+            part_str, note_idx
+        ))),
+        (None, Some(note_idx)) => Err(Error::Validation(format!(
+            "{}M{} has notes but the part declares no divisions",
+            part_str, note_idx
+        ))),
+        (Some((idx, div)), _) => {
+            let divisions = div.text().unwrap_or("").trim();
+            match divisions.parse::<u32>() {
+                Ok(quarter_division) if quarter_division > 0 => Ok((idx, quarter_division)),
+                _ => Err(Error::Validation(format!(
+                    "{}M{} has invalid divisions \"{}\"",
+                    part_str, idx, divisions
+                ))),
+            }
+        }
+        (None, None) => {
+            warn!("{} declares no divisions and holds no notes", part_str);
+            Ok((0, 1))
+        }
+    }
+}
+
+/// A tablature staff, with the pitch of each open string by the staff line it is written on, counting
+/// lines from the bottom as `<staff-tuning>` does
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TabTuning {
+    lines: u8,
+    open_strings: BTreeMap<u8, NumericPitchRest>,
+}
+
+impl TabTuning {
+    const DEFAULT_STAFF_LINES: u8 = 6;
+
+    /// Pitch sounding at `fret` on `string`, with strings numbered from the top line down as
+    /// `<string>` does, or `None` when the string's tuning is not known
+    pub fn fret_pitch(&self, string: u8, fret: u8) -> Option<NumericPitchRest> {
+        let line = self.lines.checked_add(1)?.checked_sub(string)?;
+        let open_string = *self.open_strings.get(&line)?;
+        open_string.transpose(i8::try_from(fret).ok()?).ok()
+    }
+}
+
+/// Finds the tablature staves of a part, by staff number: the staves with a `TAB` clef or with
+/// string tunings in their `<staff-details>`
+pub fn find_tab_staves(xml_part: &Node<'_, '_>) -> BTreeMap<u8, TabTuning> {
+    let staff_number = |n: &Node<'_, '_>| n.attribute("number").and_then(|s| s.parse::<u8>().ok()).unwrap_or(1);
+    let child_text = |n: &Node<'_, '_>, name: &str| {
+        n.children()
+            .find(|c| c.has_tag_name(name))
+            .and_then(|c| c.text())
+            .map(|text| text.trim().to_string())
+    };
+    let mut tab_staves: BTreeMap<u8, TabTuning> = BTreeMap::new();
+    for clef in xml_part.descendants().filter(|n| n.has_tag_name("clef")) {
+        if child_text(&clef, "sign").as_deref() == Some("TAB") {
+            tab_staves.entry(staff_number(&clef)).or_insert_with(|| TabTuning {
+                lines: TabTuning::DEFAULT_STAFF_LINES,
+                ..TabTuning::default()
+            });
+        }
     }
+    for details in xml_part.descendants().filter(|n| n.has_tag_name("staff-details")) {
+        let tunings: Vec<Node<'_, '_>> = details.children().filter(|n| n.has_tag_name("staff-tuning")).collect();
+        if tunings.is_empty() && !tab_staves.contains_key(&staff_number(&details)) {
+            continue;
+        }
+        let tuning = tab_staves.entry(staff_number(&details)).or_insert_with(|| TabTuning {
+            lines: TabTuning::DEFAULT_STAFF_LINES,
+            ..TabTuning::default()
+        });
+        if let Some(lines) = child_text(&details, "staff-lines").and_then(|text| text.parse().ok()) {
+            tuning.lines = lines;
+        }
+        for staff_tuning in tunings {
+            let line = staff_tuning.attribute("line").and_then(|s| s.parse::<u8>().ok());
+            let step = child_text(&staff_tuning, "tuning-step").and_then(|text| Step::from_str(&text).ok());
+            let octave = child_text(&staff_tuning, "tuning-octave").and_then(|text| Octave::from_str(&text).ok());
+            let alter = child_text(&staff_tuning, "tuning-alter")
+                .map_or(Ok(Alter::None), |text| Alter::from_num_string(&text));
+            let pitch = match (step, octave, alter) {
+                (Some(step), Some(octave), Ok(alter)) => NumericPitchRest::from_pitch_octave(PitchOctave {
+                    pitch: Pitch { step, alter },
+                    octave,
+                })
+                .ok(),
+                _ => None,
+            };
+            match (line, pitch) {
+                (Some(line), Some(pitch)) => {
+                    tuning.open_strings.insert(line, pitch);
+                }
+                _ => warn!("Skipping unsupported string tuning of tablature staff {}", staff_number(&details)),
+            }
+        }
+    }
+    tab_staves
+}
+
+/// Number of the staff a note is written on, the first when it names none
+pub fn note_staff(note: &Node<'_, '_>) -> u8 {
+    note.children()
+        .find(|n| n.has_tag_name("staff"))
+        .and_then(|n| n.text())
+        .and_then(|text| text.trim().parse().ok())
+        .unwrap_or(1)
+}
+
+/// Pitch of a tablature note from the string and fret of its `<technical>` notation
+fn tab_note_pitch(note: &Node<'_, '_>, tuning: &TabTuning) -> Option<NumericPitchRest> {
+    let technical = note
+        .children()
+        .filter(|n| n.has_tag_name("notations"))
+        .flat_map(|n| n.children())
+        .find(|n| n.has_tag_name("technical"))?;
+    let number = |name: &str| {
+        technical
+            .children()
+            .find(|n| n.has_tag_name(name))
+            .and_then(|n| n.text())
+            .and_then(|text| text.trim().parse::<u8>().ok())
+    };
+    tuning.fret_pitch(number("string")?, number("fret")?)
 }
 
 pub fn does_note_contain_unpitched(measure_element: &Node<'_, '_>) -> bool {
@@ -52,10 +495,41 @@ pub fn does_note_contain_unpitched(measure_element: &Node<'_, '_>) -> bool {
     unpitched.is_some()
 }
 
+/// Maps an unpitched note to a drum sound, from the MIDI key or name of its `<instrument>`, falling
+/// back to the staff position it is written at
+fn parse_drum_sound(xml_note: &Node<'_, '_>, display: NumericPitchRest) -> DrumSound {
+    let instrument_id = xml_note
+        .children()
+        .find(|n| n.has_tag_name("instrument"))
+        .and_then(|n| n.attribute("id"));
+    let instrument_child_text = |tag: &str, child: &str| {
+        let instrument = xml_note
+            .document()
+            .descendants()
+            .find(|n| n.has_tag_name(tag) && instrument_id.is_some() && n.attribute("id") == instrument_id)?;
+        instrument.children().find(|n| n.has_tag_name(child))?.text()
+    };
+    // midi-unpitched numbers the keys from one
+    let from_midi = instrument_child_text("midi-instrument", "midi-unpitched")
+        .and_then(|t| t.trim().parse::<u8>().ok())
+        .and_then(|key| FromPrimitive::from_u8(key.saturating_sub(1)));
+    from_midi
+        .or_else(|| instrument_child_text("score-instrument", "instrument-name").and_then(DrumSound::from_instrument_name))
+        .or_else(|| DrumSound::from_display_position(display))
+        .unwrap_or_else(|| {
+            warn!("No drum sound is known for an unpitched note written at {}, using a snare drum", display);
+            DrumSound::Snare
+        })
+}
+
+/// Parses a note into the part. Notes of a tablature staff, whose tuning is given in `tab_tuning`,
+/// that are written without a pitch sound at the fret of their string.
 pub fn parse_note_tag(
     xml_measure_element: &Node<'_, '_>,
-    part: &mut MusicalPart
-) {
+    part: &mut MusicalPart,
+    options: &ParseOptions,
+    tab_tuning: Option<&TabTuning>,
+) -> Result<()> {
     let mut note_data = NoteData::default();
     let mut stop_tuplet_elem: Option<MusicElement> = None;
     let xml_note_type_tag = xml_measure_element.children().find(|n| n.has_tag_name("type"));
@@ -66,7 +540,8 @@ pub fn parse_note_tag(
     let xml_grace_tag = xml_measure_element.children().find(|n| n.has_tag_name("grace"));
     note_data.special_note = match xml_grace_tag {
         Some(n) => match n.attribute("slash") {
-            None => SpecialNote::None,
+            // A grace note is unslashed unless marked otherwise
+            None => SpecialNote::Appogiatura,
             Some(t) => SpecialNote::from_str(t).expect("Unsupported Tied Type"),
         },
         None => SpecialNote::None,
@@ -76,6 +551,27 @@ pub fn parse_note_tag(
         note_data.dotted = true;
     }
 
+    note_data.note_size = if xml_measure_element.children().any(|n| n.has_tag_name("cue")) {
+        NoteSize::Cue
+    } else if xml_note_type_tag.is_some_and(|n| n.attribute("size") == Some("cue")) {
+        NoteSize::CueSized
+    } else {
+        NoteSize::Full
+    };
+
+    note_data.notehead = match xml_measure_element.children().find(|n| n.has_tag_name("notehead")) {
+        Some(n) => Notehead::from_str(n.text().unwrap_or_default().trim()).unwrap_or_else(|_| {
+            warn!("Unsupported notehead {:?}, using a normal notehead", n.text());
+            Notehead::Normal
+        }),
+        None => Notehead::Normal,
+    };
+
+    note_data.cautionary_accidental = xml_measure_element
+        .children()
+        .find(|n| n.has_tag_name("accidental"))
+        .is_some_and(|n| n.attribute("parentheses") == Some("yes") || n.attribute("cautionary") == Some("yes"));
+
     let time_mod_tag = xml_measure_element
         .children()
         .find(|n| n.has_tag_name("time-modification"));
@@ -94,20 +590,53 @@ pub fn parse_note_tag(
         .expect("Unable to parse voices string");
 
     match part.insert_new_voice(voice_num) {
-        Ok(_) => (),
+        Ok(voice_idx) => {
+            note_data.voice = FromPrimitive::from_usize(voice_idx).unwrap_or_default();
+        }
         Err(e) => {
+            if options.strict {
+                return Err(Error::StrictViolation(
+                    part.get_part_str().to_string(),
+                    format!(
+                        "voice {} exceeds the maximum of {} supported voices",
+                        voice_num,
+                        MusicalPart::MAX_SUPPORTED_VOICES
+                    ),
+                ));
+            }
             warn!("insert_new_voice err: {} Too many voices case, skipping notes", e.to_string());
-            return;
+            part.note_dropped();
+            return Ok(());
         },
     }
 
+    // Kept for now, and dropped once the part is parsed where the voice leads to the same staff
+    note_data.staff = xml_measure_element
+        .children()
+        .find(|n| n.has_tag_name("staff"))
+        .and_then(|n| n.text())
+        .and_then(|text| match text.trim().parse::<u8>().ok().and_then(Staff::from_u8) {
+            Some(staff) => Some(staff),
+            None => {
+                warn!("Ignoring unsupported staff {}", text);
+                None
+            }
+        });
+
     let time_mod_value = if let Some(n) = time_mod_tag {
         let actual_notes_tag = n.children().find(|n| n.has_tag_name("actual-notes"));
         let normal_notes_tag = n.children().find(|n| n.has_tag_name("normal-notes"));
         if let (Some(an_tag), Some(nn_tag)) = (actual_notes_tag, normal_notes_tag) {
             let actual_notes = an_tag.text().unwrap().parse().unwrap();
             let normal_notes = nn_tag.text().unwrap().parse().unwrap();
-            Some(TimeModification::new(actual_notes, normal_notes))
+            // Only kept when it differs from the note's own type, which the counts then already refer to
+            let normal_type = n
+                .children()
+                .find(|n| n.has_tag_name("normal-type"))
+                .and_then(|t| t.text())
+                .filter(|t| Some(*t) != xml_note_type_tag.and_then(|n| n.text()))
+                .and_then(|t| RhythmType::from_str(t).ok());
+            Some(TimeModification::new(actual_notes, normal_notes).with_normal_type(normal_type))
         } else {
             None
         }
@@ -116,7 +645,11 @@ pub fn parse_note_tag(
     };
 
     note_data.phrase_dynamics = part.cur_phrase_dyn.unwrap_or_default();
+    if note_data.phrase_dynamics != PhraseDynamics::None {
+        note_data.dynamics_placement = part.cur_dyn_placement;
+    }
     part.cur_phrase_dyn = None;
+    part.cur_dyn_placement = Placement::Default;
 
     if let Some(n) = notations_tag {
         let tuplet_tags = n.children().filter(|n| n.has_tag_name("tuplet"));
@@ -141,8 +674,10 @@ pub fn parse_note_tag(
             None => NoteConnection::None,
         };
 
-        note_data.arpeggiate = match arp_tag {
-            Some(_t) => Arpeggiate::Arpeggiate,
+        note_data.arpeggiate = match arp_tag.map(|t| t.attribute("direction")) {
+            Some(Some("up")) => Arpeggiate::Up,
+            Some(Some("down")) => Arpeggiate::Down,
+            Some(_) => Arpeggiate::Arpeggiate,
             None => Arpeggiate::NoArpeggiation,
         };
 
@@ -158,6 +693,18 @@ pub fn parse_note_tag(
                 .expect("Unhandled slur tag attribute case"),
             None => SlurConnection::None,
         };
+        note_data.slur_number = match slur_tag.and_then(|t| t.attribute("number")) {
+            Some(number) => SlurNumber::from_str(number).unwrap_or_else(|_| {
+                warn!("Slur number {} is not supported, using slur number 1", number);
+                SlurNumber::One
+            }),
+            None => SlurNumber::One,
+        };
+        note_data.tremolo = n
+            .children()
+            .filter(|n| n.has_tag_name("ornaments"))
+            .find_map(|o| o.children().find(|n| n.has_tag_name("tremolo")))
+            .and_then(parse_tremolo);
 
         if num_tuplets > 0 {
             if let Some(time_mod_value) = time_mod_value {
@@ -170,6 +717,9 @@ pub fn parse_note_tag(
                                 actual_notes: time_mod_value.get_actual(),
                                 normal_notes: time_mod_value.get_normal(),
                                 dotted: false,
+                                hide_bracket: t.attribute("bracket") == Some("no"),
+                                hide_number: t.attribute("show-number") == Some("none"),
+                                normal_type: time_mod_value.get_normal_type(),
                             }));
                         }
                         "stop" => {
@@ -179,6 +729,9 @@ pub fn parse_note_tag(
                                 actual_notes: time_mod_value.get_actual(),
                                 normal_notes: time_mod_value.get_normal(),
                                 dotted: false,
+                                hide_bracket: false,
+                                hide_number: false,
+                                normal_type: time_mod_value.get_normal_type(),
                             }));
                         }
                         _ => {
@@ -192,46 +745,98 @@ pub fn parse_note_tag(
         }
     }
 
-    note_data.note_type = if let Some(n) = xml_note_type_tag {
+    // Rhythm values of the tied notes a note without a "type" tag is written as
+    let mut duration_components = vec![];
+    let is_measure_rest = rest_tag.is_some_and(|n| n.attribute("measure") == Some("yes"));
+    note_data.note_type = if is_measure_rest {
+        // A whole-measure rest fills the bar whatever the time signature, which the semibreve rest
+        // is sized to, so its duration needs no inference
+        note_data.dotted = false;
+        RhythmType::SemiBreve
+    } else if let Some(n) = xml_note_type_tag {
         RhythmType::from_str(n.text().unwrap()).unwrap()
     } else {
         // Whole rests sometimes provide no "type" tag, but whole rests are different durations
         // depending on the time signature, so we must manually calculate the rhythm value based on duration
         if let Some(n) = xml_note_duration {
-            if let Some((rest_duration, is_dotted, time_mod)) = NoteData::from_numeric_duration(
-                n.text().unwrap().parse::<u32>().unwrap(),
-                part.get_cur_quarter_divisions(),
-            ) {
-                if time_mod.is_some() {
-                    warn!("time modification for rest is present, but not being used.")
+            let duration = n.text().unwrap().parse::<u32>().unwrap();
+            let quarter_division = part.get_cur_quarter_divisions();
+            let measure_init = part.get_cur_init_measure();
+            let measure_duration =
+                quarter_division * u32::from(measure_init.beats) * 4 / u32::from(measure_init.beat_type);
+            duration_components = NoteData::from_numeric_duration(duration, quarter_division);
+            if rest_tag.is_some() && duration == measure_duration && duration_components.len() > 1 {
+                // A whole rest fills the measure whatever the time signature, so it needs no tied rests
+                duration_components = vec![(RhythmType::SemiBreve, false, None)];
+            }
+            if duration_components.iter().any(|(_, _, time_mod)| time_mod.is_some()) {
+                warn!("time modification for rest is present, but not being used.")
+            }
+            match duration_components.first() {
+                Some((rest_duration, is_dotted, _)) => {
+                    note_data.dotted = *is_dotted;
+                    *rest_duration
+                }
+                None => {
+                    panic!("Could not convert numeric duration value to internal note duration representation");
                 }
-                note_data.dotted = is_dotted;
-                rest_duration
-            } else {
-                panic!("Could not convert numeric duration value to internal note duration representation");
             }
+        } else if !note_data.occupies_time() {
+            // Grace and cue notes take no time, so may give neither a type nor a duration
+            warn!("{} note without a type or duration, writing it as a quarter", part.get_part_str());
+            RhythmType::default()
         } else {
             panic!("No note duration provided.");
         }
     };
 
-    match rest_tag {
-        Some(_) => {
+    let tab_pitch = tab_tuning
+        .filter(|_| !xml_measure_element.children().any(|n| n.has_tag_name("pitch")))
+        .and_then(|tuning| tab_note_pitch(xml_measure_element, tuning));
+    // Set for the unpitched notes of percussion parts
+    let mut drum = None;
+    match (rest_tag, tab_pitch) {
+        (Some(_), _) => {
             //debug!("rest {:?}", note_data.rhythm_value);
             note_data.note_rest = NumericPitchRest::Rest;
         }
-        None => {
+        (None, Some(tab_pitch)) => {
+            note_data.chord = if xml_measure_element.children().any(|n| n.has_tag_name("chord")) {
+                Chord::Chord
+            } else {
+                Chord::NoChord
+            };
+            note_data.note_rest = tab_pitch;
+        }
+        (None, None) => {
             let chord_tag = xml_measure_element.children().find(|n| n.has_tag_name("chord"));
+            let unpitched_tag = xml_measure_element.children().find(|n| n.has_tag_name("unpitched"));
+            // Unpitched notes are stored at the staff position they are written at
             let pitch_tag = xml_measure_element
                 .children()
                 .find(|n| n.has_tag_name("pitch"))
+                .or(unpitched_tag)
                 .unwrap();
-            let step_tag = pitch_tag.children().find(|n| n.has_tag_name("step"));
-            let octave_tag = pitch_tag.children().find(|n| n.has_tag_name("octave"));
+            let child_text = |names: &[&str]| {
+                pitch_tag
+                    .children()
+                    .find(|n| names.iter().any(|name| n.has_tag_name(*name)))
+                    .and_then(|n| n.text())
+            };
+            // An unpitched note without a display position sits on the middle line of the staff
+            let step_text = child_text(&["step", "display-step"]).or(unpitched_tag.map(|_| "B"));
+            let octave_text = child_text(&["octave", "display-octave"]).or(unpitched_tag.map(|_| "4"));
             let alter_tag = pitch_tag.children().find(|n| n.has_tag_name("alter"));
 
             // alter tags are optional, others are mandatory
             let alter_note = match alter_tag {
+                Some(t) if options.microtonal => {
+                    let text = t.text().unwrap();
+                    let (alter, cents) = parse_microtonal_alter(text)
+                        .unwrap_or_else(|| panic!("Unsupported alter {}", text));
+                    note_data.cents = cents;
+                    alter
+                }
                 Some(t) => Alter::from_num_string(t.text().unwrap()).unwrap(),
                 None => Alter::None,
             };
@@ -241,12 +846,15 @@ pub fn parse_note_tag(
             };
             note_data.note_rest = NumericPitchRest::from_pitch_octave(PitchOctave {
                 pitch: Pitch {
-                    step: Step::from_str(step_tag.unwrap().text().unwrap()).unwrap(),
+                    step: Step::from_str(step_text.unwrap()).unwrap(),
                     alter: alter_note,
                 },
-                octave: Octave::from_str(octave_tag.unwrap().text().unwrap()).unwrap(),
+                octave: Octave::from_str(octave_text.unwrap()).unwrap(),
             })
             .expect("Parsed note is not supported by Music2Bin format.");
+            if unpitched_tag.is_some() {
+                drum = Some(parse_drum_sound(xml_measure_element, note_data.note_rest));
+            }
             //debug!(
             //    "note {:?} number: {:?}",
             //    note_data.rhythm_value, note_data.note_rest
@@ -254,9 +862,20 @@ pub fn parse_note_tag(
         }
     }
 
+    let note_elem = |note: NoteData| match drum {
+        Some(drum) => MusicElement::Percussion(PercussionData { drum, note }),
+        None => MusicElement::NoteRest(note),
+    };
     // The MeasureChecker checks for correct total duration. Incomplete voices are thrown away.
-    part.push_measure_elem(MusicElement::NoteRest(note_data));
+    if duration_components.len() > 1 {
+        for component in note_data.tied_components(&duration_components) {
+            part.push_measure_elem(note_elem(component));
+        }
+    } else {
+        part.push_measure_elem(note_elem(note_data));
+    }
     if let Some(st_elem) = stop_tuplet_elem {
         part.push_measure_elem(st_elem);
     }
+    Ok(())
 }
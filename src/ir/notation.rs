@@ -7,7 +7,9 @@ use muxml::muxml_types::{
     PitchElement, PitchRest, TimeModificationElement,
 };
 use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
 use std::convert::From;
+use std::fmt;
 use std::str::FromStr;
 use strum::{EnumCount, EnumIter};
 
@@ -15,6 +17,8 @@ use strum::{EnumCount, EnumIter};
 pub struct TimeModification {
     actual_notes: TupletActual,
     normal_notes: TupletNormal,
+    /// Note type the normal notes are counted in, when it differs from the type of the tuplet's notes
+    normal_type: Option<RhythmType>,
 }
 
 impl TimeModification {
@@ -22,17 +26,39 @@ impl TimeModification {
         TimeModification {
             actual_notes,
             normal_notes,
+            normal_type: None,
         }
     }
+    pub fn with_normal_type(mut self, normal_type: Option<RhythmType>) -> TimeModification {
+        self.normal_type = normal_type;
+        self
+    }
     pub fn get_actual(&self) -> TupletActual {
         self.actual_notes
     }
     pub fn get_normal(&self) -> TupletNormal {
         self.normal_notes
     }
+    pub fn get_normal_type(&self) -> Option<RhythmType> {
+        self.normal_type
+    }
+
+    /// Numerator and denominator the duration of a `note_type` note is scaled by. The actual notes take
+    /// the time of the normal notes, which are of the normal type if one is given, else of `note_type`.
+    fn scale(&self, note_type: RhythmType) -> (u32, u32) {
+        let (normal_units, note_units) = match self.normal_type {
+            Some(normal_type) => (normal_type.relative_length(), note_type.relative_length()),
+            None => (1, 1),
+        };
+        (
+            self.normal_notes.as_u32() * normal_units,
+            self.actual_notes.as_u32() * note_units,
+        )
+    }
 }
 
-#[derive(Eq, PartialEq, Default, FromPrimitive, Debug, Copy, Clone)]
+/// Key signatures numbered by position around the circle of fifths, sharp keys first
+#[derive(Eq, PartialEq, Default, FromPrimitive, EnumIter, Debug, Copy, Clone)]
 #[repr(u8)]
 pub enum KeySignature {
     #[default]
@@ -51,43 +77,62 @@ pub enum KeySignature {
 }
 
 impl ToString for KeySignature {
+    /// Returns the MusicXML fifths value, positive for sharps and negative for flats, so that the
+    /// written key signature agrees with [`KeySignature::uses_flats`].
     fn to_string(&self) -> String {
+        let fifths = *self as i8;
+        if self.uses_flats() {
+            (fifths - 12).to_string()
+        } else {
+            fifths.to_string()
+        }
+    }
+}
+
+impl KeySignature {
+    /// Moves the key around the circle of fifths by the fifths spanned in `semitones`
+    pub fn transpose(self, semitones: i8) -> KeySignature {
+        let fifths = (self as i32 + 7 * semitones as i32).rem_euclid(12);
+        FromPrimitive::from_i32(fifths).unwrap()
+    }
+
+    /// True for the keys written with flats, whose accidentals are spelled as flats
+    pub fn uses_flats(self) -> bool {
+        self as u8 >= KeySignature::GbMajorEbminor as u8
+    }
+
+    /// Returns the name of the major key, such as "Eb-maj"
+    pub fn major_name(self) -> &'static str {
         match self {
-            KeySignature::DbMajorBbminor => String::from("-5"),
-            KeySignature::AbMajorFminor => String::from("-4"),
-            KeySignature::EbMajorCminor => String::from("-3"),
-            KeySignature::BbMajorGminor => String::from("-2"),
-            KeySignature::FMajorDminor => String::from("-1"),
-            KeySignature::CMajorAminor => String::from("0"),
-            KeySignature::GMajorEminor => String::from("1"),
-            KeySignature::DMajorBminor => String::from("2"),
-            KeySignature::AMajorFsminor => String::from("3"),
-            KeySignature::EMajorCsminor => String::from("4"),
-            KeySignature::BMajorGsminor => String::from("5"),
-            KeySignature::GbMajorEbminor => String::from("6"),
+            KeySignature::CMajorAminor => "C-maj",
+            KeySignature::GMajorEminor => "G-maj",
+            KeySignature::DMajorBminor => "D-maj",
+            KeySignature::AMajorFsminor => "A-maj",
+            KeySignature::EMajorCsminor => "E-maj",
+            KeySignature::BMajorGsminor => "B-maj",
+            KeySignature::GbMajorEbminor => "Gb-maj",
+            KeySignature::DbMajorBbminor => "Db-maj",
+            KeySignature::AbMajorFminor => "Ab-maj",
+            KeySignature::EbMajorCminor => "Eb-maj",
+            KeySignature::BbMajorGminor => "Bb-maj",
+            KeySignature::FMajorDminor => "F-maj",
         }
     }
 }
 
 impl FromStr for KeySignature {
     type Err = Error;
+    /// Parses a MusicXML fifths value between -7 and 7.
+    ///
+    /// The enum holds one key per position on the circle of fifths, so the keys beyond six
+    /// accidentals collapse onto their enharmonic equivalents: 6 (F# major) reads as Gb major, 7
+    /// (C# major) as Db major and -7 (Cb major) as B major. These sound identical and are written
+    /// back as -6, -5 and 5 respectively.
     fn from_str(input: &str) -> Result<KeySignature> {
-        match input {
-            "-7" => Ok(KeySignature::BMajorGsminor),
-            "-6" => Ok(KeySignature::GbMajorEbminor),
-            "-5" => Ok(KeySignature::DbMajorBbminor),
-            "-4" => Ok(KeySignature::AbMajorFminor),
-            "-3" => Ok(KeySignature::EbMajorCminor),
-            "-2" => Ok(KeySignature::BbMajorGminor),
-            "-1" => Ok(KeySignature::FMajorDminor),
-            "0" => Ok(KeySignature::CMajorAminor),
-            "1" => Ok(KeySignature::GMajorEminor),
-            "2" => Ok(KeySignature::DMajorBminor),
-            "3" => Ok(KeySignature::AMajorFsminor),
-            "4" => Ok(KeySignature::EMajorCsminor),
-            "5" => Ok(KeySignature::BMajorGsminor),
-            "6" => Ok(KeySignature::GbMajorEbminor),
-            "7" => Ok(KeySignature::DbMajorBbminor),
+        match input.trim().parse::<i8>() {
+            Ok(fifths) if (-7..=7).contains(&fifths) => {
+                Ok(FromPrimitive::from_i8(fifths.rem_euclid(12)).unwrap())
+            }
             _ => Err(Error::Unit),
         }
     }
@@ -133,6 +178,35 @@ impl FromStr for SlurConnection {
     }
 }
 
+/// Distinguishes overlapping slurs, as given by the MusicXML `<slur number=>` attribute
+#[derive(Eq, PartialEq, Copy, Clone, FromPrimitive, Default, Debug)]
+#[repr(u8)]
+pub enum SlurNumber {
+    #[default]
+    One = 0,
+    Two,
+}
+
+impl FromStr for SlurNumber {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<SlurNumber> {
+        match input {
+            "1" => Ok(SlurNumber::One),
+            "2" => Ok(SlurNumber::Two),
+            _ => Err(Error::Unsupported),
+        }
+    }
+}
+
+impl ToString for SlurNumber {
+    fn to_string(&self) -> String {
+        match self {
+            SlurNumber::One => "1".to_string(),
+            SlurNumber::Two => "2".to_string(),
+        }
+    }
+}
+
 #[derive(Eq, PartialEq, Copy, Clone, FromPrimitive, Default, Debug)]
 #[repr(u8)]
 pub enum MeasureStartEnd {
@@ -172,6 +246,17 @@ impl ToString for Articulation {
     }
 }
 
+impl Articulation {
+    /// Velocity added on top of the dynamic level for accented notes
+    pub fn velocity_bump(self) -> u8 {
+        match self {
+            Articulation::Accent | Articulation::Stress => 12,
+            Articulation::StrongAccent => 20,
+            _ => 0,
+        }
+    }
+}
+
 impl FromStr for Articulation {
     type Err = Error;
     fn from_str(input: &str) -> Result<Articulation> {
@@ -194,17 +279,31 @@ impl FromStr for Articulation {
 
 #[derive(Eq, PartialEq, Copy, Clone, FromPrimitive, Default, Debug)]
 #[repr(u8)]
+#[allow(clippy::enum_variant_names)]
 pub enum Arpeggiate {
     #[default]
     NoArpeggiation,
     Arpeggiate,
+    Up,
+    Down,
+}
+
+impl Arpeggiate {
+    /// Value of the MusicXML `direction` attribute, for arpeggios rolled in a given direction
+    pub fn direction(self) -> Option<&'static str> {
+        match self {
+            Arpeggiate::Up => Some("up"),
+            Arpeggiate::Down => Some("down"),
+            Arpeggiate::NoArpeggiation | Arpeggiate::Arpeggiate => None,
+        }
+    }
 }
 
 impl From<Arpeggiate> for bool {
     fn from(f: Arpeggiate) -> bool {
         match f {
             Arpeggiate::NoArpeggiation => false,
-            Arpeggiate::Arpeggiate => true,
+            Arpeggiate::Arpeggiate | Arpeggiate::Up | Arpeggiate::Down => true,
         }
     }
 }
@@ -515,13 +614,19 @@ pub struct TupletData {
     pub actual_notes: TupletActual,
     pub normal_notes: TupletNormal,
     pub dotted: TupletDotted,
+    /// The tuplet is engraved without its bracket
+    pub hide_bracket: bool,
+    /// The tuplet is engraved without its number
+    pub hide_number: bool,
+    /// Note type of the normal notes, for tuplets such as three eighths in the time of one quarter
+    pub normal_type: Option<RhythmType>,
 }
 
 impl From<TupletData> for Option<TimeModification> {
     fn from(t: TupletData) -> Self {
         match t.start_stop {
             TupletStartStop::TupletStart => {
-                Some(TimeModification::new(t.actual_notes, t.normal_notes))
+                Some(TimeModification::new(t.actual_notes, t.normal_notes).with_normal_type(t.normal_type))
             }
             TupletStartStop::None => None,
             TupletStartStop::TupletStop => None,
@@ -538,6 +643,165 @@ impl From<Chord> for bool {
     }
 }
 
+/// Size a note is engraved at. Cue notes, marked by `<cue>`, are silent and like grace notes are not
+/// counted towards the length of their voice; cue sized notes, from `<type size="cue">`, are only drawn smaller.
+#[derive(Eq, PartialEq, Copy, Clone, FromPrimitive, Default, Debug)]
+#[repr(u8)]
+pub enum NoteSize {
+    #[default]
+    Full = 0,
+    CueSized,
+    Cue,
+}
+
+/// Shape of a note head, from `<notehead>`. Shapes other than normal usually mark a playing
+/// technique, such as diamond heads for harmonics or x heads for ghost notes.
+#[derive(Eq, PartialEq, Copy, Clone, FromPrimitive, Default, Debug)]
+#[repr(u8)]
+pub enum Notehead {
+    #[default]
+    Normal = 0,
+    X,
+    Diamond,
+    Triangle,
+    Slash,
+    Square,
+    CircleX,
+    Cross,
+}
+
+impl FromStr for Notehead {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<Notehead> {
+        match input {
+            "normal" => Ok(Notehead::Normal),
+            "x" => Ok(Notehead::X),
+            "diamond" => Ok(Notehead::Diamond),
+            "triangle" => Ok(Notehead::Triangle),
+            "slash" => Ok(Notehead::Slash),
+            "square" => Ok(Notehead::Square),
+            "circle-x" => Ok(Notehead::CircleX),
+            "cross" => Ok(Notehead::Cross),
+            _ => Err(Error::Parse),
+        }
+    }
+}
+
+impl ToString for Notehead {
+    fn to_string(&self) -> String {
+        match self {
+            Notehead::Normal => "normal".to_string(),
+            Notehead::X => "x".to_string(),
+            Notehead::Diamond => "diamond".to_string(),
+            Notehead::Triangle => "triangle".to_string(),
+            Notehead::Slash => "slash".to_string(),
+            Notehead::Square => "square".to_string(),
+            Notehead::CircleX => "circle-x".to_string(),
+            Notehead::Cross => "cross".to_string(),
+        }
+    }
+}
+
+/// Kind of tremolo, from the `type` attribute of `<tremolo>`. A single tremolo repeats one note, a
+/// double tremolo alternates between two notes marked as its start and stop, and an unmeasured
+/// tremolo is played as fast as possible whatever its number of marks.
+#[derive(Eq, PartialEq, Copy, Clone, FromPrimitive, Default, Debug)]
+#[repr(u8)]
+pub enum TremoloType {
+    #[default]
+    Single = 0,
+    Start,
+    Stop,
+    Unmeasured,
+}
+
+impl FromStr for TremoloType {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<TremoloType> {
+        match input {
+            "single" => Ok(TremoloType::Single),
+            "start" => Ok(TremoloType::Start),
+            "stop" => Ok(TremoloType::Stop),
+            "unmeasured" => Ok(TremoloType::Unmeasured),
+            _ => Err(Error::Parse),
+        }
+    }
+}
+
+impl ToString for TremoloType {
+    fn to_string(&self) -> String {
+        match self {
+            TremoloType::Single => "single".to_string(),
+            TremoloType::Start => "start".to_string(),
+            TremoloType::Stop => "stop".to_string(),
+            TremoloType::Unmeasured => "unmeasured".to_string(),
+        }
+    }
+}
+
+/// A tremolo marked with slashes through the stem of a note, or between the stems of the two notes
+/// of a double tremolo
+#[derive(Eq, PartialEq, Copy, Clone, Default, Debug)]
+pub struct Tremolo {
+    pub tremolo_type: TremoloType,
+    /// Number of slashes, each halving the length of the repeated notes
+    pub marks: u8,
+}
+
+impl Tremolo {
+    /// Most marks MusicXML allows
+    pub const MAX_MARKS: u8 = 8;
+
+    pub fn new(tremolo_type: TremoloType, marks: u8) -> Tremolo {
+        Tremolo {
+            tremolo_type,
+            marks: marks.min(Self::MAX_MARKS),
+        }
+    }
+
+    /// The `<ornaments>` element holding the tremolo
+    pub fn to_xml_fragment(&self) -> String {
+        format!(
+            "<ornaments><tremolo type=\"{}\">{}</tremolo></ornaments>",
+            self.tremolo_type.to_string(),
+            self.marks
+        )
+    }
+}
+
+/// Side of the staff a direction is written on, from the `placement` attribute of `<direction>`.
+/// Without one, renderers choose the side themselves.
+#[derive(Eq, PartialEq, Copy, Clone, FromPrimitive, Default, Debug)]
+#[repr(u8)]
+pub enum Placement {
+    #[default]
+    Default = 0,
+    Above,
+    Below,
+}
+
+impl Placement {
+    /// Value of the `placement` attribute, or `None` when the attribute is omitted
+    pub fn attribute_value(&self) -> Option<&'static str> {
+        match self {
+            Placement::Default => None,
+            Placement::Above => Some("above"),
+            Placement::Below => Some("below"),
+        }
+    }
+}
+
+impl FromStr for Placement {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<Placement> {
+        match input {
+            "above" => Ok(Placement::Above),
+            "below" => Ok(Placement::Below),
+            _ => Err(Error::Parse),
+        }
+    }
+}
+
 #[derive(Eq, PartialEq, Copy, Clone, FromPrimitive, Default, Debug)]
 #[repr(u8)]
 pub enum SpecialNote {
@@ -613,6 +877,69 @@ impl FromStr for PhraseDynamics {
     }
 }
 
+impl PhraseDynamics {
+    /// MIDI note-on velocity for the dynamic level. Markings without a level, such as hairpins,
+    /// and the absence of a marking use mezzo forte.
+    pub fn to_velocity(self) -> u8 {
+        match self {
+            PhraseDynamics::Pianississimo => 16,
+            PhraseDynamics::Pianissimo => 33,
+            PhraseDynamics::Piano => 49,
+            PhraseDynamics::MezzoPiano => 64,
+            PhraseDynamics::MezzoForte => 80,
+            PhraseDynamics::Forte | PhraseDynamics::Fortepiano => 96,
+            PhraseDynamics::Rinforzando => 104,
+            PhraseDynamics::Fortissimo | PhraseDynamics::Sforzando => 112,
+            PhraseDynamics::Fortississimo => 127,
+            PhraseDynamics::Niente => 1,
+            PhraseDynamics::None | PhraseDynamics::Crescendo | PhraseDynamics::Diminuendo => 80,
+        }
+    }
+
+    /// The dynamic level, from ppp to fff, whose velocity is nearest to `velocity`
+    pub fn from_velocity(velocity: f64) -> PhraseDynamics {
+        (PhraseDynamics::Pianississimo as u8..=PhraseDynamics::Fortississimo as u8)
+            .filter_map(PhraseDynamics::from_u8)
+            .min_by(|a, b| {
+                let distance = |d: &PhraseDynamics| (f64::from(d.to_velocity()) - velocity).abs();
+                distance(a).total_cmp(&distance(b))
+            })
+            .unwrap_or_default()
+    }
+
+    /// Moves a dynamic level `steps` levels louder, or softer when negative, stopping at ppp and fff.
+    /// Markings without a level are returned unchanged.
+    pub fn step(self, steps: i8) -> PhraseDynamics {
+        let (softest, loudest) = (PhraseDynamics::Pianississimo as i8, PhraseDynamics::Fortississimo as i8);
+        let level = self as i8;
+        if !(softest..=loudest).contains(&level) {
+            return self;
+        }
+        PhraseDynamics::from_i8((level + steps).clamp(softest, loudest)).unwrap_or(self)
+    }
+
+    /// Returns the conventional score marking, such as "mf"
+    pub fn abbreviation(self) -> Option<&'static str> {
+        match self {
+            PhraseDynamics::None => None,
+            PhraseDynamics::Sforzando => Some("sf"),
+            PhraseDynamics::Fortepiano => Some("fp"),
+            PhraseDynamics::Crescendo => Some("cresc"),
+            PhraseDynamics::Diminuendo => Some("dim"),
+            PhraseDynamics::Niente => Some("n"),
+            PhraseDynamics::Rinforzando => Some("rf"),
+            PhraseDynamics::Pianississimo => Some("ppp"),
+            PhraseDynamics::Pianissimo => Some("pp"),
+            PhraseDynamics::Piano => Some("p"),
+            PhraseDynamics::MezzoPiano => Some("mp"),
+            PhraseDynamics::MezzoForte => Some("mf"),
+            PhraseDynamics::Forte => Some("f"),
+            PhraseDynamics::Fortissimo => Some("ff"),
+            PhraseDynamics::Fortississimo => Some("fff"),
+        }
+    }
+}
+
 impl From<PhraseDynamics> for Option<DynamicsValue> {
     fn from(dynamics: PhraseDynamics) -> Option<DynamicsValue> {
         match dynamics {
@@ -673,6 +1000,27 @@ pub enum Trill {
     Chromatic,
 }
 
+/// How a tempo between two of the stored tempos, which are two BPM apart, is stored
+#[derive(Copy, Clone, Eq, PartialEq, Default, Debug)]
+pub enum TempoRounding {
+    /// The stored tempo below it
+    #[default]
+    Floor,
+    /// The nearest stored tempo, the one above it when halfway
+    Nearest,
+}
+
+impl FromStr for TempoRounding {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<TempoRounding> {
+        match input {
+            "floor" => Ok(TempoRounding::Floor),
+            "nearest" => Ok(TempoRounding::Nearest),
+            _ => Err(Error::Parse),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub struct Tempo(u8);
 
@@ -689,6 +1037,11 @@ impl Tempo {
     const DEFAULT_REAL_TEMPO: i32 = 120;
 
     pub fn new(real_tempo: i32) -> Tempo {
+        Tempo::new_rounded(real_tempo, TempoRounding::Floor)
+    }
+
+    /// Stores a tempo in BPM, clamped to the supported tempos and rounded by `rounding`
+    pub fn new_rounded(real_tempo: i32, rounding: TempoRounding) -> Tempo {
         let assign_tempo: i32;
         if real_tempo > Self::MAX_SUPPORTED_REAL_TEMPO {
             assign_tempo = Self::MAX_SUPPORTED_REAL_TEMPO;
@@ -697,7 +1050,11 @@ impl Tempo {
         } else {
             assign_tempo = real_tempo;
         }
-        Tempo(((assign_tempo - 20) / 2) as u8)
+        let rounding_offset = match rounding {
+            TempoRounding::Floor => 0,
+            TempoRounding::Nearest => 1,
+        };
+        Tempo(((assign_tempo - Self::MIN_SUPPORTED_REAL_TEMPO + rounding_offset) / 2) as u8)
     }
 
     pub fn new_from_raw(raw_tempo: u8) -> Tempo {
@@ -770,6 +1127,36 @@ pub enum DescriptiveTempo {
     Prestissimo,
 }
 
+impl DescriptiveTempo {
+    /// Representative BPM for the marking, the midpoint of the range `From<Tempo>` maps to it
+    pub fn to_bpm(self) -> i32 {
+        match self {
+            DescriptiveTempo::Larghissimo => 22,
+            DescriptiveTempo::Grave => 32,
+            DescriptiveTempo::Lento => 43,
+            DescriptiveTempo::Largo => 48,
+            DescriptiveTempo::Adagio => 58,
+            DescriptiveTempo::Adagietto => 67,
+            DescriptiveTempo::Andante => 73,
+            DescriptiveTempo::Moderato => 87,
+            DescriptiveTempo::Allegretto => 109,
+            DescriptiveTempo::Allegro => 135,
+            DescriptiveTempo::Vivace => 163,
+            DescriptiveTempo::Presto => 188,
+            DescriptiveTempo::Prestissimo => 237,
+        }
+    }
+
+    /// Finds a tempo marking within free text such as "Andante con moto", ignoring case
+    pub fn from_words(text: &str) -> Option<DescriptiveTempo> {
+        text.split(|c: char| !c.is_alphabetic()).find_map(|word| {
+            (DescriptiveTempo::Larghissimo as u8..=DescriptiveTempo::Prestissimo as u8)
+                .filter_map(<DescriptiveTempo as FromPrimitive>::from_u8)
+                .find(|t| t.to_string().eq_ignore_ascii_case(word))
+        })
+    }
+}
+
 impl From<Tempo> for DescriptiveTempo {
     fn from(tempo: Tempo) -> Self {
         let val = tempo.get_actual();
@@ -857,6 +1244,41 @@ impl FromStr for DescriptiveTempo {
     }
 }
 
+/// Style of the barline closing a measure. The barlines of backward repeats are drawn by the repeat.
+#[derive(Eq, PartialEq, Copy, Clone, FromPrimitive, Default, Debug)]
+#[repr(u8)]
+pub enum BarStyle {
+    #[default]
+    Regular = 0,
+    /// Two thin lines, as at a section change
+    Double,
+    /// A thin and a thick line, ending the piece
+    Final,
+    Dashed,
+}
+
+impl BarStyle {
+    /// Reads a MusicXML `<bar-style>` value. Styles without a counterpart, such as `heavy-light`, are regular.
+    pub fn from_xml(value: &str) -> BarStyle {
+        match value {
+            "light-light" => BarStyle::Double,
+            "light-heavy" => BarStyle::Final,
+            "dashed" => BarStyle::Dashed,
+            _ => BarStyle::Regular,
+        }
+    }
+
+    /// The MusicXML `<bar-style>` value
+    pub fn xml_value(self) -> &'static str {
+        match self {
+            BarStyle::Regular => "regular",
+            BarStyle::Double => "light-light",
+            BarStyle::Final => "light-heavy",
+            BarStyle::Dashed => "dashed",
+        }
+    }
+}
+
 #[derive(Eq, PartialEq, Copy, Clone, FromPrimitive, Default, Debug)]
 #[repr(u8)]
 pub enum DalSegno {
@@ -883,13 +1305,16 @@ pub enum RhythmType {
     Crochet,
     Minim,
     SemiBreve,
+    /// Double whole note. The 3-bit rhythm value of the bin format has no room for it, so it is
+    /// stored as a semibreve marked as a breve in the note extension.
+    Breve,
 }
 
 impl FromStr for RhythmType {
     type Err = Error;
     fn from_str(input: &str) -> Result<RhythmType> {
         match input {
-            "breve" => Ok(RhythmType::SemiBreve),
+            "breve" => Ok(RhythmType::Breve),
             "whole" => Ok(RhythmType::SemiBreve),
             "half" => Ok(RhythmType::Minim),
             "quarter" => Ok(RhythmType::Crochet),
@@ -907,6 +1332,11 @@ impl FromStr for RhythmType {
 }
 
 impl RhythmType {
+    /// Length in 128th notes
+    pub fn relative_length(self) -> u32 {
+        1 << (self as u32)
+    }
+
     pub fn get_type_string(self) -> String {
         match self {
             RhythmType::SemiHemiDemiSemiQuaver => String::from("128th"),
@@ -917,6 +1347,7 @@ impl RhythmType {
             RhythmType::Crochet => String::from("quarter"),
             RhythmType::Minim => String::from("half"),
             RhythmType::SemiBreve => String::from("whole"),
+            RhythmType::Breve => String::from("breve"),
         }
     }
 }
@@ -948,7 +1379,65 @@ impl From<Beats> for u32 {
     }
 }
 
-#[derive(Copy, Clone, Debug, Default, FromPrimitive, PartialEq)]
+/// Clef of one staff of an exported part. Each sign is written on its standard line.
+#[derive(Copy, Clone, Debug, Eq, FromPrimitive, PartialEq)]
+#[repr(u8)]
+pub enum Clef {
+    Treble = 0,
+    Bass,
+    Alto,
+}
+
+impl Clef {
+    pub const PIANO: [Clef; 2] = [Clef::Treble, Clef::Bass];
+
+    pub fn sign(self) -> &'static str {
+        match self {
+            Clef::Treble => "G",
+            Clef::Bass => "F",
+            Clef::Alto => "C",
+        }
+    }
+
+    pub fn line(self) -> u8 {
+        match self {
+            Clef::Treble => 2,
+            Clef::Bass => 4,
+            Clef::Alto => 3,
+        }
+    }
+
+    /// Guesses the staves of a part from its instrument name, such as "Violin I" or "Viola"
+    pub fn from_part_name(name: &str) -> Option<Vec<Clef>> {
+        let name = name.to_lowercase();
+        let has_any = |instruments: &[&str]| instruments.iter().any(|i| name.contains(i));
+        if has_any(&["piano", "harp", "organ", "celesta", "harpsichord"]) {
+            Some(Clef::PIANO.to_vec())
+        } else if has_any(&["viola"]) {
+            Some(vec![Clef::Alto])
+        } else if has_any(&["cello", "contrabass", "double bass", "bassoon", "trombone", "tuba", "timpani"]) {
+            Some(vec![Clef::Bass])
+        } else if has_any(&["violin", "flute", "piccolo", "oboe", "clarinet", "trumpet", "horn", "sax", "soprano", "guitar"]) {
+            Some(vec![Clef::Treble])
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for Clef {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<Clef> {
+        match input {
+            "treble" | "G" => Ok(Clef::Treble),
+            "bass" | "F" => Ok(Clef::Bass),
+            "alto" | "C" => Ok(Clef::Alto),
+            _ => Err(Error::Parse),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, FromPrimitive, PartialEq)]
 #[repr(u8)]
 pub enum Staff {
     #[default]
@@ -1052,12 +1541,413 @@ impl FromStr for BeatType {
     }
 }
 
+#[derive(Eq, PartialEq, Copy, Clone, FromPrimitive, Default, Debug)]
+#[repr(u8)]
+pub enum HarmonyStep {
+    #[default]
+    C = 0,
+    D,
+    E,
+    F,
+    G,
+    A,
+    B,
+}
+
+impl FromStr for HarmonyStep {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<HarmonyStep> {
+        match input {
+            "C" => Ok(HarmonyStep::C),
+            "D" => Ok(HarmonyStep::D),
+            "E" => Ok(HarmonyStep::E),
+            "F" => Ok(HarmonyStep::F),
+            "G" => Ok(HarmonyStep::G),
+            "A" => Ok(HarmonyStep::A),
+            "B" => Ok(HarmonyStep::B),
+            _ => Err(Error::Parse),
+        }
+    }
+}
+
+impl ToString for HarmonyStep {
+    fn to_string(&self) -> String {
+        match self {
+            HarmonyStep::C => "C".to_string(),
+            HarmonyStep::D => "D".to_string(),
+            HarmonyStep::E => "E".to_string(),
+            HarmonyStep::F => "F".to_string(),
+            HarmonyStep::G => "G".to_string(),
+            HarmonyStep::A => "A".to_string(),
+            HarmonyStep::B => "B".to_string(),
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Copy, Clone, FromPrimitive, Default, Debug)]
+#[repr(u8)]
+pub enum HarmonyAlter {
+    #[default]
+    None = 0,
+    Flat,
+    Sharp,
+}
+
+impl FromStr for HarmonyAlter {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<HarmonyAlter> {
+        match input {
+            "0" => Ok(HarmonyAlter::None),
+            "-1" => Ok(HarmonyAlter::Flat),
+            "1" => Ok(HarmonyAlter::Sharp),
+            _ => Err(Error::Parse),
+        }
+    }
+}
+
+impl ToString for HarmonyAlter {
+    fn to_string(&self) -> String {
+        match self {
+            HarmonyAlter::None => "".to_string(),
+            HarmonyAlter::Flat => "-1".to_string(),
+            HarmonyAlter::Sharp => "1".to_string(),
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Copy, Clone, FromPrimitive, Default, Debug)]
+#[repr(u8)]
+pub enum HarmonyKind {
+    #[default]
+    Major = 0,
+    Minor,
+    Dominant,
+    MajorSeventh,
+    MinorSeventh,
+}
+
+impl FromStr for HarmonyKind {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<HarmonyKind> {
+        match input {
+            "major" => Ok(HarmonyKind::Major),
+            "minor" => Ok(HarmonyKind::Minor),
+            "dominant" => Ok(HarmonyKind::Dominant),
+            "major-seventh" => Ok(HarmonyKind::MajorSeventh),
+            "minor-seventh" => Ok(HarmonyKind::MinorSeventh),
+            _ => Err(Error::Unsupported),
+        }
+    }
+}
+
+impl ToString for HarmonyKind {
+    fn to_string(&self) -> String {
+        match self {
+            HarmonyKind::Major => "major".to_string(),
+            HarmonyKind::Minor => "minor".to_string(),
+            HarmonyKind::Dominant => "dominant".to_string(),
+            HarmonyKind::MajorSeventh => "major-seventh".to_string(),
+            HarmonyKind::MinorSeventh => "minor-seventh".to_string(),
+        }
+    }
+}
+
+/// A clef change within a measure, applying to one staff from the next note onwards
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub struct ClefChangeData {
+    pub staff: Staff,
+    pub clef: Clef,
+    /// Octaves the staff sounds away from the plain clef, as MusicXML's `<clef-octave-change>`, such as
+    /// -1 for the treble clef of a tenor voice. Pitches are stored as written in MusicXML, which already
+    /// places them at the sounding octave, so only the clef carries it.
+    pub octave_change: i8,
+}
+
+impl ClefChangeData {
+    /// Largest octave change of a clef, that of a 15ma or 15mb clef
+    pub const MAX_OCTAVE_CHANGE: i8 = 2;
+
+    /// MusicXML `<clef-octave-change>` element, empty for a clef without an octave change
+    pub fn octave_change_fragment(octave_change: i8) -> String {
+        if octave_change == 0 {
+            String::new()
+        } else {
+            format!("<clef-octave-change>{}</clef-octave-change>", octave_change)
+        }
+    }
+}
+
+/// Sound of an unpitched note, numbered by its General MIDI percussion key
+#[derive(Eq, PartialEq, Copy, Clone, FromPrimitive, Default, Debug)]
+#[repr(u8)]
+pub enum DrumSound {
+    BassDrum = 36,
+    SideStick = 37,
+    #[default]
+    Snare = 38,
+    HandClap = 39,
+    LowFloorTom = 41,
+    ClosedHiHat = 42,
+    PedalHiHat = 44,
+    LowTom = 45,
+    OpenHiHat = 46,
+    MidTom = 47,
+    CrashCymbal = 49,
+    HighTom = 50,
+    RideCymbal = 51,
+    Tambourine = 54,
+    Cowbell = 56,
+}
+
+impl DrumSound {
+    /// The key played on MIDI channel 10
+    pub fn midi_key(self) -> u8 {
+        self as u8
+    }
+
+    /// Finds the sound of an instrument name such as "Snare Drum" or "Kick"
+    pub fn from_instrument_name(name: &str) -> Option<DrumSound> {
+        let name = name.to_lowercase();
+        let has = |words: &[&str]| words.iter().any(|w| name.contains(w));
+        if has(&["kick", "bass drum"]) {
+            Some(DrumSound::BassDrum)
+        } else if has(&["side stick", "rim"]) {
+            Some(DrumSound::SideStick)
+        } else if has(&["snare"]) {
+            Some(DrumSound::Snare)
+        } else if has(&["hi-hat", "hihat", "hi hat"]) {
+            if has(&["open"]) {
+                Some(DrumSound::OpenHiHat)
+            } else if has(&["pedal", "foot"]) {
+                Some(DrumSound::PedalHiHat)
+            } else {
+                Some(DrumSound::ClosedHiHat)
+            }
+        } else if has(&["crash"]) {
+            Some(DrumSound::CrashCymbal)
+        } else if has(&["ride"]) {
+            Some(DrumSound::RideCymbal)
+        } else if has(&["floor tom"]) {
+            Some(DrumSound::LowFloorTom)
+        } else if has(&["tom"]) {
+            if has(&["high"]) {
+                Some(DrumSound::HighTom)
+            } else if has(&["low"]) {
+                Some(DrumSound::LowTom)
+            } else {
+                Some(DrumSound::MidTom)
+            }
+        } else if has(&["clap"]) {
+            Some(DrumSound::HandClap)
+        } else if has(&["tambourine"]) {
+            Some(DrumSound::Tambourine)
+        } else if has(&["cowbell"]) {
+            Some(DrumSound::Cowbell)
+        } else {
+            None
+        }
+    }
+
+    /// The sound conventionally written at a position of the five line percussion staff, named as
+    /// the treble clef pitch of that position
+    pub fn from_display_position(display: NumericPitchRest) -> Option<DrumSound> {
+        match display.to_string().as_str() {
+            "D4" => Some(DrumSound::PedalHiHat),
+            "E4" | "F4" => Some(DrumSound::BassDrum),
+            "A4" => Some(DrumSound::LowFloorTom),
+            "C5" => Some(DrumSound::Snare),
+            "D5" => Some(DrumSound::LowTom),
+            "E5" => Some(DrumSound::MidTom),
+            "F5" => Some(DrumSound::HighTom),
+            "G5" => Some(DrumSound::ClosedHiHat),
+            "A5" => Some(DrumSound::CrashCymbal),
+            "B5" => Some(DrumSound::RideCymbal),
+            _ => None,
+        }
+    }
+}
+
+/// An unpitched note of a percussion part. The note data holds its rhythm and voice, and the
+/// position it is written at on the staff as its pitch.
+#[derive(Eq, PartialEq, Copy, Clone, Default, Debug)]
+pub struct PercussionData {
+    pub drum: DrumSound,
+    pub note: NoteData,
+}
+
+/// A chord symbol written above the staff, applying from the next note onwards
+#[derive(Eq, PartialEq, Copy, Clone, Default, Debug)]
+pub struct HarmonyData {
+    pub root_step: HarmonyStep,
+    pub root_alter: HarmonyAlter,
+    pub kind: HarmonyKind,
+    pub bass: Option<(HarmonyStep, HarmonyAlter)>,
+}
+
+impl HarmonyData {
+    const STEP_PITCH_CLASSES: [i8; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+    fn transpose_note(
+        step: HarmonyStep,
+        alter: HarmonyAlter,
+        semitones: i8,
+        flats: bool,
+    ) -> (HarmonyStep, HarmonyAlter) {
+        let alter_semitones = match alter {
+            HarmonyAlter::None => 0,
+            HarmonyAlter::Flat => -1,
+            HarmonyAlter::Sharp => 1,
+        };
+        let pitch_class =
+            (Self::STEP_PITCH_CLASSES[step as usize] + alter_semitones + semitones).rem_euclid(12);
+        if let Some(step_idx) = Self::STEP_PITCH_CLASSES.iter().position(|&pc| pc == pitch_class) {
+            (FromPrimitive::from_usize(step_idx).unwrap(), HarmonyAlter::None)
+        } else if flats {
+            let step_idx = Self::STEP_PITCH_CLASSES.iter().position(|&pc| pc == pitch_class + 1).unwrap();
+            (FromPrimitive::from_usize(step_idx).unwrap(), HarmonyAlter::Flat)
+        } else {
+            let step_idx = Self::STEP_PITCH_CLASSES.iter().position(|&pc| pc == pitch_class - 1).unwrap();
+            (FromPrimitive::from_usize(step_idx).unwrap(), HarmonyAlter::Sharp)
+        }
+    }
+
+    /// Transposes the root and bass by `semitones`, spelling black keys with flats when `flats` is set
+    pub fn transpose(self, semitones: i8, flats: bool) -> HarmonyData {
+        let (root_step, root_alter) =
+            Self::transpose_note(self.root_step, self.root_alter, semitones, flats);
+        HarmonyData {
+            root_step,
+            root_alter,
+            bass: self
+                .bass
+                .map(|(step, alter)| Self::transpose_note(step, alter, semitones, flats)),
+            ..self
+        }
+    }
+}
+
 #[derive(Eq, PartialEq, Copy, Clone, Debug)]
 pub enum MusicElement {
     MeasureInit(MeasureInitializer),
     MeasureMeta(MeasureMetaData),
     NoteRest(NoteData),
     Tuplet(TupletData),
+    Harmony(HarmonyData),
+    ClefChange(ClefChangeData),
+    Percussion(PercussionData),
+}
+
+/// Compact rendering used by the `--dump` output, e.g. `C#4 quarter v1 f` or `|start|`
+impl fmt::Display for MusicElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.display_with(PitchNotation::Letter).fmt(f)
+    }
+}
+
+impl MusicElement {
+    /// Renders the element like `Display`, naming pitches in the given notation
+    pub fn display_with(&self, notation: PitchNotation) -> ElementDisplay<'_> {
+        ElementDisplay { elem: self, notation }
+    }
+}
+
+/// A music element rendered with a chosen pitch notation, returned by [`MusicElement::display_with`]
+pub struct ElementDisplay<'a> {
+    elem: &'a MusicElement,
+    notation: PitchNotation,
+}
+
+impl fmt::Display for ElementDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.elem {
+            MusicElement::MeasureInit(m) => write!(
+                f,
+                "|{}/{} {} {}bpm|",
+                m.beats.to_string(),
+                m.beat_type.to_string(),
+                m.key_sig.major_name(),
+                m.tempo.get_actual()
+            ),
+            MusicElement::MeasureMeta(m) => {
+                let boundary = match m.start_end {
+                    MeasureStartEnd::MeasureStart => "start",
+                    MeasureStartEnd::MeasureEnd => "end",
+                    MeasureStartEnd::RepeatStart => "repeat-start",
+                    MeasureStartEnd::RepeatEnd => "repeat-end",
+                };
+                if m.ending == Ending::None {
+                    write!(f, "|{}|", boundary)
+                } else {
+                    write!(f, "|{} ending {}|", boundary, m.ending.to_string())
+                }
+            }
+            MusicElement::NoteRest(n) => {
+                write!(f, "{}", n.note_rest.name(self.notation))?;
+                if n.cents != 0 {
+                    write!(f, "{:+}c", n.cents)?;
+                }
+                write!(f, " {}", n.note_type.get_type_string())?;
+                if n.dotted {
+                    write!(f, ".")?;
+                }
+                write!(f, " v{}", n.voice as u8 + 1)?;
+                if let Some(dynamics) = n.phrase_dynamics.abbreviation() {
+                    write!(f, " {}", dynamics)?;
+                }
+                Ok(())
+            }
+            MusicElement::Tuplet(t) => {
+                let start_stop = match t.start_stop {
+                    TupletStartStop::None => "none",
+                    TupletStartStop::TupletStart => "start",
+                    TupletStartStop::TupletStop => "stop",
+                };
+                write!(
+                    f,
+                    "tuplet {} {}:{}",
+                    start_stop,
+                    t.actual_notes.as_u32(),
+                    t.normal_notes.as_u32()
+                )
+            }
+            MusicElement::Harmony(h) => {
+                let accidental = |alter: HarmonyAlter| match alter {
+                    HarmonyAlter::None => "",
+                    HarmonyAlter::Flat => "b",
+                    HarmonyAlter::Sharp => "#",
+                };
+                write!(
+                    f,
+                    "chord {}{} {}",
+                    h.root_step.to_string(),
+                    accidental(h.root_alter),
+                    h.kind.to_string()
+                )?;
+                if let Some((bass_step, bass_alter)) = h.bass {
+                    write!(f, "/{}{}", bass_step.to_string(), accidental(bass_alter))?;
+                }
+                Ok(())
+            }
+            MusicElement::ClefChange(c) => {
+                write!(f, "clef {}", c.clef.sign())?;
+                match c.octave_change {
+                    -2 => write!(f, " 15mb")?,
+                    -1 => write!(f, " 8vb")?,
+                    1 => write!(f, " 8va")?,
+                    2 => write!(f, " 15ma")?,
+                    _ => {}
+                }
+                write!(f, " staff {}", c.staff as u8)
+            }
+            MusicElement::Percussion(p) => {
+                write!(f, "drum {} {}", p.drum.midi_key(), p.note.note_type.get_type_string())?;
+                if p.note.dotted {
+                    write!(f, ".")?;
+                }
+                write!(f, " v{}", p.note.voice as u8 + 1)
+            }
+        }
+    }
 }
 
 #[derive(Eq, PartialEq, Copy, Clone, Default, Debug)]
@@ -1073,17 +1963,64 @@ pub struct MeasureMetaData {
     pub start_end: MeasureStartEnd,
     pub ending: Ending,
     pub dal_segno: DalSegno,
+    /// Times the repeated section is played, from the `times` attribute of a backward repeat. `None`
+    /// plays it twice.
+    pub repeat_times: Option<u8>,
+    /// Style of the barline a measure end closes the measure with
+    pub bar_style: BarStyle,
 }
 
 impl MeasureMetaData {
+    /// Most repeat times the 5-bit field of the bin format holds
+    pub const MAX_REPEAT_TIMES: u8 = 31;
+
     pub fn new(measure_type: MeasureStartEnd) -> MeasureMetaData {
         MeasureMetaData {
             start_end: measure_type,
             ending: Ending::default(),
             dal_segno: DalSegno::default(),
+            repeat_times: None,
+            bar_style: BarStyle::default(),
         }
     }
 }
+
+/// Deviation in cents from equal temperament of each pitch class, from C upwards, for rendering
+/// other temperaments with MIDI pitch bends. The default table is equal temperament.
+#[derive(PartialEq, Default, Clone, Copy, Debug)]
+pub struct TuningTable {
+    pub offsets: [f64; 12],
+}
+
+impl TuningTable {
+    /// Quarter comma meantone with its pure major thirds built on C, so C itself is not bent
+    pub fn quarter_comma_meantone() -> TuningTable {
+        TuningTable {
+            offsets: [0.0, -24.0, -6.8, 10.3, -13.7, 3.4, -20.5, -3.4, -27.4, -10.3, 6.8, -17.1],
+        }
+    }
+
+    /// Deviation in cents of the pitch from equal temperament, `None` for rests
+    pub fn offset_cents(&self, pitch: NumericPitchRest) -> Option<f64> {
+        let midi = pitch.get_midi_numeric_pitch_value()?;
+        Some(self.offsets[usize::from(midi % 12)])
+    }
+}
+
+impl FromStr for TuningTable {
+    type Err = Error;
+    /// Reads twelve comma separated cents offsets, from C upwards
+    fn from_str(input: &str) -> Result<TuningTable> {
+        let offsets: Vec<f64> = input
+            .split(',')
+            .map(|cents| cents.trim().parse::<f64>().map_err(|_| Error::Parse))
+            .collect::<Result<_>>()?;
+        Ok(TuningTable {
+            offsets: offsets.try_into().map_err(|_| Error::Parse)?,
+        })
+    }
+}
+
 #[derive(Eq, PartialEq, Default, Clone, Copy, Debug)]
 pub struct NoteData {
     pub note_rest: NumericPitchRest,
@@ -1097,12 +2034,32 @@ pub struct NoteData {
     pub ties: NoteConnection,
     pub chord: Chord,
     pub slur: SlurConnection,
+    pub slur_number: SlurNumber,
     pub voice: Voice,
+    /// The note's accidental is a cautionary one, engraved in parentheses
+    pub cautionary_accidental: bool,
+    pub note_size: NoteSize,
+    pub notehead: Notehead,
+    /// Placement of the direction holding the note's dynamic marking
+    pub dynamics_placement: Placement,
+    pub tremolo: Option<Tremolo>,
+    /// Deviation of a microtonal note from its pitch, from a fractional `<alter>`
+    pub cents: i8,
+    /// Staff the score puts the note on, kept only when it differs from the staff derived from the voice
+    pub staff: Option<Staff>,
 }
 
 pub type IsDotted = bool;
 
 impl NoteData {
+    /// Largest deviation in cents, half a semitone either way
+    pub const MAX_CENTS: i8 = 50;
+    /// Pitch bend of an unbent note, in the middle of the 14-bit range
+    pub const PITCH_BEND_CENTER: u16 = 8192;
+    pub const PITCH_BEND_MAX: u16 = 16383;
+    /// Cents the full pitch bend range covers either way
+    const PITCH_BEND_RANGE_CENTS: f64 = 200.0;
+    const BREVE_NUMERATOR: u32 = 2;
     const SEMIBREVE_DENOMINATOR: u32 = 1;
     const MINIM_DENOMINATOR: u32 = 2;
     const CROCHET_DENOMINATOR: u32 = 4;
@@ -1122,6 +2079,7 @@ impl NoteData {
     const MIDI_TICKS_CROCHET: u32 = 960;
     const MIDI_TICKS_MINIM: u32 = Self::MIDI_TICKS_CROCHET * 2;
     const MIDI_TICKS_SEMIBREVE: u32 = Self::MIDI_TICKS_CROCHET * 4;
+    const MIDI_TICKS_BREVE: u32 = Self::MIDI_TICKS_CROCHET * 8;
 
     pub fn new_default_rest(note_type: RhythmType, dotted: IsDotted, voice: Voice) -> NoteData {
         NoteData {
@@ -1133,6 +2091,35 @@ impl NoteData {
         }
     }
 
+    /// MIDI note-on velocity for the note. A dynamic marking on the note replaces `active_dynamics`,
+    /// which carries the most recent marking from note to note; accents add to the level.
+    pub fn midi_velocity(&self, active_dynamics: &mut PhraseDynamics) -> u8 {
+        if self.phrase_dynamics != PhraseDynamics::None {
+            *active_dynamics = self.phrase_dynamics;
+        }
+        active_dynamics
+            .to_velocity()
+            .saturating_add(self.articulation.velocity_bump())
+            .min(127)
+    }
+
+    /// MIDI pitch bend sounding the note in the temperament of `tuning`, with its microtonal deviation
+    /// added, for a bend range of two semitones either way. `None` for rests and for notes sounding at
+    /// their equal tempered pitch, so equal temperament needs no bends.
+    pub fn midi_pitch_bend(&self, tuning: &TuningTable) -> Option<u16> {
+        let cents = tuning.offset_cents(self.note_rest)? + f64::from(self.cents);
+        if cents == 0.0 {
+            return None;
+        }
+        let bend = f64::from(Self::PITCH_BEND_CENTER) + (cents / Self::PITCH_BEND_RANGE_CENTS * 8192.0).round();
+        Some(bend.clamp(0.0, f64::from(Self::PITCH_BEND_MAX)) as u16)
+    }
+
+    /// False for grace and cue notes, which do not count towards the length of their voice
+    pub fn occupies_time(&self) -> bool {
+        self.special_note == SpecialNote::None && self.note_size != NoteSize::Cue
+    }
+
     pub fn get_note_multiple(&self, time_mods: Option<TimeModification>) -> Option<u32> {
         let mut numer: u32 = 1;
         if self.special_note != SpecialNote::None {
@@ -1141,6 +2128,10 @@ impl NoteData {
         }
 
         let mut denom = match self.note_type {
+            RhythmType::Breve => {
+                numer = Self::BREVE_NUMERATOR;
+                Self::SEMIBREVE_DENOMINATOR
+            }
             RhythmType::SemiBreve => Self::SEMIBREVE_DENOMINATOR,
             RhythmType::Minim => Self::MINIM_DENOMINATOR,
             RhythmType::Crochet => Self::CROCHET_DENOMINATOR,
@@ -1157,8 +2148,9 @@ impl NoteData {
         }
 
         if let Some(val) = time_mods {
-            numer *= val.normal_notes.as_u32();
-            denom *= val.actual_notes.as_u32();
+            let (scale_numer, scale_denom) = val.scale(self.note_type);
+            numer *= scale_numer;
+            denom *= scale_denom;
         }
         let f = Fraction::new(numer, denom);
         //println!("{}",f);
@@ -1180,11 +2172,13 @@ impl NoteData {
         }
 
         if let Some(val) = time_mods {
-            numerator *= val.normal_notes.as_u32();
-            denominator *= val.actual_notes.as_u32();
+            let (scale_numer, scale_denom) = val.scale(self.note_type);
+            numerator *= scale_numer;
+            denominator *= scale_denom;
         }
 
         match self.note_type {
+            RhythmType::Breve => Self::MIDI_TICKS_BREVE * numerator / denominator,
             RhythmType::SemiBreve => Self::MIDI_TICKS_SEMIBREVE * numerator / denominator,
             RhythmType::Minim => Self::MIDI_TICKS_MINIM * numerator / denominator,
             RhythmType::Crochet => Self::MIDI_TICKS_CROCHET * numerator / denominator,
@@ -1226,8 +2220,9 @@ impl NoteData {
         }
 
         if let Some(val) = time_mods {
-            numerator *= val.normal_notes.as_u32();
-            denominator *= val.actual_notes.as_u32();
+            let (scale_numer, scale_denom) = val.scale(self.note_type);
+            numerator *= scale_numer;
+            denominator *= scale_denom;
             if denominator == 0 {
                 panic!("time_mod denominator cannot be zero.");
             }
@@ -1245,11 +2240,12 @@ impl NoteData {
                 // The duration of a semi breve rest can differ based on time signature.
                 // For example, in 4/4, it would be 4 crochets, but in 3/4, only 3 crochets
                 if self.note_rest == NumericPitchRest::Rest {
-                    ((divisions * numerator * beats * 10) / (beat_type * 10)) / denominator
+                    ((divisions * 4 * numerator * beats) / beat_type) / denominator
                 } else {
                     (divisions * 4 * numerator) / denominator
                 }
             }
+            RhythmType::Breve => (divisions * 8 * numerator) / denominator,
         }
     }
 
@@ -1264,7 +2260,19 @@ impl NoteData {
             .to_string()
     }
 
-    /// Converts a numeric duration to its corresponding musical `NoteType` and `IsDotted` representation.
+    const DURATION_NOTE_TYPES: [RhythmType; 8] = [
+        RhythmType::SemiBreve,
+        RhythmType::Minim,
+        RhythmType::Crochet,
+        RhythmType::Quaver,
+        RhythmType::SemiQuaver,
+        RhythmType::DemiSemiQuaver,
+        RhythmType::HemiDemiSemiQuaver,
+        RhythmType::SemiHemiDemiSemiQuaver,
+    ];
+
+    /// Converts a numeric duration to the rhythm values, dots and time modifications of tied notes
+    /// that together last exactly that long.
     ///
     /// # Arguments
     ///
@@ -1273,64 +2281,103 @@ impl NoteData {
     ///
     /// # Returns
     ///
-    /// Returns an `Option` containing a tuple of `NoteType` and `IsDotted` if the `numeric_duration`
-    /// matches a standard musical note duration. Returns `None` if the `numeric_duration` doesn't fit
-    /// standard note values.
+    /// A single component when the duration is a plain or dotted note value. Otherwise the duration is
+    /// split into the longest plain note values that fit, e.g. a quarter tied to a sixteenth. Tuplets are
+    /// only used for what cannot be expressed in plain note values. Returns an empty `Vec` for a zero duration.
     ///
     /// # Examples
     ///
     /// ```
-    /// # use muxml::ir::notation::{NoteType, IsDotted, from_numeric_duration};
-    /// let divisions = 480;
-    /// let numeric = 720;
-    ///
+    /// # use music2bin::ir::notation::{NoteData, RhythmType};
     /// assert_eq!(
-    ///     from_numeric_duration(numeric, divisions),
-    ///     Some((NoteType::QuarterNote, IsDotted::Dotted))
+    ///     NoteData::from_numeric_duration(5, 4),
+    ///     vec![(RhythmType::Crochet, false, None), (RhythmType::SemiQuaver, false, None)]
     /// );
+    /// ```
     pub fn from_numeric_duration(
         numeric_duration: u32,
         quarter_division: u32,
-    ) -> Option<(RhythmType, IsDotted, Option<TimeModification>)> {
-        let note_types = [
-            RhythmType::SemiBreve,
-            RhythmType::Minim,
-            RhythmType::Crochet,
-            RhythmType::Quaver,
-            RhythmType::SemiQuaver,
-            RhythmType::DemiSemiQuaver,
-            RhythmType::HemiDemiSemiQuaver,
-            RhythmType::SemiHemiDemiSemiQuaver,
-        ];
-
-        let is_dotted = |base: u32, duration| (3 * base) / 2 == duration;
-
-        // Start from a quarter note and expand in both directions.
-        let mut base_duration = quarter_division;
-        let mut exponent = 2; // index for quarter in note_types
-
-        if is_dotted(base_duration, numeric_duration) {
-            return Some((note_types[exponent], true, None));
+    ) -> Vec<(RhythmType, IsDotted, Option<TimeModification>)> {
+        let semibreve = quarter_division * 4;
+        let mut components = vec![];
+        let mut remaining = numeric_duration;
+        while remaining > 0 {
+            if let Some(component) = Self::plain_numeric_duration(remaining, quarter_division) {
+                components.push(component);
+                remaining = 0;
+                break;
+            }
+            // The longest plain note value that still fits, expressible in whole divisions
+            let longest = Self::DURATION_NOTE_TYPES
+                .iter()
+                .enumerate()
+                .map(|(exponent, note_type)| (semibreve >> exponent, exponent, *note_type))
+                .find(|(value, exponent, _)| {
+                    *value > 0 && *value <= remaining && (*value << exponent) == semibreve
+                });
+            match longest {
+                Some((value, _, note_type)) => {
+                    components.push((note_type, false, None));
+                    remaining -= value;
+                }
+                None => break,
+            }
+        }
+        if remaining == 0 {
+            return components;
         }
 
-        while base_duration > numeric_duration && exponent < note_types.len() - 1 {
-            base_duration /= 2;
-            exponent += 1;
-            if is_dotted(base_duration, numeric_duration) {
-                return Some((note_types[exponent], true, None));
+        // The duration is not a sum of plain note values, so a tuplet is needed
+        if let Some(component) = Self::tuplet_numeric_duration(numeric_duration, quarter_division) {
+            return vec![component];
+        }
+        match Self::tuplet_numeric_duration(remaining, quarter_division) {
+            Some(component) => components.push(component),
+            None => {
+                error!("Qtr{quarter_division} Duration {numeric_duration}: {remaining} divisions cannot be expressed as a note value");
+                components.push((RhythmType::SemiHemiDemiSemiQuaver, false, None));
             }
         }
+        components
+    }
 
-        while base_duration < numeric_duration && exponent > 0 {
-            base_duration *= 2;
-            exponent -= 1;
-            if is_dotted(base_duration, numeric_duration) {
-                return Some((note_types[exponent], true, None));
+    /// Finds the plain or dotted note value lasting exactly `numeric_duration`
+    fn plain_numeric_duration(
+        numeric_duration: u32,
+        quarter_division: u32,
+    ) -> Option<(RhythmType, IsDotted, Option<TimeModification>)> {
+        // A note type at `exponent` lasts semibreve / 2^exponent divisions
+        let semibreve = quarter_division * 4;
+        if numeric_duration == 2 * semibreve {
+            return Some((RhythmType::Breve, false, None));
+        }
+        if numeric_duration == 3 * semibreve {
+            return Some((RhythmType::Breve, true, None));
+        }
+        for (exponent, note_type) in Self::DURATION_NOTE_TYPES.iter().enumerate() {
+            if numeric_duration << exponent == semibreve {
+                return Some((*note_type, false, None));
+            }
+            if (numeric_duration << (exponent + 1)) == 3 * semibreve {
+                return Some((*note_type, true, None));
             }
         }
+        None
+    }
 
-        // Check for time modification representation (tuplets)
-        let mut tuplet_representation = None;
+    /// Finds the tuplet note value lasting exactly `numeric_duration`
+    fn tuplet_numeric_duration(
+        numeric_duration: u32,
+        quarter_division: u32,
+    ) -> Option<(RhythmType, IsDotted, Option<TimeModification>)> {
+        // Tuplets are written with the shortest note type that is at least as long as the duration
+        let semibreve = quarter_division * 4;
+        let exponent = Self::DURATION_NOTE_TYPES
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(exponent, _)| semibreve >= numeric_duration << exponent)
+            .map_or(0, |(exponent, _)| exponent);
         for nn in 2..=16 {
             if nn == 5 || nn == 7 || nn == 10 || nn == 11 || nn == 13 || nn == 14 {
                 // The TupletNormal type does not support these numerators
@@ -1342,39 +2389,86 @@ impl NoteData {
                     // The TupletActual type does not support these divisors
                     continue;
                 }
-                if (base_duration * nn) == numeric_duration * an {
-                    if an != nn {
-                        tuplet_representation = Some(TimeModification {
-                            actual_notes: TupletActual::try_from(an).unwrap_or_else(|_e| {
-                                panic!("Couldn't create TupletActual from u32 value {an}")
-                            }),
-                            normal_notes: TupletNormal::try_from(nn).unwrap_or_else(|_e| {
-                                panic!("Couldn't create TupletNormal from u32 value {nn}")
-                            }),
-                        });
-                    }
-                    break;
+                if an != nn && semibreve * nn == (numeric_duration << exponent) * an {
+                    let time_mod = TimeModification {
+                        actual_notes: TupletActual::try_from(an).unwrap_or_else(|_e| {
+                            panic!("Couldn't create TupletActual from u32 value {an}")
+                        }),
+                        normal_notes: TupletNormal::try_from(nn).unwrap_or_else(|_e| {
+                            panic!("Couldn't create TupletNormal from u32 value {nn}")
+                        }),
+                        normal_type: None,
+                    };
+                    return Some((Self::DURATION_NOTE_TYPES[exponent], false, Some(time_mod)));
                 }
             }
-            if tuplet_representation.is_some() {
-                break;
-            }
         }
+        None
+    }
 
-        let note_type = if exponent < note_types.len() {
-            note_types[exponent]
-        } else {
-            // exponent >= note_types.len() case
-            *note_types.last().unwrap()
-        };
-        if let Some(val) = tuplet_representation {
-            println!("TBC. Qtr{quarter_division} Duration {numeric_duration} NoteType: {:?} TimeMod: {:?}", note_type, val);
+    /// Splits the note into tied notes with the given rhythm values. Rests are not tied.
+    pub fn tied_components(
+        &self,
+        components: &[(RhythmType, IsDotted, Option<TimeModification>)],
+    ) -> Vec<NoteData> {
+        let last = components.len().saturating_sub(1);
+        components
+            .iter()
+            .enumerate()
+            .map(|(idx, (note_type, dotted, _))| {
+                let ties = if self.note_rest == NumericPitchRest::Rest || last == 0 {
+                    self.ties
+                } else if idx == 0 && self.ties == NoteConnection::EndTie {
+                    // The IR has no tie continuation, so a tie arriving from the previous note is kept instead
+                    NoteConnection::EndTie
+                } else if idx == last && self.ties != NoteConnection::StartTie {
+                    NoteConnection::EndTie
+                } else {
+                    NoteConnection::StartTie
+                };
+                NoteData {
+                    note_type: *note_type,
+                    dotted: *dotted,
+                    ties,
+                    ..*self
+                }
+            })
+            .collect()
+    }
+}
+
+/// Spelling of the black key pitches of exported notes. The bin format only stores the sounding pitch,
+/// so this only affects the MusicXML output.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum PitchSpelling {
+    /// Always sharps, giving every pitch class a single canonical spelling
+    #[default]
+    Sharp,
+    Flat,
+    /// Flats in the keys written with flats, otherwise sharps
+    Key,
+}
+
+impl PitchSpelling {
+    pub fn accidental_spelling(self, key_sig: KeySignature) -> AccidentalSpelling {
+        match self {
+            PitchSpelling::Sharp => AccidentalSpelling::Sharp,
+            PitchSpelling::Flat => AccidentalSpelling::Flat,
+            PitchSpelling::Key if key_sig.uses_flats() => AccidentalSpelling::Flat,
+            PitchSpelling::Key => AccidentalSpelling::Sharp,
         }
-        // else {
-        //     println!("TBC. Qtr{quarter_division} Duration {numeric_duration} NoteType: {:?} isDotted: {}", note_type, is_dotted);
-        // }
+    }
+}
 
-        Some((note_type, false, tuplet_representation))
+impl FromStr for PitchSpelling {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<PitchSpelling> {
+        match input {
+            "sharp" => Ok(PitchSpelling::Sharp),
+            "flat" => Ok(PitchSpelling::Flat),
+            "key" => Ok(PitchSpelling::Key),
+            _ => Err(Error::Parse),
+        }
     }
 }
 
@@ -1388,6 +2482,79 @@ pub enum NumericPitchRest {
     Pitch(u8),
 }
 
+const PITCH_STEP_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// Fixed do names of the pitch classes, sharps raising the syllable
+const SOLFEGE_NAMES: [&str; 12] = [
+    "do", "do#", "re", "re#", "mi", "fa", "fa#", "sol", "sol#", "la", "la#", "si",
+];
+
+/// Naming of pitches in the `--dump` output and the shell. This is display only and never changes
+/// what is encoded.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum PitchNotation {
+    /// Letter name and octave, e.g. `C#4`
+    #[default]
+    Letter,
+    /// Fixed do syllable of the pitch class, e.g. `do#`
+    Solfege,
+    /// MIDI note number, e.g. `61`
+    Midi,
+}
+
+impl FromStr for PitchNotation {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<PitchNotation> {
+        match input {
+            "letter" => Ok(PitchNotation::Letter),
+            "solfege" => Ok(PitchNotation::Solfege),
+            "midi" => Ok(PitchNotation::Midi),
+            _ => Err(Error::Parse),
+        }
+    }
+}
+
+/// Renders the pitch with sharps and MusicXML octave numbering, e.g. `C#4`
+impl fmt::Display for NumericPitchRest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NumericPitchRest::Rest => write!(f, "rest"),
+            NumericPitchRest::Pitch(v) => {
+                let semitones = (v - 1) as usize;
+                write!(f, "{}{}", PITCH_STEP_NAMES[semitones % 12], semitones / 12)
+            }
+        }
+    }
+}
+
+/// Parses the names written by `Display`, also accepting flats and sharpened naturals, e.g. `C#4`, `Bb3`,
+/// `B#3` or `rest`. The octave is that of the written step, so `B#3` is `C4` and `Cb4` is `B3`.
+impl FromStr for NumericPitchRest {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<NumericPitchRest> {
+        if input == "rest" {
+            return Ok(NumericPitchRest::Rest);
+        }
+        let step_len = input.find(|c: char| c.is_ascii_digit()).ok_or(Error::Parse)?;
+        let (name, octave) = input.split_at(step_len);
+        let octave: i32 = octave.parse().map_err(|_| Error::Parse)?;
+        let position = |name: &str| PITCH_STEP_NAMES.iter().position(|s| *s == name).map(|p| p as i32);
+        let semitone = match (name.strip_suffix('b'), name.strip_suffix('#')) {
+            (Some(natural), _) => position(natural).map(|p| p - 1),
+            (None, Some(natural)) => position(name).or(position(natural).map(|p| p + 1)),
+            (None, None) => position(name),
+        }
+        .ok_or(Error::Parse)?;
+        let value = octave * 12 + semitone + 1;
+        if !(Self::MIN_NOTE_VALUE as i32..=Self::MAX_NOTE_VALUE as i32).contains(&value) {
+            return Err(Error::OutofBounds);
+        }
+        Ok(NumericPitchRest::Pitch(value as u8))
+    }
+}
+
 impl NumericPitchRest {
     const MAX_NOTE_VALUE: i8 = 97;
     const MIN_NOTE_VALUE: i8 = 1;
@@ -1413,18 +2580,22 @@ impl NumericPitchRest {
     }
     /// Encodes note data into numerical form for embedding. Supported note range is C0 to C8
     ///
+    /// The octave is that of the written step, and the alteration is applied after it, so an alteration
+    /// crossing the octave boundary moves the pitch into the neighboring octave: B#3 sounds as C4 and
+    /// Cb4 as B3.
+    ///
     /// # Arguments
     ///
     /// * `pitch_octave`  -  Contains diatonic step, note accidental alterations, and octave
     pub fn from_pitch_octave(pitch_octave: PitchOctave) -> Result<NumericPitchRest> {
-        let midi_numeric = i8::from(pitch_octave.pitch.step);
-        let mut numeric_note = midi_numeric - Self::MIDI_NOTE_OFFSET;
+        // The step is looked up among the natural pitch names rather than taken as a MIDI number, whose
+        // octave would not necessarily be that of the written step
+        let step = pitch_octave.pitch.step.to_string();
+        let step_semitone = PITCH_STEP_NAMES.iter().position(|s| *s == step).ok_or(Error::Parse)? as i8;
         let numeric_alter = i8::from(pitch_octave.pitch.alter);
-        let numeric_octave = pitch_octave.octave as i8; // MIDI C3 corresponds to C4 in MusicXML
+        let numeric_octave = pitch_octave.octave as i8;
 
-        numeric_note += numeric_alter;
-        numeric_note += (numeric_octave - 4) * 12;
-        //println!("midi_numeric: {midi_numeric} numeric_octave: {numeric_octave}, numeric: {numeric_note}");
+        let numeric_note = numeric_octave * 12 + step_semitone + numeric_alter + 1;
         if !(Self::MIN_NOTE_VALUE..=Self::MAX_NOTE_VALUE).contains(&numeric_note) {
             Err(Error::OutofBounds)
         } else {
@@ -1433,14 +2604,73 @@ impl NumericPitchRest {
     }
 
     pub fn get_pitch_octave(self) -> Option<PitchOctave> {
+        self.get_spelled_pitch_octave(AccidentalSpelling::Sharp)
+    }
+
+    pub fn get_spelled_pitch_octave(self, spelling: AccidentalSpelling) -> Option<PitchOctave> {
         match self {
             NumericPitchRest::Rest => None,
             NumericPitchRest::Pitch(v) => {
                 let midi_note_numeric = (v as i8) + Self::MIDI_NOTE_OFFSET;
-                Some(
-                    PitchOctave::new_from_semitone(midi_note_numeric, AccidentalSpelling::Sharp)
-                        .ok()?,
-                )
+                Some(PitchOctave::new_from_semitone(midi_note_numeric, spelling).ok()?)
+            }
+        }
+    }
+
+    /// Converts the pitch to a MusicXML pitch, spelling black keys with sharps or flats
+    pub fn to_pitch_rest(self, spelling: AccidentalSpelling) -> PitchRest {
+        if self.get_numeric_value() == 0 {
+            PitchRest::Rest
+        } else if let Some(pabs) = self.get_spelled_pitch_octave(spelling) {
+            // TODO: Make this logic for processing alter string more terse
+            if pabs.pitch.alter == Alter::None {
+                PitchRest::Pitch(PitchElement {
+                    step: pabs.pitch.step.to_string(),
+                    octave: pabs.octave as i8 + 1,
+                    alter: None,
+                })
+            } else {
+                PitchRest::Pitch(PitchElement {
+                    step: pabs.pitch.step.to_string(),
+                    octave: pabs.octave as i8 + 1,
+                    alter: Some(pabs.pitch.alter.to_num_string()),
+                })
+            }
+        } else {
+            panic!("Decode composite note failed");
+        }
+    }
+
+    /// Shifts a pitch by `semitones`, failing when it would leave the supported range. Rests are unchanged.
+    pub fn transpose(self, semitones: i8) -> Result<NumericPitchRest> {
+        match self {
+            NumericPitchRest::Rest => Ok(NumericPitchRest::Rest),
+            NumericPitchRest::Pitch(v) => {
+                let transposed = v as i16 + semitones as i16;
+                if !(Self::MIN_NOTE_VALUE as i16..=Self::MAX_NOTE_VALUE as i16).contains(&transposed) {
+                    Err(Error::OutofBounds)
+                } else {
+                    Ok(NumericPitchRest::Pitch(transposed as u8))
+                }
+            }
+        }
+    }
+
+    /// Shifts a pitch by `octaves`, or by as many of them as keep it within the supported range.
+    /// Returns the pitch and whether it had to be held back. Rests are unchanged.
+    pub fn shift_octaves_clamped(self, octaves: i8) -> (NumericPitchRest, bool) {
+        match self {
+            NumericPitchRest::Rest => (NumericPitchRest::Rest, false),
+            NumericPitchRest::Pitch(v) => {
+                let mut shifted = v as i16 + 12 * octaves as i16;
+                let clamped = !(Self::MIN_NOTE_VALUE as i16..=Self::MAX_NOTE_VALUE as i16).contains(&shifted);
+                while shifted > Self::MAX_NOTE_VALUE as i16 {
+                    shifted -= 12;
+                }
+                while shifted < Self::MIN_NOTE_VALUE as i16 {
+                    shifted += 12;
+                }
+                (NumericPitchRest::Pitch(shifted as u8), clamped)
             }
         }
     }
@@ -1457,35 +2687,37 @@ impl NumericPitchRest {
             NumericPitchRest::Pitch(v) => Some(v + 11),
         }
     }
+
+    /// Names the pitch in the given notation. Rests are always `rest`.
+    pub fn name(self, notation: PitchNotation) -> String {
+        match (self, notation) {
+            (NumericPitchRest::Rest, _) | (_, PitchNotation::Letter) => self.to_string(),
+            (NumericPitchRest::Pitch(v), PitchNotation::Solfege) => {
+                SOLFEGE_NAMES[(v as usize - 1) % 12].to_string()
+            }
+            (NumericPitchRest::Pitch(_), PitchNotation::Midi) => {
+                self.get_midi_numeric_pitch_value().unwrap().to_string()
+            }
+        }
+    }
 }
 
 impl From<NumericPitchRest> for PitchRest {
     fn from(note_data: NumericPitchRest) -> PitchRest {
-        if note_data.get_numeric_value() == 0 {
-            PitchRest::Rest
-        } else if let Some(pabs) = note_data.get_pitch_octave() {
-            // TODO: Make this logic for processing alter string more terse
-            if pabs.pitch.alter == Alter::None {
-                return PitchRest::Pitch(PitchElement {
-                    step: pabs.pitch.step.to_string(),
-                    octave: pabs.octave as i8 + 1,
-                    alter: None,
-                });
-            } else {
-                return PitchRest::Pitch(PitchElement {
-                    step: pabs.pitch.step.to_string(),
-                    octave: pabs.octave as i8 + 1,
-                    alter: Some(pabs.pitch.alter.to_num_string()),
-                });
-            }
-        } else {
-            panic!("Decode composite note failed");
-        }
+        note_data.to_pitch_rest(AccidentalSpelling::Sharp)
     }
 }
 
-pub fn get_staff(voice: Voice, num_voices: usize) -> String {
-    if num_voices < 3 {
+/// Selects the staff for a voice. When `lower_staff_voice` is known, that voice and all voices
+/// after it go to the bass clef staff; otherwise the split is guessed from the number of voices.
+pub fn get_staff(voice: Voice, num_voices: usize, lower_staff_voice: Option<Voice>) -> String {
+    if let Some(lower_staff_voice) = lower_staff_voice {
+        if (voice as u8) < (lower_staff_voice as u8) {
+            1.to_string()
+        } else {
+            2.to_string()
+        }
+    } else if num_voices < 3 {
         if voice == Voice::One {
             1.to_string()
         } else {
@@ -1498,6 +2730,30 @@ pub fn get_staff(voice: Voice, num_voices: usize) -> String {
     }
 }
 
+/// Adds a deviation in cents to the alter of a MusicXML pitch, writing a fractional alter such as
+/// `0.5` for a quarter tone sharp
+fn detune(pitch_rest: PitchRest, cents: i8) -> PitchRest {
+    match pitch_rest {
+        PitchRest::Pitch(pitch) if cents != 0 => {
+            let semitones: i32 = pitch.alter.as_deref().and_then(|a| a.parse().ok()).unwrap_or(0);
+            let hundredths = semitones * 100 + i32::from(cents);
+            let sign = if hundredths < 0 { "-" } else { "" };
+            let (whole, fraction) = (hundredths.abs() / 100, hundredths.abs() % 100);
+            let alter = if fraction == 0 {
+                format!("{}{}", sign, whole)
+            } else {
+                format!("{}{}.{}", sign, whole, format!("{:02}", fraction).trim_end_matches('0'))
+            };
+            PitchRest::Pitch(PitchElement {
+                step: pitch.step,
+                octave: pitch.octave,
+                alter: Some(alter),
+            })
+        }
+        pitch_rest => pitch_rest,
+    }
+}
+
 pub struct NoteElementWrapper {
     note_element: NoteElement,
 }
@@ -1506,14 +2762,16 @@ impl NoteElementWrapper {
     pub fn inner(&self) -> &NoteElement {
         &self.note_element
     }
+    #[allow(clippy::too_many_arguments)]
     pub fn create_wrap(
         note: NoteData,
+        spelling: AccidentalSpelling,
         divisions: u32,
         beats: Beats,
         beat_type: BeatType,
-        t_modification: Option<TimeModificationElement>,
+        t_modification: Option<TimeModification>,
         notations: Option<NotationsElement>,
-        num_voices: usize,
+        staff: String,
     ) -> Self {
         let note_element = NoteElement {
             chord: if note.chord.eq(&Chord::Chord) {
@@ -1528,13 +2786,13 @@ impl NoteElementWrapper {
             } else {
                 None
             },
-            pitch_or_rest: PitchRest::from(note.note_rest),
+            pitch_or_rest: detune(note.note_rest.to_pitch_rest(spelling), note.cents),
             duration: if note.special_note == SpecialNote::None {
                 Some(note.get_duration_string(
                     divisions,
                     u32::from(beats),
                     u32::from(beat_type),
-                    t_modification.as_ref().map(TimeModification::from),
+                    t_modification,
                 ))
             } else {
                 None
@@ -1548,14 +2806,258 @@ impl NoteElementWrapper {
             },
             voice: (note.voice as u8 + 1).to_string(),
             r#type: note.note_type.get_type_string(),
-            time_modification: t_modification,
-            staff: get_staff(note.voice, num_voices),
+            time_modification: t_modification.map(|t| TimeModificationElement {
+                actual_notes: t.get_actual().into(),
+                normal_notes: t.get_normal().into(),
+            }),
+            staff,
             notations,
         };
         Self { note_element }
     }
 }
 
+#[cfg(test)]
+mod display_tests {
+    use super::{
+        Articulation, BeatType, Beats, KeySignature, MeasureInitializer, MusicElement, NoteConnection,
+        NoteData, NumericPitchRest, PhraseDynamics, PitchNotation, PitchSpelling, RhythmType, Tempo,
+        TempoRounding, TimeModification, TuningTable, TupletActual, TupletNormal, Voice,
+    };
+    use mulib::pitch::{Alter, Octave, Pitch, PitchOctave, Step};
+    use std::str::FromStr;
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn test_display_note_and_measure_initializer() {
+        let note = MusicElement::NoteRest(NoteData {
+            note_rest: NumericPitchRest::new_from_numeric(50),
+            note_type: RhythmType::Crochet,
+            phrase_dynamics: PhraseDynamics::Forte,
+            voice: Voice::One,
+            ..NoteData::default()
+        });
+        assert_eq!(note.to_string(), "C#4 quarter v1 f");
+        assert_eq!("C#4".parse::<NumericPitchRest>().unwrap(), NumericPitchRest::new_from_numeric(50));
+        assert_eq!("Db4".parse::<NumericPitchRest>().unwrap(), NumericPitchRest::new_from_numeric(50));
+        assert!("C9".parse::<NumericPitchRest>().is_err());
+
+        let measure_init = MusicElement::MeasureInit(MeasureInitializer {
+            beats: Beats::Four,
+            beat_type: BeatType::Four,
+            key_sig: KeySignature::CMajorAminor,
+            tempo: Tempo::new(120),
+        });
+        assert_eq!(measure_init.to_string(), "|4/4 C-maj 120bpm|");
+    }
+
+    #[test]
+    fn test_accidentals_crossing_the_octave_boundary() {
+        let pitch = |step: &str, alter: Alter, octave: &str| {
+            NumericPitchRest::from_pitch_octave(PitchOctave {
+                pitch: Pitch {
+                    step: Step::from_str(step).unwrap(),
+                    alter,
+                },
+                octave: Octave::from_str(octave).unwrap(),
+            })
+            .unwrap()
+        };
+        let b_sharp_3 = pitch("B", Alter::Sharp, "3");
+        assert_eq!(b_sharp_3, pitch("C", Alter::None, "4"));
+        assert_eq!(b_sharp_3.to_string(), "C4");
+        assert_eq!(b_sharp_3.get_midi_numeric_pitch_value(), Some(60));
+        assert_eq!("B#3".parse::<NumericPitchRest>().unwrap(), b_sharp_3);
+
+        let c_flat_4 = pitch("C", Alter::Flat, "4");
+        assert_eq!(c_flat_4, pitch("B", Alter::None, "3"));
+        assert_eq!(c_flat_4.to_string(), "B3");
+        assert_eq!(c_flat_4.get_midi_numeric_pitch_value(), Some(59));
+        assert_eq!("Cb4".parse::<NumericPitchRest>().unwrap(), c_flat_4);
+    }
+
+    #[test]
+    fn test_pitch_notations() {
+        let c4: NumericPitchRest = "C4".parse().unwrap();
+        assert_eq!(c4.name(PitchNotation::Solfege), "do");
+        assert_eq!(c4.name(PitchNotation::Letter), "C4");
+        assert_eq!(c4.name(PitchNotation::Midi), "60");
+        assert_eq!(NumericPitchRest::Rest.name(PitchNotation::Midi), "rest");
+
+        let note = MusicElement::NoteRest(NoteData {
+            note_rest: c4,
+            note_type: RhythmType::Crochet,
+            ..NoteData::default()
+        });
+        assert_eq!(note.display_with(PitchNotation::Midi).to_string(), "60 quarter v1");
+        assert_eq!(note.display_with(PitchNotation::Letter).to_string(), note.to_string());
+    }
+
+    #[test]
+    fn test_key_signature_fifths_round_trip() {
+        for key_sig in KeySignature::iter() {
+            assert_eq!(key_sig.to_string().parse::<KeySignature>().unwrap(), key_sig);
+        }
+        // Every MusicXML fifths value reads as a key with the same pitches, and those beyond six
+        // accidentals are written back as their enharmonic equivalent
+        for fifths in -7i8..=7 {
+            let key_sig = fifths.to_string().parse::<KeySignature>().unwrap();
+            let written: i8 = key_sig.to_string().parse().unwrap();
+            assert_eq!(written.rem_euclid(12), fifths.rem_euclid(12));
+        }
+        assert_eq!(KeySignature::GbMajorEbminor.to_string(), "-6");
+        assert_eq!("6".parse::<KeySignature>().unwrap(), KeySignature::GbMajorEbminor);
+        assert_eq!("-7".parse::<KeySignature>().unwrap(), KeySignature::BMajorGsminor);
+        assert_eq!("7".parse::<KeySignature>().unwrap(), KeySignature::DbMajorBbminor);
+        assert!("8".parse::<KeySignature>().is_err());
+    }
+
+    #[test]
+    fn test_dynamics_to_velocity() {
+        let expected = [
+            (PhraseDynamics::Pianississimo, 1..=24),
+            (PhraseDynamics::Pianissimo, 25..=40),
+            (PhraseDynamics::Piano, 41..=56),
+            (PhraseDynamics::MezzoPiano, 57..=72),
+            (PhraseDynamics::MezzoForte, 73..=88),
+            (PhraseDynamics::Forte, 89..=104),
+            (PhraseDynamics::Fortissimo, 105..=120),
+            (PhraseDynamics::Fortississimo, 121..=127),
+        ];
+        for (dynamics, range) in expected {
+            assert!(range.contains(&dynamics.to_velocity()), "{:?}", dynamics);
+        }
+
+        // Unmarked notes use mf until a dynamic appears, which then carries to later notes
+        let mut active = PhraseDynamics::None;
+        let mut note = NoteData::default();
+        assert_eq!(note.midi_velocity(&mut active), 80);
+        note.phrase_dynamics = PhraseDynamics::Piano;
+        assert_eq!(note.midi_velocity(&mut active), 49);
+        note.phrase_dynamics = PhraseDynamics::None;
+        note.articulation = Articulation::Accent;
+        assert!(note.midi_velocity(&mut active) > 49);
+
+        assert_eq!(PhraseDynamics::from_velocity(96.0), PhraseDynamics::Forte);
+        assert_eq!(PhraseDynamics::from_velocity(70.0), PhraseDynamics::MezzoPiano);
+        assert_eq!(PhraseDynamics::from_velocity(300.0), PhraseDynamics::Fortississimo);
+        assert_eq!(PhraseDynamics::from_velocity(0.0), PhraseDynamics::Pianississimo);
+    }
+
+    #[test]
+    fn test_meantone_major_third_pitch_bend() {
+        let c4 = NoteData {
+            note_rest: NumericPitchRest::from_str("C4").unwrap(),
+            ..NoteData::default()
+        };
+        let e4 = NoteData {
+            note_rest: NumericPitchRest::from_str("E4").unwrap(),
+            ..c4
+        };
+        // Equal temperament and rests need no bends
+        assert_eq!(e4.midi_pitch_bend(&TuningTable::default()), None);
+        assert_eq!(NoteData::default().midi_pitch_bend(&TuningTable::default()), None);
+
+        // The pure third above C is 13.7 cents narrower than the tempered one
+        let meantone = TuningTable::quarter_comma_meantone();
+        assert_eq!(c4.midi_pitch_bend(&meantone), None);
+        assert_eq!(e4.midi_pitch_bend(&meantone), Some(8192 - 561));
+        // A microtonal deviation adds to the temperament
+        let raised = NoteData { cents: 20, ..e4 };
+        assert_eq!(raised.midi_pitch_bend(&meantone), Some(8192 + 258));
+
+        let table = TuningTable::from_str("0,-24,-6.8,10.3,-13.7,3.4,-20.5,-3.4,-27.4,-10.3,6.8,-17.1").unwrap();
+        assert_eq!(table, meantone);
+        assert!(TuningTable::from_str("0,1,2").is_err());
+    }
+
+    #[test]
+    fn test_tempo_rounding() {
+        assert_eq!(Tempo::new(121).get_actual(), 120);
+        assert_eq!(Tempo::new_rounded(121, TempoRounding::Floor).get_actual(), 120);
+        assert_eq!(Tempo::new_rounded(121, TempoRounding::Nearest).get_actual(), 122);
+        // Stored tempos and the clamped extremes are the same either way
+        for bpm in [120, 20, 274, 10, 300] {
+            assert_eq!(Tempo::new_rounded(bpm, TempoRounding::Nearest), Tempo::new(bpm));
+        }
+        assert_eq!(TempoRounding::from_str("nearest").unwrap(), TempoRounding::Nearest);
+    }
+
+    #[test]
+    fn test_numeric_duration_components() {
+        // A single dotted or tuplet value needs no ties
+        assert_eq!(
+            NoteData::from_numeric_duration(6, 4),
+            vec![(RhythmType::Crochet, true, None)]
+        );
+        assert_eq!(
+            NoteData::from_numeric_duration(1, 3),
+            vec![(
+                RhythmType::Quaver,
+                false,
+                Some(TimeModification::new(TupletActual::Three, TupletNormal::Two))
+            )]
+        );
+        // A quarter tied to a sixteenth, and a half tied to a sixteenth
+        assert_eq!(
+            NoteData::from_numeric_duration(5, 4),
+            vec![(RhythmType::Crochet, false, None), (RhythmType::SemiQuaver, false, None)]
+        );
+        assert_eq!(
+            NoteData::from_numeric_duration(9, 4),
+            vec![(RhythmType::Minim, false, None), (RhythmType::SemiQuaver, false, None)]
+        );
+
+        let note = NoteData {
+            note_rest: NumericPitchRest::new_from_numeric(50),
+            ..NoteData::default()
+        };
+        let tied = note.tied_components(&NoteData::from_numeric_duration(5, 4));
+        let total: u32 = tied.iter().map(|n| n.get_duration_numeric(4, 4, 4, None)).sum();
+        assert_eq!(total, 5);
+        assert_eq!(
+            tied.iter().map(|n| n.ties).collect::<Vec<_>>(),
+            vec![NoteConnection::StartTie, NoteConnection::EndTie]
+        );
+    }
+
+    #[test]
+    fn test_breve_lasts_two_semibreves() {
+        let breve = NoteData {
+            note_rest: NumericPitchRest::new_from_numeric(50),
+            note_type: RhythmType::Breve,
+            ..NoteData::default()
+        };
+        assert_eq!("breve".parse::<RhythmType>().ok(), Some(RhythmType::Breve));
+        assert_eq!(breve.note_type.get_type_string(), "breve");
+        assert_eq!(breve.get_duration_numeric(4, 4, 4, None), 32);
+        assert_eq!(breve.get_duration_in_midi_ticks(None), 960 * 8);
+        assert_eq!(
+            NoteData::from_numeric_duration(32, 4),
+            vec![(RhythmType::Breve, false, None)]
+        );
+    }
+
+    #[test]
+    fn test_gb_spelling_policies() {
+        // Gb4, which sounds the same as F#4
+        let gb = NumericPitchRest::Pitch(55);
+        let spelled = |spelling: PitchSpelling, key_sig: KeySignature| {
+            let pitch_octave = gb
+                .get_spelled_pitch_octave(spelling.accidental_spelling(key_sig))
+                .unwrap();
+            (pitch_octave.pitch.step.to_string(), pitch_octave.pitch.alter)
+        };
+        let f_sharp = ("F".to_string(), Alter::Sharp);
+        let g_flat = ("G".to_string(), Alter::Flat);
+        assert!(spelled(PitchSpelling::Sharp, KeySignature::GbMajorEbminor) == f_sharp);
+        assert!(spelled(PitchSpelling::Flat, KeySignature::DMajorBminor) == g_flat);
+        assert!(spelled(PitchSpelling::Key, KeySignature::GbMajorEbminor) == g_flat);
+        assert!(spelled(PitchSpelling::Key, KeySignature::DMajorBminor) == f_sharp);
+        assert_eq!("key".parse::<PitchSpelling>().ok(), Some(PitchSpelling::Key));
+    }
+}
+
 // #[cfg(test)]
 // mod tests {
 //     use super::{
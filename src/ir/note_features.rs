@@ -0,0 +1,286 @@
+use super::musical_part::MusicalPart;
+use super::notation::{
+    Chord, MeasureStartEnd, MusicElement, NoteData, NumericPitchRest, PercussionData, PhraseDynamics,
+    TimeModification, TuningTable,
+};
+use crate::error::{Error, Result};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// Number of fields in a note feature record
+pub const NOTE_FEATURE_FIELDS: usize = 9;
+
+/// Returns the onset of every note and rest of an element stream, chord tones and percussion included,
+/// as its element index and the MIDI ticks, at 960 per quarter note, from the start of the piece.
+///
+/// The voices of a measure are stored one after another, each starting at the beginning of the
+/// measure as after a MusicXML backup, so every voice keeps its own position within the measure.
+/// Chord tones sound with the note before them and grace notes take no time. A measure ends where
+/// its longest voice ends.
+pub fn compute_onsets(elems: &[MusicElement]) -> Vec<(usize, u32)> {
+    let mut time_mod: Option<TimeModification> = None;
+    let mut measure_end = 0;
+    let mut voice_positions = [0; MusicalPart::MAX_SUPPORTED_VOICES];
+    let mut last_onset = 0;
+    let mut onsets = vec![];
+    for (elem_idx, elem) in elems.iter().enumerate() {
+        match elem {
+            MusicElement::MeasureMeta(meta)
+                if matches!(meta.start_end, MeasureStartEnd::MeasureStart | MeasureStartEnd::RepeatStart) =>
+            {
+                voice_positions = [measure_end; MusicalPart::MAX_SUPPORTED_VOICES];
+            }
+            MusicElement::Tuplet(t) => time_mod = (*t).into(),
+            MusicElement::NoteRest(n) | MusicElement::Percussion(PercussionData { note: n, .. }) => {
+                if n.chord == Chord::NoChord {
+                    let position = &mut voice_positions[n.voice as usize];
+                    last_onset = *position;
+                    *position += n.get_duration_in_midi_ticks(time_mod);
+                    measure_end = measure_end.max(*position);
+                }
+                onsets.push((elem_idx, last_onset));
+            }
+            _ => {}
+        }
+    }
+    onsets
+}
+
+/// One note or rest of a part as a fixed width numeric record, for loading directly as a tensor row.
+///
+/// A record is nine little endian u32 fields, 36 bytes in all, in this order:
+///
+/// | Field          | Value                                                                   |
+/// |----------------|-------------------------------------------------------------------------|
+/// | `pitch`        | Numeric pitch value, 0 for a rest                                       |
+/// | `duration`     | MIDI ticks at 960 per quarter note, after dots and tuplets, 0 for grace notes |
+/// | `voice`        | Zero based voice                                                        |
+/// | `dynamics`     | `PhraseDynamics` discriminant, 0 when no dynamic is marked on the note  |
+/// | `articulation` | `Articulation` discriminant, 0 for none                                 |
+/// | `ties`         | `NoteConnection` discriminant: 0 none, 1 tie start, 2 tie stop           |
+/// | `onset`        | MIDI ticks from the start of the piece, see [`compute_onsets`]          |
+/// | `velocity`     | MIDI note-on velocity, see `NoteData::midi_velocity`, 0 for a rest      |
+/// | `pitch_bend`   | MIDI pitch bend in the chosen tuning, 8192 when the note is not bent    |
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NoteFeatures {
+    pub pitch: u32,
+    pub duration: u32,
+    pub voice: u32,
+    pub dynamics: u32,
+    pub articulation: u32,
+    pub ties: u32,
+    pub onset: u32,
+    pub velocity: u32,
+    pub pitch_bend: u32,
+}
+
+impl NoteFeatures {
+    /// Extracts a record for every note and rest of an element stream, chord tones included, in order,
+    /// in equal temperament
+    pub fn from_elements(elems: &[MusicElement]) -> Vec<NoteFeatures> {
+        NoteFeatures::from_elements_with_tuning(elems, &TuningTable::default())
+    }
+
+    /// Extracts a record for every note and rest of an element stream, with the pitch bends sounding
+    /// each note in the temperament of `tuning`
+    pub fn from_elements_with_tuning(elems: &[MusicElement], tuning: &TuningTable) -> Vec<NoteFeatures> {
+        let onsets: BTreeMap<usize, u32> = compute_onsets(elems).into_iter().collect();
+        let mut time_mod: Option<TimeModification> = None;
+        // The most recent dynamic marking sets the velocity of the notes following it
+        let mut active_dynamics = PhraseDynamics::None;
+        let mut features = vec![];
+        for (elem_idx, elem) in elems.iter().enumerate() {
+            match elem {
+                MusicElement::Tuplet(t) => time_mod = (*t).into(),
+                MusicElement::NoteRest(n) => {
+                    // A marking on a rest still sets the level of the notes after it
+                    let velocity = n.midi_velocity(&mut active_dynamics);
+                    features.push(NoteFeatures {
+                        pitch: u32::from(n.note_rest.get_numeric_value()),
+                        duration: n.get_duration_in_midi_ticks(time_mod),
+                        voice: n.voice as u32,
+                        dynamics: n.phrase_dynamics as u32,
+                        articulation: n.articulation as u32,
+                        ties: n.ties as u32,
+                        onset: onsets[&elem_idx],
+                        velocity: match n.note_rest {
+                            NumericPitchRest::Rest => 0,
+                            NumericPitchRest::Pitch(_) => u32::from(velocity),
+                        },
+                        pitch_bend: u32::from(
+                            n.midi_pitch_bend(tuning).unwrap_or(NoteData::PITCH_BEND_CENTER),
+                        ),
+                    });
+                }
+                _ => {}
+            }
+        }
+        features
+    }
+
+    pub fn to_array(&self) -> [u32; NOTE_FEATURE_FIELDS] {
+        [
+            self.pitch,
+            self.duration,
+            self.voice,
+            self.dynamics,
+            self.articulation,
+            self.ties,
+            self.onset,
+            self.velocity,
+            self.pitch_bend,
+        ]
+    }
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+    writer.write_all(bytes).map_err(Error::from)
+}
+
+/// Writes the records back to back with no header
+pub fn write_features_flat<W: Write>(mut writer: W, features: &[NoteFeatures]) -> Result<()> {
+    for record in features {
+        for field in record.to_array() {
+            write_bytes(&mut writer, &field.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the records as a NumPy `.npy` file holding a `(notes, 9)` array of `<u4`, as read by `numpy.load`
+pub fn write_features_npy<W: Write>(mut writer: W, features: &[NoteFeatures]) -> Result<()> {
+    const MAGIC: &[u8] = b"\x93NUMPY\x01\x00";
+    let mut header = format!(
+        "{{'descr': '<u4', 'fortran_order': False, 'shape': ({}, {}), }}",
+        features.len(),
+        NOTE_FEATURE_FIELDS
+    );
+    // The magic number, header length and header are padded with spaces to a multiple of 64 bytes,
+    // ending in a newline, so the array data is aligned
+    let unpadded = MAGIC.len() + 2 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+    write_bytes(&mut writer, MAGIC)?;
+    write_bytes(&mut writer, &(header.len() as u16).to_le_bytes())?;
+    write_bytes(&mut writer, header.as_bytes())?;
+    write_features_flat(writer, features)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compute_onsets, write_features_flat, write_features_npy, NoteFeatures};
+    use crate::ir::notation::{
+        Articulation, BeatType, Beats, Chord, MusicElement, NoteConnection, NoteData, NumericPitchRest,
+        PhraseDynamics, RhythmType, TuningTable, Voice,
+    };
+    use crate::ir::PartBuilder;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_two_note_record_layout() {
+        let part = PartBuilder::new()
+            .note_data(NoteData {
+                note_rest: NumericPitchRest::Pitch(40),
+                note_type: RhythmType::Crochet,
+                dotted: true,
+                phrase_dynamics: PhraseDynamics::Forte,
+                articulation: Articulation::Staccato,
+                ties: NoteConnection::StartTie,
+                ..NoteData::default()
+            })
+            .rest(RhythmType::Quaver, Voice::Two)
+            .build("P1")
+            .unwrap();
+        let features = NoteFeatures::from_elements(part.inner());
+
+        let mut flat: Vec<u8> = vec![];
+        write_features_flat(&mut flat, &features).unwrap();
+        let fields = |values: [u32; 9]| -> Vec<u8> { values.iter().flat_map(|v| v.to_le_bytes()).collect() };
+        let mut expected = fields([
+            40,
+            1440,
+            0,
+            PhraseDynamics::Forte as u32,
+            Articulation::Staccato as u32,
+            1,
+            0,
+            96,
+            8192,
+        ]);
+        // The rest starts the second voice at the beginning of the measure
+        expected.extend(fields([0, 480, 1, 0, 0, 0, 0, 0, 8192]));
+        assert_eq!(flat, expected);
+
+        let mut npy: Vec<u8> = vec![];
+        write_features_npy(&mut npy, &features).unwrap();
+        assert!(npy.starts_with(b"\x93NUMPY\x01\x00"));
+        let header_len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+        let header = std::str::from_utf8(&npy[10..10 + header_len]).unwrap();
+        assert!(header.contains("'shape': (2, 9)"));
+        assert!(header.ends_with('\n'));
+        assert_eq!((10 + header_len) % 64, 0);
+        assert_eq!(&npy[10 + header_len..], &expected[..]);
+    }
+
+    #[test]
+    fn test_onsets_of_two_voices_over_two_measures() {
+        let note = |pitch, note_type, voice, chord| NoteData {
+            note_rest: NumericPitchRest::Pitch(pitch),
+            note_type,
+            voice,
+            chord,
+            ..NoteData::default()
+        };
+        // Voice one plays a half note with a chord tone and two quarters, voice two a whole note, in
+        // each of two measures
+        let part = PartBuilder::new()
+            .measure(Beats::Four, BeatType::Four)
+            .note_data(note(60, RhythmType::Minim, Voice::One, Chord::NoChord))
+            .note_data(note(64, RhythmType::Minim, Voice::One, Chord::Chord))
+            .note_data(note(62, RhythmType::Crochet, Voice::One, Chord::NoChord))
+            .note_data(note(64, RhythmType::Crochet, Voice::One, Chord::NoChord))
+            .note_data(note(48, RhythmType::SemiBreve, Voice::Two, Chord::NoChord))
+            .measure(Beats::Four, BeatType::Four)
+            .note_data(note(65, RhythmType::Crochet, Voice::One, Chord::NoChord))
+            .note_data(note(67, RhythmType::Crochet, Voice::One, Chord::NoChord))
+            .note_data(note(69, RhythmType::Minim, Voice::One, Chord::NoChord))
+            .note_data(note(43, RhythmType::SemiBreve, Voice::Two, Chord::NoChord))
+            .build("P1")
+            .unwrap();
+        let onsets = compute_onsets(part.inner());
+        assert!(onsets
+            .iter()
+            .all(|(elem_idx, _)| matches!(part.inner()[*elem_idx], MusicElement::NoteRest(_))));
+        let ticks: Vec<u32> = onsets.iter().map(|(_, onset)| *onset).collect();
+        assert_eq!(ticks, vec![0, 0, 1920, 2880, 0, 3840, 4800, 5760, 3840]);
+    }
+
+    #[test]
+    fn test_velocity_follows_dynamics_and_bend_follows_tuning() {
+        let note = |pitch: &str, phrase_dynamics, articulation| NoteData {
+            note_rest: NumericPitchRest::from_str(pitch).unwrap(),
+            note_type: RhythmType::Crochet,
+            phrase_dynamics,
+            articulation,
+            ..NoteData::default()
+        };
+        // C4 unmarked, then E4 marked piano and an accented E4 still at piano
+        let part = PartBuilder::new()
+            .note_data(note("C4", PhraseDynamics::None, Articulation::None))
+            .note_data(note("E4", PhraseDynamics::Piano, Articulation::None))
+            .note_data(note("E4", PhraseDynamics::None, Articulation::Accent))
+            .rest(RhythmType::Crochet, Voice::One)
+            .build("P1")
+            .unwrap();
+        let equal = NoteFeatures::from_elements(part.inner());
+        let velocities: Vec<u32> = equal.iter().map(|f| f.velocity).collect();
+        assert_eq!(velocities, vec![80, 49, 61, 0]);
+        assert!(equal.iter().all(|f| f.pitch_bend == 8192));
+
+        let tuning = TuningTable::quarter_comma_meantone();
+        let meantone = NoteFeatures::from_elements_with_tuning(part.inner(), &tuning);
+        let bends: Vec<u32> = meantone.iter().map(|f| f.pitch_bend).collect();
+        // C is not bent in meantone built on C, its major third is 13.7 cents flat
+        assert_eq!(bends, vec![8192, 8192 - 561, 8192 - 561, 8192]);
+    }
+}
@@ -0,0 +1,154 @@
+use super::musical_part::MusicalPart;
+use super::notation::{
+    BeatType, Beats, MeasureInitializer, MeasureMetaData, MeasureStartEnd, MusicElement, NoteData,
+    NumericPitchRest, RhythmType, Voice,
+};
+use crate::error::Result;
+
+/// Fluent builder of the element stream of a single part, for generating scores in code.
+///
+/// Measure boundaries are inserted automatically: every call to [`PartBuilder::measure`] closes the
+/// open measure and starts a new one, and [`PartBuilder::build`] closes the last. A measure
+/// initializer is only written when the time signature changes, like the MusicXML parser does.
+///
+/// # Examples
+///
+/// ```
+/// # use music2bin::ir::notation::{BeatType, Beats, NumericPitchRest, RhythmType, Voice};
+/// # use music2bin::ir::PartBuilder;
+/// # use music2bin::bin_format::ir_to_bin;
+/// let part = PartBuilder::new()
+///     .measure(Beats::Four, BeatType::Four)
+///     .note(NumericPitchRest::Pitch(40), RhythmType::Minim, Voice::One)
+///     .note(NumericPitchRest::Pitch(44), RhythmType::Minim, Voice::One)
+///     .build("P1")
+///     .unwrap();
+/// let mut encoded: Vec<u8> = vec![];
+/// ir_to_bin(&mut encoded, &part, None).unwrap();
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct PartBuilder {
+    elems: Vec<MusicElement>,
+    cur_init: Option<MeasureInitializer>,
+    measure_open: bool,
+}
+
+impl PartBuilder {
+    pub fn new() -> PartBuilder {
+        PartBuilder::default()
+    }
+
+    /// Starts a new measure in the given time signature, keeping the key and tempo in effect
+    pub fn measure(self, beats: Beats, beat_type: BeatType) -> PartBuilder {
+        let measure_init = MeasureInitializer {
+            beats,
+            beat_type,
+            ..self.cur_init.unwrap_or_default()
+        };
+        self.measure_with(measure_init)
+    }
+
+    /// Starts a new measure with the given initializer, written only if it differs from the one in effect
+    pub fn measure_with(mut self, measure_init: MeasureInitializer) -> PartBuilder {
+        self.close_measure();
+        if self.cur_init != Some(measure_init) {
+            self.elems.push(MusicElement::MeasureInit(measure_init));
+            self.cur_init = Some(measure_init);
+        }
+        self.elems.push(MusicElement::MeasureMeta(MeasureMetaData::new(
+            MeasureStartEnd::MeasureStart,
+        )));
+        self.measure_open = true;
+        self
+    }
+
+    /// Appends a note to the open measure
+    pub fn note(self, pitch: NumericPitchRest, note_type: RhythmType, voice: Voice) -> PartBuilder {
+        self.note_data(NoteData {
+            note_rest: pitch,
+            note_type,
+            voice,
+            ..NoteData::default()
+        })
+    }
+
+    /// Appends a rest to the open measure
+    pub fn rest(self, note_type: RhythmType, voice: Voice) -> PartBuilder {
+        self.note_data(NoteData::new_default_rest(note_type, false, voice))
+    }
+
+    /// Appends a fully specified note or rest to the open measure, opening a 4/4 measure if there is none
+    pub fn note_data(mut self, note_data: NoteData) -> PartBuilder {
+        if !self.measure_open {
+            self = self.measure_with(self.cur_init.unwrap_or_default());
+        }
+        self.elems.push(MusicElement::NoteRest(note_data));
+        self
+    }
+
+    /// Closes the last measure and returns the element stream
+    pub fn into_elements(mut self) -> Vec<MusicElement> {
+        self.close_measure();
+        self.elems
+    }
+
+    /// Closes the last measure and returns the part, ready for `ir_to_bin`
+    pub fn build(self, part_str: &str) -> Result<MusicalPart> {
+        MusicalPart::new_from_elems(part_str, self.into_elements())
+    }
+
+    fn close_measure(&mut self) {
+        if self.measure_open {
+            self.elems.push(MusicElement::MeasureMeta(MeasureMetaData::new(
+                MeasureStartEnd::MeasureEnd,
+            )));
+            self.measure_open = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PartBuilder;
+    use crate::bin_format::round_trip;
+    use crate::ir::notation::{
+        BeatType, Beats, MeasureInitializer, MeasureMetaData, MeasureStartEnd, MusicElement, NoteData,
+        NumericPitchRest, RhythmType, Voice,
+    };
+
+    #[test]
+    fn test_two_measures_round_trip() {
+        let part = PartBuilder::new()
+            .measure(Beats::Four, BeatType::Four)
+            .note(NumericPitchRest::Pitch(40), RhythmType::Minim, Voice::One)
+            .rest(RhythmType::Minim, Voice::One)
+            .measure(Beats::Four, BeatType::Four)
+            .note(NumericPitchRest::Pitch(44), RhythmType::SemiBreve, Voice::One)
+            .build("P1")
+            .unwrap();
+        let start = MusicElement::MeasureMeta(MeasureMetaData::new(MeasureStartEnd::MeasureStart));
+        let end = MusicElement::MeasureMeta(MeasureMetaData::new(MeasureStartEnd::MeasureEnd));
+        let note = |pitch, note_type| {
+            MusicElement::NoteRest(NoteData {
+                note_rest: NumericPitchRest::Pitch(pitch),
+                note_type,
+                ..NoteData::default()
+            })
+        };
+        // The unchanged time signature is not repeated in the second measure
+        let expected = vec![
+            MusicElement::MeasureInit(MeasureInitializer::default()),
+            start,
+            note(40, RhythmType::Minim),
+            MusicElement::NoteRest(NoteData::new_default_rest(RhythmType::Minim, false, Voice::One)),
+            end,
+            start,
+            note(44, RhythmType::SemiBreve),
+            end,
+        ];
+        assert_eq!(part.inner(), &expected);
+
+        let decoded = round_trip(&part).unwrap();
+        assert_eq!(decoded.inner(), &expected);
+    }
+}
@@ -1,8 +1,16 @@
 //use muxml::muxml_types::{ScorePart, PartListElement, Part};
+use muxml::muxml_types::Measure;
 use muxml::score::CompleteParts;
 
+use super::ir_to_xml::measures_from_musical_part;
 use super::musical_part::MusicalPart;
+use super::notation::{Clef, MeasureStartEnd, MusicElement, PercussionData, PitchSpelling};
+use super::xml_patch::{escape_text, PatchAnchor, PatchEdit, XmlPatcher};
 use crate::error::{Error, Result};
+use log::{debug, warn};
+use num_traits::FromPrimitive;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use std::collections::BTreeMap;
 
 // This data type uses an Index Pointer pattern
@@ -22,6 +30,9 @@ type PartIdRefValue<'a> = Option<&'a MusicalPart>;
 #[derive(Eq, PartialEq, Default, Debug, Clone)]
 pub struct PartMap {
     part_ids: PartIdMap,
+    // Part ids in the order they were added, which is their order in the score's <part-list>, since
+    // the map sorts "P10" before "P2"
+    part_order: Vec<PartId>,
     parts: Vec<PartIdValue>,
 }
 
@@ -31,6 +42,7 @@ impl PartMap {
     pub fn new() -> PartMap {
         PartMap {
             part_ids: PartIdMap::new(),
+            part_order: vec![],
             parts: vec![],
         }
     }
@@ -48,12 +60,17 @@ impl PartMap {
         )
     }
 
+    /// Part ids in score order
     pub fn keys(&self) -> Vec<String> {
-        self.part_ids.keys().map(|key| key.to_string()).collect()
+        self.part_order.clone()
     }
 
-    pub fn get_part_ids(&self) -> PartIdMap {
-        self.part_ids.clone()
+    /// Part ids in score order, each with the index of its part, or `None` for a dropped part
+    pub fn get_part_ids(&self) -> Vec<(PartId, PartIdIndex)> {
+        self.part_order
+            .iter()
+            .map(|key| (key.clone(), self.part_ids[key]))
+            .collect()
     }
 
     pub fn num_part_ids(&self) -> PartCount {
@@ -73,23 +90,150 @@ impl PartMap {
     }
 
     pub fn remove_part(&mut self, part_key: &str) {
-        if self.part_ids.insert(part_key.to_string(), None).is_none() {
+        if !self.part_ids.contains_key(part_key) {
             println!("No existing value was present for key");
         }
+        self.insert_part_id(part_key, None);
     }
+    /// Sets the clefs every part is exported with
+    pub fn set_clefs(&mut self, clefs: &[Clef]) {
+        for part in self.parts.iter_mut().flatten() {
+            part.set_clefs(Some(clefs.to_vec()));
+        }
+    }
+
+    /// Sets the spelling of black key pitches every part is exported with
+    pub fn set_spelling(&mut self, spelling: PitchSpelling) {
+        for part in self.parts.iter_mut().flatten() {
+            part.set_spelling(spelling);
+        }
+    }
+
+    /// Sets the divisions per quarter note every part is exported with
+    pub fn set_output_divisions(&mut self, divisions: u32) -> Result<()> {
+        for part in self.parts.iter_mut().flatten() {
+            part.set_output_divisions(divisions)?;
+        }
+        Ok(())
+    }
+
     /// Combine musical parts (if feasible)
     ///
+    /// Removes redundant measure initializers from every part, returning how many were removed
+    pub fn coalesce_initializers(&mut self) -> usize {
+        self.parts
+            .iter_mut()
+            .flatten()
+            .map(|part| part.coalesce_initializers())
+            .sum()
+    }
+
+    /// Rewrites every part in its canonical form, see `MusicalPart::canonicalize`
+    pub fn canonicalize(&mut self) {
+        for part in self.parts.iter_mut().flatten() {
+            part.canonicalize();
+        }
+    }
+
     /// Combines the parts in the map into one if the number and configuration
-    /// of each part is the same
-    pub fn combine_parts(&mut self) {
+    /// of each part is the same. Each part's voices are offset past the voices of the
+    /// parts before it, so voice 1 of two different parts stays distinct. The first half
+    /// of the parts is routed to the treble clef staff, the remainder to the bass clef staff.
+    pub fn combine_parts(&mut self) -> Result<()> {
+        let part_ids: Vec<PartId> = self
+            .get_part_ids()
+            .into_iter()
+            .filter(|(_k, v)| v.is_some())
+            .map(|(k, _v)| k)
+            .collect();
+        if part_ids.len() < 2 {
+            return Ok(());
+        }
+
+        let mut part_measures: Vec<Vec<MeasureSlice>> = vec![];
+        for part_id in part_ids.iter() {
+            let idx = self.part_ids[part_id].unwrap();
+            let part = self.get_part(idx).ok_or(Error::NotInitialized)?;
+            part_measures.push(split_measures(part.inner()));
+        }
+
+        let first = &part_measures[0];
+        for (measures, part_id) in part_measures.iter().zip(part_ids.iter()).skip(1) {
+            if measures.len() != first.len()
+                || measures.iter().zip(first.iter()).any(|(a, b)| a.header != b.header)
+            {
+                warn!(
+                    "Part {} does not share the measure layout of part {}, parts were not combined",
+                    part_id, part_ids[0]
+                );
+                return Err(Error::Unsupported);
+            }
+        }
+
+        // Voice offset for each part, and the first voice of the bass clef staff
+        let mut voice_offsets: Vec<u8> = vec![];
+        let mut next_voice: u8 = 0;
+        for measures in part_measures.iter() {
+            voice_offsets.push(next_voice);
+            next_voice += measures
+                .iter()
+                .flat_map(|m| m.body.iter())
+                .filter_map(|e| match e {
+                    MusicElement::NoteRest(n) | MusicElement::Percussion(PercussionData { note: n, .. }) => {
+                        Some(n.voice as u8 + 1)
+                    }
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(0);
+        }
+        if next_voice as usize > Self::MAX_SUPPORTED_VOICES {
+            warn!(
+                "Combined parts require {} voices, exceeding the supported {}",
+                next_voice,
+                Self::MAX_SUPPORTED_VOICES
+            );
+            return Err(Error::OutofBounds);
+        }
+        let lower_staff_voice = FromPrimitive::from_u8(voice_offsets[part_ids.len().div_ceil(2)]);
 
+        let mut elems: Vec<MusicElement> = vec![];
+        for measure_idx in 0..first.len() {
+            elems.extend_from_slice(&first[measure_idx].header);
+            for (measures, voice_offset) in part_measures.iter().zip(voice_offsets.iter()) {
+                elems.extend(measures[measure_idx].body.iter().map(|e| match *e {
+                    // The staves of the combined part follow from its voices
+                    MusicElement::NoteRest(mut n) => {
+                        n.voice = FromPrimitive::from_u8(n.voice as u8 + voice_offset).unwrap();
+                        n.staff = None;
+                        MusicElement::NoteRest(n)
+                    }
+                    MusicElement::Percussion(mut p) => {
+                        p.note.voice = FromPrimitive::from_u8(p.note.voice as u8 + voice_offset).unwrap();
+                        p.note.staff = None;
+                        MusicElement::Percussion(p)
+                    }
+                    other => other,
+                }));
+            }
+            elems.extend_from_slice(&first[measure_idx].end);
+        }
+
+        let mut combined = MusicalPart::new_from_elems(part_ids[0].as_str(), elems)?;
+        combined.set_lower_staff_voice(lower_staff_voice);
+        self.part_ids = PartIdMap::new();
+        self.part_order = vec![];
+        self.parts = vec![];
+        self.push_part(part_ids[0].as_str(), combined)
     }
     // pub fn extend_parts(&mut self, musical_parts: Vec<MusicalPart>) {
     //     self.parts.extend(musical_parts);
     // }
 
     fn insert_part_id(&mut self, part_key: &str, val: PartIdIndex) {
-        self.part_ids.insert(part_key.to_string(), val);
+        if self.part_ids.insert(part_key.to_string(), val).is_none() {
+            self.part_order.push(part_key.to_string());
+        }
     }
 
     pub fn push_part(&mut self, part_key: &str, part: MusicalPart) -> Result<()> {
@@ -157,19 +301,241 @@ impl PartMap {
 //     }
 // }
 
-impl TryFrom<PartMap> for CompleteParts {
-    type Error = Error;
-    fn try_from(pm: PartMap) -> std::result::Result<Self, Self::Error> {
+/// One measure of a part, split into the elements opening the measure, the notes within it,
+/// and the elements closing it
+struct MeasureSlice {
+    header: Vec<MusicElement>,
+    body: Vec<MusicElement>,
+    end: Vec<MusicElement>,
+}
+
+fn split_measures(elems: &[MusicElement]) -> Vec<MeasureSlice> {
+    let mut measures = vec![];
+    let mut cur = MeasureSlice {
+        header: vec![],
+        body: vec![],
+        end: vec![],
+    };
+    let mut in_body = false;
+    for elem in elems {
+        match elem {
+            MusicElement::MeasureMeta(m) => match m.start_end {
+                MeasureStartEnd::MeasureStart | MeasureStartEnd::RepeatStart => {
+                    cur.header.push(*elem);
+                    in_body = true;
+                }
+                MeasureStartEnd::MeasureEnd | MeasureStartEnd::RepeatEnd => {
+                    cur.end.push(*elem);
+                    measures.push(cur);
+                    cur = MeasureSlice {
+                        header: vec![],
+                        body: vec![],
+                        end: vec![],
+                    };
+                    in_body = false;
+                }
+            },
+            _ if in_body => cur.body.push(*elem),
+            _ => cur.header.push(*elem),
+        }
+    }
+    measures
+}
+
+impl PartMap {
+    /// Converts the parts into muxml parts, collecting patches for the content that muxml
+    /// cannot represent so they can be applied to the serialized score. With `strict` set, a measure
+    /// whose voices do not add up to its time signature is an error. With `parallel` set, the parts
+    /// of a multi-part score are converted concurrently when the crate is built with the `parallel` feature.
+    pub fn to_complete_parts(&self, patcher: &mut XmlPatcher, strict: bool, parallel: bool) -> Result<CompleteParts> {
+        let parts: Vec<(PartId, &MusicalPart)> = self
+            .get_part_ids()
+            .into_iter()
+            .filter_map(|(part_id, opt_idx)| Some((part_id, self.get_part(opt_idx?)?)))
+            .collect();
+        // Parts are independent until they are assembled, so each is serialized with its own patches
+        let serialize = |(part_id, part): &(PartId, &MusicalPart)| -> Result<(Vec<Measure>, XmlPatcher)> {
+            let mut part_patcher = XmlPatcher::new();
+            let measures = measures_from_musical_part(part, part_id.as_str(), &mut part_patcher, strict)?;
+            Ok((measures, part_patcher))
+        };
+        // Collecting keeps the score order, whichever order the parts finish in
+        #[cfg(feature = "parallel")]
+        let serialized: Vec<Result<(Vec<Measure>, XmlPatcher)>> = if parallel && parts.len() > 1 {
+            parts.par_iter().map(serialize).collect()
+        } else {
+            parts.iter().map(serialize).collect()
+        };
+        // Built without the parallel feature, such as for wasm32, parts are always serialized in turn
+        #[cfg(not(feature = "parallel"))]
+        let serialized: Vec<Result<(Vec<Measure>, XmlPatcher)>> = {
+            let _ = parallel;
+            parts.iter().map(serialize).collect()
+        };
+
         let mut complete_parts = CompleteParts::default();
-        for (part_id, opt_idx) in pm.get_part_ids() {
-            if let Some(idx) = opt_idx {
-                println!("Part ID: {}", part_id.as_str());
-                complete_parts.add_part(part_id.as_str(), "Piano")?;
-                let part = pm.get_part(idx).unwrap();
-                let measures = part.into();
-                complete_parts.extend_measures(part_id.as_str(), measures)?;
+        for ((part_id, part), result) in parts.iter().zip(serialized) {
+            debug!("Exporting part {}", part_id);
+            let (measures, part_patcher) = result?;
+            patcher.append(part_patcher);
+            if measures.is_empty() {
+                // A part must hold at least one measure, so a part left without any is not written
+                warn!("Part {} has no measures to export, leaving it out", part_id);
+                continue;
+            }
+            let part_name = part.get_part_name().map_or("Piano", |p| p.name.as_str());
+            complete_parts.add_part(part_id.as_str(), part_name)?;
+            if let Some(abbreviation) = part.get_part_name().and_then(|p| p.abbreviation.as_ref()) {
+                patcher.push(
+                    PatchAnchor::ScorePart {
+                        part_id: part_id.clone(),
+                    },
+                    PatchEdit::InsertChild {
+                        after: &["part-name", "part-name-display"],
+                        fragment: format!(
+                            "<part-abbreviation>{}</part-abbreviation>",
+                            escape_text(abbreviation)
+                        ),
+                    },
+                );
             }
+            complete_parts.extend_measures(part_id.as_str(), measures)?;
         }
         Ok(complete_parts)
     }
 }
+
+impl TryFrom<PartMap> for CompleteParts {
+    type Error = Error;
+    fn try_from(pm: PartMap) -> std::result::Result<Self, Self::Error> {
+        pm.to_complete_parts(&mut XmlPatcher::new(), false, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PartMap;
+    use crate::bin_format::round_trip;
+    use crate::ir::notation::{
+        Beats, MeasureInitializer, MeasureMetaData, MeasureStartEnd, MusicElement, NoteData,
+        NumericPitchRest, RhythmType, Voice,
+    };
+    use crate::ir::ir_to_xml::ir_to_xml;
+    use crate::ir::{ExportOptions, MusicalPart};
+
+    fn single_voice_part(part_id: &str, numeric_pitch: u8) -> MusicalPart {
+        let mut elems = vec![
+            MusicElement::MeasureInit(MeasureInitializer::default()),
+            MusicElement::MeasureMeta(MeasureMetaData::new(MeasureStartEnd::MeasureStart)),
+        ];
+        for _ in 0..4 {
+            elems.push(MusicElement::NoteRest(NoteData {
+                note_rest: NumericPitchRest::new_from_numeric(numeric_pitch),
+                note_type: RhythmType::Crochet,
+                ..NoteData::default()
+            }));
+        }
+        elems.push(MusicElement::MeasureMeta(MeasureMetaData::new(
+            MeasureStartEnd::MeasureEnd,
+        )));
+        MusicalPart::new_from_elems(part_id, elems).unwrap()
+    }
+
+    #[test]
+    fn test_combine_single_voice_parts() {
+        let mut part_map = PartMap::new();
+        part_map.push_part("P1", single_voice_part("P1", 60)).unwrap();
+        part_map.push_part("P2", single_voice_part("P2", 36)).unwrap();
+
+        part_map.combine_parts().unwrap();
+        assert_eq!(part_map.num_parts(), 1);
+
+        let combined = part_map.get_part(0).unwrap();
+        assert_eq!(combined.get_num_voices(), 2);
+        let voices: Vec<Voice> = combined
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some(n.voice),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(voices[..4], [Voice::One; 4]);
+        assert_eq!(voices[4..], [Voice::Two; 4]);
+        assert_eq!(combined.get_staff(Voice::One), "1");
+        assert_eq!(combined.get_staff(Voice::Two), "2");
+    }
+
+    /// Pairs every element other than an initializer with the initializer in effect for it
+    fn effective_initializers(part: &MusicalPart) -> Vec<(MeasureInitializer, MusicElement)> {
+        let mut active = MeasureInitializer::default();
+        part.inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::MeasureInit(init) => {
+                    active = *init;
+                    None
+                }
+                other => Some((active, *other)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_coalesce_redundant_initializers() -> Result<(), Box<dyn std::error::Error>> {
+        let three_four = MeasureInitializer {
+            beats: Beats::Three,
+            ..MeasureInitializer::default()
+        };
+        let measure = single_voice_part("P1", 60).inner()[1..].to_vec();
+        let mut elems = vec![];
+        for init in [
+            MeasureInitializer::default(),
+            MeasureInitializer::default(),
+            three_four,
+            three_four,
+        ] {
+            elems.push(MusicElement::MeasureInit(init));
+            elems.extend_from_slice(&measure);
+        }
+        let original = MusicalPart::new_from_elems("P1", elems)?;
+        let mut part_map = PartMap::new();
+        part_map.push_part("P1", original.clone())?;
+
+        assert_eq!(part_map.coalesce_initializers(), 2);
+        let coalesced = part_map.get_part(0).unwrap();
+        let num_inits = coalesced
+            .inner()
+            .iter()
+            .filter(|e| matches!(e, MusicElement::MeasureInit(_)))
+            .count();
+        assert_eq!(num_inits, 2);
+        assert_eq!(effective_initializers(coalesced), effective_initializers(&original));
+
+        assert_eq!(round_trip(coalesced)?.inner(), coalesced.inner());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parts_keep_score_order() -> Result<(), Box<dyn std::error::Error>> {
+        let mut part_map = PartMap::new();
+        for part_id in ["P1", "P2", "P10"] {
+            part_map.add_part_id(part_id)?;
+        }
+        // Parts may be parsed out of order, the ids keep the order of the part list
+        for (part_id, pitch) in [("P10", 40), ("P1", 60), ("P2", 50)] {
+            part_map.push_part(part_id, single_voice_part(part_id, pitch))?;
+        }
+        assert_eq!(part_map.keys(), vec!["P1", "P2", "P10"]);
+
+        let xml = ir_to_xml(part_map, &ExportOptions::default())?;
+        let doc = roxmltree::Document::parse(&xml)?;
+        let written: Vec<&str> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("part"))
+            .filter_map(|n| n.attribute("id"))
+            .collect();
+        assert_eq!(written, vec!["P1", "P2", "P10"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use super::key_estimation::estimate_key;
+use super::notation::{
+    KeySignature, MeasureMetaData, MeasureStartEnd, MusicElement, NumericPitchRest, RhythmType, TimeModification,
+};
+
+/// Note density and rhythmic complexity of a part, used to gauge how difficult a piece is
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PartStats {
+    pub measures: usize,
+    /// Pitched notes, including the notes of chords
+    pub notes: usize,
+    /// Pitched notes within a tuplet
+    pub tuplet_notes: usize,
+    /// Shortest note value of any pitched note
+    pub smallest_note: Option<RhythmType>,
+    /// Key estimated from the pitches, regardless of the key signature the part declares
+    pub estimated_key: Option<KeySignature>,
+}
+
+impl PartStats {
+    pub fn from_elements(elems: &[MusicElement]) -> PartStats {
+        let mut stats = PartStats::default();
+        let mut time_mod: Option<TimeModification> = None;
+        for elem in elems {
+            match elem {
+                MusicElement::MeasureMeta(MeasureMetaData {
+                    start_end: MeasureStartEnd::MeasureEnd | MeasureStartEnd::RepeatEnd,
+                    ..
+                }) => stats.measures += 1,
+                MusicElement::Tuplet(t) => time_mod = (*t).into(),
+                MusicElement::NoteRest(n) if n.note_rest != NumericPitchRest::Rest => {
+                    stats.notes += 1;
+                    if time_mod.is_some() {
+                        stats.tuplet_notes += 1;
+                    }
+                    stats.smallest_note = Some(match stats.smallest_note {
+                        Some(smallest) => smallest.min(n.note_type),
+                        None => n.note_type,
+                    });
+                }
+                _ => {}
+            }
+        }
+        stats.estimated_key = estimate_key(elems);
+        stats
+    }
+
+    pub fn notes_per_measure(&self) -> f64 {
+        if self.measures == 0 {
+            0.0
+        } else {
+            self.notes as f64 / self.measures as f64
+        }
+    }
+
+    pub fn tuplet_fraction(&self) -> f64 {
+        if self.notes == 0 {
+            0.0
+        } else {
+            self.tuplet_notes as f64 / self.notes as f64
+        }
+    }
+
+    pub fn smallest_note_string(&self) -> String {
+        self.smallest_note
+            .map_or(String::from("none"), RhythmType::get_type_string)
+    }
+
+    pub fn estimated_key_string(&self) -> String {
+        self.estimated_key
+            .map_or(String::from("none"), |k| k.major_name().to_string())
+    }
+
+    /// Serializes the stats of the part with the given id as a JSON object
+    pub fn to_json(&self, part_id: &str) -> String {
+        format!(
+            "{{\"part\":\"{}\",\"measures\":{},\"notes\":{},\"notes_per_measure\":{:.3},\"tuplet_fraction\":{:.3},\"smallest_note\":{},\"estimated_key\":{}}}",
+            part_id.replace('\\', "\\\\").replace('"', "\\\""),
+            self.measures,
+            self.notes,
+            self.notes_per_measure(),
+            self.tuplet_fraction(),
+            self.smallest_note
+                .map_or(String::from("null"), |n| format!("\"{}\"", n.get_type_string())),
+            self.estimated_key
+                .map_or(String::from("null"), |k| format!("\"{}\"", k.major_name()))
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PartStats;
+    use crate::ir::notation::{
+        MeasureMetaData, MeasureStartEnd, MusicElement, NoteData, NumericPitchRest, RhythmType,
+        TupletActual, TupletData, TupletNormal, TupletNumber, TupletStartStop, Voice,
+    };
+
+    fn note(note_type: RhythmType) -> MusicElement {
+        MusicElement::NoteRest(NoteData {
+            note_rest: NumericPitchRest::Pitch(40),
+            ..NoteData::new_default_rest(note_type, false, Voice::One)
+        })
+    }
+
+    fn tuplet(start_stop: TupletStartStop) -> MusicElement {
+        MusicElement::Tuplet(TupletData {
+            start_stop,
+            tuplet_number: TupletNumber::One,
+            actual_notes: TupletActual::Three,
+            normal_notes: TupletNormal::Two,
+            dotted: false,
+            hide_bracket: false,
+            hide_number: false,
+            normal_type: None,
+        })
+    }
+
+    #[test]
+    fn test_density_of_known_elements() {
+        let start = MusicElement::MeasureMeta(MeasureMetaData::new(MeasureStartEnd::MeasureStart));
+        let end = MusicElement::MeasureMeta(MeasureMetaData::new(MeasureStartEnd::MeasureEnd));
+        let elems = vec![
+            start,
+            note(RhythmType::Minim),
+            tuplet(TupletStartStop::TupletStart),
+            note(RhythmType::Quaver),
+            note(RhythmType::Quaver),
+            note(RhythmType::Quaver),
+            tuplet(TupletStartStop::TupletStop),
+            note(RhythmType::Crochet),
+            end,
+            start,
+            note(RhythmType::SemiQuaver),
+            MusicElement::NoteRest(NoteData::new_default_rest(
+                RhythmType::DemiSemiQuaver,
+                false,
+                Voice::One,
+            )),
+            end,
+        ];
+        let stats = PartStats::from_elements(&elems);
+        assert_eq!(stats.measures, 2);
+        assert_eq!(stats.notes, 6);
+        assert_eq!(stats.tuplet_notes, 3);
+        assert_eq!(stats.notes_per_measure(), 3.0);
+        assert_eq!(stats.tuplet_fraction(), 0.5);
+        // Rests do not count towards the smallest note value
+        assert_eq!(stats.smallest_note, Some(RhythmType::SemiQuaver));
+        assert_eq!(
+            stats.to_json("P1"),
+            "{\"part\":\"P1\",\"measures\":2,\"notes\":6,\"notes_per_measure\":3.000,\"tuplet_fraction\":0.500,\"smallest_note\":\"16th\",\"estimated_key\":\"Eb-maj\"}"
+        );
+    }
+}
@@ -0,0 +1,61 @@
+use super::note_features::compute_onsets;
+use super::notation::{MeasureStartEnd, MusicElement};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Where in the source score an element of a part came from
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Provenance {
+    /// Zero based index of the source measure, counting every `<measure>` of the part
+    pub measure: usize,
+    /// One based beat of the measure the element starts on, in the unit of the time signature's beat
+    /// type. Elements other than notes take the beat of the note before them.
+    pub beat: f32,
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "@m{} b{}", self.measure, self.beat)
+    }
+}
+
+/// Gives every element the source measure and beat it came from. `source_measures` holds the source
+/// measure index of each measure of `elems`, in order; measure initializers belong to the measure
+/// they precede.
+pub fn compute_provenance(elems: &[MusicElement], source_measures: &[usize]) -> Vec<Provenance> {
+    let onsets: BTreeMap<usize, u32> = compute_onsets(elems).into_iter().collect();
+    let mut measure_idx = 0;
+    let mut measure_start = None;
+    let mut ticks_per_beat = 960;
+    let mut beat = 1.0;
+    let mut provenance = Vec::with_capacity(elems.len());
+    for (elem_idx, elem) in elems.iter().enumerate() {
+        match elem {
+            MusicElement::MeasureInit(init) => {
+                ticks_per_beat = 960 * 4 / u32::from(init.beat_type);
+            }
+            MusicElement::MeasureMeta(meta)
+                if matches!(meta.start_end, MeasureStartEnd::MeasureStart | MeasureStartEnd::RepeatStart) =>
+            {
+                // Every voice starts at the beginning of the measure, so the first note's onset is its start
+                measure_start = None;
+                beat = 1.0;
+            }
+            _ => {}
+        }
+        if let Some(&onset) = onsets.get(&elem_idx) {
+            let start = *measure_start.get_or_insert(onset);
+            beat = 1.0 + (onset - start) as f32 / ticks_per_beat as f32;
+        }
+        provenance.push(Provenance {
+            measure: source_measures.get(measure_idx).copied().unwrap_or_default(),
+            beat,
+        });
+        if let MusicElement::MeasureMeta(meta) = elem {
+            if matches!(meta.start_end, MeasureStartEnd::MeasureEnd | MeasureStartEnd::RepeatEnd) {
+                measure_idx += 1;
+            }
+        }
+    }
+    provenance
+}
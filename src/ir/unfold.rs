@@ -0,0 +1,301 @@
+use super::notation::{DalSegno, Ending, MeasureInitializer, MeasureMetaData, MeasureStartEnd, MusicElement};
+use crate::error::{Error, Result};
+
+/// The elements of one measure with the navigation marks found on its start and end
+struct UnfoldMeasure {
+    elems: Vec<MusicElement>,
+    // Initializer in effect at the start of the measure
+    init: Option<MeasureInitializer>,
+    has_init: bool,
+    repeat_start: bool,
+    repeat_end: bool,
+    // Times the section ending here is played
+    repeat_times: u8,
+    ending: Ending,
+    dal_segno: Vec<DalSegno>,
+}
+
+impl UnfoldMeasure {
+    fn has_mark(&self, mark: DalSegno) -> bool {
+        self.dal_segno.contains(&mark)
+    }
+
+    fn jump(&self) -> Option<DalSegno> {
+        self.dal_segno.iter().copied().find(|d| {
+            matches!(
+                d,
+                DalSegno::DaSegno
+                    | DalSegno::DaCapo
+                    | DalSegno::DaCapoalSegno
+                    | DalSegno::DaCapoAlCoda
+                    | DalSegno::DaCapoAlFine
+            )
+        })
+    }
+}
+
+/// Splits an element stream into measures, clearing the repeat and navigation marks of their metadata
+fn split_measures(elems: &[MusicElement]) -> (Vec<UnfoldMeasure>, Vec<MusicElement>) {
+    let mut measures = vec![];
+    let mut cur = vec![];
+    let mut cur_init = None;
+    let mut measure_init = None;
+    let mut has_init = false;
+    let mut repeat_start = false;
+    let mut ending = Ending::None;
+    let mut dal_segno = vec![];
+    for elem in elems {
+        match *elem {
+            MusicElement::MeasureInit(init) => {
+                cur_init = Some(init);
+                has_init = true;
+                cur.push(*elem);
+            }
+            MusicElement::MeasureMeta(meta) => {
+                if meta.dal_segno != DalSegno::None {
+                    dal_segno.push(meta.dal_segno);
+                }
+                match meta.start_end {
+                    MeasureStartEnd::MeasureStart | MeasureStartEnd::RepeatStart => {
+                        repeat_start = meta.start_end == MeasureStartEnd::RepeatStart;
+                        ending = meta.ending;
+                        measure_init = cur_init;
+                        cur.push(MusicElement::MeasureMeta(MeasureMetaData::new(MeasureStartEnd::MeasureStart)));
+                    }
+                    MeasureStartEnd::MeasureEnd | MeasureStartEnd::RepeatEnd => {
+                        cur.push(MusicElement::MeasureMeta(MeasureMetaData::new(MeasureStartEnd::MeasureEnd)));
+                        measures.push(UnfoldMeasure {
+                            elems: std::mem::take(&mut cur),
+                            init: measure_init,
+                            has_init,
+                            repeat_start,
+                            repeat_end: meta.start_end == MeasureStartEnd::RepeatEnd,
+                            repeat_times: meta.repeat_times.unwrap_or(2),
+                            ending,
+                            dal_segno: std::mem::take(&mut dal_segno),
+                        });
+                        has_init = false;
+                    }
+                }
+            }
+            _ => cur.push(*elem),
+        }
+    }
+    (measures, cur)
+}
+
+/// Works out the order measures are performed in, following repeats, endings and D.C./D.S. jumps.
+///
+/// A repeat is taken once less than the times its section is played, twice unless the repeat says
+/// otherwise, or once per ending, and endings are selected by the pass through the repeated section. After a D.C. or D.S. jump repeats are no longer taken and first endings are
+/// skipped. Following D.C. al Coda, the first coda mark jumps to the next one. D.C. al Fine plays
+/// to the end, as there is no fine mark to stop at.
+fn performance_order(measures: &[UnfoldMeasure]) -> Result<Vec<usize>> {
+    // Guards against navigation marks that would never let the performance end
+    let most_repeat_times = measures.iter().map(|m| usize::from(m.repeat_times)).max().unwrap_or(2);
+    let max_len = measures.len() * 4 * most_repeat_times;
+    let mut order = vec![];
+    let mut idx = 0;
+    let mut repeat_from = 0;
+    let mut pass = 1;
+    // Index of the repeat end of every repeat taken without an ending
+    let mut taken_repeats: Vec<usize> = vec![];
+    let mut jumped: Option<DalSegno> = None;
+    while idx < measures.len() {
+        if order.len() > max_len {
+            return Err(Error::Validation(format!(
+                "Navigation marks do not resolve to a finite performance after {} measures",
+                max_len
+            )));
+        }
+        let m = &measures[idx];
+        if m.repeat_start && repeat_from != idx {
+            repeat_from = idx;
+            pass = 1;
+        }
+        if m.ending != Ending::None {
+            let skip = match jumped {
+                Some(_) => m.ending == Ending::One,
+                None => m.ending as u8 != pass,
+            };
+            if skip {
+                idx += 1;
+                continue;
+            }
+        }
+        order.push(idx);
+
+        let times_taken = taken_repeats.iter().filter(|&&i| i == idx).count();
+        if jumped.is_none() && m.repeat_end && times_taken + 1 < usize::from(m.repeat_times) {
+            if m.ending == Ending::None {
+                taken_repeats.push(idx);
+                pass = times_taken as u8 + 2;
+            } else {
+                pass = m.ending as u8 + 1;
+            }
+            idx = repeat_from;
+            continue;
+        }
+        if jumped == Some(DalSegno::DaCapoAlCoda) && m.has_mark(DalSegno::CodaMarker) {
+            if let Some(coda) = (idx + 1..measures.len()).find(|&i| measures[i].has_mark(DalSegno::CodaMarker)) {
+                jumped = Some(DalSegno::CodaMarker);
+                idx = coda;
+                continue;
+            }
+        }
+        if let (None, Some(jump)) = (jumped, m.jump()) {
+            jumped = Some(jump);
+            idx = match jump {
+                DalSegno::DaSegno | DalSegno::DaCapoalSegno => measures
+                    .iter()
+                    .position(|m| m.has_mark(DalSegno::SegnoMarker))
+                    .ok_or_else(|| Error::Validation(format!("D.S. in measure {} without a segno", idx + 1)))?,
+                _ => 0,
+            };
+            repeat_from = idx;
+            continue;
+        }
+        idx += 1;
+    }
+    Ok(order)
+}
+
+/// Resolves repeats, endings and D.C./D.S. jumps of an element stream into the linear sequence of
+/// measures heard in performance. The returned measures carry no repeat or navigation marks, and a
+/// measure initializer is inserted wherever a jump lands in a different time or key signature.
+pub fn unfold_elements(elems: &[MusicElement]) -> Result<Vec<MusicElement>> {
+    let (measures, trailing) = split_measures(elems);
+    let mut unfolded = vec![];
+    let mut cur_init = None;
+    for idx in performance_order(&measures)? {
+        let m = &measures[idx];
+        if let Some(init) = m.init.filter(|init| !m.has_init && Some(*init) != cur_init) {
+            unfolded.push(MusicElement::MeasureInit(init));
+        }
+        unfolded.extend(m.elems.iter().copied());
+        cur_init = m
+            .elems
+            .iter()
+            .rev()
+            .find_map(|e| match e {
+                MusicElement::MeasureInit(init) => Some(*init),
+                _ => None,
+            })
+            .or(m.init);
+    }
+    unfolded.extend(trailing);
+    Ok(unfolded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unfold_elements;
+    use crate::ir::notation::{
+        BarStyle, DalSegno, Ending, MeasureInitializer, MeasureMetaData, MeasureStartEnd, MusicElement, NoteData,
+        NumericPitchRest, RhythmType,
+    };
+
+    /// A one note measure identified by its pitch
+    fn measure(pitch: u8, start: MeasureMetaData, end: MeasureMetaData) -> Vec<MusicElement> {
+        vec![
+            MusicElement::MeasureMeta(start),
+            MusicElement::NoteRest(NoteData {
+                note_rest: NumericPitchRest::Pitch(pitch),
+                note_type: RhythmType::SemiBreve,
+                ..NoteData::default()
+            }),
+            MusicElement::MeasureMeta(end),
+        ]
+    }
+
+    fn meta(start_end: MeasureStartEnd, ending: Ending, dal_segno: DalSegno) -> MeasureMetaData {
+        MeasureMetaData {
+            start_end,
+            ending,
+            dal_segno,
+            repeat_times: None,
+            bar_style: BarStyle::Regular,
+        }
+    }
+
+    fn pitches(elems: &[MusicElement]) -> Vec<u8> {
+        elems
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some(n.note_rest.get_numeric_value()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_unfold_aaba_with_da_capo_al_coda() {
+        use DalSegno::*;
+        use MeasureStartEnd::*;
+        // |: A (to coda) :| B (D.C. al Coda) | coda
+        let mut elems = vec![MusicElement::MeasureInit(MeasureInitializer::default())];
+        elems.extend(measure(
+            40,
+            meta(RepeatStart, Ending::None, None),
+            meta(RepeatEnd, Ending::None, CodaMarker),
+        ));
+        elems.extend(measure(
+            45,
+            meta(MeasureStart, Ending::None, None),
+            meta(MeasureEnd, Ending::None, DaCapoAlCoda),
+        ));
+        elems.extend(measure(
+            52,
+            meta(MeasureStart, Ending::None, CodaMarker),
+            meta(MeasureEnd, Ending::None, None),
+        ));
+
+        let unfolded = unfold_elements(&elems).unwrap();
+        assert_eq!(pitches(&unfolded), vec![40, 40, 45, 40, 52]);
+        // Navigation marks are resolved, so none are left in the output
+        assert!(unfolded.iter().all(|e| match e {
+            MusicElement::MeasureMeta(m) => m.dal_segno == None && m.ending == Ending::None,
+            _ => true,
+        }));
+        assert_eq!(
+            unfolded.iter().filter(|e| matches!(e, MusicElement::MeasureInit(_))).count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_unfold_first_and_second_endings() {
+        use DalSegno::None;
+        use MeasureStartEnd::*;
+        // |: A | 1. B :| 2. C | D
+        let mut elems = vec![MusicElement::MeasureInit(MeasureInitializer::default())];
+        elems.extend(measure(40, meta(RepeatStart, Ending::None, None), meta(MeasureEnd, Ending::None, None)));
+        elems.extend(measure(42, meta(MeasureStart, Ending::One, None), meta(RepeatEnd, Ending::One, None)));
+        elems.extend(measure(44, meta(MeasureStart, Ending::Two, None), meta(MeasureEnd, Ending::None, None)));
+        elems.extend(measure(45, meta(MeasureStart, Ending::None, None), meta(MeasureEnd, Ending::None, None)));
+
+        let unfolded = unfold_elements(&elems).unwrap();
+        assert_eq!(pitches(&unfolded), vec![40, 42, 40, 44, 45]);
+    }
+
+    #[test]
+    fn test_unfold_repeat_played_three_times() {
+        use DalSegno::None;
+        use MeasureStartEnd::*;
+        // |: A | B :|x3 C
+        let mut elems = vec![MusicElement::MeasureInit(MeasureInitializer::default())];
+        elems.extend(measure(40, meta(RepeatStart, Ending::None, None), meta(MeasureEnd, Ending::None, None)));
+        elems.extend(measure(
+            42,
+            meta(MeasureStart, Ending::None, None),
+            MeasureMetaData {
+                repeat_times: Some(3),
+                ..meta(RepeatEnd, Ending::None, None)
+            },
+        ));
+        elems.extend(measure(44, meta(MeasureStart, Ending::None, None), meta(MeasureEnd, Ending::None, None)));
+
+        let unfolded = unfold_elements(&elems).unwrap();
+        assert_eq!(pitches(&unfolded), vec![40, 42, 40, 42, 40, 42, 44]);
+    }
+}
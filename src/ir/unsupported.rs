@@ -0,0 +1,127 @@
+use super::muxml_parser::select_voices;
+use super::notation::DescriptiveTempo;
+use super::{MusicalPart, ParseOptions};
+use crate::error::{Error, Result};
+use roxmltree::{Document, Node, ParsingOptions};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Ornaments that are carried into the intermediate representation
+const SUPPORTED_ORNAMENTS: [&str; 1] = ["tremolo"];
+
+/// Tally of the MusicXML constructs a file uses that are dropped while converting it
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UnsupportedReport {
+    /// Number of occurrences of each construct, by name, such as `ornament trill-mark`
+    pub constructs: BTreeMap<String, usize>,
+}
+
+impl UnsupportedReport {
+    fn add(&mut self, construct: String) {
+        *self.constructs.entry(construct).or_insert(0) += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.constructs.is_empty()
+    }
+
+    /// Total number of dropped occurrences
+    pub fn total(&self) -> usize {
+        self.constructs.values().sum()
+    }
+}
+
+impl fmt::Display for UnsupportedReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let constructs: Vec<String> = self
+            .constructs
+            .iter()
+            .map(|(construct, count)| format!("{} x{}", construct, count))
+            .collect();
+        write!(f, "{}", constructs.join(", "))
+    }
+}
+
+/// Scans a MusicXML document for the constructs the conversion with `options` drops: unpitched notes
+/// unless percussion is kept, parts beyond `max_parts`, notes of the voices `voice_limit` drops,
+/// ornaments other than tremolos and directions other than dynamics and tempo words.
+pub fn scan_unsupported(docstring: &str, options: &ParseOptions) -> Result<UnsupportedReport> {
+    let opt = ParsingOptions {
+        allow_dtd: true,
+        ..ParsingOptions::default()
+    };
+    let doc = Document::parse_with_options(docstring, opt).map_err(|_| Error::Parse)?;
+    let mut report = UnsupportedReport::default();
+
+    let parts = doc.root_element().children().filter(|n| n.has_tag_name("part"));
+    for (part_idx, part) in parts.enumerate() {
+        if part_idx >= options.max_parts {
+            report.add(format!("part beyond {}", options.max_parts));
+            continue;
+        }
+        let kept_voices = select_voices(&part, options.voice_limit);
+        for note in part.descendants().filter(|n| n.has_tag_name("note")) {
+            if !options.keep_percussion && note.children().any(|n| n.has_tag_name("unpitched")) {
+                report.add(String::from("unpitched note"));
+            }
+            let voice = note
+                .children()
+                .find(|n| n.has_tag_name("voice"))
+                .and_then(|n| n.text())
+                .and_then(|text| text.trim().parse::<u8>().ok());
+            if let (Some(kept), Some(voice)) = (&kept_voices, voice) {
+                if !kept.contains(&voice) {
+                    report.add(format!("note beyond {} voices", MusicalPart::MAX_SUPPORTED_VOICES));
+                }
+            }
+        }
+        for ornaments in part.descendants().filter(|n| n.has_tag_name("ornaments")) {
+            for ornament in ornaments.children().filter(Node::is_element) {
+                let name = ornament.tag_name().name();
+                if !SUPPORTED_ORNAMENTS.contains(&name) {
+                    report.add(format!("ornament {}", name));
+                }
+            }
+        }
+        for direction in part.descendants().filter(|n| n.has_tag_name("direction")) {
+            let has_sound_tempo = direction
+                .children()
+                .any(|n| n.has_tag_name("sound") && n.attribute("tempo").is_some());
+            let kinds = direction
+                .children()
+                .filter(|n| n.has_tag_name("direction-type"))
+                .flat_map(|n| n.children().filter(Node::is_element));
+            for kind in kinds {
+                let used = match kind.tag_name().name() {
+                    "dynamics" => true,
+                    // The tempo of a metronome mark is read from the accompanying sound element
+                    "metronome" => has_sound_tempo,
+                    "words" => kind.text().and_then(DescriptiveTempo::from_words).is_some(),
+                    _ => false,
+                };
+                if !used {
+                    report.add(format!("direction {}", kind.tag_name().name()));
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::scan_unsupported;
+    use crate::ir::ParseOptions;
+    use std::fs;
+
+    #[test]
+    fn test_dropped_constructs_are_reported() {
+        let docstring = fs::read_to_string("test/unsupported.musicxml").unwrap();
+        let report = scan_unsupported(&docstring, &ParseOptions::default()).unwrap();
+        assert_eq!(report.constructs.get("ornament trill-mark"), Some(&1));
+        assert_eq!(report.constructs.get("direction wedge"), Some(&2));
+        // Dynamics and a tempo marking given in words are converted
+        assert_eq!(report.constructs.len(), 2);
+        assert_eq!(report.to_string(), "direction wedge x2, ornament trill-mark x1");
+    }
+}
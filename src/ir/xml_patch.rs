@@ -0,0 +1,336 @@
+use roxmltree::{Document, Node, ParsingOptions};
+use std::cmp::Reverse;
+
+use crate::error::{Error, Result};
+
+/// Locates the element of the serialized score a patch is applied to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchAnchor {
+    /// The `<score-part>` entry of the part list with the given part id
+    ScorePart { part_id: String },
+    /// The measure at the zero based position `measure_idx` within the part with the given id
+    Measure { part_id: String, measure_idx: usize },
+    /// The `<note>` at the zero based position `note_idx` within a measure, located as for `Measure`
+    Note {
+        part_id: String,
+        measure_idx: usize,
+        note_idx: usize,
+    },
+    /// The `<direction>` at the zero based position `direction_idx` within a measure, located as for `Measure`
+    Direction {
+        part_id: String,
+        measure_idx: usize,
+        direction_idx: usize,
+    },
+    /// The `<barline>` with the given `location` attribute within a measure, located as for `Measure`
+    Barline {
+        part_id: String,
+        measure_idx: usize,
+        location: &'static str,
+    },
+    /// The `<encoding>` element of the score's identification
+    Encoding,
+    /// The `<clef>` with the given `number` attribute within the `<attributes>` of a measure, located
+    /// as for `Measure`
+    Clef {
+        part_id: String,
+        measure_idx: usize,
+        number: u8,
+    },
+}
+
+/// An edit applied to the element selected by a `PatchAnchor`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchEdit {
+    /// Insert the fragment after the last child element whose tag name is in `after`,
+    /// or as the first child when none of those children exist.
+    InsertChild {
+        after: &'static [&'static str],
+        fragment: String,
+    },
+    /// Insert the fragment before the `nth` (zero based) child element with the given tag name,
+    /// or as the last child when there are not that many.
+    InsertBeforeNth {
+        tag: &'static str,
+        nth: usize,
+        fragment: String,
+    },
+    /// Insert the fragment as the last child of the anchor's first descendant element with the given tag name
+    InsertIntoDescendant {
+        tag: &'static str,
+        fragment: String,
+    },
+    /// Write the fragment as the content of the anchor's first descendant element with the given tag
+    /// name when that element has no child elements, so the serializer writing it empty is made up for
+    FillDescendant {
+        tag: &'static str,
+        fragment: String,
+    },
+    /// Remove the anchor's first descendant element with the given tag name, if it has one
+    RemoveDescendant { tag: &'static str },
+    /// Add an attribute to the anchor element when it has the given tag name, otherwise to its first
+    /// descendant element with that tag name
+    SetAttribute {
+        tag: &'static str,
+        name: &'static str,
+        value: String,
+    },
+}
+
+/// Collects edits for MusicXML features the muxml serializer has no field for,
+/// and splices them into the serialized document.
+#[derive(Debug, Default, Clone)]
+pub struct XmlPatcher {
+    patches: Vec<(PatchAnchor, PatchEdit)>,
+}
+
+impl XmlPatcher {
+    pub fn new() -> XmlPatcher {
+        XmlPatcher { patches: vec![] }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patches.is_empty()
+    }
+
+    pub fn push(&mut self, anchor: PatchAnchor, edit: PatchEdit) {
+        self.patches.push((anchor, edit));
+    }
+
+    /// Moves the patches of `other` after those already collected
+    pub fn append(&mut self, mut other: XmlPatcher) {
+        self.patches.append(&mut other.patches);
+    }
+
+    /// Applies all collected patches to the serialized document
+    pub fn apply(&self, xml: &str) -> Result<String> {
+        if self.patches.is_empty() {
+            return Ok(xml.to_string());
+        }
+        let opt = ParsingOptions {
+            allow_dtd: true,
+            ..ParsingOptions::default()
+        };
+        let doc = Document::parse_with_options(xml, opt).map_err(|_| Error::Parse)?;
+
+        // Byte offset, number of bytes replaced there and the text to insert, in the order the patches
+        // were pushed
+        let mut insertions: Vec<(usize, usize, String)> = vec![];
+        for (anchor, edit) in self.patches.iter() {
+            let node = find_anchor(&doc, anchor).ok_or(Error::Parse)?;
+            match edit {
+                PatchEdit::InsertChild { after, fragment } => {
+                    let offset = match node
+                        .children()
+                        .rfind(|n| n.is_element() && after.contains(&n.tag_name().name()))
+                    {
+                        Some(n) => n.range().end,
+                        None => start_tag_end(xml, &node).ok_or(Error::Parse)?,
+                    };
+                    insertions.push((offset, 0, fragment.clone()));
+                }
+                PatchEdit::InsertBeforeNth { tag, nth, fragment } => {
+                    let offset = match node
+                        .children()
+                        .filter(|n| n.is_element() && n.has_tag_name(*tag))
+                        .nth(*nth)
+                    {
+                        Some(n) => n.range().start,
+                        None => end_tag_start(xml, &node).ok_or(Error::Parse)?,
+                    };
+                    insertions.push((offset, 0, fragment.clone()));
+                }
+                PatchEdit::InsertIntoDescendant { tag, fragment } => {
+                    let descendant = node
+                        .descendants()
+                        .find(|n| n.is_element() && n.has_tag_name(*tag))
+                        .ok_or(Error::Parse)?;
+                    let offset = end_tag_start(xml, &descendant).ok_or(Error::Parse)?;
+                    insertions.push((offset, 0, fragment.clone()));
+                }
+                PatchEdit::FillDescendant { tag, fragment } => {
+                    let descendant = node
+                        .descendants()
+                        .find(|n| n.is_element() && n.has_tag_name(*tag))
+                        .ok_or(Error::Parse)?;
+                    if descendant.children().any(|n| n.is_element()) {
+                        continue;
+                    }
+                    match end_tag_start(xml, &descendant) {
+                        Some(offset) => insertions.push((offset, 0, fragment.clone())),
+                        // A self closing element has its closing `/>` replaced by the content and an end tag
+                        None => insertions.push((
+                            descendant.range().end - 2,
+                            2,
+                            format!(">{}</{}>", fragment, tag),
+                        )),
+                    }
+                }
+                PatchEdit::RemoveDescendant { tag } => {
+                    let Some(descendant) = node.descendants().find(|n| n.is_element() && n.has_tag_name(*tag))
+                    else {
+                        continue;
+                    };
+                    // The indentation before the element goes with it
+                    let start = match descendant.prev_sibling() {
+                        Some(n) if n.is_text() && n.text().is_some_and(|t| t.trim().is_empty()) => n.range().start,
+                        _ => descendant.range().start,
+                    };
+                    insertions.push((start, descendant.range().end - start, String::new()));
+                }
+                PatchEdit::SetAttribute { tag, name, value } => {
+                    let descendant = node
+                        .descendants()
+                        .find(|n| n.is_element() && n.has_tag_name(*tag))
+                        .ok_or(Error::Parse)?;
+                    // Directly after the tag name of the descendant's start tag
+                    let offset = descendant.range().start + 1 + tag.len();
+                    insertions.push((offset, 0, format!(" {}=\"{}\"", name, escape_text(value))));
+                }
+            }
+        }
+
+        // Splice from the end of the document so earlier offsets stay valid. The sort is stable, and
+        // each fragment sharing an offset is placed after the previous one, preserving push order.
+        insertions.sort_by_key(|&(offset, _, _)| Reverse(offset));
+        let mut output = xml.to_string();
+        let mut last_offset = None;
+        let mut same_offset_tail = 0;
+        for (offset, replaced, fragment) in insertions {
+            if last_offset == Some(offset) {
+                output.insert_str(offset + same_offset_tail, &fragment);
+            } else {
+                output.replace_range(offset..offset + replaced, &fragment);
+                same_offset_tail = 0;
+            }
+            same_offset_tail += fragment.len();
+            last_offset = Some(offset);
+        }
+        Ok(output)
+    }
+}
+
+fn find_anchor<'a, 'input>(
+    doc: &'a Document<'input>,
+    anchor: &PatchAnchor,
+) -> Option<Node<'a, 'input>> {
+    match anchor {
+        PatchAnchor::ScorePart { part_id } => doc
+            .descendants()
+            .find(|n| n.has_tag_name("score-part") && n.attribute("id") == Some(part_id.as_str())),
+        PatchAnchor::Measure {
+            part_id,
+            measure_idx,
+        } => doc
+            .descendants()
+            .find(|n| n.has_tag_name("part") && n.attribute("id") == Some(part_id.as_str()))?
+            .children()
+            .filter(|n| n.has_tag_name("measure"))
+            .nth(*measure_idx),
+        PatchAnchor::Note {
+            part_id,
+            measure_idx,
+            note_idx,
+        } => find_anchor(
+            doc,
+            &PatchAnchor::Measure {
+                part_id: part_id.clone(),
+                measure_idx: *measure_idx,
+            },
+        )?
+        .children()
+        .filter(|n| n.has_tag_name("note"))
+        .nth(*note_idx),
+        PatchAnchor::Direction {
+            part_id,
+            measure_idx,
+            direction_idx,
+        } => find_anchor(
+            doc,
+            &PatchAnchor::Measure {
+                part_id: part_id.clone(),
+                measure_idx: *measure_idx,
+            },
+        )?
+        .children()
+        .filter(|n| n.has_tag_name("direction"))
+        .nth(*direction_idx),
+        PatchAnchor::Barline {
+            part_id,
+            measure_idx,
+            location,
+        } => find_anchor(
+            doc,
+            &PatchAnchor::Measure {
+                part_id: part_id.clone(),
+                measure_idx: *measure_idx,
+            },
+        )?
+        .children()
+        .find(|n| n.has_tag_name("barline") && n.attribute("location") == Some(*location)),
+        PatchAnchor::Encoding => doc.descendants().find(|n| n.has_tag_name("encoding")),
+        PatchAnchor::Clef {
+            part_id,
+            measure_idx,
+            number,
+        } => find_anchor(
+            doc,
+            &PatchAnchor::Measure {
+                part_id: part_id.clone(),
+                measure_idx: *measure_idx,
+            },
+        )?
+        .children()
+        .filter(|n| n.has_tag_name("attributes"))
+        .flat_map(|n| n.children())
+        .find(|n| n.has_tag_name("clef") && n.attribute("number") == Some(number.to_string().as_str())),
+    }
+}
+
+/// Returns the byte offset of the `<` opening the element's end tag
+fn end_tag_start(xml: &str, node: &Node) -> Option<usize> {
+    let element = &xml[node.range()];
+    if element.ends_with("/>") {
+        return None;
+    }
+    element.rfind("</").map(|idx| node.range().start + idx)
+}
+
+/// Returns the byte offset just past the `>` closing the element's start tag
+fn start_tag_end(xml: &str, node: &Node) -> Option<usize> {
+    let start = node.range().start;
+    let mut quote: Option<u8> = None;
+    for (idx, b) in xml.as_bytes()[start..node.range().end].iter().enumerate() {
+        match (quote, *b) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => (),
+            (None, b'"') | (None, b'\'') => quote = Some(*b),
+            (None, b'>') => {
+                // A self closing element has no room for children
+                return if idx > 0 && xml.as_bytes()[start + idx - 1] == b'/' {
+                    None
+                } else {
+                    Some(start + idx + 1)
+                };
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Escapes text for use as XML character data or an attribute value
+pub fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
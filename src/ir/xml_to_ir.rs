@@ -1,18 +1,21 @@
 use super::muxml_parser::{
-    does_note_contain_unpitched, parse_backup_tag, parse_direction_tag, parse_note_tag,
+    does_note_contain_unpitched, find_initial_attributes, is_rest_only_measure, parse_backup_tag, parse_direction_tag,
+    parse_attributes_tag, parse_bar_style, parse_harmony_tag, parse_measure_number, parse_measure_style_tag, parse_note_tag,
+    find_tab_staves, note_staff, parse_print_tag, parse_repeat_times, parse_score_part_tag, select_voices, MeasureStyle,
 };
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::ir::notation::{
-    BeatType, Beats, Ending, KeySignature, MeasureInitializer, MeasureMetaData, MeasureStartEnd,
+    BeatType, Beats, DescriptiveTempo, Ending, KeySignature, MeasureInitializer, MeasureMetaData, MeasureStartEnd,
     Tempo,
 };
-use crate::ir::{MusicalPart, PartMap};
+use crate::ir::{MusicalPart, ParseOptions, PartMap, PartName, TabStaffPolicy};
 
-use log::info;
+use log::{info, warn};
 use roxmltree::*;
+use std::collections::BTreeMap;
 use std::str::FromStr;
 const MAX_SUPPORTED_PARTS: usize = 4;
-pub fn xml_to_ir(docstring: String, _dump_input: bool) -> Result<PartMap> {
+pub fn xml_to_ir(docstring: String, _dump_input: bool, options: &ParseOptions) -> Result<PartMap> {
     let opt = ParsingOptions {
         allow_dtd: true,
         ..ParsingOptions::default()
@@ -26,11 +29,15 @@ pub fn xml_to_ir(docstring: String, _dump_input: bool) -> Result<PartMap> {
         .descendants()
         .filter(|n| n.has_tag_name("score-part"));
 
+    let mut ir_part_names: BTreeMap<String, PartName> = BTreeMap::new();
     for xml_score_part in xml_score_parts.clone() {
         let part_id = xml_score_part.attribute("id").unwrap();
         ir_part_map
             .add_part_id(part_id)
             .expect("PartMap is not empty");
+        if let Some(part_name) = parse_score_part_tag(&xml_score_part) {
+            ir_part_names.insert(part_id.to_string(), part_name);
+        }
     }
 
     info!(
@@ -48,13 +55,35 @@ pub fn xml_to_ir(docstring: String, _dump_input: bool) -> Result<PartMap> {
             .find(|n| n.has_tag_name("part") && n.attribute("id").unwrap().eq(ir_part_str.as_str()));
 
         let mut ir_musical_part: MusicalPart = MusicalPart::new(ir_part_str.as_str());
+        ir_musical_part.set_part_name(ir_part_names.remove(&ir_part_str));
+        if let Some(voices) = select_voices(&xml_part_tag.unwrap(), options.voice_limit) {
+            warn!(
+                "Part {} has more than {} voices, keeping voices {:?} and dropping the notes of the others",
+                ir_part_str,
+                MusicalPart::MAX_SUPPORTED_VOICES,
+                voices
+            );
+            ir_musical_part.keep_voices(voices);
+        }
+
+        let tab_staves = find_tab_staves(&xml_part_tag.unwrap());
+        if !tab_staves.is_empty() && options.tab_staves == TabStaffPolicy::Drop {
+            info!("Part {} dropping the notes of tablature staves {:?}", ir_part_str, tab_staves.keys());
+        }
 
         let xml_measures = xml_part_tag
             .unwrap()
             .children()
-            .filter(|n| n.has_tag_name("measure"));
+            .filter(|n| n.has_tag_name("measure"))
+            .collect::<Vec<_>>();
+
+        // Each individual part duplicates the divisions entry, usually but not always in its first measure
+        let (xml_attributes_idx, quarter_division) = find_initial_attributes(ir_part_str.as_str(), &xml_measures)?;
+        ir_musical_part.set_initial_divisions(quarter_division);
 
-        for (xml_measure_idx, xml_measure) in xml_measures.enumerate() {
+        // Number of measures back a measure repeat copies from, while inside a measure repeat
+        let mut measures_repeated: Option<usize> = None;
+        for (xml_measure_idx, xml_measure) in xml_measures.iter().enumerate() {
             // if dump_input {
             //     debug!("Measure_idx {measure_idx} start");
             // }
@@ -63,20 +92,16 @@ pub fn xml_to_ir(docstring: String, _dump_input: bool) -> Result<PartMap> {
             let mut ir_measure_meta_start = MeasureMetaData::new(MeasureStartEnd::MeasureStart);
             let mut ir_measure_meta_end = MeasureMetaData::new(MeasureStartEnd::MeasureEnd);
 
-            // Each individual part duplicates the divisions entry at measure idx 0 (usually, but not always measure number 1)
-            let mut quarter_division = 0;
-            if xml_measure_idx == 0 {
-                if let Some(div) = xml_measure.descendants().find(|n| n.has_tag_name("divisions")) {
-                    quarter_division = div.text().unwrap().parse::<u32>().unwrap();
-                } else {
-                    panic!("No divisions tag found.");
-                }
-            }
-            ir_musical_part.set_initial_divisions(quarter_division);
+            // Measures before the part's first attributes, such as an empty pickup, are read with them
+            let xml_attributes_measure = if xml_measure_idx < xml_attributes_idx {
+                &xml_measures[xml_attributes_idx]
+            } else {
+                xml_measure
+            };
 
             // TODO: All of this XML parsing logic should be abstracted away another data type with methods
             // that can be re-used across xml2bin and xml multipart
-            if let Some(xml_time_tag) = xml_measure.descendants().find(|n| n.has_tag_name("time")) {
+            if let Some(xml_time_tag) = xml_attributes_measure.descendants().find(|n| n.has_tag_name("time")) {
                 let xml_beats_tag = xml_time_tag.children().find(|n| n.has_tag_name("beats")).unwrap();
                 let xml_beat_type_tag = xml_time_tag.children().find(|n| n.has_tag_name("beat-type")).unwrap();
 
@@ -85,10 +110,12 @@ pub fn xml_to_ir(docstring: String, _dump_input: bool) -> Result<PartMap> {
                     BeatType::from_str(xml_beat_type_tag.text().unwrap()).unwrap();
             };
 
-            if let Some(xml_repeat_tag) = xml_measure.descendants().find(|n| n.has_tag_name("repeat")) {
+            // A measure may both start and end a repeat
+            for xml_repeat_tag in xml_measure.descendants().filter(|n| n.has_tag_name("repeat")) {
                 let measure_direction_str = xml_repeat_tag.attribute("direction").unwrap();
                 if measure_direction_str.eq("backward") {
                     ir_measure_meta_end.start_end = MeasureStartEnd::RepeatEnd;
+                    ir_measure_meta_end.repeat_times = parse_repeat_times(&xml_repeat_tag);
                 } else if measure_direction_str.eq("forward") {
                     ir_measure_meta_start.start_end = MeasureStartEnd::RepeatStart;
                 } else {
@@ -98,8 +125,9 @@ pub fn xml_to_ir(docstring: String, _dump_input: bool) -> Result<PartMap> {
                         measure_direction_str
                     );
                 }
-            };
+            }
 
+            ir_measure_meta_end.bar_style = parse_bar_style(xml_measure);
             let xml_barlines = xml_measure.descendants().filter(|n| n.has_tag_name("barline"));
             for xml_barline in xml_barlines {
                 if let Some(xml_ending_tag) = xml_barline.descendants().find(|n| n.has_tag_name("ending")) {
@@ -127,7 +155,7 @@ pub fn xml_to_ir(docstring: String, _dump_input: bool) -> Result<PartMap> {
                 };
             }
 
-            if let Some(ir_key_sig) = match xml_measure.descendants().find(|n| n.has_tag_name("fifths")) {
+            if let Some(ir_key_sig) = match xml_attributes_measure.descendants().find(|n| n.has_tag_name("fifths")) {
                 Some(xml_fifths_tag) => KeySignature::from_str(xml_fifths_tag.text().unwrap()).ok(),
                 None => None,
             } {
@@ -138,10 +166,22 @@ pub fn xml_to_ir(docstring: String, _dump_input: bool) -> Result<PartMap> {
                 .descendants()
                 .find(|n| n.has_tag_name("sound") && n.attribute("tempo").is_some())
             {
-                Some(n) => Tempo::from_str(n.attribute("tempo").unwrap()).ok(),
+                Some(n) => n
+                    .attribute("tempo")
+                    .unwrap()
+                    .parse::<i32>()
+                    .ok()
+                    .map(|bpm| Tempo::new_rounded(bpm, options.tempo_rounding)),
                 None => None,
             } {
                 ir_measure_init.tempo = xml_tempo;
+            } else if let Some(descriptive_tempo) = xml_measure
+                .descendants()
+                .filter(|n| n.has_tag_name("words"))
+                .find_map(|n| n.text().and_then(DescriptiveTempo::from_words))
+            {
+                // Scores giving only a textual marking such as "Andante" fall back to a representative BPM
+                ir_measure_init.tempo = Tempo::new_rounded(descriptive_tempo.to_bpm(), options.tempo_rounding);
             }
 
             if ir_musical_part.get_cur_init_measure_idx().is_none() {
@@ -160,21 +200,84 @@ pub fn xml_to_ir(docstring: String, _dump_input: bool) -> Result<PartMap> {
                 forward_duration = duration_tag.parse::<usize>().unwrap();
             }
 
+            let measure_styles = parse_measure_style_tag(xml_measure);
+            let mut multiple_rest = None;
+            for measure_style in measure_styles {
+                match measure_style {
+                    MeasureStyle::MultipleRest(num_measures) => multiple_rest = Some(num_measures),
+                    MeasureStyle::MeasureRepeatStart(num_measures) => measures_repeated = Some(num_measures),
+                    MeasureStyle::MeasureRepeatStop => measures_repeated = None,
+                }
+            }
+
+            if let Some(measure_number) = parse_measure_number(xml_measure) {
+                let measure_idx = ir_musical_part.num_measures();
+                ir_musical_part.set_measure_number(measure_idx, measure_number);
+            }
+            if let (true, Some(layout_break)) = (options.preserve_layout, parse_print_tag(xml_measure)) {
+                let measure_idx = ir_musical_part.num_measures();
+                ir_musical_part.set_layout_break(measure_idx, layout_break);
+            }
             ir_musical_part.push_meta_start(ir_measure_meta_start, forward_duration, xml_measure_idx);
 
-            let xml_measure_elements = xml_measure.children().filter(|n| {
-                n.has_tag_name("note") || n.has_tag_name("direction") || n.has_tag_name("backup")
-            });
+            // The notes written in a repeated measure are placeholders, so the referenced measure is copied instead
+            if let Some(measures_back) = measures_repeated {
+                match ir_musical_part.previous_measure_elems(measures_back) {
+                    Some(repeated_elems) => {
+                        for elem in repeated_elems {
+                            ir_musical_part.push_measure_elem(elem);
+                        }
+                    }
+                    None => warn!(
+                        "{}M{} repeats {} measures back, before the start of the part.",
+                        ir_part_str, xml_measure_idx, measures_back
+                    ),
+                }
+            }
+
+            let xml_measure_elements = xml_measure
+                .children()
+                .filter(|n| {
+                    n.has_tag_name("note")
+                        || n.has_tag_name("direction")
+                        || n.has_tag_name("backup")
+                        || n.has_tag_name("harmony")
+                        || n.has_tag_name("attributes")
+                })
+                .filter(|_| measures_repeated.is_none());
+            // The backup following dropped tablature notes rewinds over them only
+            let mut after_dropped_tab = false;
             for xml_measure_element in xml_measure_elements {
+                let tab_tuning = match xml_measure_element.tag_name().name() {
+                    "note" => tab_staves.get(&note_staff(&xml_measure_element)),
+                    _ => None,
+                };
+                if tab_tuning.is_some() && options.tab_staves == TabStaffPolicy::Drop {
+                    after_dropped_tab = true;
+                    continue;
+                }
+                if xml_measure_element.tag_name().name() == "backup" && after_dropped_tab {
+                    after_dropped_tab = false;
+                    continue;
+                }
+                after_dropped_tab = false;
                 if xml_measure_element.tag_name().name() == "note" {
-                    // If a measure contains percussive (unpitched) content,
-                    // throw this entire part away because we do not analyze drum content
-                    if !does_note_contain_unpitched(&xml_measure_element) {
+                    // If a measure contains percussive (unpitched) content, throw this entire part away
+                    // because we do not analyze drum content, unless percussion parts are kept
+                    if options.keep_percussion || !does_note_contain_unpitched(&xml_measure_element) {
                         parse_note_tag(
                             &xml_measure_element,
                             &mut ir_musical_part,
-                        );
+                            options,
+                            tab_tuning,
+                        )?;
                     } else {
+                        if options.strict {
+                            return Err(Error::StrictViolation(
+                                ir_part_str,
+                                "contains unpitched (percussion) notes".to_string(),
+                            ));
+                        }
                         remove_cur_part = true;
                         break;
                     }
@@ -182,6 +285,10 @@ pub fn xml_to_ir(docstring: String, _dump_input: bool) -> Result<PartMap> {
                     parse_direction_tag(&xml_measure_element, &mut ir_musical_part);
                 } else if xml_measure_element.tag_name().name() == "backup" {
                     parse_backup_tag(&xml_measure_element, &mut ir_musical_part);
+                } else if xml_measure_element.tag_name().name() == "harmony" {
+                    parse_harmony_tag(&xml_measure_element, &mut ir_musical_part, options)?;
+                } else if xml_measure_element.tag_name().name() == "attributes" {
+                    parse_attributes_tag(&xml_measure_element, &mut ir_musical_part);
                 }
             }
             if !remove_cur_part {
@@ -189,6 +296,24 @@ pub fn xml_to_ir(docstring: String, _dump_input: bool) -> Result<PartMap> {
             } else {
                 break;
             }
+
+            if let Some(num_measures) = multiple_rest {
+                // Exporters either keep the measures covered by the multiple rest, each holding a rest,
+                // or omit them. Only the omitted ones are inserted, as empty measures filled with a rest.
+                let covered_measures = xml_measures[xml_measure_idx + 1..]
+                    .iter()
+                    .take(num_measures.saturating_sub(1))
+                    .take_while(|m| is_rest_only_measure(m))
+                    .count();
+                for _ in covered_measures..num_measures.saturating_sub(1) {
+                    ir_musical_part.push_meta_start(
+                        MeasureMetaData::new(MeasureStartEnd::MeasureStart),
+                        0,
+                        xml_measure_idx,
+                    );
+                    ir_musical_part.push_meta_end(MeasureMetaData::new(MeasureStartEnd::MeasureEnd));
+                }
+            }
         } // Process next measure in part
         // let voice_cnt = if voices.len() > MeasureChecker::MAX_SUPPORTED_VOICES {
         //     info!(
@@ -202,13 +327,26 @@ pub fn xml_to_ir(docstring: String, _dump_input: bool) -> Result<PartMap> {
         //     voices.len()
         // };
         // ir_musical_part.set_num_voices(voice_cnt);
+        let declares_key = xml_measures
+            .iter()
+            .any(|m| m.descendants().any(|n| n.has_tag_name("fifths")));
+        if options.estimate_key && !declares_key {
+            if let Some(key_sig) = ir_musical_part.apply_estimated_key() {
+                info!("{} declares no key, using the estimated {}", ir_part_str, key_sig.major_name());
+            }
+        }
+        ir_musical_part.check_ties(options.tie_policy);
+        ir_musical_part.omit_derived_staves();
+        if options.lint {
+            ir_musical_part.lint();
+        }
         total_voices += ir_musical_part.get_num_voices();
         if !remove_cur_part {
             ir_part_map
                 .push_part(ir_part_str.as_str(), ir_musical_part)
                 .expect("Failed t push musical part to part map");
         } else {
-            println!("Remove part {}", ir_part_str);
+            info!("Remove part {}", ir_part_str);
             ir_part_map.remove_part(ir_part_str.as_str());
             remove_cur_part = false;
         }
@@ -219,10 +357,418 @@ pub fn xml_to_ir(docstring: String, _dump_input: bool) -> Result<PartMap> {
         // );
     } // Process next part or loop completed
     if ir_part_map.num_part_ids() == MAX_SUPPORTED_PARTS {
-        println!("Total voices is {}", total_voices);
+        info!("Total voices is {}", total_voices);
     }
     // At this point, any vec_idx that is still None in the parts list can be discarded from the BTreeMap
     let parts_removed = ir_part_map.get_removed_parts();
-    println!("Processing step removed {} parts", parts_removed);
+    info!("Processing step removed {} parts", parts_removed);
     Ok(ir_part_map)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::xml_to_ir;
+    use crate::error::Error;
+    use crate::bin_format::round_trip;
+    use crate::ir::notation::{
+        DescriptiveTempo, MeasureMetaData, MeasureStartEnd, MusicElement, NoteConnection, NoteData,
+        NoteSize, NumericPitchRest, PhraseDynamics, RhythmType, SpecialNote, Voice,
+    };
+    use crate::ir::ir_to_xml::ir_to_xml;
+    use crate::ir::{ExportOptions, MeasureCorrection, ParseOptions, TabStaffPolicy, TiePolicy, VoiceLimitPolicy};
+    use std::fs;
+
+    #[test]
+    fn test_words_only_tempo_falls_back_to_descriptive_bpm() {
+        let docstring = fs::read_to_string("test/vivace_words_tempo.musicxml").unwrap();
+        let part_map = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        let tempo = part_map
+            .get_part(0)
+            .unwrap()
+            .inner()
+            .iter()
+            .find_map(|e| match e {
+                MusicElement::MeasureInit(m) => Some(m.tempo),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(DescriptiveTempo::from(tempo), DescriptiveTempo::Vivace);
+        assert!((151..=176).contains(&tempo.get_actual()));
+    }
+
+    #[test]
+    fn test_empty_measure_becomes_whole_rest() {
+        let docstring = fs::read_to_string("test/empty_measure.musicxml").unwrap();
+        let part_map = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        let elems = part_map.get_part(0).unwrap().inner();
+        let measure_starts: Vec<usize> = elems
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| {
+                matches!(
+                    e,
+                    MusicElement::MeasureMeta(MeasureMetaData {
+                        start_end: MeasureStartEnd::MeasureStart,
+                        ..
+                    })
+                )
+            })
+            .map(|(idx, _)| idx)
+            .collect();
+        assert_eq!(measure_starts.len(), 3);
+        let second_measure = &elems[measure_starts[1] + 1..measure_starts[2] - 1];
+        assert_eq!(
+            second_measure,
+            &[MusicElement::NoteRest(NoteData::new_default_rest(
+                RhythmType::SemiBreve,
+                false,
+                Voice::One
+            ))]
+        );
+    }
+
+    #[test]
+    fn test_empty_measure_in_five_four_becomes_measure_rest() {
+        let docstring = fs::read_to_string("test/empty_measure_5_4.musicxml").unwrap();
+        let part_map = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        let rest = NoteData::new_default_rest(RhythmType::SemiBreve, false, Voice::One);
+        assert!(part_map.get_part(0).unwrap().inner().contains(&MusicElement::NoteRest(rest)));
+        assert_eq!(rest.get_duration_numeric(1, 5, 4, None), 5);
+
+        let xml = ir_to_xml(part_map, &ExportOptions::default()).unwrap();
+        let doc = roxmltree::Document::parse(&xml).unwrap();
+        let second_measure = doc.descendants().filter(|n| n.has_tag_name("measure")).nth(1).unwrap();
+        let notes: Vec<_> = second_measure.children().filter(|n| n.has_tag_name("note")).collect();
+        assert_eq!(notes.len(), 1);
+        let rest_tag = notes[0].children().find(|n| n.has_tag_name("rest")).unwrap();
+        assert_eq!(rest_tag.attribute("measure"), Some("yes"));
+        let duration = notes[0].children().find(|n| n.has_tag_name("duration")).and_then(|n| n.text());
+        let divisions = doc.descendants().find(|n| n.has_tag_name("divisions")).and_then(|n| n.text());
+        assert_eq!(
+            duration.unwrap().parse::<u32>().unwrap(),
+            5 * divisions.unwrap().parse::<u32>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_measure_rest_fills_compound_meter_bar() {
+        // Without the measure attribute, the 6/8 rest duration was inferred as a dotted half rest
+        let docstring = fs::read_to_string("test/measure_rest_6_8.musicxml").unwrap();
+        let part_map = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        let part = part_map.get_part(0).unwrap();
+        let measure_rest = NoteData::new_default_rest(RhythmType::SemiBreve, false, Voice::One);
+        assert_eq!(
+            part.previous_measure_elems(2).unwrap(),
+            vec![MusicElement::NoteRest(measure_rest)]
+        );
+        assert_eq!(measure_rest.get_duration_numeric(2, 6, 8, None), 6);
+    }
+
+    #[test]
+    fn test_multirest_and_measure_repeat_are_expanded() {
+        let docstring = fs::read_to_string("test/multirest.musicxml").unwrap();
+        let part_map = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        let part = part_map.get_part(0).unwrap();
+        let measures: Vec<Vec<MusicElement>> = (1..=part.num_measures())
+            .rev()
+            .map(|measures_back| part.previous_measure_elems(measures_back).unwrap())
+            .collect();
+        assert_eq!(measures.len(), 7);
+        // The measure repeat copies the four quarter notes of the first measure
+        assert_eq!(measures[1], measures[0]);
+        assert_eq!(measures[1].len(), 4);
+        let whole_rest = vec![MusicElement::NoteRest(NoteData::new_default_rest(
+            RhythmType::SemiBreve,
+            false,
+            Voice::One,
+        ))];
+        for measure in &measures[2..6] {
+            assert_eq!(measure, &whole_rest);
+        }
+        assert_ne!(measures[6], whole_rest);
+    }
+
+    #[test]
+    fn test_unterminated_tie_is_reported_and_fixed() {
+        let tie_starts = |tie_policy| {
+            let docstring = fs::read_to_string("test/unterminated_tie.musicxml").unwrap();
+            let options = ParseOptions {
+                tie_policy,
+                ..ParseOptions::default()
+            };
+            let part_map = xml_to_ir(docstring, false, &options).unwrap();
+            let part = part_map.get_part(0).unwrap();
+            assert_eq!(part.get_dangling_ties(), 1);
+            part.inner()
+                .iter()
+                .filter(|e| matches!(e, MusicElement::NoteRest(n) if n.ties == NoteConnection::StartTie))
+                .count()
+        };
+        assert_eq!(tie_starts(TiePolicy::Warn), 2);
+        // Only the C tie is removed; the E tie is stopped in the next measure
+        assert_eq!(tie_starts(TiePolicy::Fix), 1);
+    }
+
+    #[test]
+    fn test_overshooting_backup_is_ignored() {
+        let docstring = fs::read_to_string("test/overshooting_backup.musicxml").unwrap();
+        let part_map = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        let part = part_map.get_part(0).unwrap();
+        // The forward offset and the short third voice are padded with rests, the overshooting backup adds none
+        assert_eq!(part.get_discrepancies(), 2);
+        assert_eq!(
+            part.inner()
+                .iter()
+                .filter(|e| matches!(e, MusicElement::NoteRest(n) if n.note_rest == NumericPitchRest::Rest))
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_short_voice_correction_is_reported() {
+        let docstring = fs::read_to_string("test/incomplete_voice.musicxml").unwrap();
+        let part_map = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        let part = part_map.get_part(0).unwrap();
+        // The second voice holds a half note against the first voice's four quarter notes
+        assert_eq!(
+            part.get_corrections(),
+            &[MeasureCorrection {
+                measure_idx: 0,
+                voice: Voice::Two,
+                discrepancy: 2,
+                rests: vec![(RhythmType::Minim, false)],
+            }]
+        );
+        assert_eq!(
+            part.get_corrections()[0].to_string(),
+            "measure 1 voice 2: 2 divisions short, inserted half rest"
+        );
+        assert_eq!(part.get_discrepancies(), 1);
+    }
+
+    #[test]
+    fn test_zero_duration_note_is_reported_and_removed() {
+        let docstring = fs::read_to_string("test/coarse_divisions.musicxml").unwrap();
+        let part_map = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        let part = part_map.get_part(0).unwrap();
+        // A sixteenth cannot be represented with one division per quarter note
+        assert_eq!(part.get_zero_duration_notes(), 1);
+        assert!(part
+            .inner()
+            .iter()
+            .all(|e| !matches!(e, MusicElement::NoteRest(n) if n.note_type == RhythmType::SemiQuaver)));
+        assert_eq!(part.num_measures(), 2);
+    }
+
+    #[test]
+    fn test_sound_dynamics_maps_to_nearest_marking() {
+        let docstring = fs::read_to_string("test/sound_dynamics.musicxml").unwrap();
+        let part_map = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        let dynamics: Vec<PhraseDynamics> = part_map
+            .get_part(0)
+            .unwrap()
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some(n.phrase_dynamics),
+                _ => None,
+            })
+            .collect();
+        // 106.67% of the forte velocity is read as forte, unless a dynamics marking is also present
+        assert_eq!(dynamics, vec![PhraseDynamics::Forte, PhraseDynamics::Pianissimo]);
+    }
+
+    #[test]
+    fn test_grace_note_without_slash_is_kept() {
+        let docstring = fs::read_to_string("test/unslashed_grace.musicxml").unwrap();
+        let part_map = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        let notes: Vec<(NumericPitchRest, SpecialNote)> = part_map
+            .get_part(0)
+            .unwrap()
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some((n.note_rest, n.special_note)),
+                _ => None,
+            })
+            .collect();
+        let pitch = |name: &str| name.parse::<NumericPitchRest>().unwrap();
+        assert_eq!(
+            notes,
+            vec![(pitch("D5"), SpecialNote::Appogiatura), (pitch("C5"), SpecialNote::None)]
+        );
+    }
+
+    #[test]
+    fn test_cue_note_without_duration_is_kept() {
+        let docstring = fs::read_to_string("test/durationless_cue.musicxml").unwrap();
+        let part_map = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        let part = part_map.get_part(0).unwrap();
+        let sizes: Vec<NoteSize> = part
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some(n.note_size),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            sizes,
+            vec![NoteSize::Full, NoteSize::Cue, NoteSize::Full, NoteSize::Full, NoteSize::Full]
+        );
+        // The cue note takes no time, so the measure still holds four quarter notes
+        assert_eq!(part.get_discrepancies(), 0);
+        assert_eq!(part.num_measures(), 1);
+    }
+
+    #[test]
+    fn test_voice_limit_policy_selects_kept_voices() {
+        // Voices 1 to 5 hold 4, 1, 4, 2 and 4 notes on the pitches C5, D5, E4, F4 and G3
+        let kept_pitches = |voice_limit: VoiceLimitPolicy| {
+            let docstring = fs::read_to_string("test/five_voices.musicxml").unwrap();
+            let options = ParseOptions {
+                voice_limit,
+                ..ParseOptions::default()
+            };
+            let part_map = xml_to_ir(docstring, false, &options).unwrap();
+            let part = part_map.get_part(0).unwrap();
+            let mut pitches: Vec<(Voice, String)> = part
+                .inner()
+                .iter()
+                .filter_map(|e| match e {
+                    MusicElement::NoteRest(n) if n.note_rest != NumericPitchRest::Rest => {
+                        Some((n.voice, n.note_rest.to_string()))
+                    }
+                    _ => None,
+                })
+                .collect();
+            pitches.dedup();
+            (pitches, part.get_dropped_notes())
+        };
+        let expected = |voices: &[(Voice, &str)]| voices.iter().map(|(v, p)| (*v, p.to_string())).collect::<Vec<_>>();
+
+        assert_eq!(
+            kept_pitches(VoiceLimitPolicy::Lowest),
+            (
+                expected(&[(Voice::One, "C5"), (Voice::Two, "D5"), (Voice::Three, "E4"), (Voice::Four, "F4")]),
+                4
+            )
+        );
+        // Voice 2 has the fewest notes, and the later voices move up in its place
+        assert_eq!(
+            kept_pitches(VoiceLimitPolicy::MostNotes),
+            (
+                expected(&[(Voice::One, "C5"), (Voice::Two, "E4"), (Voice::Three, "F4"), (Voice::Four, "G3")]),
+                1
+            )
+        );
+    }
+
+    #[test]
+    fn test_voice_index_is_kept_when_a_lower_voice_appears_later() {
+        // Voice 2 holds every E4 and is alone in the first measure; voice 1 only joins in the second
+        let docstring = fs::read_to_string("test/late_lower_voice.musicxml").unwrap();
+        let part_map = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        let part = part_map.get_part(0).unwrap();
+        let voices: Vec<(String, Voice)> = part
+            .inner()
+            .iter()
+            .filter_map(|e| match e {
+                MusicElement::NoteRest(n) => Some((n.note_rest.to_string(), n.voice)),
+                _ => None,
+            })
+            .collect();
+        let e4 = || ("E4".to_string(), Voice::One);
+        let c5 = || ("C5".to_string(), Voice::Two);
+        assert_eq!(voices, vec![e4(), e4(), e4(), e4(), e4(), c5(), c5(), c5(), c5()]);
+        assert_eq!(part.get_num_voices(), 2);
+    }
+
+    #[test]
+    fn test_strict_mode_rejects_a_fifth_voice_the_default_drops() {
+        let docstring = fs::read_to_string("test/five_voices.musicxml").unwrap();
+        let strict = ParseOptions {
+            strict: true,
+            ..ParseOptions::default()
+        };
+        match xml_to_ir(docstring.clone(), false, &strict) {
+            Err(Error::StrictViolation(part, reason)) => {
+                assert_eq!(part, "P1");
+                assert!(reason.contains("voice 5"), "{}", reason);
+            }
+            Err(e) => panic!("expected a strict violation, got {:?}", e),
+            Ok(_) => panic!("expected a strict violation, but the part was converted"),
+        }
+
+        let part_map = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        let part = part_map.get_part(0).unwrap();
+        assert_eq!(part.get_num_voices(), 4);
+        assert_eq!(part.get_dropped_notes(), 4);
+        assert!(part.inner().iter().all(|e| match e {
+            MusicElement::NoteRest(n) => n.note_rest.to_string() != "G3",
+            _ => true,
+        }));
+    }
+
+    #[test]
+    fn test_pitched_staff_of_a_tablature_part_is_kept() {
+        // A guitar part writes the same notes on a pitched staff and, in voice 5, on a tablature staff
+        let notes = |tab_staves: TabStaffPolicy| {
+            let docstring = fs::read_to_string("test/guitar_tab.musicxml").unwrap();
+            let options = ParseOptions {
+                tab_staves,
+                ..ParseOptions::default()
+            };
+            let part_map = xml_to_ir(docstring, false, &options).unwrap();
+            let part = part_map.get_part(0).unwrap();
+            assert_eq!(part.get_discrepancies(), 0);
+            part.inner()
+                .iter()
+                .filter_map(|e| match e {
+                    MusicElement::NoteRest(n) => Some((n.voice, n.note_rest.to_string())),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let pitched = ["E3", "G3", "B3"].map(|p| (Voice::One, p.to_string()));
+        let tab = ["E3", "G3", "B3"].map(|p| (Voice::Two, p.to_string()));
+
+        let kept = notes(TabStaffPolicy::Drop);
+        assert_eq!(kept[..3], pitched);
+        assert_eq!(kept[3..], [(Voice::One, "C4".to_string())]);
+
+        // Converted, the frets sound the pitches written on the pitched staff
+        let converted = notes(TabStaffPolicy::Convert);
+        assert_eq!(converted[..3], pitched);
+        assert_eq!(converted[3..6], tab);
+        assert_eq!(
+            converted[6..],
+            [(Voice::One, "C4".to_string()), (Voice::Two, "C4".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_provenance_of_second_note_of_third_measure() {
+        let docstring = fs::read_to_string("test/moonlight.musicxml").unwrap();
+        let part_map = xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+        let part = part_map.get_part(0).unwrap();
+        let provenance = part.provenance().unwrap();
+        assert_eq!(provenance.len(), part.inner().len());
+
+        // Measure 3 opens with triplet eighths A3 C4 E4, so C4 starts a third of the way into the first beat
+        let (note, source) = part
+            .inner()
+            .iter()
+            .zip(&provenance)
+            .filter(|(e, p)| matches!(e, MusicElement::NoteRest(_)) && p.measure == 2)
+            .nth(1)
+            .unwrap();
+        assert!(matches!(note, MusicElement::NoteRest(n) if n.note_rest.to_string() == "C4"));
+        assert!((source.beat - 4.0 / 3.0).abs() < 1e-4, "{}", source);
+
+        // Decoded parts do not know their source measures
+        let decoded = round_trip(part).unwrap();
+        assert_eq!(decoded.provenance(), None);
+    }
+}
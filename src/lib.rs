@@ -0,0 +1,17 @@
+//! Converts MusicXML to and from a condensed bin format for training transformer models. The
+//! in-memory conversions of [`convert`] need neither a filesystem nor a clock, so built with
+//! `--no-default-features` the library also targets `wasm32-unknown-unknown`.
+#![allow(dead_code)]
+pub mod bin_format;
+#[cfg(feature = "cli")]
+pub mod cli_handlers;
+pub mod convert;
+pub mod error;
+pub mod ir;
+#[cfg(feature = "cli")]
+pub mod repl_funcs;
+#[cfg(feature = "cli")]
+pub mod report;
+pub mod utils;
+
+pub use convert::{bin_bytes_to_xml_string, xml_string_to_bin_bytes};
@@ -1,19 +1,25 @@
 #![allow(dead_code)]
-mod bin_format;
-mod cli_handlers;
-mod error;
-mod ir;
-mod repl_funcs;
-mod utils;
+// The library's test helpers are not built for the binary's tests, so the logger is compiled in here too
+#[cfg(test)]
+#[path = "utils/capture_log.rs"]
+mod capture_log;
 
-use crate::error::{Result,Error};
+use music2bin::error::{Result,Error};
+use music2bin::ir::notation::{BeatType, Beats, Clef, NumericPitchRest, PitchNotation, PitchSpelling, TempoRounding, TuningTable};
+use music2bin::ir::{
+    DumpFormat, DumpOptions, DUMP_TARGET, ExportOptions, MeasureNumbering, OctaveShiftPolicy, ParseOptions, PartLimitPolicy, TabStaffPolicy, TiePolicy, VoiceLimitPolicy,
+};
 
-use cli_handlers::{
-    process_bin_to_xml, process_end_to_end, process_multipartxml_to_bin, process_xml_multi, process_xml_to_bin, repl_shell
+use music2bin::cli_handlers::{
+    process_augment, process_batch, process_bin_to_xml, process_canonicalize, process_concat, process_count, process_csv_to_bin, process_end_to_end, process_features, process_filter_range, process_inspect_bits, process_multipartxml_to_bin, process_octave_shift, process_report_unsupported, process_respell, process_sample, process_script, process_split, process_stats, process_transpose, process_validate, process_xml_multi, process_xml_to_bin, repl_shell, RangePolicy
 };
 use env_logger::Env;
-use log::LevelFilter;
+use music2bin::report::{ColorChoice, ConversionReport};
+use music2bin::utils::{encoding_date, workers};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::env;
 use std::path::PathBuf;
+use std::time::SystemTime;
 use structopt::StructOpt;
 
 #[derive(Debug, Clone, PartialEq, StructOpt)]
@@ -27,10 +33,148 @@ enum Mode {
     XmlMulti,
     #[structopt(name = "e2e")]
     End2End,
+    /// Converts a MusicXML file to a bin file in canonical form, so musically equal scores encode identically
+    #[structopt(name = "canonicalize")]
+    Canonicalize,
+    /// Interactive shell, or runs the shell commands in a script file when one is given
     #[structopt(name = "shell")]
-    Shell,
+    Shell {
+        /// File of shell commands, one per line. Relative paths are resolved against the script's directory.
+        #[structopt(long = "script", parse(from_os_str))]
+        script: Option<PathBuf>,
+        /// Keep running the script after a command fails
+        #[structopt(long = "continue-on-error")]
+        continue_on_error: bool,
+    },
     #[structopt(name = "multipartxml2bin")]
     MultiPartXml2Bin,
+    /// Converts every MusicXML file in the input directory to a bin file in the output directory
+    #[structopt(name = "batch")]
+    Batch {
+        /// Number of files converted at once. Defaults to the number of logical CPUs.
+        #[structopt(long = "threads")]
+        threads: Option<usize>,
+        /// Convert every file, including those whose bin output is newer than the MusicXML source
+        #[structopt(long = "force")]
+        force: bool,
+    },
+    /// Transposes the pitches, key signatures and chord symbols of a bin file
+    #[structopt(name = "transpose")]
+    Transpose {
+        /// Number of semitones to transpose by, negative to transpose down
+        #[structopt(long = "semitones", allow_hyphen_values = true)]
+        semitones: i8,
+    },
+    /// Writes a copy of a bin file with every pitch moved up or down by whole octaves, keeping the key signatures
+    #[structopt(name = "octave-shift")]
+    OctaveShift {
+        /// Number of octaves to shift by, negative to shift down
+        #[structopt(long = "by", allow_hyphen_values = true)]
+        by: i8,
+        /// Replace notes that would leave the C0 to C8 range with rests, instead of shifting them by fewer octaves
+        #[structopt(long = "drop")]
+        drop: bool,
+    },
+    /// Writes a copy of a bin file with its tempos and note dynamics slightly perturbed, for training augmentation
+    #[structopt(name = "augment")]
+    Augment {
+        /// Seed of the perturbation, so an augmented copy can be reproduced
+        #[structopt(long = "seed-dither", default_value = "0")]
+        seed: u64,
+        /// Largest change of each tempo marking, in BPM
+        #[structopt(long = "tempo-range", default_value = "4")]
+        tempo_range: u8,
+    },
+    /// Writes each part of a multi-part MusicXML input to its own bin file in the output directory
+    #[structopt(name = "split")]
+    Split,
+    /// Parses the MusicXML input into the intermediate representation and reports on it without writing output
+    #[structopt(name = "validate")]
+    Validate,
+    /// Appends the element streams of several bin files into a single bin file
+    #[structopt(name = "concat")]
+    Concat {
+        #[structopt(parse(from_os_str), required = true)]
+        inputs: Vec<PathBuf>,
+        /// Number the measures of every file from its own first measure, instead of on from the file before it
+        #[structopt(long = "reset-measure-numbers")]
+        reset_measure_numbers: bool,
+    },
+    /// Copies a key signature balanced subset of the bin files in the input directory to the output directory
+    #[structopt(name = "sample")]
+    Sample {
+        /// Number of files to select per key signature. Defaults to the size of the rarest key.
+        #[structopt(long = "per-key")]
+        per_key: Option<usize>,
+        /// Seed for the random selection, so a subset can be reproduced
+        #[structopt(long = "seed", default_value = "0")]
+        seed: u64,
+    },
+    /// Copies the bin files of the input directory whose notes all lie within a pitch range to the output directory
+    #[structopt(name = "filter-range")]
+    FilterRange {
+        /// Lowest accepted pitch, such as "C2"
+        #[structopt(long = "min")]
+        min: NumericPitchRest,
+        /// Highest accepted pitch, such as "C6"
+        #[structopt(long = "max")]
+        max: NumericPitchRest,
+        /// Either "drop" to leave out files with notes outside the range, or "error" to stop on them
+        #[structopt(long = "policy", default_value = "drop")]
+        policy: RangePolicy,
+    },
+    /// Exports a bin file to MusicXML, spelling black key pitches with sharps, flats or as the key signature does
+    #[structopt(name = "respell")]
+    Respell {
+        /// One of "sharp", "flat" or "key"
+        #[structopt(long = "spelling", default_value = "sharp")]
+        spelling: PitchSpelling,
+    },
+    /// Prints the note density and rhythmic complexity of each part of a MusicXML or bin input
+    #[structopt(name = "stats")]
+    Stats {
+        /// Print the stats as JSON
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    /// Lists the constructs of a MusicXML input, or of each MusicXML file of an input directory, that are dropped when converting
+    #[structopt(name = "report-unsupported")]
+    ReportUnsupported,
+    /// Prints each bit field of one element of a bin input with its offset, width, raw value and meaning
+    #[structopt(name = "inspect-bits")]
+    InspectBits {
+        /// Zero based position of the element, not counting the header
+        #[structopt(long = "index")]
+        index: usize,
+    },
+    /// Prints how many elements, measures, notes and rests a bin input holds, without decoding it fully
+    #[structopt(name = "count")]
+    Count,
+    /// Encodes a CSV melody of pitch,octave,type,dotted,voice rows, such as "F#,4,quarter,false,1", to a bin file
+    #[structopt(name = "csv2bin")]
+    Csv2Bin {
+        /// Beats per measure
+        #[structopt(long = "beats", default_value = "4")]
+        beats: Beats,
+        /// Note value of one beat
+        #[structopt(long = "beat-type", default_value = "4")]
+        beat_type: BeatType,
+    },
+    /// Writes a fixed width numeric record of pitch, duration, voice, dynamics, articulation, ties,
+    /// onset, MIDI velocity and MIDI pitch bend for every note of a bin file, for loading as a tensor
+    #[structopt(name = "features")]
+    Features {
+        /// Write a NumPy .npy array instead of flat little endian u32 records
+        #[structopt(long = "npy")]
+        npy: bool,
+        /// Write out repeats, endings and D.C./D.S. jumps in performance order before extracting the records
+        #[structopt(long = "unfold")]
+        unfold: bool,
+        /// Twelve comma separated cents offsets from equal temperament, from C upwards, for the pitch
+        /// bends. Equal temperament when not given.
+        #[structopt(long = "tuning")]
+        tuning: Option<TuningTable>,
+    },
 }
 
 #[derive(Debug, Clone, StructOpt)]
@@ -39,57 +183,432 @@ enum Mode {
     about = "An application for transforming MusicXML files into a condensed binary format targeted at ML training."
 )]
 struct CliOpts {
-    #[structopt(
-        short = "i",
-        long = "input",
-        default_value = "frelise.musicxml",
-        parse(from_os_str)
-    )]
-    input: PathBuf,
-    #[structopt(
-        short = "o",
-        long = "output",
-        default_value = "music.bin",
-        parse(from_os_str)
-    )]
-    output: PathBuf,
+    /// Input file or directory. Required by every mode except shell and concat.
+    #[structopt(short = "i", long = "input", parse(from_os_str))]
+    input: Option<PathBuf>,
+    /// Output file or directory. Required by every mode except shell, validate and stats.
+    #[structopt(short = "o", long = "output", parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Print every element to standard output as it is encoded or decoded, with the source measure and beat
+    /// of elements read from MusicXML. Printed whatever the log level.
     #[structopt(short = "d", long = "dump")]
     dump_input: bool,
+    /// Names pitches in the --dump output and the shell as letters (C4), solfege (do) or MIDI numbers (60)
+    #[structopt(long = "pitch-notation", default_value = "letter")]
+    pitch_notation: PitchNotation,
+    /// Layout of the --dump output: debug (one compact line per element), table (aligned columns) or json (JSON lines)
+    #[structopt(long = "dump-format", default_value = "debug")]
+    dump_format: DumpFormat,
+    /// Overrides the <encoding-date> written to exported MusicXML (YYYY-MM-DD). Defaults to the current UTC date.
+    #[structopt(long = "encoding-date")]
+    encoding_date: Option<String>,
+    /// Fail instead of silently dropping parts or voices that cannot be represented in the output, or
+    /// exporting measures whose voices do not add up to the time signature
+    #[structopt(long = "strict")]
+    strict: bool,
+    /// Remove redundant elements, such as repeated measure initializers, from the bin output
+    #[structopt(long = "optimize")]
+    optimize: bool,
+    /// Remove ties that are started but never stopped, instead of only reporting them
+    #[structopt(long = "fix-ties")]
+    fix_ties: bool,
+    /// Keep percussion parts, storing their unpitched notes with a General MIDI drum sound, instead of dropping them
+    #[structopt(long = "keep-percussion")]
+    keep_percussion: bool,
+    /// Estimate the key from the notes of parts that declare no key signature, instead of assuming C major
+    #[structopt(long = "estimate-key")]
+    estimate_key: bool,
+    /// Check that repeats and endings are balanced in every part, warning about the offending measures
+    #[structopt(long = "lint")]
+    lint: bool,
+    /// Read fractional alters, such as quarter tones, as a deviation in cents from the nearest semitone
+    /// instead of failing on them
+    #[structopt(long = "microtonal")]
+    microtonal: bool,
+    /// Most parts a multi-part score may have
+    #[structopt(long = "max-parts", default_value = "4")]
+    max_parts: usize,
+    /// Keep the first --max-parts parts of a score with more parts, instead of failing
+    #[structopt(long = "truncate-parts")]
+    truncate_parts: bool,
+    /// Keep the system and page breaks of MusicXML input, storing them in the bin metadata section so they
+    /// are restored on export
+    #[structopt(long = "preserve-layout")]
+    preserve_layout: bool,
+    /// Keep the voices with the most notes from a part with more than four voices, instead of the lowest
+    /// numbered ones
+    #[structopt(long = "keep-busiest-voices")]
+    keep_busiest_voices: bool,
+    /// Keep the notes of tablature staves, reading their pitches from the string and fret where none is
+    /// written, instead of keeping only the pitched staves of a part
+    #[structopt(long = "convert-tab")]
+    convert_tab: bool,
+    /// How tempos between two of the stored tempos, which are two BPM apart, are rounded: "floor" or "nearest"
+    #[structopt(long = "tempo-rounding", default_value = "floor")]
+    tempo_rounding: TempoRounding,
+    /// Start a new system every N measures in exported MusicXML
+    #[structopt(long = "measures-per-system")]
+    measures_per_system: Option<usize>,
+    /// Divisions per quarter note of exported MusicXML durations, such as 480. Must be a multiple of the
+    /// divisions the shortest notes need.
+    #[structopt(long = "output-divisions")]
+    output_divisions: Option<u32>,
+    /// Clef of each staff in exported MusicXML, such as "treble" or "treble,bass". Defaults to the clefs
+    /// suited to each part's instrument name.
+    #[structopt(long = "clef", use_delimiter = true)]
+    clef: Vec<Clef>,
+    /// Serialize the parts of multi-part scores to MusicXML concurrently
+    #[structopt(long = "parallel")]
+    parallel: bool,
+    /// Color the end of run summary of conversions: "auto" when printing to a terminal, "always" or "never"
+    #[structopt(long = "color", default_value = "auto")]
+    color: ColorChoice,
+    /// Only print errors. Ignored when RUST_LOG is set.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+    /// Print debug messages, or trace messages when given twice. Ignored when RUST_LOG is set.
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    verbose: u8,
+    /// Reject bin files whose header declares more elements than this, instead of decoding them
+    #[structopt(long = "max-elements", default_value = "5000000")]
+    max_elements: usize,
+    /// Memory map bin files when converting them to MusicXML, instead of reading them into memory.
+    /// Nothing may modify or truncate the input file while it is being converted.
+    #[structopt(long = "mmap")]
+    mmap: bool,
     #[structopt(subcommand)]
     mode: Option<Mode>,
 }
 
-fn main() -> Result<()> {
-    let mut builder = env_logger::Builder::from_env(Env::default());
+impl CliOpts {
+    /// The --input and --output paths, failing with a usage error when the mode needs one that was not
+    /// given. A path the mode does not use is left empty.
+    fn paths(&self) -> Result<(PathBuf, PathBuf)> {
+        let (needs_input, needs_output) = match self.mode {
+            None | Some(Mode::Shell { .. }) => (false, false),
+            Some(Mode::Concat { .. }) => (false, true),
+            Some(Mode::Validate)
+            | Some(Mode::Stats { .. })
+            | Some(Mode::ReportUnsupported)
+            | Some(Mode::InspectBits { .. })
+            | Some(Mode::Count) => (true, false),
+            Some(_) => (true, true),
+        };
+        let required = |path: &Option<PathBuf>, needed: bool, flag: &'static str| match path {
+            Some(path) => Ok(path.clone()),
+            None if needed => Err(Error::MissingArgument(flag)),
+            None => Ok(PathBuf::new()),
+        };
+        Ok((
+            required(&self.input, needs_input, "--input")?,
+            required(&self.output, needs_output, "--output")?,
+        ))
+    }
+}
 
-    builder
-        .filter(Some("repl_funcs"), LevelFilter::Info)
-        .filter(Some("cli_handlers"), LevelFilter::Info)
-        .init();
+/// Log level of this crate's messages selected by the --quiet and --verbose flags
+fn log_level(cli_opt: &CliOpts) -> LevelFilter {
+    match (cli_opt.quiet, cli_opt.verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    }
+}
+
+/// Logger of the command line tool. The `--dump` lines are written to standard output as they are,
+/// everything else is left to env_logger.
+struct CliLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for CliLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target() == DUMP_TARGET || self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.target() == DUMP_TARGET {
+            println!("{}", record.args());
+        } else {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
 
+/// Installs the logger. --quiet and --verbose set this crate's log level unless RUST_LOG is set, which
+/// then decides on its own.
+fn init_logger(cli_opt: &CliOpts) {
+    let mut builder = env_logger::Builder::from_env(Env::default());
+    if env::var_os("RUST_LOG").is_none() {
+        builder.filter_module(env!("CARGO_PKG_NAME"), log_level(cli_opt));
+    }
+    let inner = builder.build();
+    // Dump lines are logged at info level, and written even when the filter would drop them
+    let max_level = inner.filter().max(LevelFilter::Info);
+    log::set_boxed_logger(Box::new(CliLogger { inner })).expect("the logger is installed once");
+    log::set_max_level(max_level);
+}
+
+fn main() {
     let cli_opt = CliOpts::from_args();
 
+    init_logger(&cli_opt);
+
+    let (input, output) = match cli_opt.paths() {
+        Ok(paths) => paths,
+        Err(e) => {
+            // Returning the error would have it printed a second time, as its Debug form
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let encoding_date = match encoding_date::resolve(cli_opt.encoding_date.as_deref(), SystemTime::now) {
+        Some(date) => date,
+        None => {
+            eprintln!(
+                "Encoding date {} is not in YYYY-MM-DD format.",
+                cli_opt.encoding_date.as_deref().unwrap_or_default()
+            );
+            std::process::exit(2);
+        }
+    };
+
+    let parse_options = ParseOptions {
+        strict: cli_opt.strict,
+        optimize: cli_opt.optimize,
+        tie_policy: if cli_opt.fix_ties {
+            TiePolicy::Fix
+        } else {
+            TiePolicy::Warn
+        },
+        keep_percussion: cli_opt.keep_percussion,
+        estimate_key: cli_opt.estimate_key,
+        lint: cli_opt.lint,
+        microtonal: cli_opt.microtonal,
+        max_parts: cli_opt.max_parts,
+        part_limit: if cli_opt.truncate_parts {
+            PartLimitPolicy::Truncate
+        } else {
+            PartLimitPolicy::Error
+        },
+        voice_limit: if cli_opt.keep_busiest_voices {
+            VoiceLimitPolicy::MostNotes
+        } else {
+            VoiceLimitPolicy::Lowest
+        },
+        preserve_layout: cli_opt.preserve_layout,
+        tab_staves: if cli_opt.convert_tab {
+            TabStaffPolicy::Convert
+        } else {
+            TabStaffPolicy::Drop
+        },
+        tempo_rounding: cli_opt.tempo_rounding,
+    };
+    let export_options = ExportOptions {
+        encoding_date: Some(encoding_date),
+        measures_per_system: cli_opt.measures_per_system,
+        clefs: if cli_opt.clef.is_empty() {
+            None
+        } else {
+            Some(cli_opt.clef.clone())
+        },
+        spelling: PitchSpelling::default(),
+        output_divisions: cli_opt.output_divisions,
+        strict: cli_opt.strict,
+        parallel: cli_opt.parallel,
+    };
+
+    let dump_options = cli_opt
+        .dump_input
+        .then_some(DumpOptions::new(cli_opt.pitch_notation, cli_opt.dump_format));
+    let color = cli_opt.color.enabled();
+    let print_report = |report: ConversionReport| {
+        if !cli_opt.quiet {
+            println!("{}", report.render(color));
+        }
+    };
     let result: Result<()> = match cli_opt.mode {
         Some(Mode::End2End) => {
-            process_end_to_end(&cli_opt.input, &cli_opt.output, cli_opt.dump_input)
+            process_end_to_end(
+                &input,
+                &output,
+                dump_options,
+                &export_options,
+                &parse_options,
+            )
+            .map(print_report)
         }
         Some(Mode::Bin2Xml) => {
-            process_bin_to_xml(&cli_opt.input, &cli_opt.output, cli_opt.dump_input)
+            process_bin_to_xml(
+                &input,
+                &output,
+                dump_options,
+                &export_options,
+                cli_opt.max_elements,
+                cli_opt.mmap,
+            )
+            .map(print_report)
         }
         Some(Mode::XmlMulti) => {
-            process_xml_multi(&cli_opt.input, &cli_opt.output, cli_opt.dump_input)
+            process_xml_multi(
+                &input,
+                &output,
+                cli_opt.dump_input,
+                &export_options,
+                &parse_options,
+            )
+            .map(print_report)
         }
         Some(Mode::Xml2Bin) => {
-            process_xml_to_bin(&cli_opt.input, &cli_opt.output, cli_opt.dump_input)
+            process_xml_to_bin(&input, &output, dump_options, &parse_options).map(print_report)
         }
-        Some(Mode::Shell) => {
-            match repl_shell() {
+        Some(Mode::Canonicalize) => {
+            process_canonicalize(&input, &output, dump_options, &parse_options).map(print_report)
+        }
+        Some(Mode::Shell {
+            script: Some(ref script),
+            continue_on_error,
+        }) => process_script(script, continue_on_error, cli_opt.pitch_notation),
+        Some(Mode::Shell { script: None, .. }) => {
+            match repl_shell(cli_opt.pitch_notation) {
                 Ok(_) => Ok(()),
                 Err(err) => Err(Error::from(err)),
             }
         }
         Some(Mode::MultiPartXml2Bin) => {
-            process_multipartxml_to_bin(&cli_opt.input, &cli_opt.output, cli_opt.dump_input)
+            process_multipartxml_to_bin(
+                &input,
+                &output,
+                dump_options,
+                &parse_options,
+            )
+            .map(print_report)
+        }
+        Some(Mode::Batch { threads, force }) => {
+            process_batch(
+                &input,
+                &output,
+                threads.unwrap_or_else(workers::default_threads),
+                dump_options,
+                &parse_options,
+                force,
+            )
+            .map(|_| ())
+        }
+        Some(Mode::Transpose { semitones }) => {
+            process_transpose(
+                &input,
+                &output,
+                semitones,
+                dump_options,
+                cli_opt.max_elements,
+            )
+        }
+        Some(Mode::OctaveShift { by, drop }) => process_octave_shift(
+            &input,
+            &output,
+            by,
+            if drop {
+                OctaveShiftPolicy::Drop
+            } else {
+                OctaveShiftPolicy::Clamp
+            },
+            dump_options,
+            cli_opt.max_elements,
+        ),
+        Some(Mode::Augment { seed, tempo_range }) => process_augment(
+            &input,
+            &output,
+            seed,
+            tempo_range,
+            dump_options,
+            cli_opt.max_elements,
+        ),
+        Some(Mode::Split) => {
+            process_split(&input, &output, dump_options, &parse_options)
+                .map(|_| ())
+        }
+        Some(Mode::Validate) => {
+            process_validate(&input, cli_opt.dump_input, &parse_options)
+        }
+        Some(Mode::Sample { per_key, seed }) => {
+            process_sample(
+                &input,
+                &output,
+                per_key,
+                seed,
+                dump_options,
+                cli_opt.max_elements,
+            )
+        }
+        Some(Mode::FilterRange { min, max, policy }) => {
+            process_filter_range(
+                &input,
+                &output,
+                min,
+                max,
+                policy,
+                dump_options,
+                cli_opt.max_elements,
+            )
+            .map(|_| ())
+        }
+        Some(Mode::Respell { spelling }) => {
+            process_respell(
+                &input,
+                &output,
+                spelling,
+                dump_options,
+                &export_options,
+                cli_opt.max_elements,
+            )
+            .map(print_report)
+        }
+        Some(Mode::Csv2Bin { beats, beat_type }) => {
+            process_csv_to_bin(&input, &output, beats, beat_type, dump_options)
+        }
+        Some(Mode::Features { npy, unfold, tuning }) => process_features(
+            &input,
+            &output,
+            npy,
+            unfold,
+            &tuning.unwrap_or_default(),
+            dump_options,
+            cli_opt.max_elements,
+        ),
+        Some(Mode::Stats { json }) => {
+            process_stats(
+                &input,
+                json,
+                dump_options,
+                &parse_options,
+                cli_opt.max_elements,
+            )
+        }
+        Some(Mode::ReportUnsupported) => process_report_unsupported(&input, &parse_options).map(|_| ()),
+        Some(Mode::InspectBits { index }) => process_inspect_bits(&input, index),
+        Some(Mode::Count) => process_count(&input, cli_opt.max_elements),
+        Some(Mode::Concat {
+            ref inputs,
+            reset_measure_numbers,
+        }) => {
+            process_concat(
+                inputs,
+                &output,
+                dump_options,
+                if reset_measure_numbers {
+                    MeasureNumbering::Reset
+                } else {
+                    MeasureNumbering::Continue
+                },
+                cli_opt.max_elements,
+            )
         }
         None => {
             println!("No command mode provided.");
@@ -97,5 +616,51 @@ fn main() -> Result<()> {
         }
     };
 
-    Ok(())
+    if let Err(e) = &result {
+        // Printed once here rather than returned, which would print it again in its Debug form
+        eprintln!("{}", ConversionReport::render_error(&input, e, color));
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{log_level, CliOpts};
+    use crate::capture_log;
+    use music2bin::error::Error;
+    use music2bin::ir::{xml_to_ir, ParseOptions};
+    use std::fs;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_quiet_suppresses_discrepancy_messages() {
+        capture_log::init();
+        let discrepancy_messages = |args: &[&str]| {
+            log::set_max_level(log_level(&CliOpts::from_iter(args)));
+            capture_log::clear();
+            let docstring = fs::read_to_string("test/overshooting_backup.musicxml").unwrap();
+            xml_to_ir(docstring, false, &ParseOptions::default()).unwrap();
+            capture_log::messages()
+                .iter()
+                .filter(|m| m.contains("discrepancy"))
+                .count()
+        };
+        assert_eq!(discrepancy_messages(&["music2bin", "-q"]), 0);
+        assert!(discrepancy_messages(&["music2bin"]) >= 2);
+    }
+
+    #[test]
+    fn test_missing_input_is_a_usage_error() {
+        let paths = |args: &[&str]| CliOpts::from_iter(args).paths();
+        let err = paths(&["music2bin", "xml2bin", "-o", "out.bin"]).unwrap_err();
+        assert_eq!(err, Error::MissingArgument("--input"));
+        assert_eq!(err.to_string(), "Missing required argument --input");
+        assert_eq!(
+            paths(&["music2bin", "-i", "in.musicxml", "xml2bin"]).unwrap_err(),
+            Error::MissingArgument("--output")
+        );
+        // Modes that only read their input need no output
+        assert!(paths(&["music2bin", "-i", "in.musicxml", "validate"]).is_ok());
+        assert!(paths(&["music2bin", "shell"]).is_ok());
+    }
 }
@@ -1,41 +1,266 @@
-use crate::error::Result;
+use crate::bin_format::bin_to_ir;
+use crate::cli_handlers::write_bin_file;
+use crate::error::{Error, Result};
+use crate::ir::notation::PitchNotation;
+use crate::ir::{xml_to_ir, MusicalPart, ParseOptions};
 
-use repl_rs::Convert;
+use log::{error, warn};
 use repl_rs::Value;
 use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
 
 #[derive(Default)]
 pub struct Context {
     list: VecDeque<String>,
+    // Part loaded by the load command
+    part: Option<MusicalPart>,
+    // Directory relative file paths are resolved against
+    base_dir: PathBuf,
+    // Naming of pitches in the dump output
+    pitch_notation: PitchNotation,
 }
 
-// Append name to list
-pub fn append(args: HashMap<String, Value>, context: &mut Context) -> Result<Option<String>> {
-    let name: String = args["name"].convert()?;
+impl Context {
+    pub fn new(pitch_notation: PitchNotation) -> Context {
+        Context {
+            pitch_notation,
+            ..Context::default()
+        }
+    }
+
+    pub fn with_base_dir(base_dir: &Path, pitch_notation: PitchNotation) -> Context {
+        Context {
+            base_dir: base_dir.to_path_buf(),
+            pitch_notation,
+            ..Context::default()
+        }
+    }
+
+    fn resolve(&self, path: &str) -> PathBuf {
+        self.base_dir.join(path)
+    }
+}
+
+fn append_name(name: String, context: &mut Context) -> Result<Option<String>> {
     context.list.push_back(name);
     let list: Vec<String> = context.list.clone().into();
 
     Ok(Some(list.join(", ")))
 }
 
-// Prepend name to list
-pub fn prepend(args: HashMap<String, Value>, context: &mut Context) -> Result<Option<String>> {
-    let name: String = args["name"].convert()?;
+fn prepend_name(name: String, context: &mut Context) -> Result<Option<String>> {
     context.list.push_front(name);
     let list: Vec<String> = context.list.clone().into();
 
     Ok(Some(list.join(", ")))
 }
 
-// Add two numbers.
-pub fn add<T>(args: HashMap<String, Value>, _context: &mut T) -> Result<Option<String>> {
-    let first: i32 = args["first"].convert()?;
-    let second: i32 = args["second"].convert()?;
+// Loads a bin file, or the first part of a MusicXML file
+fn load_part(path: &str, context: &mut Context) -> Result<Option<String>> {
+    let path = context.resolve(path);
+    let part = if path.extension().is_some_and(|ext| ext == "bin") {
+        let infile = File::open(&path)?;
+        bin_to_ir(BufReader::new(infile), None)?
+    } else {
+        let docstring = fs::read_to_string(&path)?;
+        let partmap = xml_to_ir(docstring, false, &ParseOptions::default())?;
+        partmap.get_part(0).ok_or(Error::NotInitialized)?.clone()
+    };
+    let loaded = format!("Loaded {} elements from {}", part.len(), path.display());
+    context.part = Some(part);
+    Ok(Some(loaded))
+}
+
+fn dump_part(context: &mut Context) -> Result<Option<String>> {
+    let part = context.part.as_ref().ok_or(Error::NotInitialized)?;
+    let lines: Vec<String> = part
+        .inner()
+        .iter()
+        .map(|e| e.display_with(context.pitch_notation).to_string())
+        .collect();
+    Ok(Some(lines.join("\n")))
+}
+
+fn save_part(path: &str, context: &mut Context) -> Result<Option<String>> {
+    let part = context.part.as_ref().ok_or(Error::NotInitialized)?;
+    let path = context.resolve(path);
+    write_bin_file(&path, part, None)?;
+    Ok(Some(format!(
+        "Saved {} elements to {}",
+        part.len(),
+        path.display()
+    )))
+}
+
+/// A command of the interactive shell. Scripts run the same commands, giving the parameters in order.
+pub struct ShellCommand {
+    pub name: &'static str,
+    /// Required parameters, in the order a script line gives them
+    pub params: &'static [&'static str],
+    pub help: &'static str,
+    // Runs the command on its arguments, one for each parameter
+    run: fn(&[&str], &mut Context) -> Result<Option<String>>,
+    /// Callback registered with the shell, which passes the arguments by parameter name
+    pub shell: fn(HashMap<String, Value>, &mut Context) -> Result<Option<String>>,
+}
+
+/// Every command, for both the shell and scripts
+pub const COMMANDS: &[ShellCommand] = &[
+    ShellCommand {
+        name: "append",
+        params: &["name"],
+        help: "Append name to end of list",
+        run: |args, context| append_name(args[0].to_string(), context),
+        shell: |args, context| run_named("append", args, context),
+    },
+    ShellCommand {
+        name: "prepend",
+        params: &["name"],
+        help: "Prepend name to front of list",
+        run: |args, context| prepend_name(args[0].to_string(), context),
+        shell: |args, context| run_named("prepend", args, context),
+    },
+    ShellCommand {
+        name: "add",
+        params: &["first", "second"],
+        help: "Add two numbers together",
+        run: |args, _context| Ok(Some((args[0].parse::<i32>()? + args[1].parse::<i32>()?).to_string())),
+        shell: |args, context| run_named("add", args, context),
+    },
+    ShellCommand {
+        name: "hello",
+        params: &["who"],
+        help: "Greetings!",
+        run: |args, _context| Ok(Some(format!("Hello, {}", args[0]))),
+        shell: |args, context| run_named("hello", args, context),
+    },
+    ShellCommand {
+        name: "load",
+        params: &["path"],
+        help: "Load a bin or MusicXML file",
+        run: |args, context| load_part(args[0], context),
+        shell: |args, context| run_named("load", args, context),
+    },
+    ShellCommand {
+        name: "dump",
+        params: &[],
+        help: "Print the elements of the loaded file",
+        run: |_args, context| dump_part(context),
+        shell: |args, context| run_named("dump", args, context),
+    },
+    ShellCommand {
+        name: "save",
+        params: &["path"],
+        help: "Write the loaded file in bin format",
+        run: |args, context| save_part(args[0], context),
+        shell: |args, context| run_named("save", args, context),
+    },
+];
+
+// Runs a command with the arguments the shell collected by parameter name
+fn run_named(name: &str, args: HashMap<String, Value>, context: &mut Context) -> Result<Option<String>> {
+    let command = COMMANDS
+        .iter()
+        .find(|c| c.name == name)
+        .ok_or_else(|| Error::UnknownCommand(name.to_string()))?;
+    let args: Vec<String> = command.params.iter().map(|param| args[*param].to_string()).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    (command.run)(&args, context)
+}
+
+/// Runs a single command line, with the same commands the interactive shell provides
+pub fn dispatch(line: &str, context: &mut Context) -> Result<Option<String>> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let Some((name, args)) = tokens.split_first() else {
+        return Ok(None);
+    };
+    match COMMANDS.iter().find(|c| c.name == *name && c.params.len() == args.len()) {
+        Some(command) => (command.run)(args, context),
+        None => Err(Error::UnknownCommand(line.to_string())),
+    }
+}
 
-    Ok(Some((first + second).to_string()))
+/// Runs every line of a script through `dispatch`, skipping blank lines and `#` comments.
+/// Relative paths are resolved against the script's directory.
+pub fn run_script(script: &Path, continue_on_error: bool, pitch_notation: PitchNotation) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(script)?;
+    let mut context = Context::with_base_dir(script.parent().unwrap_or(Path::new("")), pitch_notation);
+    let mut outputs = vec![];
+    for (line_idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match dispatch(line, &mut context) {
+            Ok(Some(output)) => outputs.push(output),
+            Ok(None) => {}
+            Err(e) if continue_on_error => {
+                warn!(
+                    "{}:{} {} failed: {}",
+                    script.display(),
+                    line_idx + 1,
+                    line,
+                    e
+                );
+            }
+            Err(e) => {
+                error!("{}:{} {} failed", script.display(), line_idx + 1, line);
+                return Err(e);
+            }
+        }
+    }
+    Ok(outputs)
 }
 
-// Write "Hello"
-pub fn hello<T>(args: HashMap<String, Value>, _context: &mut T) -> Result<Option<String>> {
-    Ok(Some(format!("Hello, {}", args["who"])))
+#[cfg(test)]
+mod tests {
+    use super::{dispatch, run_script, Context, COMMANDS};
+    use crate::error::Error;
+    use crate::ir::notation::PitchNotation;
+    use std::collections::BTreeSet;
+    use std::path::Path;
+
+    #[test]
+    fn test_dispatch_runs_the_shell_commands() {
+        let names: BTreeSet<&str> = COMMANDS.iter().map(|c| c.name).collect();
+        assert_eq!(names.len(), COMMANDS.len());
+
+        let mut context = Context::new(PitchNotation::Letter);
+        assert_eq!(dispatch("add 2 3", &mut context), Ok(Some("5".to_string())));
+        assert_eq!(dispatch("hello you", &mut context), Ok(Some("Hello, you".to_string())));
+        assert_eq!(dispatch("append a", &mut context), Ok(Some("a".to_string())));
+        assert_eq!(dispatch("prepend b", &mut context), Ok(Some("b, a".to_string())));
+        assert_eq!(dispatch("   ", &mut context), Ok(None));
+        // A command given the wrong number of arguments is not run
+        assert_eq!(
+            dispatch("add 2", &mut context),
+            Err(Error::UnknownCommand("add 2".to_string()))
+        );
+        assert_eq!(dispatch("dump", &mut context), Err(Error::NotInitialized));
+    }
+
+    #[test]
+    fn test_script_loads_and_dumps_fixture() {
+        let outputs = run_script(Path::new("test/load_and_dump.script"), false, PitchNotation::Letter).unwrap();
+        assert_eq!(outputs.len(), 2);
+        assert!(outputs[0].starts_with("Loaded"));
+        assert!(outputs[1].lines().any(|l| l == "|start|"));
+        assert_eq!(
+            outputs[1]
+                .lines()
+                .filter(|l| l.ends_with("quarter v1"))
+                .count(),
+            4
+        );
+
+        assert!(run_script(Path::new("test/missing_file.script"), false, PitchNotation::Letter).is_err());
+        assert_eq!(
+            run_script(Path::new("test/missing_file.script"), true, PitchNotation::Letter)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
 }
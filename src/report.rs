@@ -0,0 +1,153 @@
+//! End of run summary of a conversion, printed with optional ANSI colors so dropped parts and
+//! errors stand out when running interactively.
+
+use crate::error::{Error, Result};
+use crate::ir::PartMap;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+/// When the summary is colored
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Only when standard output is a terminal
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<ColorChoice> {
+        match input {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(Error::Parse),
+        }
+    }
+}
+
+impl ColorChoice {
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => std::io::stdout().is_terminal(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+fn paint(text: &str, color: &str, enabled: bool) -> String {
+    if enabled {
+        format!("{}{}{}", color, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+/// What a conversion kept, dropped and wrote
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConversionReport {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    /// Ids of the parts written to the output
+    pub parts_kept: Vec<String>,
+    /// Ids of the parts left out, such as percussion parts
+    pub parts_dropped: Vec<String>,
+    pub warnings: Vec<String>,
+    /// Size of the written output in bytes
+    pub output_bytes: u64,
+    /// Time spent serializing the parts to MusicXML, for comparing serial and `--parallel` export
+    pub export_time: Option<Duration>,
+}
+
+impl ConversionReport {
+    pub fn new(input: &Path, output: &Path) -> ConversionReport {
+        ConversionReport {
+            input: input.to_path_buf(),
+            output: output.to_path_buf(),
+            ..ConversionReport::default()
+        }
+    }
+
+    /// Records the parts of a parsed score, with a warning for every dropped part, every part
+    /// that needed repairs while parsing and every lint issue
+    pub fn add_parts(&mut self, partmap: &PartMap) {
+        for (part_id, part_idx) in partmap.get_part_ids() {
+            match part_idx.and_then(|idx| partmap.get_part(idx)) {
+                Some(part) => {
+                    let repairs = [
+                        (part.get_discrepancies(), "measure discrepancies"),
+                        (part.get_dropped_notes(), "dropped notes"),
+                        (part.get_zero_duration_notes(), "zero duration notes"),
+                        (part.get_dangling_ties(), "dangling ties"),
+                    ];
+                    for (count, what) in repairs.iter().filter(|(count, _)| *count > 0) {
+                        self.warnings.push(format!("Part {}: {} {}", part_id, count, what));
+                    }
+                    for issue in part.get_lint_issues() {
+                        self.warnings.push(format!("Part {}: {}", part_id, issue));
+                    }
+                    self.parts_kept.push(part_id);
+                }
+                None => {
+                    self.warnings.push(format!("Part {} was dropped", part_id));
+                    self.parts_dropped.push(part_id);
+                }
+            }
+        }
+    }
+
+    /// Reads the size of the output file once it is written
+    pub fn record_output_size(&mut self) {
+        self.output_bytes = fs::metadata(&self.output).map_or(0, |m| m.len());
+    }
+
+    /// Multi-line summary, with the status and warnings colored when `color` is set
+    pub fn render(&self, color: bool) -> String {
+        let status = if self.warnings.is_empty() {
+            paint("OK", GREEN, color)
+        } else {
+            paint("WARN", YELLOW, color)
+        };
+        let mut lines = vec![format!(
+            "{} {} -> {} ({} bytes)",
+            status,
+            self.input.display(),
+            self.output.display(),
+            self.output_bytes
+        )];
+        if !self.parts_kept.is_empty() || !self.parts_dropped.is_empty() {
+            lines.push(format!(
+                "  {} parts kept, {} dropped",
+                self.parts_kept.len(),
+                self.parts_dropped.len()
+            ));
+        }
+        if let Some(export_time) = self.export_time {
+            lines.push(format!(
+                "  exported {} parts in {:.1} ms",
+                self.parts_kept.len(),
+                export_time.as_secs_f64() * 1000.0
+            ));
+        }
+        for warning in &self.warnings {
+            lines.push(format!("  {}", paint(warning, YELLOW, color)));
+        }
+        lines.join("\n")
+    }
+
+    /// One line summary of a conversion that failed
+    pub fn render_error(input: &Path, error: &Error, color: bool) -> String {
+        format!("{} {}: {}", paint("ERROR", RED, color), input.display(), error)
+    }
+}
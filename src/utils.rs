@@ -25,3 +25,223 @@ pub mod into_option {
         }
     }
 }
+
+pub mod encoding_date {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const SECONDS_PER_DAY: u64 = 86_400;
+
+    /// Returns the current UTC date formatted as `YYYY-MM-DD`
+    pub fn today_utc() -> String {
+        utc_date(SystemTime::now())
+    }
+
+    /// Returns the UTC date of `time` formatted as `YYYY-MM-DD`
+    pub fn utc_date(time: SystemTime) -> String {
+        let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        from_unix_seconds(secs)
+    }
+
+    /// Picks the date written as `<encoding-date>`: the `--encoding-date` override when there is one,
+    /// otherwise the UTC date of the time read from `clock`. Returns None for a malformed override.
+    pub fn resolve(date_override: Option<&str>, clock: impl FnOnce() -> SystemTime) -> Option<String> {
+        match date_override {
+            Some(date) => is_valid(date).then(|| date.to_string()),
+            None => Some(utc_date(clock())),
+        }
+    }
+
+    /// Formats a UNIX timestamp (in seconds) as a `YYYY-MM-DD` UTC date string
+    pub fn from_unix_seconds(secs: u64) -> String {
+        // Civil-from-days conversion, counting eras of 400 years starting from 0000-03-01
+        let days = (secs / SECONDS_PER_DAY) as i64;
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        format!("{:04}-{:02}-{:02}", year, month, day)
+    }
+
+    /// Checks that the date string is in the `YYYY-MM-DD` form expected by `<encoding-date>`
+    pub fn is_valid(date: &str) -> bool {
+        let fields: Vec<&str> = date.split('-').collect();
+        if fields.len() != 3 || fields[0].len() != 4 || fields[1].len() != 2 || fields[2].len() != 2 {
+            return false;
+        }
+        match (
+            fields[0].parse::<u32>(),
+            fields[1].parse::<u32>(),
+            fields[2].parse::<u32>(),
+        ) {
+            (Ok(year), Ok(month), Ok(day)) => {
+                (1..=12).contains(&month) && (1..=days_in_month(year, month)).contains(&day)
+            }
+            _ => false,
+        }
+    }
+
+    /// Number of days in the month of the proleptic Gregorian calendar
+    fn days_in_month(year: u32, month: u32) -> u32 {
+        let is_leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+        match month {
+            2 if is_leap_year => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+}
+
+pub mod sampling {
+    use std::collections::BTreeMap;
+
+    /// SplitMix64 pseudo random generator, small enough to keep sampled subsets reproducible
+    /// from a seed without pulling in an external crate
+    pub struct SplitMix64(u64);
+
+    impl SplitMix64 {
+        pub fn new(seed: u64) -> SplitMix64 {
+            SplitMix64(seed)
+        }
+
+        pub fn next_u64(&mut self) -> u64 {
+            self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = self.0;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            z ^ (z >> 31)
+        }
+
+        /// Returns a value in `0..bound`
+        pub fn below(&mut self, bound: usize) -> usize {
+            (self.next_u64() % bound as u64) as usize
+        }
+    }
+
+    /// Fisher-Yates shuffle
+    pub fn shuffle<T>(items: &mut [T], rng: &mut SplitMix64) {
+        for idx in (1..items.len()).rev() {
+            let swap_idx = rng.below(idx + 1);
+            items.swap(idx, swap_idx);
+        }
+    }
+
+    /// Picks up to `per_class` items at random from every class, approximating a uniform
+    /// distribution over the classes. Without `per_class`, the size of the smallest class is used
+    /// so the result is exactly uniform. Classes smaller than the target contribute everything they have.
+    pub fn select_uniform<K: Ord, T>(
+        classes: BTreeMap<K, Vec<T>>,
+        per_class: Option<usize>,
+        seed: u64,
+    ) -> BTreeMap<K, Vec<T>> {
+        let target = per_class.unwrap_or_else(|| classes.values().map(|v| v.len()).min().unwrap_or(0));
+        let mut rng = SplitMix64::new(seed);
+        classes
+            .into_iter()
+            .map(|(class, mut items)| {
+                shuffle(&mut items, &mut rng);
+                items.truncate(target);
+                (class, items)
+            })
+            .collect()
+    }
+}
+
+pub mod workers {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::thread;
+
+    /// Number of logical CPUs, the default worker count of batch jobs
+    pub fn default_threads() -> usize {
+        thread::available_parallelism().map_or(1, |n| n.get())
+    }
+
+    /// Runs `job` over every item on at most `threads` worker threads. Items are taken in order, and
+    /// the results are returned with the index of their item in the order they completed, so a single
+    /// thread completes them in item order.
+    pub fn run<T, R, F>(items: &[T], threads: usize, job: F) -> Vec<(usize, R)>
+    where
+        T: Sync,
+        R: Send,
+        F: Fn(&T) -> R + Sync,
+    {
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(Vec::with_capacity(items.len()));
+        thread::scope(|scope| {
+            for _ in 0..threads.clamp(1, items.len().max(1)) {
+                scope.spawn(|| loop {
+                    let idx = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(idx) else {
+                        break;
+                    };
+                    let result = job(item);
+                    results.lock().unwrap().push((idx, result));
+                });
+            }
+        });
+        results.into_inner().unwrap()
+    }
+}
+
+#[cfg(test)]
+pub mod capture_log;
+
+/// Path in the temporary directory for a test's scratch file or directory, kept apart per process
+/// so concurrent test runs don't clobber each other
+#[cfg(test)]
+pub fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("music2bin_{}_{}", std::process::id(), name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::encoding_date::{from_unix_seconds, is_valid, resolve, today_utc};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const DAY: u64 = 86_400;
+
+    #[test]
+    fn test_encoding_date_override_wins_over_the_clock() {
+        let clock = || UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(resolve(None, clock), Some("2023-11-14".to_string()));
+        assert_eq!(resolve(Some("2001-02-03"), clock), Some("2001-02-03".to_string()));
+        assert_eq!(resolve(Some("2001-2-3"), clock), None);
+    }
+
+    #[test]
+    fn test_from_unix_seconds_across_leap_days() {
+        assert_eq!(from_unix_seconds(0), "1970-01-01");
+        assert_eq!(from_unix_seconds(DAY - 1), "1970-01-01");
+        // 2000 is a leap year as a multiple of 400, 2100 is not as a multiple of 100
+        assert_eq!(from_unix_seconds(951_782_400), "2000-02-29");
+        assert_eq!(from_unix_seconds(951_782_400 + DAY), "2000-03-01");
+        assert_eq!(from_unix_seconds(1_709_164_800), "2024-02-29");
+        assert_eq!(from_unix_seconds(1_709_164_800 + DAY), "2024-03-01");
+        assert_eq!(from_unix_seconds(1_677_542_400 + DAY), "2023-03-01");
+        assert_eq!(from_unix_seconds(4_107_456_000), "2100-02-28");
+        assert_eq!(from_unix_seconds(4_107_456_000 + DAY), "2100-03-01");
+        assert_eq!(from_unix_seconds(1_704_067_199), "2023-12-31");
+    }
+
+    #[test]
+    fn test_is_valid_encoding_date() {
+        assert!(is_valid("2023-11-22"));
+        assert!(is_valid(&today_utc()));
+        for date in ["2024-02-29", "2000-02-29", "2023-04-30", "2023-12-31"] {
+            assert!(is_valid(date), "{} should be accepted", date);
+        }
+        for date in ["2023-11-2", "23-11-22", "2023/11/22", "2023-13-01", "2023-00-10", "2023-11-32", "2023-1a-22", ""] {
+            assert!(!is_valid(date), "{} should be rejected", date);
+        }
+        // Days past the end of the month, including February of non-leap years
+        for date in ["2023-02-31", "2024-02-30", "2023-02-29", "1900-02-29", "2023-04-31", "2023-11-31"] {
+            assert!(!is_valid(date), "{} should be rejected", date);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! Logger capturing the messages of each test thread, so tests can assert on what was logged.
+//! Only one logger can be installed per process, so every test shares this one.
+
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::Mutex;
+use std::thread::{self, ThreadId};
+
+static MESSAGES: Mutex<Vec<(ThreadId, String)>> = Mutex::new(vec![]);
+
+struct CapturingLogger;
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            MESSAGES
+                .lock()
+                .unwrap()
+                .push((thread::current().id(), record.args().to_string()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger;
+
+/// Installs the logger, letting at least errors through
+pub fn init() {
+    // Fails when another test already installed it
+    let _ = log::set_logger(&LOGGER);
+    if log::max_level() < LevelFilter::Error {
+        log::set_max_level(LevelFilter::Error);
+    }
+}
+
+/// Drops the messages logged by the current thread
+pub fn clear() {
+    let id = thread::current().id();
+    MESSAGES.lock().unwrap().retain(|(thread_id, _)| *thread_id != id);
+}
+
+/// Messages logged by the current thread
+pub fn messages() -> Vec<String> {
+    let id = thread::current().id();
+    MESSAGES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(thread_id, _)| *thread_id == id)
+        .map(|(_, message)| message.clone())
+        .collect()
+}